@@ -0,0 +1,179 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2022 Antonio32A (antonio32a.com) <~@antonio32a.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Fires concurrent mosaic requests at a running server and reports throughput and latency, to reproduce
+//! the burst of identical requests a viral tweet causes:
+//!
+//! ```text
+//! cargo run --release --example loadtest -- --ids a,b,c,d --requests 500 --concurrency 64
+//! ```
+//!
+//! Requests cycle through every combination of `--formats` and `--counts`, using the first N of `--ids`.
+//! By default they are identical, so the server should share one render between them; `--distinct` adds a
+//! unique query parameter to each so every one is rendered on its own.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::stream::{self, StreamExt};
+
+const USAGE: &str = "usage: loadtest --ids <id,id,...> [--url http://127.0.0.1:3030] [--requests 100] \
+                     [--concurrency 16] [--counts 2,3,4] [--formats jpeg] [--distinct]";
+
+struct Args {
+    url: String,
+    ids: Vec<String>,
+    requests: usize,
+    concurrency: usize,
+    counts: Vec<usize>,
+    formats: Vec<String>,
+    distinct: bool,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        url: "http://127.0.0.1:3030".to_string(),
+        ids: vec![],
+        requests: 100,
+        concurrency: 16,
+        counts: vec![2, 3, 4],
+        formats: vec!["jpeg".to_string()],
+        distinct: false,
+    };
+
+    let list = |value: String| value.split(',').map(str::to_string).collect::<Vec<_>>();
+    let number = |value: String| value.parse::<usize>().map_err(|err| format!("{}: {}", value, err));
+
+    let mut raw = std::env::args().skip(1);
+    while let Some(arg) = raw.next() {
+        if arg == "--distinct" {
+            args.distinct = true;
+            continue;
+        }
+        let value = raw.next().ok_or_else(|| USAGE.to_string())?;
+        match arg.as_str() {
+            "--url" => args.url = value.trim_end_matches('/').to_string(),
+            "--ids" => args.ids = list(value),
+            "--requests" => args.requests = number(value)?,
+            "--concurrency" => args.concurrency = number(value)?.max(1),
+            "--counts" => args.counts = list(value).into_iter().map(number).collect::<Result<_, _>>()?,
+            "--formats" => args.formats = list(value),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+
+    if args.counts.iter().any(|count| !(2..=4).contains(count) || *count > args.ids.len()) {
+        return Err(format!("each count must be 2 to 4 and at most the number of ids\n{}", USAGE));
+    }
+    if args.formats.is_empty() || args.counts.is_empty() {
+        return Err(USAGE.to_string());
+    }
+
+    Ok(args)
+}
+
+/// The path of the `index`th request.
+fn request_path(args: &Args, index: usize) -> String {
+    let format = &args.formats[index % args.formats.len()];
+    let count = args.counts[index / args.formats.len() % args.counts.len()];
+    let mut path = format!("{}/{}/1/{}", args.url, format, args.ids[..count].join("/"));
+    if args.distinct {
+        path.push_str(&format!("?loadtest={}", index));
+    }
+    path
+}
+
+/// The latency that `fraction` of the sorted `latencies` are at or under.
+fn percentile(latencies: &[Duration], fraction: f64) -> Duration {
+    let index = ((latencies.len() as f64 * fraction).ceil() as usize).clamp(1, latencies.len());
+    latencies[index - 1]
+}
+
+#[tokio::main]
+async fn main() {
+    let args = match parse_args() {
+        Ok(args) => Arc::new(args),
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(2);
+        }
+    };
+    let client = reqwest::Client::new();
+
+    let start = Instant::now();
+    let results: Vec<(Result<u16, String>, Duration)> = stream::iter(0..args.requests)
+        .map(|index| {
+            let client = client.clone();
+            let path = request_path(&args, index);
+            async move {
+                let sent = Instant::now();
+                let status = match client.get(&path).send().await {
+                    // Time the whole body, as that is when a client actually has the image.
+                    Ok(response) => {
+                        let status = response.status().as_u16();
+                        response.bytes().await.map(|_| status).map_err(|err| err.to_string())
+                    }
+                    Err(err) => Err(err.to_string()),
+                };
+                (status, sent.elapsed())
+            }
+        })
+        .buffer_unordered(args.concurrency)
+        .collect()
+        .await;
+    let elapsed = start.elapsed();
+
+    let mut statuses = BTreeMap::new();
+    for (status, _) in &results {
+        let key = match status {
+            Ok(status) => status.to_string(),
+            Err(_) => "error".to_string(),
+        };
+        *statuses.entry(key).or_insert(0) += 1;
+    }
+    if let Some(err) = results.iter().find_map(|(status, _)| status.as_ref().err()) {
+        eprintln!("first error: {}", err);
+    }
+
+    let mut latencies: Vec<Duration> = results.iter().map(|(_, latency)| *latency).collect();
+    latencies.sort();
+
+    println!(
+        "{} requests in {:.2}s, {:.1} requests/s",
+        results.len(),
+        elapsed.as_secs_f64(),
+        results.len() as f64 / elapsed.as_secs_f64()
+    );
+    println!("statuses: {:?}", statuses);
+    if !latencies.is_empty() {
+        println!(
+            "latency: p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+            percentile(&latencies, 0.5),
+            percentile(&latencies, 0.9),
+            percentile(&latencies, 0.99),
+            latencies[latencies.len() - 1]
+        );
+    }
+}
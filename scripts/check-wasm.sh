@@ -0,0 +1,24 @@
+#!/bin/sh
+# Type-checks the mosaic module for wasm32-unknown-unknown, which the server itself can't be built for, so the
+# `cfg(target_arch = "wasm32")` code the worker relies on is compiled somewhere. The module only needs a few of
+# the server's dependencies, so it is checked on its own as a throwaway library under target/.
+#
+# Needs the target installed first: rustup target add wasm32-unknown-unknown
+set -eu
+
+root=$(cd "$(dirname "$0")/.." && pwd)
+crate="$root/target/wasm-check"
+mkdir -p "$crate/src"
+
+{
+    printf '[package]\nname = "mosaic-wasm-check"\nversion = "0.0.0"\nedition = "2021"\npublish = false\n\n'
+    printf '[workspace]\n\n[dependencies]\n'
+    grep -E '^(image|lazy_static|serde|serde_json|tracing) =' "$root/Cargo.toml"
+} > "$crate/Cargo.toml"
+
+# The module is pulled in from src/ so its submodules resolve as they do in the server, and re-exported at the
+# root for its `crate::mosaic` paths. Without the server around it, much of it goes unused.
+printf '#![allow(dead_code)]\n\n#[path = "%s/src"]\nmod src {\n    pub mod mosaic;\n}\npub use src::mosaic;\n' \
+    "$root" > "$crate/src/lib.rs"
+
+cargo check --manifest-path "$crate/Cargo.toml" --target wasm32-unknown-unknown "$@"
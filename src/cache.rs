@@ -0,0 +1,168 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2022 Antonio32A (antonio32a.com) <~@antonio32a.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use image::RgbImage;
+
+/// A least-recently-used cache of decoded source images, keyed by the URL they were fetched from.
+///
+/// A capacity of 0 disables the cache entirely.
+pub struct ImageCache {
+    capacity: usize,
+    inner: Mutex<CacheInner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct CacheInner {
+    entries: HashMap<String, RgbImage>,
+    // Front is the least recently used key.
+    order: VecDeque<String>,
+}
+
+impl ImageCache {
+    pub fn new(capacity: usize) -> ImageCache {
+        ImageCache {
+            capacity,
+            inner: Mutex::new(CacheInner::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn get(&self, key: &str) -> Option<RgbImage> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let image = inner.entries.get(key).cloned();
+
+        if image.is_some() {
+            inner.touch(key);
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+
+        image
+    }
+
+    pub fn insert(&self, key: &str, image: RgbImage) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.entries.insert(key.to_string(), image).is_some() {
+            inner.touch(key);
+            return;
+        }
+
+        inner.order.push_back(key.to_string());
+
+        while inner.order.len() > self.capacity {
+            if let Some(evicted) = inner.order.pop_front() {
+                tracing::trace!("evicting {} from image cache", evicted);
+                inner.entries.remove(&evicted);
+            }
+        }
+    }
+
+    pub fn hit_rate(&self) -> f64 {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let total = hits + self.misses.load(Ordering::Relaxed);
+
+        if total == 0 {
+            0.0
+        } else {
+            hits as f64 / total as f64
+        }
+    }
+}
+
+impl CacheInner {
+    fn touch(&mut self, key: &str) {
+        if let Some(pos) = self.order.iter().position(|k| k == key) {
+            if let Some(k) = self.order.remove(pos) {
+                self.order.push_back(k);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbImage;
+
+    use crate::cache::ImageCache;
+
+    fn image(width: u32) -> RgbImage {
+        RgbImage::new(width, 1)
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = ImageCache::new(2);
+        cache.insert("a", image(1));
+        cache.insert("b", image(2));
+
+        // Touch "a" so "b" becomes the eviction candidate.
+        assert!(cache.get("a").is_some());
+        cache.insert("c", image(3));
+
+        assert_eq!(cache.get("a").unwrap().width(), 1);
+        assert!(cache.get("b").is_none());
+        assert_eq!(cache.get("c").unwrap().width(), 3);
+    }
+
+    #[test]
+    fn tracks_hit_rate() {
+        let cache = ImageCache::new(4);
+        cache.insert("a", image(1));
+
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("missing").is_none());
+
+        assert_eq!(cache.hit_rate(), 0.5);
+    }
+
+    #[test]
+    fn zero_capacity_disables_cache() {
+        let cache = ImageCache::new(0);
+        cache.insert("a", image(1));
+
+        assert!(cache.get("a").is_none());
+        assert_eq!(cache.hit_rate(), 0.0);
+    }
+}
@@ -0,0 +1,304 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2022 Antonio32A (antonio32a.com) <~@antonio32a.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use image::RgbImage;
+
+use crate::mosaic::{animated_mosaic, mosaic, prepare_image, MosaicOptions};
+use crate::utils::{decode_frames, decode_image, encode_animated_webp, encode_image, EncodeOptions};
+use crate::ImageType;
+
+const USAGE: &str = "usage: mosaic [--uncapped] [--animated] --out <output> <image> <image> [image] [image]";
+
+/// Arguments for building a mosaic from local files, like `mosaic --out result.webp a.png b.png`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CliArgs {
+    pub out: PathBuf,
+    pub inputs: Vec<PathBuf>,
+    /// Keep the mosaic at full resolution instead of shrinking it to fit the server's size cap.
+    pub uncapped: bool,
+    /// Let one animated GIF input keep playing in an animated WebP.
+    pub animated: bool,
+}
+
+/// Parses the process arguments, not including the program name. Returns `None` when there are none,
+/// which means the server should be started instead.
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Option<Result<CliArgs, String>> {
+    let mut args = args.into_iter().peekable();
+    args.peek()?;
+
+    let mut out = None;
+    let mut inputs = vec![];
+    let mut uncapped = false;
+    let mut animated = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--out" | "-o" => match args.next() {
+                Some(path) => out = Some(PathBuf::from(path)),
+                None => return Some(Err(USAGE.to_string())),
+            },
+            "--uncapped" => uncapped = true,
+            "--animated" => animated = true,
+            _ => inputs.push(PathBuf::from(arg)),
+        }
+    }
+
+    Some(match out {
+        Some(out) => Ok(CliArgs {
+            out,
+            inputs,
+            uncapped,
+            animated,
+        }),
+        None => Err(USAGE.to_string()),
+    })
+}
+
+/// Reads the input images, builds a mosaic and writes it out in the format given by the output's extension.
+pub fn run(args: &CliArgs) -> Result<(), String> {
+    let image_type = args
+        .out
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .and_then(ImageType::from_extension)
+        .ok_or_else(|| format!("unsupported output format: {}", args.out.display()))?;
+
+    if !(2..=4).contains(&args.inputs.len()) {
+        return Err(format!("expected 2 to 4 images, got {}\n{}", args.inputs.len(), USAGE));
+    }
+
+    let inputs = args
+        .inputs
+        .iter()
+        .map(|path| {
+            let buf = std::fs::read(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+            let decoded = if args.animated {
+                decode_frames(&buf)
+            } else {
+                decode_image(&buf).map(|image| (vec![image], Duration::ZERO))
+            };
+            decoded.map_err(|err| format!("{}: {}", path.display(), err))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if args.uncapped {
+        tracing::warn!(
+            "building an uncapped mosaic, which holds every image at full resolution and can take \
+             gigabytes of memory for large inputs"
+        );
+    }
+
+    let options = MosaicOptions {
+        uncapped: args.uncapped,
+        ..MosaicOptions::default()
+    };
+
+    let mut animated = inputs.iter().enumerate().filter(|(_, (frames, _))| frames.len() > 1);
+    if let Some((index, _)) = animated.next() {
+        if animated.next().is_some() {
+            return Err("only one input can be animated".to_string());
+        }
+        if !matches!(image_type, ImageType::Webp) {
+            return Err(format!("animated mosaics can only be written as WebP: {}", args.out.display()));
+        }
+        return run_animated(args, inputs, index, &options);
+    }
+
+    let images = inputs.into_iter().map(|(mut frames, _)| frames.remove(0)).collect();
+    let mosaic = mosaic(images, &options);
+    tracing::info!(
+        layout = mosaic.layout.name(),
+        "built {}x{} mosaic",
+        mosaic.image.width(),
+        mosaic.image.height()
+    );
+
+    let encoded = encode_image(mosaic.image, image_type, EncodeOptions::default())
+        .map_err(|err| format!("could not encode image: {}", err))?;
+    std::fs::write(&args.out, encoded).map_err(|err| format!("{}: {}", args.out.display(), err))
+}
+
+/// Builds an animated WebP where the input at `index` plays and the others stay still.
+fn run_animated(
+    args: &CliArgs,
+    inputs: Vec<(Vec<RgbImage>, Duration)>,
+    index: usize,
+    options: &MosaicOptions,
+) -> Result<(), String> {
+    let frame_duration = inputs[index].1;
+    let mut images = vec![];
+    let mut frames = vec![];
+    for (position, (input, _)) in inputs.into_iter().enumerate() {
+        let mut input = input.into_iter().map(|frame| prepare_image(frame, options));
+        images.push(input.next().unwrap());
+        if position == index {
+            frames = input.collect();
+        }
+    }
+
+    let frames = animated_mosaic(images, index, frames, options);
+    let (width, height) = frames[0].dimensions();
+    tracing::info!(frames = frames.len(), "built {}x{} animated mosaic", width, height);
+
+    let encoded = encode_animated_webp(&frames, frame_duration)
+        .map_err(|err| format!("could not encode animation: {}", err))?;
+    std::fs::write(&args.out, encoded).map_err(|err| format!("{}: {}", args.out.display(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use image::{codecs::gif::GifEncoder, Delay, Frame, Rgb, RgbImage};
+
+    use crate::cli::{parse_args, run, CliArgs};
+
+    fn args(args: &[&str]) -> Option<Result<CliArgs, String>> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parses_arguments() {
+        assert_eq!(args(&[]), None);
+        assert_eq!(
+            args(&["a.png", "--out", "result.webp", "b.png"]),
+            Some(Ok(CliArgs {
+                out: PathBuf::from("result.webp"),
+                inputs: vec![PathBuf::from("a.png"), PathBuf::from("b.png")],
+                uncapped: false,
+                animated: false,
+            }))
+        );
+        assert!(matches!(
+            args(&["--uncapped", "-o", "r.png", "a.png", "b.png"]),
+            Some(Ok(CliArgs { uncapped: true, .. }))
+        ));
+        assert!(matches!(
+            args(&["--animated", "-o", "r.webp", "a.gif", "b.png"]),
+            Some(Ok(CliArgs { animated: true, .. }))
+        ));
+        assert!(matches!(args(&["a.png", "b.png"]), Some(Err(_))));
+        assert!(matches!(args(&["a.png", "--out"]), Some(Err(_))));
+    }
+
+    #[test]
+    fn builds_mosaic_from_files() {
+        let dir = std::env::temp_dir().join(format!("mosaic-cli-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let left = dir.join("left.png");
+        let right = dir.join("right.png");
+        RgbImage::from_pixel(100, 400, Rgb([255, 0, 0])).save(&left).unwrap();
+        RgbImage::from_pixel(200, 400, Rgb([0, 0, 255])).save(&right).unwrap();
+        let out = dir.join("result.tiff");
+
+        run(&CliArgs {
+            out: out.clone(),
+            inputs: vec![left, right],
+            uncapped: false,
+            animated: false,
+        })
+        .unwrap();
+
+        let result = image::open(&out).unwrap().into_rgb8();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(result.dimensions(), (310, 400));
+        assert_eq!(*result.get_pixel(50, 200), Rgb([255, 0, 0]));
+        assert_eq!(*result.get_pixel(200, 200), Rgb([0, 0, 255]));
+    }
+
+    #[test]
+    fn builds_animated_mosaic_from_gif() {
+        let dir = std::env::temp_dir().join(format!("mosaic-cli-animated-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let animated = dir.join("animated.gif");
+        let still = dir.join("still.png");
+        let frames = [Rgb([255, 0, 0]), Rgb([0, 255, 0])].map(|colour| {
+            let image = image::DynamicImage::ImageRgb8(RgbImage::from_pixel(100, 100, colour)).into_rgba8();
+            Frame::from_parts(image, 0, 0, Delay::from_numer_denom_ms(50, 1))
+        });
+        let mut gif = vec![];
+        GifEncoder::new(&mut gif).encode_frames(frames).unwrap();
+        std::fs::write(&animated, gif).unwrap();
+        RgbImage::from_pixel(100, 100, Rgb([0, 0, 255])).save(&still).unwrap();
+        let out = |path: &str| dir.join(path);
+
+        let built = run(&CliArgs {
+            out: out("result.webp"),
+            inputs: vec![animated.clone(), still.clone()],
+            uncapped: false,
+            animated: true,
+        });
+        let encoded = std::fs::read(out("result.webp"));
+        let not_webp = run(&CliArgs {
+            out: out("result.png"),
+            inputs: vec![animated.clone(), still],
+            uncapped: false,
+            animated: true,
+        });
+        let two_animated = run(&CliArgs {
+            out: out("result.webp"),
+            inputs: vec![animated.clone(), animated],
+            uncapped: false,
+            animated: true,
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        built.unwrap();
+        let encoded = encoded.unwrap();
+        assert_eq!(&encoded[8..12], b"WEBP");
+        assert!(encoded.windows(4).any(|chunk| chunk == b"ANIM"));
+        assert!(not_webp.is_err());
+        assert!(two_animated.is_err());
+    }
+
+    #[test]
+    fn rejects_bad_invocations() {
+        let out = |path: &str| PathBuf::from(path);
+        let inputs = vec![PathBuf::from("a.png"), PathBuf::from("b.png")];
+
+        assert!(run(&CliArgs {
+            out: out("result.gif"),
+            inputs: inputs.clone(),
+            uncapped: false,
+            animated: false,
+        })
+        .is_err());
+        assert!(run(&CliArgs {
+            out: out("result.png"),
+            inputs: inputs[..1].to_vec(),
+            uncapped: false,
+            animated: false,
+        })
+        .is_err());
+        assert!(run(&CliArgs {
+            out: out("result.png"),
+            inputs: vec![PathBuf::from("/nonexistent/a.png"); 2],
+            uncapped: false,
+            animated: false,
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,180 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2022 Antonio32A (antonio32a.com) <~@antonio32a.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use futures::future::{BoxFuture, FutureExt, WeakShared};
+
+/// Lets identical concurrent requests share a single computation.
+///
+/// The first caller for a key starts the computation, and everyone who asks for the same key while it is
+/// running awaits that same result. Nothing is kept once it finishes, so results (including errors) are
+/// never served to requests that arrive afterwards.
+pub struct Coalescer<T: Clone> {
+    /// Weak, so a computation whose requests were all cancelled is dropped rather than kept alive here.
+    in_flight: Mutex<HashMap<String, WeakShared<BoxFuture<'static, T>>>>,
+}
+
+impl<T: Clone + Send + Sync + 'static> Coalescer<T> {
+    pub fn new() -> Coalescer<T> {
+        Coalescer {
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn run<F>(self: &Arc<Self>, key: String, compute: F) -> T
+    where
+        F: Future<Output = T> + Send + 'static,
+    {
+        let future = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+
+            match in_flight.get(&key).and_then(WeakShared::upgrade) {
+                Some(future) => {
+                    tracing::debug!("joining in-flight request for {}", key);
+                    future
+                }
+                None => {
+                    let entry = Entry {
+                        coalescer: self.clone(),
+                        key: key.clone(),
+                    };
+                    // Remove the entry from inside the computation, so it goes away even if the request
+                    // that started it was cancelled and another waiter finished driving it.
+                    let future = async move {
+                        let result = compute.await;
+                        entry.coalescer.in_flight.lock().unwrap().remove(&entry.key);
+                        result
+                    }
+                    .boxed()
+                    .shared();
+
+                    // It hasn't been polled yet, so it can't have finished.
+                    in_flight.insert(key, future.downgrade().unwrap());
+                    future
+                }
+            }
+        };
+
+        future.await
+    }
+}
+
+/// A computation's place in `in_flight`, which it takes with it if it is dropped before finishing, when every
+/// request waiting on it was cancelled.
+struct Entry<T: Clone> {
+    coalescer: Arc<Coalescer<T>>,
+    key: String,
+}
+
+impl<T: Clone> Drop for Entry<T> {
+    fn drop(&mut self) {
+        let mut in_flight = self.coalescer.in_flight.lock().unwrap();
+        // A new computation may have taken the key over already, in which case it stays.
+        let current = in_flight.get(&self.key).and_then(WeakShared::upgrade);
+        if current.is_none() {
+            in_flight.remove(&self.key);
+        }
+        // Dropping `current` could drop the last handle on that computation, whose own entry needs the lock.
+        drop(in_flight);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::coalesce::Coalescer;
+
+    fn counted(calls: &Arc<AtomicUsize>, result: Result<u32, String>) -> impl std::future::Future<Output = Result<u32, String>> {
+        let calls = calls.clone();
+        async move {
+            calls.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            result
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_share_one_computation() {
+        let coalescer = Arc::new(Coalescer::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let (a, b) = tokio::join!(
+            coalescer.run("key".to_string(), counted(&calls, Ok(1))),
+            coalescer.run("key".to_string(), counted(&calls, Ok(2))),
+        );
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(a, Ok(1));
+        assert_eq!(b, Ok(1));
+    }
+
+    #[tokio::test]
+    async fn errors_reach_every_waiter_but_are_not_kept() {
+        let coalescer = Arc::new(Coalescer::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let (a, b) = tokio::join!(
+            coalescer.run("key".to_string(), counted(&calls, Err("failed".to_string()))),
+            coalescer.run("key".to_string(), counted(&calls, Ok(2))),
+        );
+        assert_eq!(a, Err("failed".to_string()));
+        assert_eq!(b, Err("failed".to_string()));
+
+        let c = coalescer.run("key".to_string(), counted(&calls, Ok(3))).await;
+        assert_eq!(c, Ok(3));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn different_keys_compute_separately() {
+        let coalescer = Arc::new(Coalescer::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let (a, b) = tokio::join!(
+            coalescer.run("a".to_string(), counted(&calls, Ok(1))),
+            coalescer.run("b".to_string(), counted(&calls, Ok(2))),
+        );
+
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!((a, b), (Ok(1), Ok(2)));
+    }
+
+    #[tokio::test]
+    async fn cancelled_computations_are_forgotten() {
+        let coalescer = Arc::new(Coalescer::<Result<u32, String>>::new());
+
+        let never = coalescer.run("key".to_string(), std::future::pending());
+        assert!(tokio::time::timeout(Duration::from_millis(10), never).await.is_err());
+
+        assert!(coalescer.in_flight.lock().unwrap().is_empty());
+        let calls = Arc::new(AtomicUsize::new(0));
+        assert_eq!(coalescer.run("key".to_string(), counted(&calls, Ok(1))).await, Ok(1));
+    }
+}
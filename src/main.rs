@@ -22,102 +22,1090 @@
  * SOFTWARE.
  */
 
+use std::borrow::Cow;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use axum::{
-    extract::Path, http::StatusCode, response::IntoResponse, routing::get, Extension, Router,
+    extract::{rejection::PathRejection, Path, Query},
+    http::{header, HeaderMap, HeaderValue, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use bytes::Bytes;
+use image::{ImageError, Rgb, RgbImage};
+use serde::{de::Error, Deserialize, Deserializer, Serialize};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::JoinError,
 };
-use serde::Deserialize;
 use tracing::instrument;
 
-use crate::mosaic::mosaic;
-use crate::utils::{fetch_image, image_response};
+use crate::cache::ImageCache;
+use crate::coalesce::Coalescer;
+use crate::mosaic::{
+    mosaic_prepared, mosaic_tiles, plan_mosaic, prepare_image, slideshow_frames, Anchor,
+    BackgroundMode, DebugOverlay, Direction, FitMode, Flip, FocalPoint, ImageOffset, LayoutMode,
+    Mosaic, MosaicOptions, MosaicStyle, NativeImage, ResizeFilter, ResizeFilters, Size, Spacing,
+    MAX_SIZE,
+};
+use crate::utils::{
+    animation_response, can_stream, content_type, decode_inline_image, deserialize_aspect_ratios,
+    deserialize_flips, deserialize_focal_points, deserialize_hex_colour, deserialize_opacities,
+    deserialize_scales, deserialize_size, deserialize_weights, encode_image, fetch_image,
+    image_response, is_valid_image_id, join_partial, negotiate_image_type, placeholder_image,
+    request_hash, streamed_image_response, tiles_response, DownloadBudget, EncodeOptions,
+    PngCompression, PngMode, SourceFormat, MAX_IMAGE_SIZE, MAX_REQUEST_DOWNLOAD_SIZE, TWIMG_MEDIA_URL,
+};
 
+mod cache;
+mod cli;
+mod coalesce;
 mod mosaic;
 mod utils;
 
+/// The fewest images a mosaic is made of, so the most a partial mosaic has to wait for.
+const MIN_MOSAIC_IMAGES: usize = 2;
+/// The most images a mosaic can be made of, counting inline ones.
+const MAX_MOSAIC_IMAGES: usize = 4;
+/// How long each image of a slideshow is shown for when `frame_ms` isn't given.
+const DEFAULT_FRAME_MS: u64 = 2000;
+/// How many mosaics are built at once when `MAX_CONCURRENT_REQUESTS` isn't set.
+const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 64;
+/// How long shed requests are told to wait before trying again, in seconds.
+const RETRY_AFTER_SECS: u32 = 1;
+
 #[derive(Debug, Deserialize)]
+struct RequestPath {
+    image_type: RequestedType,
+    image_ids: String,
+}
+
+#[derive(Debug)]
 struct HandlePath {
     image_type: ImageType,
     image_ids: String,
 }
 
+/// The format asked for in the path. `auto` picks one from the formats the `Accept` header lists.
+#[derive(Copy, Clone, Debug)]
+enum RequestedType {
+    Auto,
+    Exact(ImageType),
+}
+
+impl<'de> Deserialize<'de> for RequestedType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let name = String::deserialize(deserializer)?;
+        if name == "auto" {
+            return Ok(RequestedType::Auto);
+        }
+
+        ImageType::ALL
+            .into_iter()
+            .find(|image_type| image_type.name() == name)
+            .map(RequestedType::Exact)
+            .ok_or_else(|| D::Error::custom(format!("unknown image type: {}", name)))
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct HandleQuery {
+    mode: RenderMode,
+    /// How long each slideshow frame is shown, in milliseconds.
+    frame_ms: Option<u64>,
+    /// The formats a manifest holds, comma separated. Empty means just the format in the path.
+    #[serde(deserialize_with = "deserialize_image_types")]
+    formats: Vec<ImageType>,
+    order: ImageOrder,
+    style: MosaicStyle,
+    fit: FitMode,
+    layout: LayoutMode,
+    /// Gutter between images side by side, in pixels.
+    column_gap: Option<u32>,
+    /// Gutter between images stacked on top of each other, in pixels.
+    row_gap: Option<u32>,
+    /// Border of background around the whole mosaic, in pixels.
+    margin: u32,
+    native: NativeImage,
+    anchor: Anchor,
+    dir: Direction,
+    /// Never render any image bigger than its native size.
+    no_upscale: bool,
+    /// Respond to failures with a small placeholder image instead of a text body.
+    placeholder: bool,
+    border: u32,
+    #[serde(deserialize_with = "deserialize_hex_colour")]
+    border_color: Option<Rgb<u8>>,
+    /// Text for a bar below the mosaic. Only ASCII is drawn; anything else shows up as `?`.
+    caption: Option<String>,
+    #[serde(deserialize_with = "deserialize_hex_colour")]
+    caption_color: Option<Rgb<u8>>,
+    /// How much to brighten, or with a negative value darken, the finished mosaic by.
+    brightness: Option<i32>,
+    /// The gamma to correct the finished mosaic with. Above 1 brightens the midtones, below 1 darkens them.
+    gamma: Option<f32>,
+    /// The format source images are requested from twimg in, JPEG unless `?source=png` is given.
+    source: Option<SourceFormat>,
+    max_aspect: Option<f32>,
+    /// Whether to crop images towards the median aspect ratio of them all.
+    shared_aspect: bool,
+    /// Per-image corrected aspect ratios, in the order images are laid out in.
+    #[serde(deserialize_with = "deserialize_aspect_ratios")]
+    aspect: Vec<Option<f32>>,
+    /// Per-image intrinsic scales, in the order images are laid out in.
+    #[serde(deserialize_with = "deserialize_scales")]
+    scale: Vec<f32>,
+    /// Per-image opacities, in the order images are laid out in.
+    #[serde(deserialize_with = "deserialize_opacities")]
+    opacity: Vec<f32>,
+    trim: bool,
+    equalize: bool,
+    bleed: u32,
+    bg: BackgroundMode,
+    /// How blurry `bg=blur` backgrounds are, in pixels.
+    blurradius: Option<f32>,
+    png: PngMode,
+    png_compression: PngCompression,
+    /// Check the encoded output parses before sending it, failing with a 500 if it doesn't.
+    validate: bool,
+    /// Send the encoded image while it's still being encoded, for the formats that allow it.
+    stream: bool,
+    /// The most bytes a JPEG or WebP may be. Its quality is lowered as far as needed to fit.
+    maxbytes: Option<usize>,
+    upscale: Option<ResizeFilter>,
+    downscale: Option<ResizeFilter>,
+    /// The share of the top row the first of two images over a third gets, like 0.5.
+    top_split: Option<f32>,
+    /// Whether all four cells of a 2x2 mosaic are the same size.
+    equal: bool,
+    max_columns: Option<u32>,
+    max_rows: Option<u32>,
+    /// How much less uniformly scaled than the best layout a squarer one may be.
+    tolerance: Option<f32>,
+    /// Which candidate layout to use, 1 being the best.
+    rank: Option<usize>,
+    /// Per-image weights, in the order images are laid out in.
+    #[serde(deserialize_with = "deserialize_weights")]
+    weights: Vec<f32>,
+    /// Milliseconds after which to stop waiting for slow downloads, if enough images have arrived.
+    partial_ms: Option<u64>,
+    /// Milliseconds after which to give up on the whole request, downloads, retries and all.
+    deadline_ms: Option<u64>,
+    #[serde(deserialize_with = "deserialize_size")]
+    canvas: Option<Size>,
+    /// Per-image focal points for layouts that crop, in the order images are laid out in.
+    #[serde(deserialize_with = "deserialize_focal_points")]
+    focus: Vec<FocalPoint>,
+    /// Per-image flips, in the order images are laid out in.
+    #[serde(deserialize_with = "deserialize_flips")]
+    flip: Vec<Flip>,
+    debug: DebugOverlay,
+}
+
+impl HandleQuery {
+    /// The mosaic options these parameters ask for. The per-image lists are moved out rather than copied.
+    fn mosaic_options(&mut self) -> MosaicOptions {
+        let defaults = MosaicOptions::default();
+        MosaicOptions {
+            style: self.style,
+            fit: self.fit,
+            layout: self.layout,
+            spacing: Spacing {
+                horizontal: self.column_gap.unwrap_or(defaults.spacing.horizontal),
+                vertical: self.row_gap.unwrap_or(defaults.spacing.vertical),
+            },
+            margin: self.margin,
+            native: self.native,
+            anchor: self.anchor,
+            direction: self.dir,
+            no_upscale: self.no_upscale,
+            border_width: self.border,
+            border_colour: self.border_color.unwrap_or(defaults.border_colour),
+            max_aspect_ratio: self.max_aspect,
+            shared_aspect: self.shared_aspect,
+            aspect_ratios: std::mem::take(&mut self.aspect),
+            scales: std::mem::take(&mut self.scale),
+            opacities: std::mem::take(&mut self.opacity),
+            trim: self.trim,
+            equalize: self.equalize,
+            bleed: self.bleed,
+            background: self.bg,
+            blur_radius: self
+                .blurradius
+                .filter(|radius| radius.is_finite())
+                .unwrap_or(defaults.blur_radius),
+            filters: ResizeFilters {
+                upscale: self.upscale.unwrap_or(defaults.filters.upscale),
+                downscale: self.downscale.unwrap_or(defaults.filters.downscale),
+            },
+            top_split: self.top_split,
+            equal: self.equal,
+            max_columns: self.max_columns,
+            max_rows: self.max_rows,
+            scale_tolerance: self
+                .tolerance
+                .filter(|tolerance| tolerance.is_finite())
+                .map_or(defaults.scale_tolerance, |tolerance| tolerance.max(0.0)),
+            rank: self.rank.unwrap_or(defaults.rank),
+            weights: std::mem::take(&mut self.weights),
+            canvas: self.canvas,
+            caption: self.caption.take().filter(|caption| !caption.trim().is_empty()),
+            caption_colour: self.caption_color.unwrap_or(defaults.caption_colour),
+            // Badges are passed inline, so `render` decodes them along with the inline images.
+            badge: None,
+            brightness: self.brightness.unwrap_or(defaults.brightness),
+            gamma: self.gamma.unwrap_or(defaults.gamma),
+            focal_points: std::mem::take(&mut self.focus),
+            flips: std::mem::take(&mut self.flip),
+            debug: self.debug,
+            // The server's mosaics always fit in `MAX_SIZE`, so a URL can't ask for unbounded memory.
+            uncapped: false,
+        }
+    }
+}
+
+/// How image IDs are ordered before being laid out.
+#[derive(Copy, Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ImageOrder {
+    /// Keep the order the IDs were given in the path.
+    #[default]
+    Path,
+    /// Sort the IDs alphabetically, so the same set of IDs always produces the same mosaic.
+    Sorted,
+}
+
+/// What kind of image is built from the inputs.
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum RenderMode {
+    /// All images composited into one still image.
+    #[default]
+    Mosaic,
+    /// An animated WebP showing one image at a time.
+    Slideshow,
+    /// Each image scaled to its place in the mosaic, sent separately as the parts of a multipart body.
+    Tiles,
+    /// The mosaic encoded in each of `formats`, sent together inline in a JSON manifest.
+    Manifest,
+}
+
 #[derive(Copy, Clone, Debug, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ImageType {
     Webp,
     Png,
     Jpeg,
+    Bmp,
+    Tiff,
+    /// Lossless JPEG XL, for clients that can show it.
+    #[cfg(feature = "jxl")]
+    Jxl,
+}
+
+impl ImageType {
+    const ALL: [ImageType; 5 + cfg!(feature = "jxl") as usize] = [
+        ImageType::Webp,
+        ImageType::Png,
+        ImageType::Jpeg,
+        ImageType::Bmp,
+        ImageType::Tiff,
+        #[cfg(feature = "jxl")]
+        ImageType::Jxl,
+    ];
+
+    /// The name of the format in the path, as `HandlePath` deserializes it.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ImageType::Webp => "webp",
+            ImageType::Png => "png",
+            ImageType::Jpeg => "jpeg",
+            ImageType::Bmp => "bmp",
+            ImageType::Tiff => "tiff",
+            #[cfg(feature = "jxl")]
+            ImageType::Jxl => "jxl",
+        }
+    }
+
+    /// Picks the output format for a file extension, like `webp` or `jpg`.
+    pub fn from_extension(extension: &str) -> Option<ImageType> {
+        match extension.to_ascii_lowercase().as_str() {
+            "webp" => Some(ImageType::Webp),
+            "png" => Some(ImageType::Png),
+            "jpg" | "jpeg" => Some(ImageType::Jpeg),
+            "bmp" => Some(ImageType::Bmp),
+            "tif" | "tiff" => Some(ImageType::Tiff),
+            #[cfg(feature = "jxl")]
+            "jxl" => Some(ImageType::Jxl),
+            _ => None,
+        }
+    }
+
+    /// The usual file extension for the format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageType::Webp => "webp",
+            ImageType::Png => "png",
+            ImageType::Jpeg => "jpg",
+            ImageType::Bmp => "bmp",
+            ImageType::Tiff => "tiff",
+            #[cfg(feature = "jxl")]
+            ImageType::Jxl => "jxl",
+        }
+    }
+
+    /// Whether the format is compressed already. BMP and TIFF are written as raw pixels, which a
+    /// compression layer can still make much smaller.
+    pub fn is_compressed(&self) -> bool {
+        !matches!(self, ImageType::Bmp | ImageType::Tiff)
+    }
+}
+
+/// Deserializes a comma separated list of format names as they appear in the path, like `webp,jpeg`.
+fn deserialize_image_types<'de, D>(deserializer: D) -> Result<Vec<ImageType>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let names = String::deserialize(deserializer)?;
+    names
+        .split(',')
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            ImageType::ALL
+                .into_iter()
+                .find(|image_type| image_type.name() == name)
+                .ok_or_else(|| D::Error::custom(format!("unknown image type: {}", name)))
+        })
+        .collect()
+}
+
+/// Bounds how many encodes run at once, so a burst of slow encodes can't take over the blocking
+/// threads that the mosaic stage of other requests needs.
+#[derive(Clone)]
+struct EncodePool(Arc<Semaphore>);
+
+/// Bounds how many requests are rendered at once. Requests beyond it are turned away with a 503 instead of
+/// queueing, so a spike can't pile up downloads and decoded images until memory runs out.
+#[derive(Clone)]
+struct RequestLimit(Arc<Semaphore>);
+
+/// A request's place under `RequestLimit`. The blocking work a request starts holds on to a clone, so a request
+/// that gives up at its deadline still counts until that work is done.
+#[derive(Clone)]
+struct RequestPermit(#[allow(dead_code)] Arc<OwnedSemaphorePermit>);
+
+/// The base URL source images are downloaded from.
+#[derive(Clone)]
+struct Upstream(Arc<str>);
+
+/// Describes why a blocking task failed, including the panic message if it panicked.
+fn join_error_message(err: JoinError) -> String {
+    if !err.is_panic() {
+        return err.to_string();
+    }
+
+    let payload = err.into_panic();
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        format!("panicked: {}", message)
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        format!("panicked: {}", message)
+    } else {
+        "panicked with a non-string payload".to_string()
+    }
+}
+
+/// Formats phase durations as a `Server-Timing` header, so clients can see where a request's time went.
+fn server_timing(phases: &[(&str, Duration)]) -> HeaderValue {
+    let value = phases
+        .iter()
+        .map(|(name, duration)| format!("{};dur={}", name, duration.as_millis()))
+        .collect::<Vec<_>>()
+        .join(", ");
+    // Phase names are static identifiers and durations are digits, so this is always a valid header value.
+    HeaderValue::from_str(&value).unwrap()
 }
 
-#[instrument(skip(path, client))]
+/// Formats a region as an `x,y,width,height` rectangle.
+fn format_region(region: &ImageOffset) -> String {
+    format!(
+        "{},{},{},{}",
+        region.offset.width, region.offset.height, region.dimensions.width, region.dimensions.height
+    )
+}
+
+/// Formats where each image ended up as `x,y,width,height` rectangles separated by `;`, in input order.
+fn regions_header(regions: &[ImageOffset]) -> HeaderValue {
+    let value = regions.iter().map(format_region).collect::<Vec<_>>().join(";");
+    // Only digits and separators, so this is always a valid header value.
+    HeaderValue::from_str(&value).unwrap()
+}
+
+/// Sets `X-Image-Count` to how many images made it into the response, after any failed downloads were
+/// dropped, and for laid out images `X-Layout-Family` to which set of layouts it was picked from.
+fn insert_count_headers(headers: &mut HeaderMap, count: usize, laid_out: bool) {
+    headers.insert("X-Image-Count", HeaderValue::from(count));
+    if laid_out {
+        let family = match count {
+            2 => "twos",
+            3 => "threes",
+            _ => "fours",
+        };
+        headers.insert("X-Layout-Family", HeaderValue::from_static(family));
+    }
+}
+
+/// Builds an error response. If `placeholder` is set, the body is a placeholder image in that format and
+/// the error is moved to the `X-Mosaic-Error` header, so clients that expect an image always get one. The
+/// status stays the same either way, so caches don't keep the placeholder as if it were the mosaic.
+fn error_response(
+    status: StatusCode,
+    message: impl Into<Cow<'static, str>>,
+    placeholder: Option<ImageType>,
+) -> Response {
+    let message = message.into();
+    if let Some(image_type) = placeholder {
+        match image_response(placeholder_image(), image_type, EncodeOptions::default()) {
+            Ok(res) => {
+                let mut res = res.into_response();
+                *res.status_mut() = status;
+                // Messages only mention image IDs, which are validated, so are always valid header values.
+                if let Ok(value) = HeaderValue::from_str(&message) {
+                    res.headers_mut().insert("X-Mosaic-Error", value);
+                }
+                return res;
+            }
+            Err(err) => tracing::error!("could not encode placeholder image: {}", err),
+        }
+    }
+
+    (status, message).into_response()
+}
+
+/// A fully buffered response that can be cloned to every request waiting on the same computation.
+#[derive(Clone)]
+struct SharedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl SharedResponse {
+    async fn from_response(response: Response) -> SharedResponse {
+        let (parts, body) = response.into_parts();
+        let body = match hyper::body::to_bytes(body).await {
+            Ok(body) => body,
+            Err(err) => {
+                tracing::error!("could not buffer response body: {}", err);
+
+                return SharedResponse {
+                    status: StatusCode::INTERNAL_SERVER_ERROR,
+                    headers: HeaderMap::new(),
+                    body: Bytes::from_static(b"Response could not be buffered."),
+                };
+            }
+        };
+
+        SharedResponse {
+            status: parts.status,
+            headers: parts.headers,
+            body,
+        }
+    }
+}
+
+impl IntoResponse for SharedResponse {
+    fn into_response(self) -> Response {
+        (self.status, self.headers, self.body).into_response()
+    }
+}
+
+/// Parses the `/:tweet_id/*image_ids.ext` form of the path, where the format is the extension of the last
+/// image ID instead of a segment of its own. The extension can't be `auto`.
+fn extension_path(uri: &Uri) -> Option<RequestPath> {
+    let (_tweet_id, image_ids) = uri.path().trim_start_matches('/').split_once('/')?;
+    let (image_ids, extension) = image_ids.rsplit_once('.')?;
+    if extension.contains('/') {
+        return None;
+    }
+
+    Some(RequestPath {
+        image_type: RequestedType::Exact(ImageType::from_extension(extension)?),
+        image_ids: image_ids.to_string(),
+    })
+}
+
+/// Explains why the path couldn't be parsed. That's almost always an unknown format, which gets a message
+/// listing the supported ones instead of the generic deserialization error.
+fn path_rejection_response(uri: &Uri, rejection: PathRejection) -> Response {
+    let path = uri.path().trim_start_matches('/');
+    // A path with an extension asked for its format with that instead of the first segment.
+    let image_type = match path.rsplit_once('.') {
+        Some((_, extension)) if !extension.contains('/') => extension,
+        _ => path.split('/').next().unwrap_or_default(),
+    };
+    if image_type == "auto" || ImageType::ALL.iter().any(|supported| supported.name() == image_type) {
+        return rejection.into_response();
+    }
+
+    let mut supported: Vec<_> = ImageType::ALL.iter().map(ImageType::name).collect();
+    supported.push("auto");
+    let message = format!(
+        "Unsupported image type {:?}. Supported types are: {}.",
+        image_type,
+        supported.join(", ")
+    );
+    (StatusCode::BAD_REQUEST, message).into_response()
+}
+
+// Axum hands every extractor over as its own argument.
+#[allow(clippy::too_many_arguments)]
 async fn handle(
-    path: Path<HandlePath>,
+    uri: Uri,
+    headers: HeaderMap,
+    path: Result<Path<RequestPath>, PathRejection>,
+    query: Query<HandleQuery>,
     Extension(client): Extension<reqwest::Client>,
-) -> impl IntoResponse {
-    let image_ids: Vec<_> = path
+    Extension(cache): Extension<Arc<ImageCache>>,
+    Extension(encode_pool): Extension<EncodePool>,
+    Extension(upstream): Extension<Upstream>,
+    Extension(request_limit): Extension<RequestLimit>,
+    Extension(coalescer): Extension<Arc<Coalescer<SharedResponse>>>,
+) -> Response {
+    let (requested, image_ids) = match path {
+        Ok(Path(path)) => (path.image_type, path.image_ids),
+        Err(rejection) => match extension_path(&uri) {
+            Some(path) => (path.image_type, path.image_ids),
+            None => return path_rejection_response(&uri, rejection),
+        },
+    };
+    let image_type = match requested {
+        RequestedType::Auto => {
+            negotiate_image_type(headers.get(header::ACCEPT).and_then(|accept| accept.to_str().ok()))
+        }
+        RequestedType::Exact(image_type) => image_type,
+    };
+    // Tiles are sent as a multipart body and manifests as JSON rather than a single image, so there's
+    // nothing to name.
+    let named = !matches!(query.mode, RenderMode::Tiles | RenderMode::Manifest);
+    let path = Path(HandlePath {
+        image_type,
+        image_ids,
+    });
+
+    let inline_data = inline_data(uri.query());
+    let filename = format!(
+        "inline; filename=\"mosaic-{:016x}.{}\"",
+        request_hash(
+            path.image_type,
+            &path.image_ids,
+            uri.query(),
+            matches!(query.order, ImageOrder::Sorted)
+        ),
+        path.image_type.extension()
+    );
+
+    let stream = query.stream;
+    let work = async move {
+        // Identical requests share this one's permit, so only requests that do work of their own count.
+        let permit = match request_limit.0.try_acquire_owned() {
+            Ok(permit) => RequestPermit(Arc::new(permit)),
+            Err(_err) => {
+                tracing::warn!("shedding request, too many are already running");
+                let response = (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    [(header::RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+                    "Too many requests are being handled, try again shortly.",
+                );
+                return response.into_response();
+            }
+        };
+        let deadline = query.deadline_ms.map(Duration::from_millis);
+        let placeholder = query.placeholder.then(|| path.image_type);
+        let rendering =
+            render(path, query, inline_data, client, cache, upstream, encode_pool, permit);
+        let mut response = match deadline {
+            Some(deadline) => match tokio::time::timeout(deadline, rendering).await {
+                Ok(response) => response,
+                Err(_elapsed) => {
+                    tracing::warn!(deadline_ms = deadline.as_millis(), "request missed its deadline");
+                    return error_response(
+                        StatusCode::GATEWAY_TIMEOUT,
+                        "The mosaic couldn't be made in time.",
+                        placeholder,
+                    );
+                }
+            },
+            None => rendering.await,
+        };
+        // Placeholders stand in for a failure, so they shouldn't be saved under the mosaic's name.
+        let failed = response.headers().contains_key("X-Mosaic-Error");
+        if named && response.status().is_success() && !failed {
+            // The hash is hex and the extension is static, so this is always a valid header value.
+            response
+                .headers_mut()
+                .insert(header::CONTENT_DISPOSITION, HeaderValue::from_str(&filename).unwrap());
+        }
+        if let RequestedType::Auto = requested {
+            response
+                .headers_mut()
+                .insert(header::VARY, HeaderValue::from_static("Accept"));
+        }
+        response
+    };
+
+    // Sharing a response means buffering all of it, which is what streaming is meant to avoid.
+    if stream {
+        return work.await;
+    }
+
+    // Identical requests made while one is already running just wait for its result. With `auto`, the same
+    // URL can produce different formats, so the format is part of what makes requests identical.
+    let key = format!("{} {}", image_type.name(), uri);
+    coalescer
+        .run(key, async move { SharedResponse::from_response(work.await).await })
+        .await
+        .into_response()
+}
+
+/// One image's rectangle in a plan, in pixels from the top left of the mosaic.
+#[derive(Serialize)]
+struct PlanRegion {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl From<&ImageOffset> for PlanRegion {
+    fn from(offset: &ImageOffset) -> Self {
+        PlanRegion {
+            x: offset.offset.width,
+            y: offset.offset.height,
+            width: offset.dimensions.width,
+            height: offset.dimensions.height,
+        }
+    }
+}
+
+/// The layout `plan` picked for a list of sizes, as `render` would lay out images of those sizes.
+#[derive(Serialize)]
+struct PlanResponse {
+    layout: &'static str,
+    width: u32,
+    height: u32,
+    /// Where each image goes, in the order the sizes were given in.
+    regions: Vec<PlanRegion>,
+}
+
+/// Lays out images of the posted sizes without any images involved, for clients that draw the mosaic
+/// themselves. Takes the same layout parameters as a mosaic, so the margin, caption and canvas are planned
+/// for too; ones that only change pixels, like `bleed`, don't affect the plan.
+async fn plan(Query(mut query): Query<HandleQuery>, Json(sizes): Json<Vec<Size>>) -> Response {
+    if !(MIN_MOSAIC_IMAGES..=MAX_MOSAIC_IMAGES).contains(&sizes.len()) {
+        return (StatusCode::BAD_REQUEST, "Plans take between 2 and 4 sizes.").into_response();
+    }
+    if sizes.iter().any(|size| size.width == 0 || size.height == 0) {
+        return (StatusCode::BAD_REQUEST, "Sizes can't be empty.").into_response();
+    }
+    // No image that big could be downloaded, so there's no mosaic of it to plan.
+    if sizes.iter().any(|size| size.width as u64 * size.height as u64 > MAX_IMAGE_SIZE as u64) {
+        return (StatusCode::BAD_REQUEST, "Sizes can't be over 10 megapixels.").into_response();
+    }
+
+    let plan = plan_mosaic(&sizes, &query.mosaic_options());
+    let regions = plan.images.iter().map(PlanRegion::from).collect();
+
+    Json(PlanResponse {
+        layout: plan.layout.name(),
+        width: plan.total_size.width,
+        height: plan.total_size.height,
+        regions,
+    })
+    .into_response()
+}
+
+/// The base64 images passed in the query, which are kept out of `HandleQuery` so they aren't logged.
+#[derive(Default)]
+struct InlineData {
+    /// Images to lay out, from repeated `data` parameters, which `HandleQuery` couldn't hold anyway.
+    images: Vec<String>,
+    /// The `badge` to draw over the mosaic.
+    badge: Option<String>,
+}
+
+fn inline_data(query: Option<&str>) -> InlineData {
+    let pairs: Vec<(String, String)> = query
+        .and_then(|query| serde_urlencoded::from_str(query).ok())
+        .unwrap_or_default();
+
+    let mut inline_data = InlineData::default();
+    for (key, value) in pairs {
+        match key.as_str() {
+            "data" => inline_data.images.push(value),
+            "badge" => inline_data.badge = Some(value),
+            _ => {}
+        }
+    }
+    inline_data
+}
+
+/// Runs `prepare_image` on a blocking thread, so preparing one image overlaps with downloading the rest.
+/// Images are passed straight through when there is nothing to prepare.
+async fn prepare(
+    image: RgbImage,
+    options: Arc<MosaicOptions>,
+    permit: RequestPermit,
+) -> Option<RgbImage> {
+    if !options.prepares() {
+        return Some(image);
+    }
+
+    let span = tracing::Span::current();
+    match tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        span.in_scope(|| prepare_image(image, &options))
+    })
+    .await
+    {
+        Ok(image) => Some(image),
+        Err(err) => {
+            tracing::error!("prepare task failed: {}", join_error_message(err));
+            None
+        }
+    }
+}
+
+// Like `handle`'s, these are everything a request needs to reach.
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip(path, inline_data, client, cache, upstream, encode_pool, permit))]
+async fn render(
+    path: Path<HandlePath>,
+    Query(mut query): Query<HandleQuery>,
+    inline_data: InlineData,
+    client: reqwest::Client,
+    cache: Arc<ImageCache>,
+    upstream: Upstream,
+    encode_pool: EncodePool,
+    permit: RequestPermit,
+) -> Response {
+    let mut image_ids: Vec<_> = path
         .image_ids
         .split('/')
         .filter(|image_id| !image_id.is_empty())
+        .filter(|image_id| {
+            let valid = is_valid_image_id(image_id);
+            if !valid {
+                tracing::warn!("skipping invalid image id: {:?}", image_id);
+            }
+            valid
+        })
         .collect();
 
-    tracing::info!(image_type = ?path.image_type, "given image ids: {}", image_ids.join(", "));
+    if let ImageOrder::Sorted = query.order {
+        image_ids.sort_unstable();
+    }
+
+    let placeholder = query.placeholder.then(|| path.image_type);
+
+    tracing::info!(
+        image_type = ?path.image_type,
+        inline = inline_data.images.len(),
+        "given image ids: {}",
+        image_ids.join(", ")
+    );
+
+    if image_ids.len() + inline_data.images.len() > MAX_MOSAIC_IMAGES {
+        tracing::warn!("too many images were given");
+        return error_response(StatusCode::BAD_REQUEST, "Too many images.", placeholder);
+    }
+
+    if query.mode == RenderMode::Slideshow && !matches!(path.image_type, ImageType::Webp) {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "Slideshows can only be encoded as WebP.",
+            placeholder,
+        );
+    }
+
+    let source_format = query.source.unwrap_or_default();
+
+    let mut options = query.mosaic_options();
+    options.badge = inline_data.badge.as_deref().and_then(|data| decode_inline_image(data, MAX_SIZE));
+    let options = Arc::new(options);
+    let decode_side = options.decode_side();
 
     let start = Instant::now();
-    let images: Vec<_> = futures::future::join_all(
-        image_ids
+    // Inline images are already here, so they skip the download and go after the fetched ones.
+    let inline_images: Vec<_> = futures::future::join_all(
+        inline_data
+            .images
             .iter()
-            .map(|image_id| fetch_image(&client, image_id)),
+            .filter_map(|data| decode_inline_image(data, decode_side))
+            .map(|image| prepare(image, options.clone(), permit.clone())),
     )
     .await
     .into_iter()
     .flatten()
     .collect();
+    // Each image is prepared as soon as it arrives, while the others are still downloading.
+    let budget = DownloadBudget::new(MAX_REQUEST_DOWNLOAD_SIZE);
+    let mut images = join_partial(
+        image_ids.iter().map(|image_id| {
+            let options = options.clone();
+            let permit = permit.clone();
+            let fetch = fetch_image(
+                &client,
+                &cache,
+                &budget,
+                &upstream.0,
+                image_id,
+                source_format,
+                decode_side,
+            );
+            async move { prepare(fetch.await?, options, permit).await }
+        }),
+        MIN_MOSAIC_IMAGES.saturating_sub(inline_images.len()),
+        query.partial_ms.map(Duration::from_millis),
+    )
+    .await;
+    images.extend(inline_images);
     let download_time = start.elapsed();
 
+    if budget.is_exceeded() {
+        tracing::warn!(max_bytes = MAX_REQUEST_DOWNLOAD_SIZE, "images were too large in total");
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "The images are too large in total.",
+            placeholder,
+        );
+    }
+
+    // Dropping an image for its size would otherwise look just like it not existing. It's numbered as it
+    // was given in the path, before invalid IDs were skipped or the rest sorted. Inline images go after
+    // the path's, and are never downloaded, so they don't change the number.
+    let oversized = image_ids.iter().find(|image_id| budget.is_oversized(image_id));
+    let mut given = path.image_ids.split('/').filter(|image_id| !image_id.is_empty());
+    if let Some(index) = oversized.and_then(|oversized| given.position(|id| id == *oversized)) {
+        tracing::warn!(index, "an image was too large");
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            format!("Image {} is too large.", index + 1),
+            placeholder,
+        );
+    }
+
     if images.is_empty() {
         tracing::warn!("no images were found");
-        return (StatusCode::BAD_REQUEST, "No images could be found.").into_response();
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "No images could be found.",
+            placeholder,
+        );
     }
 
+    if query.mode == RenderMode::Slideshow {
+        let frame_duration = Duration::from_millis(query.frame_ms.unwrap_or(DEFAULT_FRAME_MS));
+        return render_slideshow(
+            images,
+            options,
+            frame_duration,
+            placeholder,
+            encode_pool,
+            permit,
+            download_time,
+        )
+        .await;
+    }
+
+    if query.mode == RenderMode::Tiles {
+        let encode_options = EncodeOptions {
+            png: query.png,
+            png_compression: query.png_compression,
+            validate: query.validate,
+            max_bytes: query.maxbytes,
+        };
+        return render_tiles(
+            images,
+            options,
+            path.image_type,
+            encode_options,
+            placeholder,
+            encode_pool,
+            permit,
+            download_time,
+        )
+        .await;
+    }
+
+    let sizes: Vec<_> = images
+        .iter()
+        .map(|image| format!("{}x{}", image.width(), image.height()))
+        .collect();
+
     let span = tracing::Span::current();
 
     let mosaic_start = Instant::now();
-    let image = match tokio::task::spawn_blocking(move || span.in_scope(|| mosaic(images))).await {
-        Ok(image) => image,
+    let held = permit.clone();
+    let mosaic = match tokio::task::spawn_blocking(move || {
+        let _held = held;
+        span.in_scope(|| mosaic_prepared(images, &options))
+    })
+    .await
+    {
+        Ok(mosaic) => mosaic,
         Err(err) => {
-            tracing::error!("could not spawn mosaic task: {}", err);
+            tracing::error!(
+                count = sizes.len(),
+                sizes = %sizes.join(", "),
+                "mosaic task failed: {}",
+                join_error_message(err)
+            );
 
-            return (
+            return error_response(
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "Mosaic task failed to complete.",
-            )
-                .into_response();
+                placeholder,
+            );
         }
     };
     let mosaic_time = mosaic_start.elapsed();
-    let size = format!("{0}x{1}", image.width(), image.height());
 
+    if query.mode == RenderMode::Manifest {
+        let mut formats = std::mem::take(&mut query.formats);
+        if formats.is_empty() {
+            formats.push(path.image_type);
+        }
+        let encode_options = EncodeOptions {
+            png: query.png,
+            png_compression: query.png_compression,
+            validate: query.validate,
+            max_bytes: query.maxbytes,
+        };
+        let timings = (download_time, mosaic_time);
+        return render_manifest(mosaic, formats, encode_options, encode_pool, permit, timings).await;
+    }
+
+    let image = mosaic.image;
+    let layout = mosaic.layout.name();
+    let count = mosaic.regions.len();
+    let regions = regions_header(&mosaic.regions);
+    let (width, height) = image.dimensions();
+    let size = format!("{0}x{1}", width, height);
+
+    let image_type = path.image_type;
+    let encode_options = EncodeOptions {
+        png: query.png,
+        png_compression: query.png_compression,
+        validate: query.validate,
+        max_bytes: query.maxbytes,
+    };
+    if query.stream && can_stream(image_type, encode_options) {
+        // The semaphore is never closed, so acquiring can't fail. The encoder outlives this function, so
+        // it holds on to the permits itself.
+        let queue_start = Instant::now();
+        let permit = (permit, encode_pool.0.acquire_owned().await.unwrap());
+        let queue_time = queue_start.elapsed();
+        let mut streamed =
+            streamed_image_response(image, image_type, encode_options, permit).into_response();
+        let headers = streamed.headers_mut();
+        headers.insert("X-Mosaic-Layout", HeaderValue::from_static(layout));
+        headers.insert("X-Image-Width", HeaderValue::from(width));
+        headers.insert("X-Image-Height", HeaderValue::from(height));
+        headers.insert("X-Image-Regions", regions);
+        insert_count_headers(headers, count, true);
+        // Encoding hasn't finished when the headers are sent, so there's no encode phase to report.
+        headers.insert(
+            "Server-Timing",
+            server_timing(&[
+                ("download", download_time),
+                ("mosaic", mosaic_time),
+                ("queue", queue_time),
+            ]),
+        );
+
+        tracing::info!(
+            layout,
+            download = download_time.as_millis(),
+            mosaic = mosaic_time.as_millis(),
+            "streaming encode with final dimensions: {}",
+            size
+        );
+
+        return streamed;
+    }
+
+    // The semaphore is never closed, so acquiring can't fail. The permits go with the encode, so it still
+    // counts if the request stops waiting for it.
+    let queue_start = Instant::now();
+    let held = (permit, encode_pool.0.acquire_owned().await.unwrap());
+    let queue_time = queue_start.elapsed();
     let encoding_start = Instant::now();
-    let encoded = match image_response(image, path.image_type) {
-        Ok(res) => res.into_response(),
-        Err(err) => {
+    let span = tracing::Span::current();
+    let encoded = tokio::task::spawn_blocking(move || {
+        let _held = held;
+        span.in_scope(|| {
+            image_response(image, image_type, encode_options).map(IntoResponse::into_response)
+        })
+    })
+    .await;
+    let encoding_time = encoding_start.elapsed();
+    let mut encoded = match encoded {
+        Ok(Ok(res)) => res,
+        Ok(Err(err)) => {
             tracing::error!("could not encode image: {}", err);
 
-            return (
+            // Encoding is what failed, so don't try to encode a placeholder either.
+            return error_response(
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "Image could not be encoded.",
-            )
-                .into_response();
+                None,
+            );
+        }
+        Err(err) => {
+            tracing::error!(size, "encode task failed: {}", join_error_message(err));
+
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Encode task failed to complete.",
+                None,
+            );
         }
     };
 
+    encoded
+        .headers_mut()
+        .insert("X-Mosaic-Layout", HeaderValue::from_static(layout));
+    encoded
+        .headers_mut()
+        .insert("X-Image-Width", HeaderValue::from(width));
+    encoded
+        .headers_mut()
+        .insert("X-Image-Height", HeaderValue::from(height));
+    encoded.headers_mut().insert("X-Image-Regions", regions);
+    insert_count_headers(encoded.headers_mut(), count, true);
+    encoded.headers_mut().insert(
+        "Server-Timing",
+        server_timing(&[
+            ("download", download_time),
+            ("mosaic", mosaic_time),
+            ("queue", queue_time),
+            ("encode", encoding_time),
+        ]),
+    );
+
     tracing::info!(
+        layout,
         time = start.elapsed().as_millis(),
         download = download_time.as_millis(),
         mosaic = mosaic_time.as_millis(),
-        encoding = encoding_start.elapsed().as_millis(),
+        encoding = encoding_time.as_millis(),
         "completed encode with final dimensions: {}",
         size
     );
@@ -125,6 +1113,357 @@ async fn handle(
     encoded
 }
 
+/// One encoding of the mosaic in a manifest.
+#[derive(Serialize)]
+struct ManifestImage {
+    format: &'static str,
+    content_type: &'static str,
+    bytes: usize,
+    /// The encoded image, in standard base64.
+    data: String,
+}
+
+/// Every encoding `render_manifest` made of one mosaic, along with its layout.
+#[derive(Serialize)]
+struct ManifestResponse {
+    layout: &'static str,
+    width: u32,
+    height: u32,
+    regions: Vec<PlanRegion>,
+    images: Vec<ManifestImage>,
+}
+
+/// Encodes one finished mosaic in each of `formats` and returns them all in a JSON manifest, so assets can be
+/// pre-generated in several formats without building the mosaic once per format.
+async fn render_manifest(
+    mosaic: Mosaic,
+    formats: Vec<ImageType>,
+    encode_options: EncodeOptions,
+    encode_pool: EncodePool,
+    permit: RequestPermit,
+    (download_time, mosaic_time): (Duration, Duration),
+) -> Response {
+    let layout = mosaic.layout.name();
+    let (width, height) = mosaic.image.dimensions();
+    let count = mosaic.regions.len();
+    let regions = mosaic.regions.iter().map(PlanRegion::from).collect();
+    let image = mosaic.image;
+
+    let queue_start = Instant::now();
+    // The semaphore is never closed, so acquiring can't fail. The encodes share one permit, as they run one
+    // after another.
+    let held = (permit, encode_pool.0.acquire_owned().await.unwrap());
+    let queue_time = queue_start.elapsed();
+    let encoding_start = Instant::now();
+    let span = tracing::Span::current();
+    let encoded = tokio::task::spawn_blocking(move || {
+        let _held = held;
+        span.in_scope(|| {
+            formats
+                .into_iter()
+                .map(|format| {
+                    let encoded = encode_image(image.clone(), format, encode_options)?;
+                    Ok(ManifestImage {
+                        format: format.name(),
+                        content_type: content_type(format),
+                        bytes: encoded.len(),
+                        data: base64::encode_config(&encoded, base64::STANDARD),
+                    })
+                })
+                .collect::<Result<Vec<_>, ImageError>>()
+        })
+    })
+    .await;
+    let encoding_time = encoding_start.elapsed();
+    let images = match encoded {
+        Ok(Ok(images)) => images,
+        Ok(Err(err)) => {
+            tracing::error!("could not encode manifest image: {}", err);
+
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Image could not be encoded.",
+                None,
+            );
+        }
+        Err(err) => {
+            tracing::error!("encode task failed: {}", join_error_message(err));
+
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Encode task failed to complete.",
+                None,
+            );
+        }
+    };
+
+    tracing::info!(
+        layout,
+        formats = images.len(),
+        download = download_time.as_millis(),
+        mosaic = mosaic_time.as_millis(),
+        encoding = encoding_time.as_millis(),
+        "completed manifest with final dimensions: {}x{}",
+        width,
+        height
+    );
+
+    let manifest = ManifestResponse {
+        layout,
+        width,
+        height,
+        regions,
+        images,
+    };
+    let mut response = Json(manifest).into_response();
+    insert_count_headers(response.headers_mut(), count, true);
+    response.headers_mut().insert(
+        "Server-Timing",
+        server_timing(&[
+            ("download", download_time),
+            ("mosaic", mosaic_time),
+            ("queue", queue_time),
+            ("encode", encoding_time),
+        ]),
+    );
+
+    response
+}
+
+/// Builds and encodes a slideshow of `images`, the animated counterpart of the mosaic half of `render`.
+async fn render_slideshow(
+    images: Vec<RgbImage>,
+    options: Arc<MosaicOptions>,
+    frame_duration: Duration,
+    placeholder: Option<ImageType>,
+    encode_pool: EncodePool,
+    permit: RequestPermit,
+    download_time: Duration,
+) -> Response {
+    let count = images.len();
+    let span = tracing::Span::current();
+
+    let frames_start = Instant::now();
+    let held = permit.clone();
+    let frames = match tokio::task::spawn_blocking(move || {
+        let _held = held;
+        span.in_scope(|| slideshow_frames(images, &options))
+    })
+    .await
+    {
+        Ok(frames) => frames,
+        Err(err) => {
+            tracing::error!(count, "slideshow task failed: {}", join_error_message(err));
+
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Slideshow task failed to complete.",
+                placeholder,
+            );
+        }
+    };
+    let frames_time = frames_start.elapsed();
+    let (width, height) = frames[0].dimensions();
+
+    let queue_start = Instant::now();
+    // The semaphore is never closed, so acquiring can't fail.
+    let held = (permit, encode_pool.0.acquire_owned().await.unwrap());
+    let queue_time = queue_start.elapsed();
+    let encoding_start = Instant::now();
+    let span = tracing::Span::current();
+    let encoded = tokio::task::spawn_blocking(move || {
+        let _held = held;
+        span.in_scope(|| {
+            animation_response(&frames, frame_duration).map(IntoResponse::into_response)
+        })
+    })
+    .await;
+    let encoding_time = encoding_start.elapsed();
+    let mut encoded = match encoded {
+        Ok(Ok(res)) => res,
+        Ok(Err(err)) => {
+            tracing::error!("could not encode slideshow: {}", err);
+
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Image could not be encoded.",
+                None,
+            );
+        }
+        Err(err) => {
+            tracing::error!(count, "encode task failed: {}", join_error_message(err));
+
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Encode task failed to complete.",
+                None,
+            );
+        }
+    };
+
+    encoded
+        .headers_mut()
+        .insert("X-Image-Width", HeaderValue::from(width));
+    encoded
+        .headers_mut()
+        .insert("X-Image-Height", HeaderValue::from(height));
+    insert_count_headers(encoded.headers_mut(), count, false);
+    encoded.headers_mut().insert(
+        "Server-Timing",
+        server_timing(&[
+            ("download", download_time),
+            ("frames", frames_time),
+            ("queue", queue_time),
+            ("encode", encoding_time),
+        ]),
+    );
+
+    tracing::info!(
+        count,
+        download = download_time.as_millis(),
+        frames = frames_time.as_millis(),
+        encoding = encoding_time.as_millis(),
+        "completed slideshow with frame size: {}x{}",
+        width,
+        height
+    );
+
+    encoded
+}
+
+/// Lays out `images` and encodes each one as it would appear in the mosaic, without compositing them.
+#[allow(clippy::too_many_arguments)]
+async fn render_tiles(
+    images: Vec<RgbImage>,
+    options: Arc<MosaicOptions>,
+    image_type: ImageType,
+    encode_options: EncodeOptions,
+    placeholder: Option<ImageType>,
+    encode_pool: EncodePool,
+    permit: RequestPermit,
+    download_time: Duration,
+) -> Response {
+    let count = images.len();
+    let span = tracing::Span::current();
+
+    let tiles_start = Instant::now();
+    let held = permit.clone();
+    let tiles = match tokio::task::spawn_blocking(move || {
+        let _held = held;
+        span.in_scope(|| mosaic_tiles(images, &options))
+    })
+    .await
+    {
+        Ok(tiles) => tiles,
+        Err(err) => {
+            tracing::error!(count, "tiles task failed: {}", join_error_message(err));
+
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Tiles task failed to complete.",
+                placeholder,
+            );
+        }
+    };
+    let tiles_time = tiles_start.elapsed();
+    let layout = tiles.layout.name();
+    let regions = regions_header(&tiles.regions);
+    let Size { width, height } = tiles.total_size;
+
+    let queue_start = Instant::now();
+    // The semaphore is never closed, so acquiring can't fail.
+    let held = (permit, encode_pool.0.acquire_owned().await.unwrap());
+    let queue_time = queue_start.elapsed();
+    let encoding_start = Instant::now();
+    let span = tracing::Span::current();
+    let encoded = tokio::task::spawn_blocking(move || {
+        let _held = held;
+        span.in_scope(|| {
+            let parts = tiles.tiles.into_iter().zip(tiles.regions.iter().map(format_region)).collect();
+            tiles_response(parts, image_type, encode_options).map(IntoResponse::into_response)
+        })
+    })
+    .await;
+    let encoding_time = encoding_start.elapsed();
+    let mut encoded = match encoded {
+        Ok(Ok(res)) => res,
+        Ok(Err(err)) => {
+            tracing::error!("could not encode tiles: {}", err);
+
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Image could not be encoded.",
+                None,
+            );
+        }
+        Err(err) => {
+            tracing::error!(count, "encode task failed: {}", join_error_message(err));
+
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Encode task failed to complete.",
+                None,
+            );
+        }
+    };
+
+    encoded
+        .headers_mut()
+        .insert("X-Mosaic-Layout", HeaderValue::from_static(layout));
+    encoded
+        .headers_mut()
+        .insert("X-Image-Width", HeaderValue::from(width));
+    encoded
+        .headers_mut()
+        .insert("X-Image-Height", HeaderValue::from(height));
+    encoded.headers_mut().insert("X-Image-Regions", regions);
+    insert_count_headers(encoded.headers_mut(), count, true);
+    encoded.headers_mut().insert(
+        "Server-Timing",
+        server_timing(&[
+            ("download", download_time),
+            ("tiles", tiles_time),
+            ("queue", queue_time),
+            ("encode", encoding_time),
+        ]),
+    );
+
+    tracing::info!(
+        layout,
+        count,
+        download = download_time.as_millis(),
+        tiles = tiles_time.as_millis(),
+        encoding = encoding_time.as_millis(),
+        "completed tiles for mosaic dimensions: {}x{}",
+        width,
+        height
+    );
+
+    encoded
+}
+
+fn router(
+    client: reqwest::Client,
+    cache: Arc<ImageCache>,
+    upstream: Upstream,
+    encode_pool: EncodePool,
+    request_limit: RequestLimit,
+) -> Router {
+    Router::new()
+        .route("/:image_type/:tweet_id/*image_ids", get(handle))
+        // `/:tweet_id/*image_ids.ext` can't be a route of its own, because it overlaps the one above. Those
+        // paths match either that route or this one instead, and `handle` falls back to parsing them itself.
+        .route("/:image_type/:tweet_id", get(handle))
+        .route("/plan", post(plan))
+        .layer(tower_http::trace::TraceLayer::new_for_http())
+        .layer(Extension(client))
+        .layer(Extension(cache))
+        .layer(Extension(upstream))
+        .layer(Extension(encode_pool))
+        .layer(Extension(request_limit))
+        .layer(Extension(Arc::new(Coalescer::<SharedResponse>::new())))
+}
+
 #[tokio::main]
 async fn main() {
     if std::env::var_os("RUST_LOG").is_none() {
@@ -133,15 +1472,39 @@ async fn main() {
 
     tracing_subscriber::fmt::init();
 
+    if let Some(args) = cli::parse_args(std::env::args().skip(1)) {
+        if let Err(err) = args.and_then(|args| cli::run(&args)) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let client = reqwest::ClientBuilder::default()
         .timeout(Duration::from_secs(5))
         .build()
         .unwrap();
 
-    let app = Router::new()
-        .route("/:image_type/:tweet_id/*image_ids", get(handle))
-        .layer(tower_http::trace::TraceLayer::new_for_http())
-        .layer(Extension(client));
+    let cache_size = std::env::var("IMAGE_CACHE_SIZE")
+        .unwrap_or_else(|_err| "0".to_string())
+        .parse()
+        .expect("IMAGE_CACHE_SIZE was invalid");
+    let cache = Arc::new(ImageCache::new(cache_size));
+
+    let encode_threads = match std::env::var("ENCODE_THREADS") {
+        Ok(threads) => threads.parse().expect("ENCODE_THREADS was invalid"),
+        Err(_err) => std::thread::available_parallelism().map_or(4, |threads| threads.get()),
+    };
+    let encode_pool = EncodePool(Arc::new(Semaphore::new(encode_threads)));
+
+    let max_requests = std::env::var("MAX_CONCURRENT_REQUESTS")
+        .map(|max| max.parse().expect("MAX_CONCURRENT_REQUESTS was invalid"))
+        .unwrap_or(DEFAULT_MAX_CONCURRENT_REQUESTS);
+    let request_limit = RequestLimit(Arc::new(Semaphore::new(max_requests)));
+
+    let upstream = std::env::var("TWIMG_BASE_URL").unwrap_or_else(|_err| TWIMG_MEDIA_URL.to_string());
+    let upstream = Upstream(Arc::from(upstream.trim_end_matches('/')));
+    let app = router(client, cache, upstream, encode_pool, request_limit);
 
     let port = std::env::var("PORT")
         .unwrap_or_else(|_err| "3030".to_string())
@@ -155,3 +1518,535 @@ async fn main() {
         .await
         .unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use axum::{extract::Path, http::StatusCode, response::IntoResponse, routing::get, Router};
+    use image::{ImageFormat, RgbImage};
+    use tokio::sync::Semaphore;
+
+    use crate::cache::ImageCache;
+    use crate::mosaic::{plan_mosaic, MosaicOptions, Spacing};
+    use crate::utils::{encode_image, parse_size, EncodeOptions};
+    use crate::{
+        router, EncodePool, ImageType, RequestLimit, Upstream, DEFAULT_MAX_CONCURRENT_REQUESTS,
+    };
+
+    /// Stands in for twimg: serves a solid PNG sized by its ID, like `200x100`, or a 404.
+    async fn fake_media(Path(id): Path<String>) -> impl IntoResponse {
+        match parse_size(&id) {
+            Some(size) => {
+                let image = RgbImage::from_pixel(size.width, size.height, image::Rgb([255, 0, 0]));
+                let png = encode_image(image, ImageType::Png, EncodeOptions::default()).unwrap();
+                (StatusCode::OK, png).into_response()
+            }
+            None => StatusCode::NOT_FOUND.into_response(),
+        }
+    }
+
+    fn serve(app: Router) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+        addr
+    }
+
+    /// Starts the server against a fake upstream, rendering up to `max_requests` at once, and returns its
+    /// address.
+    fn start_with_limit(max_requests: usize) -> SocketAddr {
+        start_against(Router::new().route("/media/:id", get(fake_media)), max_requests)
+    }
+
+    /// Like `start_with_limit`, with `media` standing in for twimg.
+    fn start_against(media: Router, max_requests: usize) -> SocketAddr {
+        let media = serve(media);
+        let upstream = Upstream(Arc::from(format!("http://{}/media", media)));
+        let encode_pool = EncodePool(Arc::new(Semaphore::new(2)));
+        let request_limit = RequestLimit(Arc::new(Semaphore::new(max_requests)));
+        let client = reqwest::Client::new();
+        serve(router(client, Arc::new(ImageCache::new(0)), upstream, encode_pool, request_limit))
+    }
+
+    fn start() -> SocketAddr {
+        start_with_limit(DEFAULT_MAX_CONCURRENT_REQUESTS)
+    }
+
+    async fn get_mosaic(addr: SocketAddr, path: &str) -> reqwest::Response {
+        reqwest::get(format!("http://{}{}", addr, path)).await.unwrap()
+    }
+
+    fn header<'a>(response: &'a reqwest::Response, name: &str) -> &'a str {
+        response.headers()[name].to_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_a_mosaic() {
+        let addr = start();
+
+        let response = get_mosaic(addr, "/webp/1/200x100/100x200").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, "content-type"), "image/webp");
+        let width: u32 = header(&response, "X-Image-Width").parse().unwrap();
+        let height: u32 = header(&response, "X-Image-Height").parse().unwrap();
+        let sizes = [parse_size("200x100").unwrap(), parse_size("100x200").unwrap()];
+        let plan = plan_mosaic(&sizes, &MosaicOptions::default());
+        assert_eq!((width, height), (plan.total_size.width, plan.total_size.height));
+
+        let body = response.bytes().await.unwrap();
+        let decoded = image::load_from_memory_with_format(&body, ImageFormat::WebP).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (width, height));
+    }
+
+    #[tokio::test]
+    async fn times_the_encode_queue_apart_from_the_encode() {
+        let addr = start();
+
+        let response = get_mosaic(addr, "/jpeg/1/200x100/100x200").await;
+
+        let phases: Vec<_> = header(&response, "Server-Timing")
+            .split(", ")
+            .map(|phase| phase.split(';').next().unwrap())
+            .collect();
+        assert_eq!(phases, ["download", "mosaic", "queue", "encode"]);
+    }
+
+    #[tokio::test]
+    async fn retries_truncated_downloads() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let flaky_media = move |Path(id): Path<String>| {
+            let first = counter.fetch_add(1, Ordering::SeqCst) == 0;
+            async move {
+                let mut response = fake_media(Path(id)).await.into_response();
+                let body = hyper::body::to_bytes(response.body_mut()).await.unwrap();
+                // Only the very first download is cut in half.
+                let body = if first { body.slice(..body.len() / 2) } else { body };
+                (StatusCode::OK, body).into_response()
+            }
+        };
+        let addr = start_against(Router::new().route("/media/:id", get(flaky_media)), 1);
+
+        let response = get_mosaic(addr, "/png/1/200x100/100x200").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        let sizes = [parse_size("200x100").unwrap(), parse_size("100x200").unwrap()];
+        let plan = plan_mosaic(&sizes, &MosaicOptions::default());
+        assert_eq!(header(&response, "X-Image-Width"), plan.total_size.width.to_string());
+    }
+
+    #[tokio::test]
+    async fn gives_up_at_the_deadline() {
+        let slow_media = |Path(id): Path<String>| async move {
+            if id == "300x300" {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+            fake_media(Path(id)).await
+        };
+        let addr = start_against(Router::new().route("/media/:id", get(slow_media)), 1);
+
+        let sent = Instant::now();
+        let late = get_mosaic(addr, "/png/1/200x100/300x300?deadline_ms=200").await;
+        let partial =
+            get_mosaic(addr, "/png/1/200x100/100x200/300x300?deadline_ms=2000&partial_ms=100").await;
+
+        assert_eq!(late.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(partial.status(), StatusCode::OK);
+        assert!(sent.elapsed() < Duration::from_secs(4));
+    }
+
+    #[tokio::test]
+    async fn plans_posted_sizes() {
+        let addr = start();
+        let post = |body: &'static str| {
+            reqwest::Client::new()
+                .post(format!("http://{}/plan?column_gap=4", addr))
+                .header("content-type", "application/json")
+                .body(body)
+                .send()
+        };
+
+        let response = post(r#"[{"width": 200, "height": 100}, {"width": 100, "height": 200}]"#)
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, "content-type"), "application/json");
+        let sizes = [parse_size("200x100").unwrap(), parse_size("100x200").unwrap()];
+        let options = MosaicOptions {
+            spacing: Spacing {
+                horizontal: 4,
+                ..Spacing::default()
+            },
+            ..MosaicOptions::default()
+        };
+        let plan = plan_mosaic(&sizes, &options);
+        let regions: Vec<String> = plan
+            .images
+            .iter()
+            .map(|image| {
+                format!(
+                    r#"{{"x":{},"y":{},"width":{},"height":{}}}"#,
+                    image.offset.width, image.offset.height, image.dimensions.width, image.dimensions.height
+                )
+            })
+            .collect();
+        let expected = format!(
+            r#"{{"layout":"{}","width":{},"height":{},"regions":[{}]}}"#,
+            plan.layout.name(),
+            plan.total_size.width,
+            plan.total_size.height,
+            regions.join(",")
+        );
+        assert_eq!(response.text().await.unwrap(), expected);
+
+        let response = post(r#"[{"width": 200, "height": 100}]"#).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = post(r#"[{"width": 0, "height": 100}, {"width": 100, "height": 200}]"#)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = post(r#"[{"width": 4294967295, "height": 100}, {"width": 100, "height": 200}]"#)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn plans_match_the_render() {
+        let addr = start();
+        let query = "margin=20&caption=Hello";
+
+        let rendered = get_mosaic(addr, &format!("/png/1/200x100/100x200?{}", query)).await;
+        let planned = reqwest::Client::new()
+            .post(format!("http://{}/plan?{}", addr, query))
+            .header("content-type", "application/json")
+            .body(r#"[{"width": 200, "height": 100}, {"width": 100, "height": 200}]"#)
+            .send()
+            .await
+            .unwrap();
+
+        let (width, height) = (header(&rendered, "X-Image-Width"), header(&rendered, "X-Image-Height"));
+        let expected = format!(r#""width":{},"height":{},"#, width, height);
+        assert!(planned.text().await.unwrap().contains(&expected));
+        assert!(header(&rendered, "X-Image-Regions").starts_with("20,20,"));
+    }
+
+    #[tokio::test]
+    async fn plans_fit_the_canvas() {
+        let addr = start();
+        let query = "canvas=600x300&margin=20";
+
+        let rendered = get_mosaic(addr, &format!("/png/1/200x100/100x200?{}", query)).await;
+        let planned = reqwest::Client::new()
+            .post(format!("http://{}/plan?{}", addr, query))
+            .header("content-type", "application/json")
+            .body(r#"[{"width": 200, "height": 100}, {"width": 100, "height": 200}]"#)
+            .send()
+            .await
+            .unwrap();
+        let planned: serde_json::Value = serde_json::from_str(&planned.text().await.unwrap()).unwrap();
+
+        assert_eq!((planned["width"].as_u64(), planned["height"].as_u64()), (Some(600), Some(300)));
+        let regions: Vec<_> = planned["regions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|region| format!("{},{},{},{}", region["x"], region["y"], region["width"], region["height"]))
+            .collect();
+        assert_eq!(header(&rendered, "X-Image-Regions"), regions.join(";"));
+    }
+
+    #[tokio::test]
+    async fn scales_oversized_jpegs_from_their_full_size() {
+        // A JPEG this big would otherwise be shrunk to half its size while decoding.
+        async fn oversized_jpeg() -> impl IntoResponse {
+            let image = RgbImage::from_pixel(8400, 1000, image::Rgb([255, 0, 0]));
+            encode_image(image, ImageType::Jpeg, EncodeOptions::default()).unwrap()
+        }
+        let addr = start_against(
+            Router::new()
+                .route("/media/big", get(oversized_jpeg))
+                .route("/media/:id", get(fake_media)),
+            8,
+        );
+        let query = "scale=4";
+
+        let rendered = get_mosaic(addr, &format!("/png/1/big/1000x1000?{}", query)).await;
+        let planned = reqwest::Client::new()
+            .post(format!("http://{}/plan?{}", addr, query))
+            .header("content-type", "application/json")
+            .body(r#"[{"width": 8400, "height": 1000}, {"width": 1000, "height": 1000}]"#)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(rendered.status(), StatusCode::OK);
+        let (width, height) = (header(&rendered, "X-Image-Width"), header(&rendered, "X-Image-Height"));
+        let expected = format!(r#""width":{},"height":{},"#, width, height);
+        assert!(planned.text().await.unwrap().contains(&expected));
+    }
+
+    #[tokio::test]
+    async fn content_type_follows_format() {
+        let addr = start();
+
+        for (image_type, content_type, format) in [
+            ("png", "image/png", ImageFormat::Png),
+            ("jpeg", "image/jpeg", ImageFormat::Jpeg),
+        ] {
+            let response = get_mosaic(addr, &format!("/{}/1/120x80/80x120", image_type)).await;
+
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(header(&response, "content-type"), content_type);
+            let body = response.bytes().await.unwrap();
+            assert!(image::load_from_memory_with_format(&body, format).is_ok());
+        }
+    }
+
+    #[cfg(feature = "jxl")]
+    #[tokio::test]
+    async fn serves_jpeg_xl() {
+        let addr = start();
+
+        let response = get_mosaic(addr, "/jxl/1/120x80/80x120?validate=true").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, "content-type"), "image/jxl");
+        let width: u32 = header(&response, "X-Image-Width").parse().unwrap();
+        let height: u32 = header(&response, "X-Image-Height").parse().unwrap();
+        let body = response.bytes().await.unwrap();
+        let decoded = jxl_oxide::JxlImage::builder().read(&body[..]).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (width, height));
+        assert!(decoded.render_frame(0).is_ok());
+    }
+
+    #[tokio::test]
+    async fn reports_quality_fit_to_byte_budget() {
+        let addr = start();
+
+        let response = get_mosaic(addr, "/jpeg/1/120x80/80x120?maxbytes=1").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, "X-Image-Quality"), "20");
+
+        let response = get_mosaic(addr, "/jpeg/1/120x80/80x120").await;
+        assert!(response.headers().get("X-Image-Quality").is_none());
+    }
+
+    #[tokio::test]
+    async fn manifests_every_format() {
+        let addr = start();
+
+        let path = "/webp/1/120x80/80x120?mode=manifest&formats=webp,jpeg,png";
+        let response = get_mosaic(addr, path).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, "content-type"), "application/json");
+        assert!(!response.headers().contains_key("content-disposition"));
+        let body = response.text().await.unwrap();
+        let images: Vec<&str> = body.split(r#"{"format":"#).skip(1).collect();
+        assert_eq!(images.len(), 3);
+        let formats = [ImageFormat::WebP, ImageFormat::Jpeg, ImageFormat::Png];
+        for (image, format) in images.iter().zip(formats) {
+            let data = image.split(r#""data":""#).nth(1).unwrap().split('"').next().unwrap();
+            let decoded = base64::decode(data).unwrap();
+            assert!(image.contains(&format!(r#""bytes":{}"#, decoded.len())));
+            image::load_from_memory_with_format(&decoded, format).unwrap();
+        }
+
+        // Without `formats`, the manifest holds just the format in the path.
+        let response = get_mosaic(addr, "/png/1/120x80/80x120?mode=manifest").await;
+        let body = response.text().await.unwrap();
+        assert_eq!(body.matches(r#""format":"#).count(), 1);
+        assert!(body.contains(r#""format":"png""#));
+    }
+
+    #[tokio::test]
+    async fn streams_encoded_images() {
+        let addr = start();
+
+        for (image_type, format) in [("png", ImageFormat::Png), ("jpeg", ImageFormat::Jpeg)] {
+            let path = format!("/{}/1/120x80/80x120?stream=true", image_type);
+            let response = get_mosaic(addr, &path).await;
+
+            assert_eq!(response.status(), StatusCode::OK);
+            // Streamed bodies are chunked, since their length isn't known when the headers are sent.
+            assert!(response.headers().get("content-length").is_none());
+            let width: u32 = header(&response, "X-Image-Width").parse().unwrap();
+            let height: u32 = header(&response, "X-Image-Height").parse().unwrap();
+            let body = response.bytes().await.unwrap();
+            let decoded = image::load_from_memory_with_format(&body, format).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (width, height));
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_the_images_that_arrived() {
+        let addr = start();
+
+        let response = get_mosaic(addr, "/png/1/200x100/missing/100x200").await;
+        let all = get_mosaic(addr, "/png/1/200x100/100x100/100x200?mode=manifest").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, "X-Image-Count"), "2");
+        assert_eq!(header(&response, "X-Layout-Family"), "twos");
+        assert_eq!(header(&all, "X-Image-Count"), "3");
+        assert_eq!(header(&all, "X-Layout-Family"), "threes");
+    }
+
+    #[tokio::test]
+    async fn serves_tiles_as_multipart() {
+        let addr = start();
+
+        let response = get_mosaic(addr, "/png/1/100x400/100x200?mode=tiles").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let content_type = header(&response, "content-type").to_string();
+        let boundary = content_type.strip_prefix("multipart/mixed; boundary=").unwrap();
+        assert_eq!(header(&response, "X-Image-Regions"), "0,0,100,400;0,410,100,200");
+        assert!(!response.headers().contains_key("content-disposition"));
+
+        let body = response.bytes().await.unwrap();
+        let body = String::from_utf8_lossy(&body);
+        assert_eq!(body.matches(&format!("--{}\r\n", boundary)).count(), 2);
+        assert!(body.contains("X-Image-Region: 0,410,100,200\r\n"));
+        assert!(body.ends_with(&format!("--{}--\r\n", boundary)));
+    }
+
+    #[tokio::test]
+    async fn takes_format_from_extension() {
+        let addr = start();
+
+        let response = get_mosaic(addr, "/1/120x80/80x120.png").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, "content-type"), "image/png");
+
+        // With a single image ID, the path has too few segments for the main route.
+        let response = get_mosaic(addr, "/1/missing.jpg").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.text().await.unwrap(), "No images could be found.");
+
+        let response = get_mosaic(addr, "/1/120x80/80x120.webp").await;
+        let legacy = get_mosaic(addr, "/webp/1/120x80/80x120").await;
+        assert_eq!(header(&response, "X-Image-Regions"), header(&legacy, "X-Image-Regions"));
+        assert_eq!(
+            header(&response, "content-disposition"),
+            header(&legacy, "content-disposition")
+        );
+    }
+
+    #[tokio::test]
+    async fn names_responses_by_image_order() {
+        let addr = start();
+
+        let forwards = get_mosaic(addr, "/webp/1/120x80/80x120").await;
+        let backwards = get_mosaic(addr, "/webp/1/80x120/120x80").await;
+        assert_ne!(
+            header(&forwards, "content-disposition"),
+            header(&backwards, "content-disposition")
+        );
+
+        let forwards = get_mosaic(addr, "/webp/1/120x80/80x120?order=sorted").await;
+        let backwards = get_mosaic(addr, "/webp/1/80x120/120x80?order=sorted").await;
+        assert_eq!(
+            header(&forwards, "content-disposition"),
+            header(&backwards, "content-disposition")
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_extensions() {
+        let addr = start();
+
+        let response = get_mosaic(addr, "/1/120x80/80x120.gif").await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(response.text().await.unwrap().starts_with("Unsupported image type \"gif\"."));
+    }
+
+    #[tokio::test]
+    async fn sheds_requests_over_the_limit() {
+        let addr = start_with_limit(0);
+
+        let response = get_mosaic(addr, "/webp/1/200x100/100x200").await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(header(&response, "retry-after"), "1");
+    }
+
+    #[tokio::test]
+    async fn rejects_requests_without_images() {
+        let addr = start();
+
+        let response = get_mosaic(addr, "/png/1/missing/gone").await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.text().await.unwrap(), "No images could be found.");
+    }
+
+    #[tokio::test]
+    async fn rejects_too_many_images() {
+        let addr = start();
+
+        let response = get_mosaic(addr, "/jpeg/1/10x10/10x10/10x10/10x10/10x10").await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.text().await.unwrap(), "Too many images.");
+
+        // Inline images count towards the limit too.
+        let png = encode_image(RgbImage::new(10, 10), ImageType::Png, EncodeOptions::default()).unwrap();
+        let data = base64::encode_config(&png, base64::URL_SAFE_NO_PAD);
+        let path = format!("/jpeg/1/10x10/10x10/10x10?data={0}&data={0}", data);
+        let response = get_mosaic(addr, &path).await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.text().await.unwrap(), "Too many images.");
+    }
+
+    #[tokio::test]
+    async fn names_images_over_the_size_limit() {
+        let media = |Path(id): Path<String>| async move {
+            match id.as_str() {
+                "huge" => vec![0u8; 10_000_001].into_response(),
+                _ => fake_media(Path(id)).await.into_response(),
+            }
+        };
+        let addr = start_against(Router::new().route("/media/:id", get(media)), 1);
+
+        let response = get_mosaic(addr, "/jpeg/1/10x10/huge/10x10").await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.text().await.unwrap(), "Image 2 is too large.");
+
+        let response = get_mosaic(addr, "/jpeg/1/10x10/huge/10x10?placeholder=true").await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(header(&response, "X-Mosaic-Error"), "Image 2 is too large.");
+
+        // Images are numbered as they were given, counting skipped IDs and not sorting.
+        let response = get_mosaic(addr, "/jpeg/1/not.an.id/10x10/huge").await;
+
+        assert_eq!(response.text().await.unwrap(), "Image 3 is too large.");
+
+        let response = get_mosaic(addr, "/jpeg/1/huge/10x10?order=sorted").await;
+
+        assert_eq!(response.text().await.unwrap(), "Image 1 is too large.");
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_formats() {
+        let addr = start();
+
+        let response = get_mosaic(addr, "/gif/1/10x10/10x10").await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(response.text().await.unwrap().contains("Supported types are: webp, png, jpeg"));
+    }
+}
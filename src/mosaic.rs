@@ -25,400 +25,3295 @@
 use std::cmp::max;
 use std::cmp::Ordering::Equal;
 use std::iter::zip;
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
 use std::time::Instant;
 
-use image::{imageops::FilterType, RgbImage};
+use image::{imageops::FilterType, Rgb, RgbImage};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
 use tracing::instrument;
 
-use crate::mosaic::fours::build_4_mosaic;
-use crate::mosaic::threes::build_3_mosaic;
-use crate::mosaic::twos::build_2_mosaic;
+use crate::mosaic::caption::{add_caption, caption_height, MAX_BAR_HEIGHT};
+use crate::mosaic::debug::draw_debug_overlay;
+use crate::mosaic::fours::plan_4_mosaic;
+use crate::mosaic::grid::plan_grid_mosaic;
+use crate::mosaic::threes::plan_3_mosaic;
+use crate::mosaic::twos::plan_2_mosaic;
 
 mod twos;
 mod threes;
 mod fours;
+mod grid;
+mod caption;
+mod debug;
 mod testutils;
 
 const SPACING_SIZE: u32 = 10;
-const MAX_SIZE: u32 = 4000;
+/// The widest gutter allowed, so the gutters alone can't take up the whole `MAX_SIZE`.
+const MAX_SPACING: u32 = 200;
+/// The widest margin allowed around a mosaic, for the same reason.
+const MAX_MARGIN: u32 = 200;
+/// The longest a mosaic's sides may be, unless it is `uncapped`.
+pub const MAX_SIZE: u32 = 4000;
+/// How much worse than the most uniformly scaled layout a layout's scale factor ratio may be and still be
+/// picked for being squarer, when `MosaicOptions::scale_tolerance` isn't changed.
+const DEFAULT_SCALE_TOLERANCE: f32 = 0.5;
+/// How much more the first image weighs when picking a featured layout.
+const FEATURED_WEIGHT: f32 = 4.0;
+/// How many times `scale_to_fit` shrinks a layout a little more to undo rounding that upscaled an image.
+const UPSCALE_CORRECTION_STEPS: usize = 10;
+const MAX_BLEED: u32 = 4;
+/// The smallest share of the top row `top_split` can give either image, so neither is cropped to a sliver.
+const MIN_TOP_SPLIT: f32 = 0.1;
+/// Blur radius of blurred backgrounds, in output pixels, unless `MosaicOptions::blur_radius` changes it.
+const BLUR_SIGMA: f32 = 40.0;
+/// The blurriest a background gets, as a fraction of its long side. Past that it is close to a flat colour
+/// and more blur only costs time.
+const MAX_BLUR_FRACTION: f32 = 0.1;
+/// How much smaller than the output blurred backgrounds are blurred at.
+const BLUR_DOWNSCALE: u32 = 8;
+/// How many pixels along each side are sampled at most to find an image's mean brightness.
+const EXPOSURE_SAMPLES: u32 = 64;
+/// The furthest equalizing moves an image's brightness, out of 255, so a night shot next to a snowy one
+/// isn't washed out to grey.
+const MAX_EXPOSURE_SHIFT: i32 = 64;
+/// The range `gamma` is clamped to, as values outside it wash the mosaic out to black or white.
+const MIN_GAMMA: f32 = 0.1;
+const MAX_GAMMA: f32 = 10.0;
+/// The biggest a badge is drawn, as a share of the mosaic's short side.
+const MAX_BADGE_FRACTION: f32 = 0.2;
+/// The most of an image's width or height `shared_aspect` crops away.
+const MAX_SHARED_ASPECT_CROP: f32 = 0.2;
+/// The tracing target every layout decision is written to as a line of JSON when `LOG_LAYOUT_CANDIDATES`
+/// is set, for tuning the selection offline.
+const CANDIDATES_TARGET: &str = "layout_candidates";
+/// How far a channel may stray from the border colour for a pixel to still count as border, so JPEG noise
+/// doesn't stop a trim.
+const TRIM_TOLERANCE: u8 = 16;
+/// How far a diagonal edge leans, as a fraction of the mosaic height.
+const DIAGONAL_SLANT_RATIO: f32 = 0.1;
 
-pub fn mosaic(mut images: Vec<RgbImage>) -> RgbImage {
-    match images.len() {
-        2 => {
-            let second = images.pop().unwrap();
-            let first = images.pop().unwrap();
-            build_2_mosaic(first, second)
-        }
-        3 => {
-            let third = images.pop().unwrap();
-            let second = images.pop().unwrap();
-            let first = images.pop().unwrap();
-            build_3_mosaic(first, second, third)
-        }
-        4 => {
-            let fourth = images.pop().unwrap();
-            let third = images.pop().unwrap();
-            let second = images.pop().unwrap();
-            let first = images.pop().unwrap();
-            build_4_mosaic(first, second, third, fourth)
-        }
-        _ => panic!("impossible image length"),
-    }
+lazy_static! {
+    static ref LOG_LAYOUT_CANDIDATES: bool = std::env::var_os("LOG_LAYOUT_CANDIDATES").is_some();
 }
 
-fn create_background(size: Size) -> RgbImage {
-    RgbImage::from_pixel(size.width, size.height, image::Rgb([0, 0, 0]))
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LayoutMode {
+    /// Pick the best rectangular layout.
+    #[default]
+    Auto,
+    /// Split three images along slanted edges. Other image counts use the rectangular layouts.
+    Diagonal,
+    /// Show the first image large, with the rest in a strip beneath or beside it.
+    Featured,
+    /// Put two images side by side in halves of exactly the same size, cropping each to fill its half, for
+    /// before and after comparisons. Other image counts use the rectangular layouts.
+    Diptych,
 }
 
-fn scale_height_dimension(image_size: Size, other_height: u32) -> Size {
-    let scale_factor = image_size.height as f32 / other_height as f32;
-    Size {
-        width: (image_size.width as f32 / scale_factor).round() as u32,
-        height: other_height,
-    }
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FitMode {
+    /// Scale each image to fill its cell, so the edges images share line up.
+    #[default]
+    Scale,
+    /// Give every image a cell of the same aspect ratio and centre it there, filling the rest like the
+    /// gutters, so the cells form a clean grid without cropping or stretching anything.
+    Pad,
 }
 
-fn scale_width_dimension(image_size: Size, other_width: u32) -> Size {
-    let scale_factor = image_size.width as f32 / other_width as f32;
-    Size {
-        width: other_width,
-        height: (image_size.height as f32 / scale_factor).round() as u32,
-    }
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MosaicStyle {
+    /// Keep every image's aspect ratio and size the cells to match.
+    #[default]
+    Fit,
+    /// Use identical cells in a fixed grid, center-cropping each image to fill its cell.
+    Grid,
 }
 
-fn resize_images(images: Vec<(RgbImage, Size)>) -> Vec<RgbImage> {
-    tracing::debug!("resizing {} images", images.len());
-
-    let span = tracing::Span::current();
-
-    let images: Vec<_> = images
-        .into_iter()
-        .map(|(im, size)| {
-            let span = span.clone();
-
-            std::thread::spawn(move || {
-                let _span = span.entered();
-                resize_image(im, size)
-            })
-        })
-        .collect::<Vec<_>>() // eagerly evaluate map to spawn threads
-        .into_iter()
-        .map(|thread| thread.join().unwrap())
-        .collect();
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackgroundMode {
+    /// Fill the gutters with black.
+    #[default]
+    Solid,
+    /// Fill the gutters with a blurred, stretched copy of the first image.
+    Blur,
+}
 
-    images
+/// Which image a mosaic is scaled to show at its native resolution, before being capped at `MAX_SIZE`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NativeImage {
+    /// The image the layout shrinks the most, so no image loses detail but others may be upscaled.
+    #[default]
+    Smallest,
+    /// The image the layout enlarges the most, so no image is upscaled but others may be shrunk.
+    Largest,
 }
 
-#[instrument(skip(image, size))]
-fn resize_image(image: RgbImage, size: Size) -> RgbImage {
-    tracing::trace!("starting image resize");
+/// Which image of a pair keeps its size along the edge they share while the other is scaled to match it.
+/// The mosaic is rescaled as a whole afterwards, so this only matters for rounding: scaling a big image
+/// down to a tiny one's edge and back up can visibly distort it, and blows the gutter up along with the
+/// tiny image. Only two image mosaics have a choice; bigger ones chain their rows and columns from the first
+/// image.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Anchor {
+    #[default]
+    First,
+    /// Whichever image is longer along the shared edge, so the other is scaled up to it.
+    Larger,
+}
 
-    let start = Instant::now();
+/// Which way images are ordered within a row.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// The first image is on the left.
+    #[default]
+    Ltr,
+    /// The first image is on the right, for right-to-left reading contexts. Rows stay in the same order.
+    Rtl,
+}
 
-    if image.width() != size.width && image.height() != size.height {
-        let im = image::imageops::resize(
-            &image,
-            size.width,
-            size.height,
-            FilterType::Triangle, // The original uses Lanczos3 but in practice the difference is not visible.
-        );
+/// Which ways to mirror an image before it is laid out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Flip {
+    /// Mirror left to right.
+    pub horizontal: bool,
+    /// Mirror top to bottom.
+    pub vertical: bool,
+}
 
-        tracing::debug!(time = start.elapsed().as_millis(), "resized image");
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DebugOverlay {
+    /// Leave the mosaic as is.
+    #[default]
+    None,
+    /// Outline each image's rectangle and label it with its index.
+    Grid,
+}
 
-        im
-    } else {
-        tracing::debug!("image was already acceptable size");
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeFilter {
+    /// Copies the closest source pixel without blending, which keeps pixel art and sprites crisp.
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
 
-        image
+impl From<ResizeFilter> for FilterType {
+    fn from(filter: ResizeFilter) -> Self {
+        match filter {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
     }
 }
 
-#[derive(Clone, Copy, Default)]
-pub struct Size {
-    pub width: u32,
-    pub height: u32,
+/// The filters used when an image gets bigger or smaller.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResizeFilters {
+    pub upscale: ResizeFilter,
+    pub downscale: ResizeFilter,
 }
 
-impl Size {
-    fn scale(&self, scale_factor: f32) -> Size {
-        Size {
-            width: (self.width as f32 / scale_factor).round() as u32,
-            height: (self.height as f32 / scale_factor).round() as u32,
-        }
-    }
-    fn add(&self, other: Size) -> Size {
-        Size {
-            width: self.width + other.width,
-            height: self.height + other.height,
+impl Default for ResizeFilters {
+    fn default() -> Self {
+        ResizeFilters {
+            // Upscaling with a triangle filter looks soft, a sharper filter holds up much better.
+            upscale: ResizeFilter::CatmullRom,
+            // The original uses Lanczos3 but in practice the difference is not visible.
+            downscale: ResizeFilter::Triangle,
         }
     }
 }
 
-#[derive(Clone, Copy, Default)]
-pub struct ImageOffset {
-    pub offset: Size,
-    pub dimensions: Size,
-    pub original_dimensions: Size,
+/// Where the subject of an image is, as a fraction of its width and height from the top left. Crops keep
+/// this point as close to their middle as they can.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FocalPoint {
+    pub x: f32,
+    pub y: f32,
 }
 
-impl ImageOffset {
-    fn scale(&self, scale_factor: f32) -> ImageOffset {
-        ImageOffset {
-            offset: self.offset.scale(scale_factor),
-            dimensions: self.dimensions.scale(scale_factor),
-            original_dimensions: self.original_dimensions,
-        }
+impl Default for FocalPoint {
+    fn default() -> Self {
+        FocalPoint { x: 0.5, y: 0.5 }
     }
-    fn add_height(&self, height: u32) -> ImageOffset {
-        ImageOffset {
-            offset: Size {
-                width: self.offset.width,
-                height: self.offset.height + height,
-            },
-            dimensions: Size {
-                width: self.dimensions.width,
-                height: self.dimensions.height,
-            },
-            original_dimensions: self.original_dimensions,
+}
+
+/// The gutters between images, in pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Spacing {
+    /// Between images side by side.
+    pub horizontal: u32,
+    /// Between images stacked on top of each other.
+    pub vertical: u32,
+}
+
+impl Default for Spacing {
+    fn default() -> Self {
+        Spacing {
+            horizontal: SPACING_SIZE,
+            vertical: SPACING_SIZE,
         }
     }
-    fn add_width(&self, width: u32) -> ImageOffset {
-        ImageOffset {
-            offset: Size {
-                width: self.offset.width + width,
-                height: self.offset.height,
-            },
-            dimensions: Size {
-                width: self.dimensions.width,
-                height: self.dimensions.height,
-            },
-            original_dimensions: self.original_dimensions,
+}
+
+impl Spacing {
+    fn clamped(self) -> Spacing {
+        Spacing {
+            horizontal: self.horizontal.min(MAX_SPACING),
+            vertical: self.vertical.min(MAX_SPACING),
         }
     }
-    fn total_width(&self) -> u32 {
-        self.offset.width + self.dimensions.width
-    }
-    fn total_height(&self) -> u32 {
-        self.offset.height + self.dimensions.height
-    }
 }
 
-trait MosaicDims {
-    fn total_size(&self) -> Size;
-    fn scale(&self, scale_factor: f32) -> Self;
-    fn image_scale_factors(&self) -> Vec<f32>;
-    fn min_scale_factor(&self) -> f32;
-    fn max_scale_factor(&self) -> f32;
-    fn scale_factor_ratio(&self) -> f32;
-    fn scale_to_fit(&self) -> Self;
-    fn add_height(&self, height: u32) -> Self;
-    fn add_width(&self, width: u32) -> Self;
+impl ResizeFilters {
+    fn for_resize(&self, image_size: Size, target: Size) -> FilterType {
+        let image_area = image_size.width as u64 * image_size.height as u64;
+        let target_area = target.width as u64 * target.height as u64;
 
-    fn unsquaredness(&self) -> f32 {
-        let total_size = self.total_size();
-        if total_size.width < total_size.height {
-            total_size.height as f32 / total_size.width as f32
+        if target_area > image_area {
+            self.upscale.into()
         } else {
-            total_size.width as f32 / total_size.height as f32
+            self.downscale.into()
         }
     }
 }
 
-#[derive(Clone, Copy)]
-pub struct MosaicImageDims<const LEN: usize> {
-    images: [ImageOffset; LEN],
+#[derive(Clone, Debug)]
+pub struct MosaicOptions {
+    pub style: MosaicStyle,
+    pub fit: FitMode,
+    pub layout: LayoutMode,
+    /// The gutters between images, up to `MAX_SPACING`.
+    pub spacing: Spacing,
+    /// The border of background around the whole mosaic, up to `MAX_MARGIN`. 0 leaves the images flush
+    /// with its edges.
+    pub margin: u32,
+    pub native: NativeImage,
+    pub anchor: Anchor,
+    pub direction: Direction,
+    /// Never render an image bigger than its native size: the layout is scaled as with `NativeImage::Largest`
+    /// whatever `native` says, the finished mosaic isn't enlarged to fit `canvas`, and bleed and the diagonal
+    /// layout, which both stretch images past their rectangles, are turned off.
+    pub no_upscale: bool,
+    /// Width of the border drawn just inside each image, clamped to the spacing. 0 draws no border.
+    pub border_width: u32,
+    pub border_colour: Rgb<u8>,
+    /// Inputs whose long side is more than this many times their short side are center-cropped down to this
+    /// ratio before layout, so one extreme banner can't squash the whole mosaic.
+    pub max_aspect_ratio: Option<f32>,
+    /// Center-crop each image towards the median aspect ratio of them all, by at most
+    /// `MAX_SHARED_ASPECT_CROP` of its width or height, so mismatched shapes make for tidier layouts.
+    /// Doesn't apply with `FitMode::Pad`, which evens out the shapes by padding instead.
+    pub shared_aspect: bool,
+    /// The true width to height ratio of each image, in order, for images whose pixels are distorted. Images
+    /// are laid out, and stretched, as if they had this ratio. Missing or `None` entries keep the pixels' own
+    /// ratio. `max_aspect_ratio` is applied to the pixels, before this.
+    pub aspect_ratios: Vec<Option<f32>>,
+    /// How many pixels of each image, in order, make up one pixel of layout, like 2 for a screenshot taken
+    /// at 2x. Images are laid out as if they were this many times smaller, so sources of different
+    /// resolutions come out at consistent physical sizes. Missing scales are 1.
+    pub scales: Vec<f32>,
+    /// How opaque each image, in order, is drawn over the background, from 0 to 1. Missing entries are 1.
+    pub opacities: Vec<f32>,
+    /// Crop solid colour margins, like letterboxing on screenshots, off each input before layout.
+    pub trim: bool,
+    /// Brighten or darken each image towards the mean brightness of them all, so a dark photo next to a
+    /// bright one doesn't leave the mosaic looking lopsided.
+    pub equalize: bool,
+    /// Pixels each image is grown by towards its neighbours, up to `MAX_BLEED`. With no spacing this
+    /// overlaps neighbouring images so lossy encoders don't ring on a hard seam between them.
+    pub bleed: u32,
+    pub background: BackgroundMode,
+    /// How blurry blurred backgrounds are, in output pixels, up to `MAX_BLUR_FRACTION` of their long side.
+    /// Lower leaves more of the image recognisable.
+    pub blur_radius: f32,
+    pub filters: ResizeFilters,
+    /// The share of the top row the first image gets when two images sit over a third spanning both, like
+    /// 0.5 for an even split. Both images are cropped to fill their share. `None` splits the row by the
+    /// images' own widths at a shared height.
+    pub top_split: Option<f32>,
+    /// Gives all four images of a 2x2 mosaic the same cell, cropping each to fill it, instead of keeping
+    /// their own aspect ratios.
+    pub equal: bool,
+    /// Only consider layouts with at most this many images side by side.
+    pub max_columns: Option<u32>,
+    /// Only consider layouts with at most this many images stacked on top of each other.
+    pub max_rows: Option<u32>,
+    /// How far above the lowest scale factor ratio among the candidate layouts, that is the biggest image
+    /// scale over the smallest, a layout may be and still be picked. Lower keeps the images closer to the
+    /// same scale, so small images aren't blown up next to big ones; higher lets squarer layouts win more.
+    pub scale_tolerance: f32,
+    /// Which of the candidate layouts to use, as ranked by how well they scored: 1 is the best, 2 the
+    /// runner-up and so on, up to the number of candidates.
+    pub rank: usize,
+    /// How much each image, in order, should be favoured when picking a layout. Missing weights are 1.
+    pub weights: Vec<f32>,
+    /// Scale the finished mosaic to fit this size exactly, filling the leftover space like the gutters.
+    pub canvas: Option<Size>,
+    /// Text written in a bar added below the mosaic, before it is fitted to `canvas`. Only ASCII is drawn;
+    /// anything else shows up as `?`.
+    pub caption: Option<String>,
+    pub caption_colour: Rgb<u8>,
+    /// A small image drawn as a circle where the gutters of a 2x2 mosaic cross, or in the middle of other
+    /// layouts, scaled down to at most `MAX_BADGE_FRACTION` of the mosaic's short side.
+    pub badge: Option<RgbImage>,
+    /// How much to add to every channel of the composited mosaic, from -255 to 255, after `gamma`. Like
+    /// `gamma`, this is applied before the badge, caption and canvas are drawn, so those keep their colours.
+    pub brightness: i32,
+    /// The gamma correction applied to the composited mosaic, between `MIN_GAMMA` and `MAX_GAMMA`. Above 1
+    /// brightens the shadows and midtones, below 1 darkens them, and 1 leaves the mosaic alone.
+    pub gamma: f32,
+    /// Where to centre crops of each image, in order, when a layout crops images to fill their cell. Missing
+    /// focal points are the middle of the image.
+    pub focal_points: Vec<FocalPoint>,
+    /// How to mirror each image, in order, before it is laid out. Missing entries aren't flipped.
+    pub flips: Vec<Flip>,
+    /// Draw over the finished mosaic to show how it was laid out. Only meant for development.
+    pub debug: DebugOverlay,
+    /// Don't shrink mosaics to fit in `MAX_SIZE`, for full resolution output. The mosaic can then take as
+    /// much memory as its images at native size add up to, so this isn't for untrusted input.
+    pub uncapped: bool,
 }
 
-impl<const LEN: usize> MosaicDims for MosaicImageDims<LEN> {
-    fn total_size(&self) -> Size {
-        let last = self.images.last().unwrap();
-        last.offset.add(last.dimensions)
+impl Default for MosaicOptions {
+    fn default() -> Self {
+        MosaicOptions {
+            style: MosaicStyle::default(),
+            fit: FitMode::default(),
+            layout: LayoutMode::default(),
+            spacing: Spacing::default(),
+            margin: 0,
+            native: NativeImage::default(),
+            anchor: Anchor::default(),
+            direction: Direction::default(),
+            no_upscale: false,
+            border_width: 0,
+            border_colour: Rgb([255, 255, 255]),
+            max_aspect_ratio: None,
+            shared_aspect: false,
+            aspect_ratios: Vec::new(),
+            scales: Vec::new(),
+            opacities: Vec::new(),
+            trim: false,
+            equalize: false,
+            bleed: 0,
+            background: BackgroundMode::default(),
+            blur_radius: BLUR_SIGMA,
+            filters: ResizeFilters::default(),
+            top_split: None,
+            equal: false,
+            max_columns: None,
+            max_rows: None,
+            scale_tolerance: DEFAULT_SCALE_TOLERANCE,
+            rank: 1,
+            weights: Vec::new(),
+            canvas: None,
+            caption: None,
+            caption_colour: Rgb([255, 255, 255]),
+            badge: None,
+            brightness: 0,
+            gamma: 1.0,
+            focal_points: Vec::new(),
+            flips: Vec::new(),
+            debug: DebugOverlay::default(),
+            uncapped: false,
+        }
     }
+}
 
-    fn scale(&self, scale_factor: f32) -> Self {
-        let mut new_images = [ImageOffset::default(); LEN];
-        for (x, image) in self.images.iter().enumerate() {
-            new_images[x] = image.scale(scale_factor);
+impl MosaicOptions {
+    /// Which image the layout is scaled around, taking `no_upscale` into account.
+    fn native_image(&self) -> NativeImage {
+        if self.no_upscale {
+            NativeImage::Largest
+        } else {
+            self.native
         }
+    }
 
-        MosaicImageDims {
-            images: new_images
-        }
+    /// The corrected aspect ratio of the image at `index`, if it has one.
+    fn aspect_ratio(&self, index: usize) -> Option<f32> {
+        self.aspect_ratios.get(index).copied().flatten()
     }
 
-    fn image_scale_factors(&self) -> Vec<f32> {
-        self.images.iter().map(|image| {
-            image.dimensions.width as f32 / image.original_dimensions.width as f32
-        }).collect()
+    fn margin(&self) -> u32 {
+        self.margin.min(MAX_MARGIN)
     }
 
-    fn min_scale_factor(&self) -> f32 {
-        *(self.image_scale_factors().iter().min_by(|a, b| {
-            a.partial_cmp(&b).unwrap_or(Equal)
-        }).unwrap())
+    /// Whether images are cropped to fill their rectangles in `layout`, rather than already matching them.
+    fn crops(&self, layout: Layout) -> bool {
+        layout.crops_to_fill()
+            || (layout == Layout::TopTopBottom && self.top_split.is_some())
+            || (layout == Layout::TwoRowsOfTwo && self.equal)
+            || self.crops_to_shared_aspect()
     }
 
-    fn max_scale_factor(&self) -> f32 {
-        *self.image_scale_factors().iter().max_by(|a, b| {
-            a.partial_cmp(&b).unwrap_or(Equal)
-        }).unwrap()
+    fn crops_to_shared_aspect(&self) -> bool {
+        self.shared_aspect && self.fit == FitMode::Scale
     }
 
-    fn scale_factor_ratio(&self) -> f32 {
-        self.max_scale_factor() / self.min_scale_factor()
+    /// Whether `prepare_image` has anything to do to an image.
+    pub fn prepares(&self) -> bool {
+        self.trim || self.max_aspect_ratio.is_some()
     }
 
-    fn scale_to_fit(&self) -> Self {
-        // Scale mosaic so that the smallest image is 1:1 scale
-        let mut scaled_mosaic = self.scale(self.min_scale_factor());
-        // Scale down to fit into maximum dimensions
-        let total_size = scaled_mosaic.total_size();
-        let biggest = max(total_size.width, total_size.height);
-        if biggest > MAX_SIZE {
-            let scale_factor = biggest as f32 / MAX_SIZE as f32;
-            scaled_mosaic = scaled_mosaic.scale(scale_factor);
+    /// The weights to pick a featured layout with: the given ones, with the first image's multiplied by
+    /// `FEATURED_WEIGHT` so layouts that make it bigger win.
+    fn featured_weights(&self) -> Vec<f32> {
+        let mut weights = self.weights.clone();
+        if weights.is_empty() {
+            weights.push(1.0);
         }
-        scaled_mosaic
+        weights[0] *= FEATURED_WEIGHT;
+        weights
     }
 
-    fn add_height(&self, height: u32) -> Self {
-        let mut new_images = [ImageOffset::default(); LEN];
-        for (x, image) in self.images.iter().enumerate() {
-            new_images[x] = image.add_height(height);
-        }
-        MosaicImageDims {
-            images: new_images
+    /// The biggest the layout's longest side may be, taking `uncapped` into account. The margin goes around
+    /// the layout and the caption below it, so room for them comes out of `MAX_SIZE` first.
+    fn max_size(&self) -> u32 {
+        if self.uncapped {
+            return u32::MAX;
         }
+
+        let caption = if self.caption.is_some() { MAX_BAR_HEIGHT } else { 0 };
+        MAX_SIZE - 2 * self.margin() - caption
     }
 
-    fn add_width(&self, width: u32) -> Self {
-        let mut new_images = [ImageOffset::default(); LEN];
-        for (x, image) in self.images.iter().enumerate() {
-            new_images[x] = image.add_width(width);
-        }
-        MosaicImageDims {
-            images: new_images
+    /// The long side sources may be shrunk to while decoding without changing the mosaic. Per-image scales
+    /// and the native image lay the mosaic out from the sources' own sizes, and an uncapped mosaic can be
+    /// bigger than `MAX_SIZE`, so with any of those they have to be decoded in full.
+    pub fn decode_side(&self) -> u32 {
+        if self.uncapped || !self.scales.is_empty() || self.native_image() != NativeImage::default() {
+            u32::MAX
+        } else {
+            MAX_SIZE
         }
     }
+
+    /// The intrinsic scale of the image at `index`, 1 unless it was given one.
+    fn scale(&self, index: usize) -> f32 {
+        self.scales.get(index).copied().unwrap_or(1.0)
+    }
+
+    /// The opacity of the image at `index`, 1 unless it was given one.
+    fn opacity(&self, index: usize) -> f32 {
+        self.opacities.get(index).map_or(1.0, |opacity| opacity.clamp(0.0, 1.0))
+    }
 }
 
-fn best_mosaic<T: MosaicDims + Copy>(mosaics: &[&T]) -> T {
-    // Ensure all mosaics have a minimum scaling ratio of 1, and fit within the box
-    let scaled_mosaics: Vec<T> = mosaics.iter().map(|mosaic| {
-        mosaic.scale_to_fit()
-    }).collect();
+pub fn mosaic(images: Vec<RgbImage>, options: &MosaicOptions) -> Mosaic {
+    let images = images.into_iter().map(|image| prepare_image(image, options)).collect();
+    mosaic_prepared(images, options)
+}
 
-    // Find the lowest scaling ratio, to discard mosaics with a scaling ratio 50% higher than that
-    let min_scale_factor_ratio = scaled_mosaics.iter().map(|mosaic| {
-        mosaic.scale_factor_ratio()
-    }).min_by(|a, b| {
-        a.partial_cmp(&b).unwrap_or(Equal)
-    }).unwrap();
+/// Applies the steps of `options` that only depend on the image itself, like trimming. These can run on
+/// each image as soon as it arrives, before the others are there to plan the layout with.
+#[instrument(skip(image, options))]
+pub fn prepare_image(mut image: RgbImage, options: &MosaicOptions) -> RgbImage {
+    let start = Instant::now();
 
-    let scale_factor_ratio_cap = min_scale_factor_ratio + 0.5;
+    if options.trim {
+        image = trim_border(image);
+    }
 
-    // Then select squarest within 50% of that
-    *scaled_mosaics.iter().filter(|mosaic| {
-        mosaic.scale_factor_ratio() < scale_factor_ratio_cap
-    }).min_by(|mosaic_a, mosaic_b| {
-        let ratio_a = mosaic_a.unsquaredness();
-        let ratio_b = mosaic_b.unsquaredness();
-        ratio_a.partial_cmp(&ratio_b).unwrap_or(Equal)
-    }).unwrap()
-}
+    if let Some(max_aspect_ratio) = options.max_aspect_ratio {
+        image = crop_to_aspect_ratio(image, max_aspect_ratio);
+    }
 
+    tracing::trace!(time = start.elapsed().as_millis(), "prepared image");
 
-fn build_mosaic<const LEN: usize>(mosaic: MosaicImageDims<LEN>, images: [RgbImage; LEN]) -> RgbImage {
-    let resize_args = zip(images, mosaic.images).map(|(image, offset)| {
-        (
-            image,
-            offset.dimensions,
-        )
-    }).collect();
+    image
+}
+
+/// Like `mosaic`, for images that have already been through `prepare_image`.
+pub fn mosaic_prepared(images: Vec<RgbImage>, options: &MosaicOptions) -> Mosaic {
+    let images = equalize_images(flip_images(images, &options.flips), options);
+    let sizes: Vec<Size> = images
+        .iter()
+        .map(|image| Size {
+            width: image.width(),
+            height: image.height(),
+        })
+        .collect();
+    let plan = plan_layout(&sizes, options);
 
-    let resized = resize_images(resize_args);
+    let mosaic = match plan.layout {
+        Layout::Diagonal => build_diagonal_mosaic(DiagonalMosaicDims::new(plan), images, options),
+        _ => build_mosaic(plan, images, options),
+    };
 
-    let mut background = create_background(mosaic.total_size());
-    for (image, offset) in zip(resized, mosaic.images) {
-        image::imageops::overlay(&mut background, &image, offset.offset.width as i64, offset.offset.height as i64);
-    }
-    background
+    finish_mosaic(mosaic, options)
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::mosaic;
-    use crate::mosaic::testutils::{
-        BLUE,
-        create_with_colour,
-        GREEN,
-        has_black_horizontal_line,
-        has_black_vertical_line,
-        has_black_vertical_line_partial,
-        is_colour_in_range,
-        PURPLE,
-        RED,
-        save_result,
+/// Like `mosaic_prepared`, but the image at `animated` is the first frame of an animation and `frames`
+/// are the rest of it. Returns one mosaic image per frame. The static images are composited into a base
+/// once, laid out around the first frame, and only the animated slot is redrawn for the later frames.
+/// Every frame must already have been through `prepare_image`.
+pub fn animated_mosaic(
+    images: Vec<RgbImage>,
+    animated: usize,
+    frames: Vec<RgbImage>,
+    options: &MosaicOptions,
+) -> Vec<RgbImage> {
+    let flip = options.flips.get(animated).copied().unwrap_or_default();
+    let flips = vec![flip; frames.len()];
+    let frames = flip_images(frames, &flips);
+    let images = flip_images(images, &options.flips);
+    // Frames are shifted as much as the still that stands in for them, so the animation doesn't flicker.
+    let (images, frames) = if options.equalize {
+        let shifts = exposure_shifts(&images);
+        let frames = frames.into_iter().map(|frame| shift_brightness(frame, shifts[animated])).collect();
+        (zip(images, shifts).map(|(image, shift)| shift_brightness(image, shift)).collect(), frames)
+    } else {
+        (images, frames)
     };
+    let sizes: Vec<Size> = images
+        .iter()
+        .map(|image| Size {
+            width: image.width(),
+            height: image.height(),
+        })
+        .collect();
+    let plan = plan_layout(&sizes, options);
 
-    #[test]
-    fn pick_less_square_option_for_better_scaling_ratio() {
-        let top_left = create_with_colour(100, 100, RED);
-        let top_right = create_with_colour(300, 100, BLUE);
-        let bot_left = create_with_colour(300, 100, GREEN);
-        let bot_right = create_with_colour(100, 100, PURPLE);
+    // Diagonal slots are slanted and blend into their neighbours, so those are rebuilt whole.
+    if plan.layout == Layout::Diagonal {
+        let build = |images: Vec<RgbImage>| {
+            let mosaic = build_diagonal_mosaic(DiagonalMosaicDims::new(plan.clone()), images, options);
+            finish_mosaic(mosaic, options).image
+        };
+        let mut images = images;
+        let mut outputs = vec![build(images.clone())];
+        for frame in frames {
+            images[animated] = frame;
+            outputs.push(build(images.clone()));
+        }
+        return outputs;
+    }
 
-        let result = mosaic(vec![top_left, top_right, bot_left, bot_right]);
+    let placements = Placements::new(&plan, &images, options);
+    let base = composite(&plan, &placements, images, options);
+    let frames: Vec<_> = frames.into_iter().map(|frame| (animated, frame)).collect();
 
-        save_result(&result, "less_square_better_scaling_ratio");
-        assert!(is_colour_in_range(0, 0, 100, 100, &result, RED));
-        assert!(has_black_vertical_line_partial(105, 0, 100, &result));
-        assert!(is_colour_in_range(120, 0, 400, 100, &result, BLUE));
-        assert!(has_black_horizontal_line(105, &result));
-        assert!(is_colour_in_range(0, 120, 300, 200, &result, GREEN));
-        assert!(has_black_vertical_line_partial(305, 120, 200, &result));
-        assert!(is_colour_in_range(320, 120, 400, 200, &result, PURPLE));
+    let mut outputs = Vec::with_capacity(frames.len() + 1);
+    for frame in placements.fit(frames, options) {
+        let mut image = base.image.clone();
+        placements.draw(&mut image, animated, &frame, options);
+        let mosaic = Mosaic {
+            image,
+            layout: base.layout,
+            regions: base.regions.clone(),
+        };
+        outputs.push(finish_mosaic(mosaic, options).image);
     }
+    outputs.insert(0, finish_mosaic(base, options).image);
 
-    #[test]
-    fn wont_scale_down_to_match() {
-        let left = create_with_colour(100, 200, RED);
-        let right = create_with_colour(200, 400, BLUE);
+    outputs
+}
 
-        let result = mosaic(vec![left, right]);
+/// Fits a composited mosaic to the canvas and draws the debug overlay, whichever `options` asks for.
+fn finish_mosaic(mut mosaic: Mosaic, options: &MosaicOptions) -> Mosaic {
+    // Only the images are toned, not what is drawn over them.
+    adjust_tone(&mut mosaic.image, options.brightness, options.gamma);
 
-        save_result(&result, "wont_scale_down_to_match");
-        assert!(is_colour_in_range(0, 0, 200, 400, &result, RED));
-        assert!(has_black_vertical_line(205, &result));
-        assert!(is_colour_in_range(220, 0, 400, 400, &result, BLUE));
+    if let Some(badge) = &options.badge {
+        let centre = badge_centre(&mosaic);
+        draw_badge(&mut mosaic.image, badge, centre, options.filters);
     }
 
-    #[test]
-    fn scale_down_to_fit() {
-        let left = create_with_colour(3000, 3300, RED);
-        let right = create_with_colour(3000, 3300, BLUE);
+    let mosaic = match &options.caption {
+        Some(caption) => Mosaic {
+            image: add_caption(mosaic.image, caption, options.caption_colour),
+            ..mosaic
+        },
+        None => mosaic,
+    };
 
-        let result = mosaic(vec![left, right]);
+    let mut mosaic = match options.canvas {
+        Some(canvas) => {
+            let size = Size {
+                width: mosaic.image.width(),
+                height: mosaic.image.height(),
+            };
+            let (image, placement) = fit_to_canvas(mosaic.image, canvas, options);
+            Mosaic {
+                image,
+                layout: mosaic.layout,
+                regions: mosaic.regions.iter().map(|region| region.place_within(size, placement)).collect(),
+            }
+        }
+        None => mosaic,
+    };
 
-        save_result(&result, "scale_down_to_fit");
-        assert!(is_colour_in_range(0, 0, 1980, 2180, &result, RED));
-        assert!(has_black_vertical_line(2000, &result));
-        assert!(is_colour_in_range(2020, 0, 4000, 2180, &result, BLUE));
+    if options.debug == DebugOverlay::Grid {
+        draw_debug_overlay(&mut mosaic.image, &mosaic.regions);
     }
 
-    #[test]
-    fn doesnt_attempt_removed_mosaic() {
-        let left_top = create_with_colour(200, 300, RED);
-        let left_bot = create_with_colour(200, 300, BLUE);
-        let mid = create_with_colour(200, 600, GREEN);
-        let right = create_with_colour(200, 600, PURPLE);
+    mosaic
+}
 
-        let result = mosaic(vec![left_top, left_bot, mid, right]);
+/// Applies `gamma` and then adds `brightness` to every channel of `image`, through a lookup table so each
+/// value is only worked out once.
+fn adjust_tone(image: &mut RgbImage, brightness: i32, gamma: f32) {
+    let brightness = brightness.clamp(-255, 255);
+    let gamma = if gamma.is_finite() { gamma.clamp(MIN_GAMMA, MAX_GAMMA) } else { 1.0 };
+    if brightness == 0 && gamma == 1.0 {
+        return;
+    }
 
-        save_result(&result, "doesnt_attempt_removed_mosaic");
-        assert!((result.width() < 590) | (result.width() > 630));
-        assert!((result.width() < 590) | (result.width() > 630));
-        assert!(has_black_horizontal_line(305, &result));
-        assert!(has_black_vertical_line(205, &result));
+    let table: Vec<u8> = (0..=255)
+        .map(|value: i32| {
+            let corrected = (value as f32 / 255.0).powf(1.0 / gamma) * 255.0;
+            (corrected.round() as i32 + brightness).clamp(0, 255) as u8
+        })
+        .collect();
+    for channel in image.iter_mut() {
+        *channel = table[*channel as usize];
+    }
+}
+
+/// Where the gutters of a 2x2 mosaic cross, or the middle of the mosaic for other layouts. When the rows
+/// split at different points, this is halfway between the two splits.
+fn badge_centre(mosaic: &Mosaic) -> (u32, u32) {
+    let two_by_two = matches!(mosaic.layout, Layout::TwoRowsOfTwo | Layout::Grid) && mosaic.regions.len() == 4;
+    if !two_by_two {
+        return (mosaic.image.width() / 2, mosaic.image.height() / 2);
+    }
+
+    // Measured from the inner edges either side, so it holds however the images are mirrored.
+    let between = |start: u32, end: u32, other_start: u32, other_end: u32| {
+        (start.max(other_start) + end.min(other_end)) / 2
+    };
+    let [first, second, third, fourth] = [0, 1, 2, 3].map(|index| mosaic.regions[index]);
+    let first_gutter = between(first.offset.width, first.total_width(), second.offset.width, second.total_width());
+    let second_gutter = between(third.offset.width, third.total_width(), fourth.offset.width, fourth.total_width());
+    let row_gutter = between(first.offset.height, first.total_height(), third.offset.height, third.total_height());
+
+    ((first_gutter + second_gutter) / 2, row_gutter)
+}
+
+/// Draws the circle inscribed in `badge` centred on `centre`, scaled down to at most `MAX_BADGE_FRACTION`
+/// of the short side of `image`.
+fn draw_badge(image: &mut RgbImage, badge: &RgbImage, centre: (u32, u32), filters: ResizeFilters) {
+    let max_side = ((image.width().min(image.height()) as f32 * MAX_BADGE_FRACTION) as u32).max(1);
+    let badge_size = Size {
+        width: badge.width(),
+        height: badge.height(),
+    };
+    let size = contain_dimension(badge_size, Size { width: max_side, height: max_side });
+    let badge = if size.width < badge.width() || size.height < badge.height() {
+        image::imageops::resize(badge, size.width, size.height, filters.for_resize(badge_size, size))
+    } else {
+        badge.clone()
+    };
+
+    let (radius_x, radius_y) = (badge.width() as f32 / 2.0, badge.height() as f32 / 2.0);
+    let left = centre.0 as i64 - badge.width() as i64 / 2;
+    let top = centre.1 as i64 - badge.height() as i64 / 2;
+    for (x, y, pixel) in badge.enumerate_pixels() {
+        let dx = (x as f32 + 0.5 - radius_x) / radius_x;
+        let dy = (y as f32 + 0.5 - radius_y) / radius_y;
+        let (target_x, target_y) = (left + x as i64, top + y as i64);
+        let inside = target_x >= 0 && target_y >= 0 && target_x < image.width() as i64 && target_y < image.height() as i64;
+        if dx * dx + dy * dy <= 1.0 && inside {
+            image.put_pixel(target_x as u32, target_y as u32, *pixel);
+        }
+    }
+}
+
+/// Like `mosaic_prepared`, but hands back each image scaled, and cropped for layouts that crop, to its
+/// rectangle in the mosaic instead of compositing them. Gutters, borders, bleed and the canvas only exist
+/// on the composite, so they don't apply.
+pub fn mosaic_tiles(images: Vec<RgbImage>, options: &MosaicOptions) -> MosaicTiles {
+    let images = equalize_images(flip_images(images, &options.flips), options);
+    let sizes: Vec<Size> = images
+        .iter()
+        .map(|image| Size {
+            width: image.width(),
+            height: image.height(),
+        })
+        .collect();
+    let plan = plan_layout(&sizes, options);
+
+    // Diagonal regions are the rectangles the slanted ones are based on, which the images only fill by cropping.
+    let tiles = if options.crops(plan.layout) || plan.layout == Layout::Diagonal {
+        let resize_args = zip(images, &plan.images)
+            .enumerate()
+            .map(|(index, (image, offset))| (index, image, offset.dimensions))
+            .collect();
+        resize_images_to_fill(resize_args, options)
+    } else {
+        let resize_args = zip(images, &plan.images)
+            .map(|(image, offset)| (image, offset.dimensions))
+            .collect();
+        resize_images(resize_args, options.filters)
+    };
+
+    MosaicTiles {
+        layout: plan.layout,
+        total_size: plan.total_size,
+        regions: plan.images,
+        tiles,
+    }
+}
+
+/// Scales each image to fit a common frame size, the largest width and height among them, for showing them
+/// one at a time instead of side by side. Leftover space is filled like the gutters of a mosaic. The
+/// images must already have been through `prepare_image`.
+pub fn slideshow_frames(images: Vec<RgbImage>, options: &MosaicOptions) -> Vec<RgbImage> {
+    let images = equalize_images(flip_images(images, &options.flips), options);
+    let frame = options.canvas.unwrap_or_else(|| Size {
+        width: images.iter().map(|image| image.width()).max().unwrap_or(1),
+        height: images.iter().map(|image| image.height()).max().unwrap_or(1),
+    });
+
+    images
+        .into_iter()
+        .map(|image| fit_to_canvas(image, frame, options).0)
+        .collect()
+}
+
+/// Works out where each image of the given sizes goes in the final mosaic, without needing the images
+/// themselves. The margin, caption and canvas are included, so this is the size and regions a mosaic of the
+/// images comes out with.
+pub fn plan_mosaic(sizes: &[Size], options: &MosaicOptions) -> MosaicPlan {
+    let plan = plan_layout(sizes, options);
+    let margin = options.margin();
+    let mut total_size = plan.total_size.grow(margin);
+    if let Some(caption) = &options.caption {
+        total_size.height += caption_height(total_size.width, caption);
+    }
+    let images = plan.images.iter().map(|offset| with_margin(offset, margin));
+
+    match options.canvas {
+        Some(canvas) => {
+            let (canvas, placement) = canvas_placement(total_size, canvas, options);
+            MosaicPlan {
+                layout: plan.layout,
+                total_size: canvas,
+                images: images.map(|offset| offset.place_within(total_size, placement)).collect(),
+            }
+        }
+        None => MosaicPlan {
+            layout: plan.layout,
+            total_size,
+            images: images.collect(),
+        },
+    }
+}
+
+/// Like `plan_mosaic`, but without the margin, which is left to whatever composites the images.
+fn plan_layout(sizes: &[Size], options: &MosaicOptions) -> MosaicPlan {
+    let sizes: Vec<Size> = sizes
+        .iter()
+        .enumerate()
+        .map(|(index, size)| {
+            let size = match options.max_aspect_ratio {
+                Some(max_aspect_ratio) => crop_size_to_aspect_ratio(*size, max_aspect_ratio),
+                None => *size,
+            };
+            let size = correct_aspect_ratio(size, options.aspect_ratio(index));
+            intrinsic_size(size, options.scale(index))
+        })
+        .collect();
+    let sizes = match options.fit {
+        FitMode::Scale if options.crops_to_shared_aspect() => crop_to_shared_aspect_ratio(&sizes),
+        FitMode::Scale => sizes,
+        FitMode::Pad => pad_to_common_aspect_ratio(&sizes),
+    };
+
+    let plan = if options.style == MosaicStyle::Grid {
+        plan_grid_mosaic(&sizes, options.spacing.clamped(), options.max_size())
+    } else {
+        match sizes[..] {
+            [first, second] => plan_2_mosaic(first, second, options).into(),
+            [first, second, third] => plan_3_mosaic(first, second, third, options).into(),
+            [first, second, third, fourth] => plan_4_mosaic(first, second, third, fourth, options).into(),
+            _ => panic!("impossible image length"),
+        }
+    };
+
+    match options.direction {
+        Direction::Ltr => plan,
+        Direction::Rtl => plan.mirrored(),
+    }
+}
+
+/// Mirrors each image the way its entry in `flips` asks for. Flipping doesn't change any sizes, so it can
+/// happen at any point before the images are composited.
+fn flip_images(images: Vec<RgbImage>, flips: &[Flip]) -> Vec<RgbImage> {
+    images
+        .into_iter()
+        .enumerate()
+        .map(|(index, mut image)| {
+            let flip = flips.get(index).copied().unwrap_or_default();
+            if flip.horizontal {
+                image::imageops::flip_horizontal_in_place(&mut image);
+            }
+            if flip.vertical {
+                image::imageops::flip_vertical_in_place(&mut image);
+            }
+            image
+        })
+        .collect()
+}
+
+/// Shifts each image's brightness towards the mean of them all, if `options` asks for it.
+fn equalize_images(images: Vec<RgbImage>, options: &MosaicOptions) -> Vec<RgbImage> {
+    if !options.equalize {
+        return images;
+    }
+
+    let shifts = exposure_shifts(&images);
+    zip(images, shifts).map(|(image, shift)| shift_brightness(image, shift)).collect()
+}
+
+/// How much to add to every channel of each image to bring its mean brightness to the mean of them all,
+/// up to `MAX_EXPOSURE_SHIFT` either way.
+fn exposure_shifts(images: &[RgbImage]) -> Vec<i32> {
+    let means: Vec<f32> = images.iter().map(mean_luma).collect();
+    let target = means.iter().sum::<f32>() / means.len().max(1) as f32;
+
+    means
+        .iter()
+        .map(|mean| ((target - mean).round() as i32).clamp(-MAX_EXPOSURE_SHIFT, MAX_EXPOSURE_SHIFT))
+        .collect()
+}
+
+/// The mean Rec. 601 luma of `image`, sampled on a grid of at most `EXPOSURE_SAMPLES` pixels each way.
+fn mean_luma(image: &RgbImage) -> f32 {
+    let step = (image.width().max(image.height()) / EXPOSURE_SAMPLES).max(1) as usize;
+
+    let (mut total, mut samples) = (0.0, 0);
+    for y in (0..image.height()).step_by(step) {
+        for x in (0..image.width()).step_by(step) {
+            let Rgb([r, g, b]) = *image.get_pixel(x, y);
+            total += r as f32 * 0.299 + g as f32 * 0.587 + b as f32 * 0.114;
+            samples += 1;
+        }
+    }
+
+    total / samples.max(1) as f32
+}
+
+/// Adds `shift` to every channel of every pixel, clamping at black and white.
+fn shift_brightness(mut image: RgbImage, shift: i32) -> RgbImage {
+    if shift == 0 {
+        return image;
+    }
+
+    let table: Vec<u8> = (0..=255).map(|value: i32| (value + shift).clamp(0, 255) as u8).collect();
+    for channel in image.iter_mut() {
+        *channel = table[*channel as usize];
+    }
+    image
+}
+
+/// Widens or narrows `size` to `aspect_ratio`, keeping its height, for images whose pixels don't have their
+/// true aspect ratio.
+fn correct_aspect_ratio(size: Size, aspect_ratio: Option<f32>) -> Size {
+    match aspect_ratio {
+        Some(aspect_ratio) => Size {
+            width: max((size.height as f32 * aspect_ratio).round() as u32, 1),
+            height: size.height,
+        },
+        None => size,
+    }
+}
+
+/// The shape `image`, the one at `index`, is laid out with once its aspect ratio is corrected.
+fn layout_size(image: &RgbImage, index: usize, options: &MosaicOptions) -> Size {
+    correct_aspect_ratio(
+        Size {
+            width: image.width(),
+            height: image.height(),
+        },
+        options.aspect_ratio(index),
+    )
+}
+
+/// The size an image of `size` pixels is laid out at, given how many of its pixels make up one pixel of
+/// layout. The layout then treats it as natively that size, so it isn't blown up to match its neighbours.
+fn intrinsic_size(size: Size, scale: f32) -> Size {
+    if scale == 1.0 {
+        return size;
+    }
+
+    Size {
+        width: max((size.width as f32 / scale).round() as u32, 1),
+        height: max((size.height as f32 / scale).round() as u32, 1),
+    }
+}
+
+/// Shrinks the long side of `size` so it is at most `max_aspect_ratio` times the short side.
+fn crop_size_to_aspect_ratio(size: Size, max_aspect_ratio: f32) -> Size {
+    let max_aspect_ratio = max_aspect_ratio.max(1.0);
+
+    if size.width as f32 > size.height as f32 * max_aspect_ratio {
+        Size {
+            width: (size.height as f32 * max_aspect_ratio).round() as u32,
+            height: size.height,
+        }
+    } else if size.height as f32 > size.width as f32 * max_aspect_ratio {
+        Size {
+            width: size.width,
+            height: (size.width as f32 * max_aspect_ratio).round() as u32,
+        }
+    } else {
+        size
+    }
+}
+
+/// Center-crops the long side of `image` so it is at most `max_aspect_ratio` times the short side.
+fn crop_to_aspect_ratio(image: RgbImage, max_aspect_ratio: f32) -> RgbImage {
+    let (width, height) = image.dimensions();
+    let cropped = crop_size_to_aspect_ratio(Size { width, height }, max_aspect_ratio);
+
+    if cropped.width == width && cropped.height == height {
+        return image;
+    }
+
+    tracing::debug!("cropping {}x{} image to {}x{}", width, height, cropped.width, cropped.height);
+    image::imageops::crop_imm(
+        &image,
+        (width - cropped.width) / 2,
+        (height - cropped.height) / 2,
+        cropped.width,
+        cropped.height,
+    )
+    .to_image()
+}
+
+/// Crops away the rows and columns along each edge that are entirely the colour of the top left pixel.
+/// Images that are that colour all over are left alone.
+fn trim_border(image: RgbImage) -> RgbImage {
+    let (width, height) = image.dimensions();
+    let border = *image.get_pixel(0, 0);
+    let is_border = |x: u32, y: u32| {
+        zip(image.get_pixel(x, y).0, border.0).all(|(a, b)| a.abs_diff(b) <= TRIM_TOLERANCE)
+    };
+    let is_border_row = |y: u32| (0..width).all(|x| is_border(x, y));
+    let is_border_column = |x: u32, top: u32, bottom: u32| (top..bottom).all(|y| is_border(x, y));
+
+    let top = match (0..height).find(|&y| !is_border_row(y)) {
+        Some(top) => top,
+        None => return image,
+    };
+    let bottom = (top..height).rev().find(|&y| !is_border_row(y)).unwrap() + 1;
+    let left = (0..width).find(|&x| !is_border_column(x, top, bottom)).unwrap();
+    let right = (left..width).rev().find(|&x| !is_border_column(x, top, bottom)).unwrap() + 1;
+
+    if (left, top, right, bottom) == (0, 0, width, height) {
+        return image;
+    }
+
+    tracing::debug!("trimming {}x{} image to {}x{}", width, height, right - left, bottom - top);
+    image::imageops::crop_imm(&image, left, top, right - left, bottom - top).to_image()
+}
+
+fn create_background(size: Size) -> RgbImage {
+    RgbImage::from_pixel(size.width, size.height, image::Rgb([0, 0, 0]))
+}
+
+/// Creates the canvas images are composited onto, according to `options.background`.
+fn create_background_for(images: &[RgbImage], size: Size, options: &MosaicOptions) -> RgbImage {
+    match options.background {
+        BackgroundMode::Solid => create_background(size),
+        BackgroundMode::Blur => create_blurred_background(&images[0], size, options.blur_radius),
+    }
+}
+
+/// Stretches a copy of `image` blurred by `radius` over the whole canvas, like a letterbox fill.
+#[instrument(skip(image))]
+fn create_blurred_background(image: &RgbImage, size: Size, radius: f32) -> RgbImage {
+    let start = Instant::now();
+    let radius = radius.clamp(1.0, (size.width.max(size.height) as f32 * MAX_BLUR_FRACTION).max(1.0));
+
+    // Blurring is slow at full size, and the detail is thrown away anyway, so blur a small copy and scale it up.
+    let small = Size {
+        width: max(size.width / BLUR_DOWNSCALE, 1),
+        height: max(size.height / BLUR_DOWNSCALE, 1),
+    };
+    let image_size = Size {
+        width: image.width(),
+        height: image.height(),
+    };
+    let cover = cover_dimension(image_size, small);
+    let resized = image::imageops::resize(image, cover.width, cover.height, FilterType::Triangle);
+    let blurred = image::imageops::blur(&crop_center(&resized, small), radius / BLUR_DOWNSCALE as f32);
+    let background = image::imageops::resize(&blurred, size.width, size.height, FilterType::Triangle);
+
+    tracing::debug!(time = start.elapsed().as_millis(), "created blurred background");
+
+    background
+}
+
+fn scale_height_dimension(image_size: Size, other_height: u32) -> Size {
+    let scale_factor = image_size.height as f32 / other_height as f32;
+    Size {
+        width: (image_size.width as f32 / scale_factor).round() as u32,
+        height: other_height,
+    }
+}
+
+fn scale_width_dimension(image_size: Size, other_width: u32) -> Size {
+    let scale_factor = image_size.width as f32 / other_width as f32;
+    Size {
+        width: other_width,
+        height: (image_size.height as f32 / scale_factor).round() as u32,
+    }
+}
+
+fn resize_images(images: Vec<(RgbImage, Size)>, filters: ResizeFilters) -> Vec<RgbImage> {
+    tracing::debug!("resizing {} images", images.len());
+
+    let resized = resize_all(images, filters);
+
+    // Outside of wasm the resizes run in parallel, so the slowest one is what the mosaic actually waits for.
+    let total: Duration = resized.iter().map(|(_, time)| *time).sum();
+    if let Some((slowest, (_, max))) = resized.iter().enumerate().max_by_key(|(_, (_, time))| *time) {
+        tracing::debug!(
+            total = total.as_millis(),
+            max = max.as_millis(),
+            slowest,
+            "resized {} images",
+            resized.len()
+        );
+    }
+
+    resized.into_iter().map(|(im, _)| im).collect()
+}
+
+/// Resizes every image on its own thread, returning how long each took.
+#[cfg(not(target_arch = "wasm32"))]
+fn resize_all(images: Vec<(RgbImage, Size)>, filters: ResizeFilters) -> Vec<(RgbImage, Duration)> {
+    let span = tracing::Span::current();
+
+    images
+        .into_iter()
+        .enumerate()
+        .map(|(slot, (im, size))| {
+            let span = span.clone();
+
+            std::thread::spawn(move || {
+                let _span = span.entered();
+                let start = Instant::now();
+                let im = resize_image(im, size, Some(slot), filters);
+                (im, start.elapsed())
+            })
+        })
+        .collect::<Vec<_>>() // eagerly evaluate map to spawn threads
+        .into_iter()
+        .map(|thread| thread.join().unwrap())
+        .collect()
+}
+
+/// Resizes the images one after another, as wasm has no threads to spawn, nor a clock to time them with.
+#[cfg(target_arch = "wasm32")]
+fn resize_all(images: Vec<(RgbImage, Size)>, filters: ResizeFilters) -> Vec<(RgbImage, Duration)> {
+    images
+        .into_iter()
+        .enumerate()
+        .map(|(slot, (im, size))| (resize_image(im, size, Some(slot), filters), Duration::ZERO))
+        .collect()
+}
+
+/// Stands in for `std::time::Instant`, which panics on wasm32-unknown-unknown as there is no clock to read,
+/// for the timings in traces. Everything takes no time at all as far as it can tell.
+#[cfg(target_arch = "wasm32")]
+struct Instant;
+
+#[cfg(target_arch = "wasm32")]
+impl Instant {
+    fn now() -> Instant {
+        Instant
+    }
+
+    fn elapsed(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+/// Resizes `image` to exactly `size`. `slot` is the image's place in the mosaic, when it has one, and is
+/// only there to tell the resizes apart in traces.
+#[instrument(
+    skip_all,
+    fields(
+        slot = slot,
+        source_width = image.width(),
+        source_height = image.height(),
+        target_width = size.width,
+        target_height = size.height,
+    )
+)]
+fn resize_image(image: RgbImage, size: Size, slot: Option<usize>, filters: ResizeFilters) -> RgbImage {
+    tracing::trace!("starting image resize");
+
+    let start = Instant::now();
+
+    if image.width() != size.width || image.height() != size.height {
+        let image_size = Size {
+            width: image.width(),
+            height: image.height(),
+        };
+        let im = image::imageops::resize(
+            &image,
+            size.width,
+            size.height,
+            filters.for_resize(image_size, size),
+        );
+
+        tracing::debug!(time = start.elapsed().as_millis(), "resized image");
+
+        im
+    } else {
+        tracing::debug!("image was already acceptable size");
+
+        image
+    }
+}
+
+/// Returns the smallest size with the same aspect ratio as `image_size` that fully covers `target`.
+fn cover_dimension(image_size: Size, target: Size) -> Size {
+    let by_width = scale_width_dimension(image_size, target.width);
+    if by_width.height >= target.height {
+        by_width
+    } else {
+        scale_height_dimension(image_size, target.height)
+    }
+}
+
+/// Grows each size along one side to the mean aspect ratio of them all, for `FitMode::Pad`.
+fn pad_to_common_aspect_ratio(sizes: &[Size]) -> Vec<Size> {
+    let aspect_ratios = sizes.iter().map(|size| size.width as f32 / size.height as f32);
+    let aspect_ratio = aspect_ratios.sum::<f32>() / sizes.len() as f32;
+
+    sizes
+        .iter()
+        .map(|size| {
+            if (size.width as f32 / size.height as f32) < aspect_ratio {
+                Size {
+                    width: max((size.height as f32 * aspect_ratio).round() as u32, size.width),
+                    height: size.height,
+                }
+            } else {
+                Size {
+                    width: size.width,
+                    height: max((size.width as f32 / aspect_ratio).round() as u32, size.height),
+                }
+            }
+        })
+        .collect()
+}
+
+/// Shrinks each size along one side towards the median aspect ratio of them all, for `shared_aspect`,
+/// keeping at least `1 - MAX_SHARED_ASPECT_CROP` of that side.
+fn crop_to_shared_aspect_ratio(sizes: &[Size]) -> Vec<Size> {
+    let mut aspect_ratios: Vec<f32> = sizes.iter().map(|size| size.width as f32 / size.height as f32).collect();
+    aspect_ratios.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Equal));
+    let middle = aspect_ratios.len() / 2;
+    let aspect_ratio = match aspect_ratios.len() % 2 {
+        0 => (aspect_ratios[middle - 1] + aspect_ratios[middle]) / 2.0,
+        _ => aspect_ratios[middle],
+    };
+    let kept = 1.0 - MAX_SHARED_ASPECT_CROP;
+
+    sizes
+        .iter()
+        .map(|size| {
+            if (size.width as f32 / size.height as f32) > aspect_ratio {
+                let width = (size.height as f32 * aspect_ratio).max(size.width as f32 * kept);
+                Size {
+                    width: (width.round() as u32).clamp(1, size.width.max(1)),
+                    height: size.height,
+                }
+            } else {
+                let height = (size.width as f32 / aspect_ratio).max(size.height as f32 * kept);
+                Size {
+                    width: size.width,
+                    height: (height.round() as u32).clamp(1, size.height.max(1)),
+                }
+            }
+        })
+        .collect()
+}
+
+/// The part of `placement` a padded image of `image_size` takes up: as big as fits, in the middle.
+fn pad_placement(image_size: Size, placement: ImageOffset) -> ImageOffset {
+    let dimensions = contain_dimension(image_size, placement.dimensions);
+
+    ImageOffset {
+        offset: Size {
+            width: placement.offset.width + (placement.dimensions.width - dimensions.width) / 2,
+            height: placement.offset.height + (placement.dimensions.height - dimensions.height) / 2,
+        },
+        dimensions,
+        original_dimensions: placement.original_dimensions,
+    }
+}
+
+/// Returns the biggest size with the same aspect ratio as `image_size` that fits inside `target`.
+fn contain_dimension(image_size: Size, target: Size) -> Size {
+    let by_width = scale_width_dimension(image_size, target.width);
+    let contained = if by_width.height <= target.height {
+        by_width
+    } else {
+        scale_height_dimension(image_size, target.height)
+    };
+
+    Size {
+        width: max(contained.width, 1),
+        height: max(contained.height, 1),
+    }
+}
+
+/// Scales a finished mosaic up or down to fit `canvas`, then centres it on a background of exactly that size.
+/// Also returns where on the canvas the mosaic ended up.
+#[instrument(skip(image, options))]
+fn fit_to_canvas(image: RgbImage, canvas: Size, options: &MosaicOptions) -> (RgbImage, ImageOffset) {
+    let image_size = Size {
+        width: image.width(),
+        height: image.height(),
+    };
+    let (canvas, placement) = canvas_placement(image_size, canvas, options);
+
+    let resized = resize_image(image, placement.dimensions, None, options.filters);
+    let mut background = create_background_for(std::slice::from_ref(&resized), canvas, options);
+    image::imageops::overlay(
+        &mut background,
+        &resized,
+        placement.offset.width as i64,
+        placement.offset.height as i64,
+    );
+
+    (background, placement)
+}
+
+/// Where a mosaic of `image_size` goes when fitted to `canvas`, along with the size the canvas really is once
+/// it has been kept within bounds.
+fn canvas_placement(image_size: Size, canvas: Size, options: &MosaicOptions) -> (Size, ImageOffset) {
+    // The canvas is the whole image, margin and caption included, so it is only held to `MAX_SIZE` itself.
+    let max_size = if options.uncapped { u32::MAX } else { MAX_SIZE };
+    let canvas = Size {
+        width: canvas.width.clamp(1, max_size),
+        height: canvas.height.clamp(1, max_size),
+    };
+    let fitted = contain_dimension(image_size, canvas);
+    let fitted = if options.no_upscale && (fitted.width > image_size.width || fitted.height > image_size.height) {
+        image_size
+    } else {
+        fitted
+    };
+
+    let placement = ImageOffset {
+        offset: Size {
+            width: (canvas.width - fitted.width) / 2,
+            height: (canvas.height - fitted.height) / 2,
+        },
+        dimensions: fitted,
+        original_dimensions: image_size,
+    };
+    (canvas, placement)
+}
+
+/// Resizes each image to cover its target size, then crops it down to exactly that size around its focal point.
+/// Images with a corrected aspect ratio are stretched to it before being cropped. Each image comes with the
+/// index of its slot, whose focal point and aspect ratio it takes.
+fn resize_images_to_fill(images: Vec<(usize, RgbImage, Size)>, options: &MosaicOptions) -> Vec<RgbImage> {
+    let targets: Vec<(usize, Size)> = images.iter().map(|(index, _, size)| (*index, *size)).collect();
+    let resize_args = images
+        .into_iter()
+        .map(|(index, image, size)| {
+            let image_size = correct_aspect_ratio(
+                Size {
+                    width: image.width(),
+                    height: image.height(),
+                },
+                options.aspect_ratio(index),
+            );
+            (image, cover_dimension(image_size, size))
+        })
+        .collect();
+
+    zip(resize_images(resize_args, options.filters), targets)
+        .map(|(image, (index, target))| {
+            crop_around(&image, target, options.focal_points.get(index).copied().unwrap_or_default())
+        })
+        .collect()
+}
+
+/// Crops the middle `size` out of an image at least that large.
+fn crop_center(image: &RgbImage, size: Size) -> RgbImage {
+    crop_around(image, size, FocalPoint::default())
+}
+
+/// Crops `size` out of an image at least that large, centred on `focus` as far as the edges allow.
+fn crop_around(image: &RgbImage, size: Size, focus: FocalPoint) -> RgbImage {
+    let start = |length: u32, target: u32, focus: f32| {
+        let spare = length - target;
+        let centred = focus.clamp(0.0, 1.0) * length as f32 - target as f32 / 2.0;
+        (centred.floor().max(0.0) as u32).min(spare)
+    };
+    let x = start(image.width(), size.width, focus.x);
+    let y = start(image.height(), size.height, focus.y);
+    image::imageops::crop_imm(image, x, y, size.width, size.height).to_image()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    LeftRight,
+    TopBottom,
+    ThreeColumns,
+    TopTopBottom,
+    LeftLeftRight,
+    LeftRightRight,
+    TopBottomBottom,
+    ThreeRows,
+    FourColumns,
+    FourRows,
+    TwoRowsOfTwo,
+    TwoRowsOneThree,
+    TwoRowsThreeOne,
+    TwoColumnsOfTwo,
+    TwoColumnsOneThree,
+    TwoColumnsThreeOne,
+    ThreeRows211,
+    ThreeRows121,
+    ThreeRows112,
+    ThreeColumns211,
+    ThreeColumns121,
+    ThreeColumns112,
+    Diagonal,
+    Grid,
+    Diptych,
+}
+
+impl Layout {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Layout::LeftRight => "left_right",
+            Layout::TopBottom => "top_bottom",
+            Layout::ThreeColumns => "three_columns",
+            Layout::TopTopBottom => "top_top_bottom",
+            Layout::LeftLeftRight => "left_left_right",
+            Layout::LeftRightRight => "left_right_right",
+            Layout::TopBottomBottom => "top_bottom_bottom",
+            Layout::ThreeRows => "three_rows",
+            Layout::FourColumns => "four_columns",
+            Layout::FourRows => "four_rows",
+            Layout::TwoRowsOfTwo => "two_rows_of_two",
+            Layout::TwoRowsOneThree => "two_rows_one_three",
+            Layout::TwoRowsThreeOne => "two_rows_three_one",
+            Layout::TwoColumnsOfTwo => "two_columns_of_two",
+            Layout::TwoColumnsOneThree => "two_columns_one_three",
+            Layout::TwoColumnsThreeOne => "two_columns_three_one",
+            Layout::ThreeRows211 => "three_rows_211",
+            Layout::ThreeRows121 => "three_rows_121",
+            Layout::ThreeRows112 => "three_rows_112",
+            Layout::ThreeColumns211 => "three_columns_211",
+            Layout::ThreeColumns121 => "three_columns_121",
+            Layout::ThreeColumns112 => "three_columns_112",
+            Layout::Diagonal => "diagonal",
+            Layout::Grid => "grid",
+            Layout::Diptych => "diptych",
+        }
+    }
+
+    /// Whether images are cropped to fill their rectangles rather than resized to them, because the
+    /// rectangles don't follow the images' own aspect ratios.
+    fn crops_to_fill(&self) -> bool {
+        matches!(self, Layout::Grid | Layout::Diptych)
+    }
+}
+
+pub struct Mosaic {
+    pub image: RgbImage,
+    pub layout: Layout,
+    /// Where each image ended up in `image`, in input order. Diagonal mosaics report the rectangles their
+    /// slanted regions are based on.
+    pub regions: Vec<ImageOffset>,
+}
+
+/// The images of a mosaic scaled to their places in it, but not composited.
+pub struct MosaicTiles {
+    pub layout: Layout,
+    pub total_size: Size,
+    /// Where each tile goes in a mosaic of `total_size`, in input order.
+    pub regions: Vec<ImageOffset>,
+    pub tiles: Vec<RgbImage>,
+}
+
+/// The final placement of every image in a mosaic, in input order.
+#[derive(Clone, Debug)]
+pub struct MosaicPlan {
+    pub layout: Layout,
+    pub total_size: Size,
+    pub images: Vec<ImageOffset>,
+}
+
+impl MosaicPlan {
+    /// Flips the placements left to right, so each row runs the other way. The images themselves aren't
+    /// mirrored.
+    fn mirrored(mut self) -> MosaicPlan {
+        for image in &mut self.images {
+            image.offset.width = self.total_size.width.saturating_sub(image.total_width());
+        }
+        self
+    }
+}
+
+impl<const LEN: usize> From<MosaicImageDims<LEN>> for MosaicPlan {
+    fn from(mosaic: MosaicImageDims<LEN>) -> Self {
+        MosaicPlan {
+            layout: mosaic.layout,
+            total_size: mosaic.total_size(),
+            images: mosaic.images.to_vec(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub struct Size {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Size {
+    fn scale(&self, scale_factor: f32) -> Size {
+        Size {
+            width: (self.width as f32 / scale_factor).round() as u32,
+            height: (self.height as f32 / scale_factor).round() as u32,
+        }
+    }
+    /// Adds the sizes, saturating instead of wrapping on huge inputs so they still get scaled down to fit
+    /// `MAX_SIZE` rather than turning into tiny mosaics.
+    fn add(&self, other: Size) -> Size {
+        Size {
+            width: self.width.saturating_add(other.width),
+            height: self.height.saturating_add(other.height),
+        }
+    }
+    /// Adds `margin` on every side.
+    fn grow(&self, margin: u32) -> Size {
+        self.add(Size {
+            width: margin * 2,
+            height: margin * 2,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ImageOffset {
+    pub offset: Size,
+    pub dimensions: Size,
+    pub original_dimensions: Size,
+}
+
+impl ImageOffset {
+    /// Scales the rectangle by rounding where its edges land rather than its size, so the size is rounded
+    /// down or up as needed. Neighbours that shared an edge before scaling then still share it afterwards,
+    /// instead of each rounding on its own and leaving a 1px seam or overlap between them.
+    fn scale(&self, scale_factor: f32) -> ImageOffset {
+        let start = self.offset.scale(scale_factor);
+        let end = self.offset.add(self.dimensions).scale(scale_factor);
+        ImageOffset {
+            offset: start,
+            dimensions: Size {
+                width: end.width - start.width,
+                height: end.height - start.height,
+            },
+            original_dimensions: self.original_dimensions,
+        }
+    }
+    fn add_height(&self, height: u32) -> ImageOffset {
+        ImageOffset {
+            offset: Size {
+                width: self.offset.width,
+                height: self.offset.height.saturating_add(height),
+            },
+            dimensions: Size {
+                width: self.dimensions.width,
+                height: self.dimensions.height,
+            },
+            original_dimensions: self.original_dimensions,
+        }
+    }
+    fn add_width(&self, width: u32) -> ImageOffset {
+        ImageOffset {
+            offset: Size {
+                width: self.offset.width.saturating_add(width),
+                height: self.offset.height,
+            },
+            dimensions: Size {
+                width: self.dimensions.width,
+                height: self.dimensions.height,
+            },
+            original_dimensions: self.original_dimensions,
+        }
+    }
+    fn total_width(&self) -> u32 {
+        self.offset.width.saturating_add(self.dimensions.width)
+    }
+    fn total_height(&self) -> u32 {
+        self.offset.height.saturating_add(self.dimensions.height)
+    }
+    /// Moves a rectangle on a `from` sized image to where it lands when that image is scaled into `to`.
+    fn place_within(&self, from: Size, to: ImageOffset) -> ImageOffset {
+        let scale_x = to.dimensions.width as f32 / from.width as f32;
+        let scale_y = to.dimensions.height as f32 / from.height as f32;
+        let scale = |value: u32, scale: f32| (value as f32 * scale).round() as u32;
+
+        ImageOffset {
+            offset: Size {
+                width: to.offset.width + scale(self.offset.width, scale_x),
+                height: to.offset.height + scale(self.offset.height, scale_y),
+            },
+            dimensions: Size {
+                width: scale(self.dimensions.width, scale_x),
+                height: scale(self.dimensions.height, scale_y),
+            },
+            original_dimensions: self.original_dimensions,
+        }
+    }
+}
+
+trait MosaicDims {
+    fn total_size(&self) -> Size;
+    fn scale(&self, scale_factor: f32) -> Self;
+    fn image_scale_factors(&self) -> Vec<f32>;
+    fn min_scale_factor(&self) -> f32;
+    fn max_scale_factor(&self) -> f32;
+    fn scale_factor_ratio(&self) -> f32;
+    fn scale_to_fit(&self, native: NativeImage, max_size: u32) -> Self;
+    fn layout(&self) -> Layout;
+    /// Whether any image ends up bigger than its native size in either direction.
+    fn upscales(&self) -> bool;
+    fn add_height(&self, height: u32) -> Self;
+    fn add_width(&self, width: u32) -> Self;
+    fn image_areas(&self) -> Vec<f32>;
+    fn original_sizes(&self) -> Vec<Size>;
+
+    /// The average weight over the area of the mosaic, so layouts that give heavily weighted images more
+    /// room score higher. Images without a weight count as 1, so with no weights this is always 1.
+    fn prominence(&self, weights: &[f32]) -> f32 {
+        let areas = self.image_areas();
+        let weighted: f32 = areas.iter().enumerate().map(|(index, area)| {
+            area * weights.get(index).copied().unwrap_or(1.0)
+        }).sum();
+        weighted / areas.iter().sum::<f32>()
+    }
+
+    fn unsquaredness(&self) -> f32 {
+        let total_size = self.total_size();
+        if total_size.width < total_size.height {
+            total_size.height as f32 / total_size.width as f32
+        } else {
+            total_size.width as f32 / total_size.height as f32
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct MosaicImageDims<const LEN: usize> {
+    images: [ImageOffset; LEN],
+    layout: Layout,
+    /// Whether the images are cropped to fill rectangles that don't follow their aspect ratios.
+    cropped: bool,
+}
+
+impl<const LEN: usize> MosaicDims for MosaicImageDims<LEN> {
+    fn total_size(&self) -> Size {
+        let last = self.images.last().unwrap();
+        last.offset.add(last.dimensions)
+    }
+
+    fn scale(&self, scale_factor: f32) -> Self {
+        let mut new_images = [ImageOffset::default(); LEN];
+        for (x, image) in self.images.iter().enumerate() {
+            new_images[x] = image.scale(scale_factor);
+        }
+
+        MosaicImageDims {
+            images: new_images,
+            layout: self.layout,
+            cropped: self.cropped,
+        }
+    }
+
+    fn image_scale_factors(&self) -> Vec<f32> {
+        // Images cropped to fill their rectangle are scaled to cover it, so the bigger ratio is their scale.
+        // Otherwise the two only differ by rounding, and the width is used as it always has been.
+        self.images.iter().map(|image| {
+            let width = image.dimensions.width as f32 / image.original_dimensions.width as f32;
+            let height = image.dimensions.height as f32 / image.original_dimensions.height as f32;
+            if self.cropped {
+                width.max(height)
+            } else {
+                width
+            }
+        }).collect()
+    }
+
+    fn min_scale_factor(&self) -> f32 {
+        *(self.image_scale_factors().iter().min_by(|a, b| {
+            a.partial_cmp(b).unwrap_or(Equal)
+        }).unwrap())
+    }
+
+    fn max_scale_factor(&self) -> f32 {
+        *self.image_scale_factors().iter().max_by(|a, b| {
+            a.partial_cmp(b).unwrap_or(Equal)
+        }).unwrap()
+    }
+
+    fn scale_factor_ratio(&self) -> f32 {
+        self.max_scale_factor() / self.min_scale_factor()
+    }
+
+    fn scale_to_fit(&self, native: NativeImage, max_size: u32) -> Self {
+        // Scale mosaic so that the smallest (or largest) image is 1:1 scale
+        let reference = match native {
+            NativeImage::Smallest => self.min_scale_factor(),
+            NativeImage::Largest => self.max_scale_factor(),
+        };
+        let mut scaled_mosaic = self.scale(reference);
+        if native == NativeImage::Largest {
+            // Rounding the edges can leave an image a pixel over its native size, so shrink a little
+            // further until none is.
+            let mut reference = reference;
+            for _ in 0..UPSCALE_CORRECTION_STEPS {
+                if !scaled_mosaic.upscales() {
+                    break;
+                }
+                reference *= 1.002;
+                scaled_mosaic = self.scale(reference);
+            }
+        }
+        // Scale down to fit into maximum dimensions
+        let total_size = scaled_mosaic.total_size();
+        let biggest = max(total_size.width, total_size.height);
+        if biggest > max_size {
+            let scale_factor = biggest as f32 / max_size as f32;
+            scaled_mosaic = scaled_mosaic.scale(scale_factor);
+        }
+        scaled_mosaic
+    }
+
+    fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    fn upscales(&self) -> bool {
+        self.images.iter().any(|image| {
+            image.dimensions.width > image.original_dimensions.width
+                || image.dimensions.height > image.original_dimensions.height
+        })
+    }
+
+    fn add_height(&self, height: u32) -> Self {
+        let mut new_images = [ImageOffset::default(); LEN];
+        for (x, image) in self.images.iter().enumerate() {
+            new_images[x] = image.add_height(height);
+        }
+        MosaicImageDims {
+            images: new_images,
+            layout: self.layout,
+            cropped: self.cropped,
+        }
+    }
+
+    fn add_width(&self, width: u32) -> Self {
+        let mut new_images = [ImageOffset::default(); LEN];
+        for (x, image) in self.images.iter().enumerate() {
+            new_images[x] = image.add_width(width);
+        }
+        MosaicImageDims {
+            images: new_images,
+            layout: self.layout,
+            cropped: self.cropped,
+        }
+    }
+
+    fn image_areas(&self) -> Vec<f32> {
+        self.images.iter().map(|image| {
+            image.dimensions.width as f32 * image.dimensions.height as f32
+        }).collect()
+    }
+
+    fn original_sizes(&self) -> Vec<Size> {
+        self.images.iter().map(|image| image.original_dimensions).collect()
+    }
+}
+
+impl<const LEN: usize> MosaicImageDims<LEN> {
+    /// The most images side by side in any row of the mosaic.
+    fn columns(&self) -> usize {
+        self.images.iter().map(|image| {
+            let y = image.offset.height.saturating_add(image.dimensions.height / 2);
+            self.images.iter().filter(|other| other.offset.height <= y && y < other.total_height()).count()
+        }).max().unwrap()
+    }
+
+    /// The most images stacked on top of each other in any column of the mosaic.
+    fn rows(&self) -> usize {
+        self.images.iter().map(|image| {
+            let x = image.offset.width.saturating_add(image.dimensions.width / 2);
+            self.images.iter().filter(|other| other.offset.width <= x && x < other.total_width()).count()
+        }).max().unwrap()
+    }
+}
+
+/// Drops the candidates with more columns or rows than `options` allows. If that would drop all of them, the
+/// limits can't be met and every candidate is kept instead.
+fn within_limits<'a, const LEN: usize>(
+    mosaics: &[&'a MosaicImageDims<LEN>],
+    options: &MosaicOptions,
+) -> Vec<&'a MosaicImageDims<LEN>> {
+    let within: Vec<_> = mosaics.iter().copied().filter(|mosaic| fits_limits(mosaic, options)).collect();
+
+    if within.is_empty() {
+        tracing::debug!("no layout fits within the column and row limits, ignoring them");
+        mosaics.to_vec()
+    } else {
+        within
+    }
+}
+
+/// Whether `mosaic` has no more columns and rows than `options` allow.
+fn fits_limits<const LEN: usize>(mosaic: &MosaicImageDims<LEN>, options: &MosaicOptions) -> bool {
+    let allowed = |limit: Option<u32>, count: usize| limit.iter().all(|&limit| count <= limit as usize);
+    allowed(options.max_columns, mosaic.columns()) && allowed(options.max_rows, mosaic.rows())
+}
+
+/// Whether `layout` can be used without searching the other candidates, because the images are squares of
+/// the same size, like avatars, which `layout` arranges into a grid. Weights could favour another layout,
+/// asking for a lower ranked layout needs the others, and it still has to fit the column and row limits.
+fn takes_uniform_fast_path<const LEN: usize>(sizes: &[Size], layout: &MosaicImageDims<LEN>, options: &MosaicOptions) -> bool {
+    let first = sizes[0];
+    let uniform = sizes.iter().all(|size| size.width == first.width && size.height == first.height);
+    uniform && first.width == first.height && options.weights.is_empty() && options.rank <= 1 && fits_limits(layout, options)
+}
+
+/// Picks the best of `mosaics`, or the `rank`th best counting from 1 as long as there are that many, scaled to
+/// fit. Returns `None` when there are no candidates, or when none of them have a usable scale factor, such
+/// as when an image has no pixels.
+fn best_mosaic<T: MosaicDims + Copy>(mosaics: &[&T], weights: &[f32], native: NativeImage, max_size: u32, tolerance: f32, rank: usize) -> Option<T> {
+    // Ensure all mosaics have a minimum (or maximum) scaling ratio of 1, and fit within the box
+    let scaled_mosaics: Vec<T> = mosaics.iter().map(|mosaic| {
+        mosaic.scale_to_fit(native, max_size)
+    }).collect();
+
+    // Find the lowest scaling ratio, to discard mosaics with a scaling ratio more than `tolerance` higher than that
+    let min_scale_factor_ratio = scaled_mosaics.iter().map(|mosaic| {
+        mosaic.scale_factor_ratio()
+    }).min_by(|a, b| {
+        a.partial_cmp(b).unwrap_or(Equal)
+    })?;
+
+    let scale_factor_ratio_cap = min_scale_factor_ratio + tolerance;
+    let eligible = |mosaic: &T| mosaic.scale_factor_ratio() <= scale_factor_ratio_cap;
+    let score = |mosaic: &T| mosaic.unsquaredness() / mosaic.prominence(weights);
+
+    // Then select squarest within that, favouring layouts that give weighted images more room. The rest are
+    // ranked too, so the runner-up can be logged.
+    let mut ranked: Vec<&T> = scaled_mosaics.iter().collect();
+    ranked.sort_by(|mosaic_a, mosaic_b| {
+        eligible(mosaic_b).cmp(&eligible(mosaic_a))
+            .then_with(|| score(mosaic_a).partial_cmp(&score(mosaic_b)).unwrap_or(Equal))
+    });
+
+    // With degenerate sizes the ratios can be NaN, which leaves nothing eligible.
+    ranked.first().filter(|mosaic| eligible(mosaic))?;
+    let rank = rank.clamp(1, ranked.len());
+    let best = ranked[rank - 1];
+    let runner_up = ranked.get(rank);
+    tracing::info!(
+        rank,
+        layout = best.layout().name(),
+        scale_factor_ratio = best.scale_factor_ratio(),
+        unsquaredness = best.unsquaredness(),
+        runner_up = runner_up.map(|mosaic| mosaic.layout().name()),
+        runner_up_scale_factor_ratio = runner_up.map(|mosaic| mosaic.scale_factor_ratio()),
+        runner_up_unsquaredness = runner_up.map(|mosaic| mosaic.unsquaredness()),
+        candidates = ranked.len(),
+        "picked layout"
+    );
+    if *LOG_LAYOUT_CANDIDATES {
+        tracing::info!(target: CANDIDATES_TARGET, "{}", candidates_row(&scaled_mosaics, best, tolerance, score, eligible));
+    }
+
+    Some(*best)
+}
+
+/// One layout decision, as logged to `CANDIDATES_TARGET`.
+#[derive(Serialize)]
+struct CandidatesRow {
+    sizes: Vec<[u32; 2]>,
+    tolerance: Option<f32>,
+    candidates: Vec<CandidateRow>,
+    chosen: &'static str,
+}
+
+/// One candidate layout of a `CandidatesRow`, with its scores.
+#[derive(Serialize)]
+struct CandidateRow {
+    layout: &'static str,
+    scale_factor_ratio: Option<f32>,
+    unsquaredness: Option<f32>,
+    score: Option<f32>,
+    eligible: bool,
+}
+
+/// One JSON object describing a layout decision: the input sizes, every candidate's scores, and which was
+/// picked. Scores that aren't finite are written as `null`, as JSON has no NaN.
+fn candidates_row<T: MosaicDims>(
+    candidates: &[T],
+    chosen: &T,
+    tolerance: f32,
+    score: impl Fn(&T) -> f32,
+    eligible: impl Fn(&T) -> bool,
+) -> String {
+    let number = |value: f32| value.is_finite().then_some(value);
+    let row = CandidatesRow {
+        sizes: chosen.original_sizes().iter().map(|size| [size.width, size.height]).collect(),
+        tolerance: number(tolerance),
+        candidates: candidates
+            .iter()
+            .map(|mosaic| CandidateRow {
+                layout: mosaic.layout().name(),
+                scale_factor_ratio: number(mosaic.scale_factor_ratio()),
+                unsquaredness: number(mosaic.unsquaredness()),
+                score: number(score(mosaic)),
+                eligible: eligible(mosaic),
+            })
+            .collect(),
+        chosen: chosen.layout().name(),
+    };
+    // Nothing in a row can fail to serialize: the keys are fixed and every value is a plain number or string.
+    serde_json::to_string(&row).unwrap()
+}
+
+fn build_mosaic(mosaic: MosaicPlan, images: Vec<RgbImage>, options: &MosaicOptions) -> Mosaic {
+    let placements = Placements::new(&mosaic, &images, options);
+    composite(&mosaic, &placements, images, options)
+}
+
+/// Draws every image of `mosaic` into its place over the background.
+fn composite(mosaic: &MosaicPlan, placements: &Placements, images: Vec<RgbImage>, options: &MosaicOptions) -> Mosaic {
+    let mut background = create_background_for(&images, mosaic.total_size.grow(options.margin()), options);
+    let resized = placements.fit(images.into_iter().enumerate().collect(), options);
+    for (index, image) in resized.iter().enumerate() {
+        placements.draw(&mut background, index, image, options);
+    }
+
+    Mosaic {
+        image: background,
+        layout: mosaic.layout,
+        regions: placements.regions.clone(),
+    }
+}
+
+/// Where the images of a plan are drawn, so another image can be drawn into the same slot later on, like
+/// the frames of an animation.
+struct Placements {
+    /// Each image's slot, margin included.
+    regions: Vec<ImageOffset>,
+    /// Where each image is actually drawn, which bleeding and padding can move off its slot.
+    placements: Vec<ImageOffset>,
+    /// Whether images are cropped to fill their placements, rather than stretched.
+    crops: bool,
+}
+
+impl Placements {
+    fn new(mosaic: &MosaicPlan, images: &[RgbImage], options: &MosaicOptions) -> Placements {
+        let bleed = if options.no_upscale { 0 } else { options.bleed.min(MAX_BLEED) };
+        let placements: Vec<ImageOffset> = mosaic.images.iter().map(|offset| {
+            bleed_offset(*offset, bleed, mosaic.total_size)
+        }).collect();
+
+        let placements: Vec<ImageOffset> = match options.fit {
+            FitMode::Scale => placements,
+            FitMode::Pad => zip(images, placements)
+                .enumerate()
+                .map(|(index, (image, placement))| pad_placement(layout_size(image, index, options), placement))
+                .collect(),
+        };
+
+        let margin = options.margin();
+        // Bled rectangles are slightly off the image's aspect ratio, so crop a pixel or two instead of stretching.
+        let crops = bleed > 0 || options.crops(mosaic.layout);
+        Placements {
+            regions: mosaic.images.iter().map(|offset| with_margin(offset, margin)).collect(),
+            placements: placements.iter().map(|offset| with_margin(offset, margin)).collect(),
+            crops: crops && options.fit == FitMode::Scale,
+        }
+    }
+
+    /// Resizes each image to the placement of the slot it's paired with.
+    fn fit(&self, images: Vec<(usize, RgbImage)>, options: &MosaicOptions) -> Vec<RgbImage> {
+        if self.crops {
+            let resize_args = images
+                .into_iter()
+                .map(|(slot, image)| (slot, image, self.placements[slot].dimensions))
+                .collect();
+            resize_images_to_fill(resize_args, options)
+        } else {
+            let resize_args = images
+                .into_iter()
+                .map(|(slot, image)| (image, self.placements[slot].dimensions))
+                .collect();
+            resize_images(resize_args, options.filters)
+        }
+    }
+
+    /// Draws `image`, already resized by `fit`, into `slot` over `background`, and then the slot's border.
+    fn draw(&self, background: &mut RgbImage, slot: usize, image: &RgbImage, options: &MosaicOptions) {
+        overlay_with_opacity(background, image, self.placements[slot].offset, options.opacity(slot));
+
+        let spacing = options.spacing.clamped();
+        let border_width = options.border_width.min(spacing.horizontal.min(spacing.vertical));
+        if border_width > 0 {
+            draw_border(background, self.regions[slot], border_width, options.border_colour);
+        }
+    }
+}
+
+/// Draws `image` over `background` with its top left corner at `offset`, blended in at `opacity`.
+fn overlay_with_opacity(background: &mut RgbImage, image: &RgbImage, offset: Size, opacity: f32) {
+    if opacity >= 1.0 {
+        image::imageops::overlay(background, image, offset.width as i64, offset.height as i64);
+        return;
+    }
+
+    for (x, y, pixel) in image.enumerate_pixels() {
+        let (x, y) = (offset.width + x, offset.height + y);
+        if x < background.width() && y < background.height() {
+            let below = background.get_pixel_mut(x, y);
+            *below = blend(*below, *pixel, opacity);
+        }
+    }
+}
+
+/// Moves `offset` in from the top left corner by `margin`.
+fn with_margin(offset: &ImageOffset, margin: u32) -> ImageOffset {
+    offset.add_width(margin).add_height(margin)
+}
+
+/// Grows `offset` by `bleed` pixels on every side that faces another image rather than the edge of the mosaic.
+fn bleed_offset(offset: ImageOffset, bleed: u32, total_size: Size) -> ImageOffset {
+    let left = offset.offset.width.saturating_sub(bleed);
+    let top = offset.offset.height.saturating_sub(bleed);
+    let right = (offset.total_width() + bleed).min(total_size.width);
+    let bottom = (offset.total_height() + bleed).min(total_size.height);
+
+    ImageOffset {
+        offset: Size {
+            width: left,
+            height: top,
+        },
+        dimensions: Size {
+            width: right - left,
+            height: bottom - top,
+        },
+        original_dimensions: offset.original_dimensions,
+    }
+}
+
+/// Draws a border of `width` pixels just inside the rectangle covered by `offset`.
+fn draw_border(background: &mut RgbImage, offset: ImageOffset, width: u32, colour: Rgb<u8>) {
+    let left = offset.offset.width;
+    let top = offset.offset.height;
+    let right = offset.total_width().min(background.width());
+    let bottom = offset.total_height().min(background.height());
+
+    for y in top..bottom {
+        for x in left..right {
+            let on_edge = x < left + width || x + width >= right || y < top + width || y + width >= bottom;
+            if on_edge {
+                background.put_pixel(x, y, colour);
+            }
+        }
+    }
+}
+
+/// A row of images where the edges between neighbours lean, forming parallelograms instead of rectangles.
+///
+/// Each image covers its rectangle from `base` shifted by up to `slant` pixels, and is masked to its own
+/// slanted region when composited.
+struct DiagonalMosaicDims {
+    base: MosaicPlan,
+    slant: f32,
+    /// The indices of the images at the left and right ends of the row, which are first and last unless
+    /// the row runs right to left.
+    leftmost: usize,
+    rightmost: usize,
+}
+
+impl DiagonalMosaicDims {
+    /// Builds a diagonal mosaic from an already scaled single-row layout.
+    fn new(base: MosaicPlan) -> Self {
+        let total_size = base.total_size;
+        let by_offset = |index: &usize| base.images[*index].offset.width;
+        let leftmost = (0..base.images.len()).min_by_key(by_offset).unwrap_or(0);
+        let rightmost = (0..base.images.len()).max_by_key(by_offset).unwrap_or(0);
+        let outer = base.images[leftmost].dimensions.width.min(base.images[rightmost].dimensions.width);
+        // The outer images lose up to `slant` pixels at one corner, so keep the lean well within them.
+        let slant = (total_size.height as f32 * DIAGONAL_SLANT_RATIO).min(outer as f32 / 2.0);
+
+        DiagonalMosaicDims {
+            base,
+            slant,
+            leftmost,
+            rightmost,
+        }
+    }
+
+    fn total_size(&self) -> Size {
+        self.base.total_size
+    }
+
+    /// How far the edges are shifted to the right at row `y`, going from `slant` at the top to `-slant` at the bottom.
+    fn edge_shift(&self, y: f32) -> f32 {
+        self.slant * (1.0 - 2.0 * y / self.total_size().height as f32)
+    }
+
+    /// The horizontal range of pixels that image `index` might cover.
+    fn bounds(&self, index: usize) -> (u32, u32) {
+        let image = self.base.images[index];
+        let start = if index == self.leftmost {
+            0
+        } else {
+            (image.offset.width as f32 - self.slant).floor() as u32
+        };
+        let end = if index == self.rightmost {
+            self.total_size().width
+        } else {
+            ((image.total_width() as f32 + self.slant).ceil() as u32).min(self.total_size().width)
+        };
+        (start, end)
+    }
+
+    /// How much of the pixel at (`x`, `y`) belongs to image `index`, from 0 to 1, antialiasing the slanted edges.
+    fn coverage(&self, index: usize, x: u32, y: u32) -> f32 {
+        let image = self.base.images[index];
+        let shift = self.edge_shift(y as f32 + 0.5);
+        let x = x as f32;
+
+        let left = if index == self.leftmost {
+            1.0
+        } else {
+            (x + 1.0 - (image.offset.width as f32 + shift)).clamp(0.0, 1.0)
+        };
+        let right = if index == self.rightmost {
+            1.0
+        } else {
+            (image.total_width() as f32 + shift - x).clamp(0.0, 1.0)
+        };
+
+        left * right
+    }
+}
+
+fn build_diagonal_mosaic(mosaic: DiagonalMosaicDims, images: Vec<RgbImage>, options: &MosaicOptions) -> Mosaic {
+    let height = mosaic.total_size().height;
+    let margin = options.margin();
+    let mut background = create_background_for(&images, mosaic.total_size().grow(margin), options);
+
+    let resize_args = images
+        .into_iter()
+        .enumerate()
+        .map(|(index, image)| {
+            let (start, end) = mosaic.bounds(index);
+            (
+                index,
+                image,
+                Size {
+                    width: end - start,
+                    height,
+                },
+            )
+        })
+        .collect();
+
+    let resized = resize_images_to_fill(resize_args, options);
+
+    for (index, image) in resized.iter().enumerate() {
+        let (start, _) = mosaic.bounds(index);
+        for (x, y, pixel) in image.enumerate_pixels() {
+            let coverage = mosaic.coverage(index, start + x, y) * options.opacity(index);
+            if coverage <= 0.0 {
+                continue;
+            }
+
+            let target = background.get_pixel_mut(margin + start + x, margin + y);
+            *target = blend(*target, *pixel, coverage);
+        }
+    }
+
+    Mosaic {
+        image: background,
+        layout: Layout::Diagonal,
+        regions: mosaic.base.images.iter().map(|offset| with_margin(offset, margin)).collect(),
+    }
+}
+
+fn blend(below: Rgb<u8>, above: Rgb<u8>, alpha: f32) -> Rgb<u8> {
+    let mut blended = below;
+    for (channel, above) in zip(blended.0.iter_mut(), above.0) {
+        *channel = (*channel as f32 * (1.0 - alpha) + above as f32 * alpha).round() as u8;
+    }
+    blended
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mosaic::{self, mosaic};
+    use crate::mosaic::caption::MAX_BAR_HEIGHT;
+    use image::{imageops::FilterType, Rgb, RgbImage};
+
+    use crate::mosaic::{adjust_tone, animated_mosaic, Anchor, best_mosaic, candidates_row, crop_around, FitMode, ImageOffset, FocalPoint, mosaic_prepared, NativeImage, plan_mosaic, DEFAULT_SCALE_TOLERANCE, MAX_SIZE, prepare_image, slideshow_frames, trim_border, MosaicImageDims, MosaicPlan, BackgroundMode, Layout, LayoutMode, MosaicOptions, ResizeFilter, ResizeFilters, Size, Spacing, MosaicDims};
+    use crate::mosaic::testutils::{
+        assert_matches_golden,
+        BLACK,
+        BLUE,
+        create_with_colour,
+        diff_images,
+        GREEN,
+        has_gutter,
+        has_gutter_between,
+        is_colour_at_pixel,
+        is_colour_in_range,
+        Orientation,
+        PURPLE,
+        RED,
+        save_result,
+        WHITE,
+    };
+
+    #[test]
+    fn pick_less_square_option_for_better_scaling_ratio() {
+        let top_left = create_with_colour(100, 100, RED);
+        let top_right = create_with_colour(300, 100, BLUE);
+        let bot_left = create_with_colour(300, 100, GREEN);
+        let bot_right = create_with_colour(100, 100, PURPLE);
+
+        let result = mosaic(vec![top_left, top_right, bot_left, bot_right], &MosaicOptions::default()).image;
+
+        save_result(&result, "less_square_better_scaling_ratio");
+        assert!(is_colour_in_range(0, 0, 100, 100, &result, RED));
+        assert!(has_gutter_between(100, 10, BLACK, Orientation::Vertical, 0, 100, &result));
+        assert!(is_colour_in_range(120, 0, 400, 100, &result, BLUE));
+        assert!(has_gutter(100, 10, BLACK, Orientation::Horizontal, &result));
+        assert!(is_colour_in_range(0, 120, 300, 200, &result, GREEN));
+        assert!(has_gutter_between(300, 10, BLACK, Orientation::Vertical, 120, 200, &result));
+        assert!(is_colour_in_range(320, 120, 400, 200, &result, PURPLE));
+    }
+
+    #[test]
+    fn wont_scale_down_to_match() {
+        let left = create_with_colour(100, 200, RED);
+        let right = create_with_colour(200, 400, BLUE);
+
+        let result = mosaic(vec![left, right], &MosaicOptions::default()).image;
+
+        save_result(&result, "wont_scale_down_to_match");
+        assert!(is_colour_in_range(0, 0, 200, 400, &result, RED));
+        assert!(has_gutter(200, 10, BLACK, Orientation::Vertical, &result));
+        assert!(is_colour_in_range(220, 0, 400, 400, &result, BLUE));
+    }
+
+    #[test]
+    fn scale_down_to_fit() {
+        let left = create_with_colour(3000, 3300, RED);
+        let right = create_with_colour(3000, 3300, BLUE);
+
+        let result = mosaic(vec![left, right], &MosaicOptions::default()).image;
+
+        save_result(&result, "scale_down_to_fit");
+        assert!(is_colour_in_range(0, 0, 1980, 2180, &result, RED));
+        assert!(has_gutter(2000, 1, BLACK, Orientation::Vertical, &result));
+        assert!(is_colour_in_range(2020, 0, 4000, 2180, &result, BLUE));
+    }
+
+    #[test]
+    fn animated_mosaic_redraws_only_the_animated_slot() {
+        let first = create_with_colour(100, 100, RED);
+        let still = create_with_colour(100, 100, BLUE);
+        let later = create_with_colour(100, 100, GREEN);
+
+        let frames = animated_mosaic(vec![first, still], 0, vec![later], &MosaicOptions::default());
+
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].dimensions(), frames[1].dimensions());
+        assert!(is_colour_in_range(0, 0, 100, 100, &frames[0], RED));
+        assert!(is_colour_in_range(0, 0, 100, 100, &frames[1], GREEN));
+        for frame in &frames {
+            assert!(has_gutter(100, 10, BLACK, Orientation::Vertical, frame));
+            assert!(is_colour_in_range(110, 0, 210, 100, frame, BLUE));
+        }
+    }
+
+    #[test]
+    fn animated_frames_crop_around_their_slots_focal_point() {
+        let images = vec![create_with_colour(200, 200, BLUE), create_with_colour(200, 200, RED)];
+        // Red on the left half and green on the right, so which half is kept shows where the crop went.
+        let frame = RgbImage::from_fn(400, 200, |x, _| if x < 200 { RED } else { GREEN });
+        let options = MosaicOptions {
+            layout: LayoutMode::Diptych,
+            focal_points: vec![FocalPoint::default(), FocalPoint { x: 1.0, y: 0.5 }],
+            ..MosaicOptions::default()
+        };
+
+        let frames = animated_mosaic(images, 1, vec![frame], &options);
+        let plan = plan_mosaic(&[Size { width: 200, height: 200 }, Size { width: 200, height: 200 }], &options);
+
+        let slot = plan.images[1];
+        let (x, y) = (slot.offset.width, slot.offset.height);
+        assert!(is_colour_in_range(x, y, slot.total_width(), slot.total_height(), &frames[1], GREEN));
+    }
+
+    #[test]
+    fn pads_images_to_a_common_aspect_ratio() {
+        let tall = create_with_colour(100, 200, RED);
+        let wide = create_with_colour(200, 100, BLUE);
+        let options = MosaicOptions {
+            fit: FitMode::Pad,
+            ..MosaicOptions::default()
+        };
+
+        let result = mosaic(vec![tall, wide], &options);
+
+        save_result(&result.image, "pads_images_to_a_common_aspect_ratio");
+        let aspect_ratio = |region: &ImageOffset| region.dimensions.width as f32 / region.dimensions.height as f32;
+        assert!((aspect_ratio(&result.regions[0]) - aspect_ratio(&result.regions[1])).abs() < 0.02);
+        for (region, colour, tall) in [(result.regions[0], RED, true), (result.regions[1], BLUE, false)] {
+            let Size { width: x, height: y } = region.offset;
+            let Size { width, height } = region.dimensions;
+            // The image fills its cell along one side and is padded with the background along the other.
+            assert_eq!(*result.image.get_pixel(x + width / 2, y + height / 2), colour);
+            let padding = if tall {
+                result.image.get_pixel(x + 2, y + height / 2)
+            } else {
+                result.image.get_pixel(x + width / 2, y + 2)
+            };
+            assert_eq!(*padding, BLACK);
+        }
+    }
+
+    #[test]
+    fn uncapped_keeps_full_resolution() {
+        let sizes = [Size {
+            width: 3000,
+            height: 3300,
+        }; 2];
+        let options = MosaicOptions {
+            uncapped: true,
+            ..MosaicOptions::default()
+        };
+
+        let capped = plan_mosaic(&sizes, &MosaicOptions::default());
+        let uncapped = plan_mosaic(&sizes, &options);
+
+        assert!(capped.total_size.width <= MAX_SIZE);
+        assert_eq!(uncapped.total_size.width, 6010);
+        assert_eq!(uncapped.total_size.height, 3300);
+    }
+
+    #[test]
+    fn doesnt_attempt_removed_mosaic() {
+        let left_top = create_with_colour(200, 300, RED);
+        let left_bot = create_with_colour(200, 300, BLUE);
+        let mid = create_with_colour(200, 600, GREEN);
+        let right = create_with_colour(200, 600, PURPLE);
+
+        let result = mosaic(vec![left_top, left_bot, mid, right], &MosaicOptions::default()).image;
+
+        save_result(&result, "doesnt_attempt_removed_mosaic");
+        assert!((result.width() < 590) | (result.width() > 630));
+        assert!((result.width() < 590) | (result.width() > 630));
+        assert!(has_gutter(300, 10, BLACK, Orientation::Horizontal, &result));
+        assert!(has_gutter(200, 10, BLACK, Orientation::Vertical, &result));
+    }
+
+    #[test]
+    fn reports_chosen_layout() {
+        let left = create_with_colour(100, 400, RED);
+        let right = create_with_colour(200, 400, BLUE);
+
+        let result = mosaic(vec![left, right], &MosaicOptions::default());
+
+        assert_eq!(result.layout, Layout::LeftRight);
+        assert_eq!(result.layout.name(), "left_right");
+    }
+
+    #[test]
+    fn draws_border_inside_each_image() {
+        let left = create_with_colour(100, 400, RED);
+        let right = create_with_colour(200, 400, BLUE);
+        let options = MosaicOptions {
+            border_width: 3,
+            ..MosaicOptions::default()
+        };
+
+        let result = mosaic(vec![left, right], &options).image;
+
+        save_result(&result, "border");
+        assert!(is_colour_in_range(0, 0, 100, 3, &result, WHITE));
+        assert!(is_colour_in_range(0, 0, 3, 400, &result, WHITE));
+        assert!(is_colour_in_range(97, 0, 100, 400, &result, WHITE));
+        assert!(is_colour_in_range(3, 3, 97, 397, &result, RED));
+        assert!(has_gutter(100, 10, BLACK, Orientation::Vertical, &result));
+        assert!(is_colour_in_range(110, 0, 300, 3, &result, WHITE));
+        assert!(is_colour_in_range(113, 3, 297, 397, &result, BLUE));
+        assert!(is_colour_at_pixel(299, 399, &result, WHITE));
+    }
+
+    #[test]
+    fn equalizes_exposure() {
+        let dark = Rgb([60, 80, 100]);
+        let light = Rgb([160, 180, 200]);
+        let images = || vec![create_with_colour(100, 100, dark), create_with_colour(100, 100, light)];
+        let centre = |result: &mosaic::Mosaic, index: usize| {
+            let region = result.regions[index];
+            *result.image.get_pixel(region.offset.width + 50, region.offset.height + 50)
+        };
+
+        let result = mosaic(images(), &MosaicOptions::default());
+        assert_eq!((centre(&result, 0), centre(&result, 1)), (dark, light));
+
+        let options = MosaicOptions {
+            equalize: true,
+            ..MosaicOptions::default()
+        };
+        let result = mosaic(images(), &options);
+        // Both move 50 towards their mean, keeping their colour.
+        assert_eq!((centre(&result, 0), centre(&result, 1)), (Rgb([110, 130, 150]), Rgb([110, 130, 150])));
+
+        let extremes = vec![create_with_colour(100, 100, BLACK), create_with_colour(100, 100, WHITE)];
+        let result = mosaic(extremes, &options);
+        assert_eq!((centre(&result, 0), centre(&result, 1)), (Rgb([64, 64, 64]), Rgb([191, 191, 191])));
+    }
+
+    #[test]
+    fn adjusts_brightness_and_gamma() {
+        let image = || RgbImage::from_fn(256, 1, |x, _| Rgb([x as u8, x as u8, x as u8]));
+        let value = |image: &RgbImage, x: u32| image.get_pixel(x, 0)[0];
+
+        let mut unchanged = image();
+        adjust_tone(&mut unchanged, 0, 1.0);
+        assert_eq!(unchanged, image());
+
+        let mut brighter = image();
+        adjust_tone(&mut brighter, 20, 1.0);
+        assert_eq!((value(&brighter, 0), value(&brighter, 100), value(&brighter, 250)), (20, 120, 255));
+
+        let mut darker = image();
+        adjust_tone(&mut darker, i32::MIN, 1.0);
+        assert!(darker.pixels().all(|pixel| pixel[0] == 0));
+
+        // Gamma keeps black and white where they are and lifts or lowers the values between them.
+        let mut lifted = image();
+        adjust_tone(&mut lifted, 0, 2.0);
+        assert_eq!((value(&lifted, 0), value(&lifted, 64), value(&lifted, 255)), (0, 128, 255));
+        let mut lowered = image();
+        adjust_tone(&mut lowered, 0, 0.5);
+        assert_eq!((value(&lowered, 0), value(&lowered, 128), value(&lowered, 255)), (0, 64, 255));
+    }
+
+    #[test]
+    fn tone_leaves_the_caption_alone() {
+        let options = MosaicOptions {
+            brightness: -255,
+            caption: Some("Hi".to_string()),
+            ..MosaicOptions::default()
+        };
+
+        let result = mosaic(vec![create_with_colour(100, 100, RED), create_with_colour(100, 100, BLUE)], &options);
+
+        assert!(is_colour_in_range(0, 0, 210, 100, &result.image, BLACK));
+        assert!(result.image.pixels().any(|pixel| *pixel == Rgb([255, 255, 255])));
+    }
+
+    #[test]
+    fn blends_images_by_opacity() {
+        let left = create_with_colour(100, 400, RED);
+        let right = create_with_colour(200, 400, BLUE);
+        let options = MosaicOptions {
+            opacities: vec![1.0, 0.5],
+            ..MosaicOptions::default()
+        };
+
+        let result = mosaic(vec![left, right], &options).image;
+
+        save_result(&result, "opacity");
+        assert!(is_colour_in_range(0, 0, 100, 400, &result, RED));
+        assert!(is_colour_in_range(110, 0, 310, 400, &result, Rgb([0, 0, 128])));
+    }
+
+    #[test]
+    fn adds_margin_on_every_edge() {
+        let left = create_with_colour(100, 400, RED);
+        let right = create_with_colour(200, 400, BLUE);
+        let options = MosaicOptions {
+            margin: 20,
+            ..MosaicOptions::default()
+        };
+
+        let result = mosaic(vec![left, right], &options);
+
+        save_result(&result.image, "margin");
+        assert_eq!(result.image.dimensions(), (350, 440));
+        assert!(is_colour_in_range(0, 0, 350, 20, &result.image, BLACK));
+        assert!(is_colour_in_range(0, 420, 350, 440, &result.image, BLACK));
+        assert!(is_colour_in_range(0, 0, 20, 440, &result.image, BLACK));
+        assert!(is_colour_in_range(330, 0, 350, 440, &result.image, BLACK));
+        assert!(is_colour_in_range(20, 20, 120, 420, &result.image, RED));
+        assert!(has_gutter(120, 10, BLACK, Orientation::Vertical, &result.image));
+        assert!(is_colour_in_range(130, 20, 330, 420, &result.image, BLUE));
+        assert_eq!((result.regions[0].offset.width, result.regions[0].offset.height), (20, 20));
+        assert_eq!((result.regions[1].offset.width, result.regions[1].offset.height), (130, 20));
+    }
+
+    #[test]
+    fn caption_fits_within_max_size() {
+        let sizes = [Size { width: 4000, height: 4000 }, Size { width: 4000, height: 8000 }];
+        let options = MosaicOptions {
+            caption: Some("A caption long enough to take up all three lines of the bar. ".repeat(10)),
+            ..MosaicOptions::default()
+        };
+
+        let plan = plan_mosaic(&sizes, &options);
+        let result = mosaic(vec![create_with_colour(400, 400, RED), create_with_colour(400, 800, BLUE)], &options);
+
+        assert!(plan.total_size.height <= MAX_SIZE);
+        assert!(plan.total_size.height > MAX_SIZE - MAX_BAR_HEIGHT);
+        let small = plan_mosaic(&[Size { width: 400, height: 400 }, Size { width: 400, height: 800 }], &options);
+        assert_eq!(result.image.dimensions(), (small.total_size.width, small.total_size.height));
+    }
+
+    #[test]
+    fn margin_fits_within_max_size() {
+        let sizes = [Size { width: 4000, height: 4000 }, Size { width: 4000, height: 4000 }];
+        let options = MosaicOptions {
+            margin: 50,
+            ..MosaicOptions::default()
+        };
+
+        let plan = plan_mosaic(&sizes, &options);
+        let result = mosaic(vec![create_with_colour(400, 400, RED), create_with_colour(400, 400, BLUE)], &options);
+
+        assert_eq!(plan.total_size.width, MAX_SIZE);
+        assert_eq!((plan.images[0].offset.width, plan.images[0].offset.height), (50, 50));
+        let small = plan_mosaic(&[Size { width: 400, height: 400 }, Size { width: 400, height: 400 }], &options);
+        assert_eq!(result.image.dimensions(), (small.total_size.width, small.total_size.height));
+        let corners = |offsets: &[ImageOffset]| -> Vec<_> {
+            offsets.iter().map(|offset| (offset.offset.width, offset.offset.height, offset.total_width())).collect()
+        };
+        assert_eq!(corners(&result.regions), corners(&small.images));
+    }
+
+    #[test]
+    fn border_is_clamped_to_spacing() {
+        let left = create_with_colour(100, 400, RED);
+        let right = create_with_colour(200, 400, BLUE);
+        let options = MosaicOptions {
+            border_width: 50,
+            ..MosaicOptions::default()
+        };
+
+        let result = mosaic(vec![left, right], &options).image;
+
+        assert!(is_colour_in_range(0, 0, 10, 400, &result, WHITE));
+        assert!(is_colour_in_range(10, 10, 90, 390, &result, RED));
+    }
+
+    #[test]
+    fn crops_inputs_beyond_max_aspect_ratio() {
+        let mut banner = create_with_colour(1000, 100, RED);
+        for x in 300..700 {
+            for y in 0..100 {
+                banner.put_pixel(x, y, BLUE);
+            }
+        }
+        let square = create_with_colour(100, 100, GREEN);
+        let options = MosaicOptions {
+            max_aspect_ratio: Some(4.0),
+            ..MosaicOptions::default()
+        };
+
+        let result = mosaic(vec![banner, square], &options).image;
+
+        save_result(&result, "max_aspect_ratio");
+        // The banner keeps only its blue middle 400px, laid out next to the square.
+        assert_eq!((result.width(), result.height()), (510, 100));
+        assert!(is_colour_in_range(0, 0, 400, 100, &result, BLUE));
+        assert!(has_gutter(400, 10, BLACK, Orientation::Vertical, &result));
+        assert!(is_colour_in_range(410, 0, 510, 100, &result, GREEN));
+    }
+
+    #[test]
+    fn draws_badges_where_the_gutters_cross() {
+        let squares = |count: usize| {
+            [RED, BLUE, GREEN, PURPLE][..count].iter().map(|colour| create_with_colour(100, 100, *colour)).collect()
+        };
+        let options = MosaicOptions {
+            badge: Some(create_with_colour(500, 500, WHITE)),
+            ..MosaicOptions::default()
+        };
+
+        let grid = mosaic(squares(4), &options);
+        let pair = mosaic(squares(2), &options);
+
+        save_result(&grid.image, "badge");
+        assert_eq!(grid.layout, Layout::TwoRowsOfTwo);
+        // Scaled down to a fifth of the 210px mosaic, and round, so its bounding box's corners are untouched.
+        assert_eq!(*grid.image.get_pixel(105, 105), WHITE);
+        assert_eq!(*grid.image.get_pixel(105, 85), WHITE);
+        assert_eq!(*grid.image.get_pixel(84, 105), WHITE);
+        assert_eq!(*grid.image.get_pixel(82, 105), BLACK);
+        assert_eq!(*grid.image.get_pixel(85, 85), RED);
+        assert!(is_colour_in_range(0, 0, 60, 60, &grid.image, RED));
+        // Other layouts get it in the middle.
+        assert_eq!(pair.layout, Layout::LeftRight);
+        assert_eq!(*pair.image.get_pixel(105, 50), WHITE);
+        assert_eq!(*pair.image.get_pixel(85, 50), RED);
+    }
+
+    #[test]
+    fn crops_towards_a_shared_aspect_ratio() {
+        let size = |width, height| Size { width, height };
+        let sizes = [size(400, 300), size(300, 300), size(800, 300)];
+        let options = MosaicOptions {
+            shared_aspect: true,
+            ..MosaicOptions::default()
+        };
+        let images = || vec![
+            create_with_colour(400, 300, RED),
+            create_with_colour(300, 300, GREEN),
+            create_with_colour(800, 300, BLUE),
+        ];
+
+        let plan = plan_mosaic(&sizes, &options);
+        let result = mosaic(images(), &options);
+
+        // The median shape is left alone, and the others are cropped towards it by at most a fifth.
+        let cropped: Vec<(u32, u32)> = plan.images.iter().map(|image| {
+            (image.original_dimensions.width, image.original_dimensions.height)
+        }).collect();
+        assert_eq!(cropped, [(400, 300), (300, 240), (640, 300)]);
+        save_result(&result.image, "shared_aspect");
+        for (region, colour) in result.regions.iter().zip([RED, GREEN, BLUE]) {
+            assert!(is_colour_in_range(
+                region.offset.width,
+                region.offset.height,
+                region.total_width(),
+                region.total_height(),
+                &result.image,
+                colour
+            ));
+        }
+    }
+
+    /// A `width`x`height` image that shades from black to red left to right and to green top to bottom, so
+    /// any drift in how it is scaled or placed shows up in a diff.
+    fn gradient(width: u32, height: u32) -> image::RgbImage {
+        image::RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x * 255 / width) as u8, (y * 255 / height) as u8, 128])
+        })
+    }
+
+    #[test]
+    fn diffs_images() {
+        let expected = gradient(40, 30);
+        let mut actual = expected.clone();
+        actual.put_pixel(0, 7, WHITE);
+        actual.put_pixel(20, 12, BLUE);
+        actual.get_pixel_mut(30, 20).0[0] += 1;
+
+        let diff = diff_images(&actual, &expected, 1);
+
+        assert_eq!(diff.max, 255);
+        assert!(diff.mean > 0.0);
+        // The pixel that is only a little off is allowed through.
+        assert_eq!(diff.bounds, Some((0, 7, 21, 6)));
+        assert_eq!(diff_images(&expected, &expected, 0).bounds, None);
+    }
+
+    #[test]
+    fn matches_golden_gradients() {
+        let images = vec![gradient(120, 80), gradient(80, 120), gradient(100, 100)];
+
+        let result = mosaic(images, &MosaicOptions::default());
+
+        assert_matches_golden(&result.image, "three_gradients", 2);
+    }
+
+    /// A `width`x`height` image of `border` with a `colour` rectangle from `start` to `end`.
+    fn framed(width: u32, height: u32, border: image::Rgb<u8>, colour: image::Rgb<u8>, start: (u32, u32), end: (u32, u32)) -> image::RgbImage {
+        let mut image = create_with_colour(width, height, border);
+        for x in start.0..end.0 {
+            for y in start.1..end.1 {
+                image.put_pixel(x, y, colour);
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn trims_uniform_borders() {
+        let image = framed(100, 80, GREEN, PURPLE, (10, 5), (70, 75));
+
+        let trimmed = trim_border(image);
+
+        assert_eq!(trimmed.dimensions(), (60, 70));
+        assert!(is_colour_in_range(0, 0, 60, 70, &trimmed, PURPLE));
+    }
+
+    #[test]
+    fn trims_noisy_borders() {
+        let mut image = framed(100, 100, WHITE, RED, (20, 0), (80, 100));
+        image.put_pixel(5, 40, image::Rgb([250, 245, 252]));
+
+        let trimmed = trim_border(image);
+
+        assert_eq!(trimmed.dimensions(), (60, 100));
+    }
+
+    #[test]
+    fn leaves_untrimmable_images_alone() {
+        assert_eq!(trim_border(create_with_colour(50, 40, BLUE)).dimensions(), (50, 40));
+        let mut speck = create_with_colour(50, 40, RED);
+        speck.put_pixel(0, 0, BLUE);
+        assert_eq!(trim_border(speck).dimensions(), (50, 40));
+    }
+
+    #[test]
+    fn lays_out_trimmed_inputs() {
+        let letterboxed = framed(200, 400, WHITE, RED, (50, 0), (150, 400));
+        let right = create_with_colour(200, 400, BLUE);
+        let options = MosaicOptions {
+            trim: true,
+            ..MosaicOptions::default()
+        };
+
+        let result = mosaic(vec![letterboxed, right], &options).image;
+
+        save_result(&result, "trimmed");
+        assert_eq!(result.dimensions(), (310, 400));
+        assert!(is_colour_in_range(0, 0, 100, 400, &result, RED));
+        assert!(is_colour_in_range(110, 0, 310, 400, &result, BLUE));
+    }
+
+    #[test]
+    fn keeps_largest_image_native() {
+        let sizes = [
+            Size {
+                width: 100,
+                height: 400,
+            },
+            Size {
+                width: 400,
+                height: 1600,
+            },
+        ];
+        let options = MosaicOptions {
+            native: NativeImage::Largest,
+            ..MosaicOptions::default()
+        };
+
+        let default = plan_mosaic(&sizes, &MosaicOptions::default());
+        let largest = plan_mosaic(&sizes, &options);
+
+        // By default the small image is blown up to match the big one, otherwise the big one is shrunk.
+        assert_eq!(default.images[0].dimensions.width, 400);
+        assert_eq!(largest.images[0].dimensions.width, 100);
+        for image in &largest.images {
+            assert!(image.dimensions.width <= image.original_dimensions.width);
+            assert!(image.dimensions.height <= image.original_dimensions.height);
+        }
+    }
+
+    #[test]
+    fn lays_out_corrected_aspect_ratios() {
+        let squashed = create_with_colour(100, 100, RED);
+        let square = create_with_colour(100, 100, BLUE);
+        let options = MosaicOptions {
+            aspect_ratios: vec![Some(2.0)],
+            ..MosaicOptions::default()
+        };
+
+        let result = mosaic(vec![squashed, square], &options);
+
+        let (first, second) = (result.regions[0].dimensions, result.regions[1].dimensions);
+        assert_eq!(first.width, first.height * 2);
+        assert_eq!(second.width, second.height);
+        let region = result.regions[0];
+        assert!(is_colour_in_range(
+            region.offset.width,
+            region.offset.height,
+            region.total_width(),
+            region.total_height(),
+            &result.image,
+            RED
+        ));
+    }
+
+    #[test]
+    fn lays_out_images_at_their_intrinsic_scale() {
+        let sizes = [
+            Size {
+                width: 1000,
+                height: 1000,
+            },
+            Size {
+                width: 500,
+                height: 500,
+            },
+        ];
+        let options = MosaicOptions {
+            scales: vec![2.0],
+            ..MosaicOptions::default()
+        };
+
+        // Without a scale, the small image is blown up to the big one's resolution.
+        let short_side = |plan: &MosaicPlan| plan.total_size.width.min(plan.total_size.height);
+        assert_eq!(short_side(&plan_mosaic(&sizes, &MosaicOptions::default())), 1000);
+        let plan = plan_mosaic(&sizes, &options);
+        assert_eq!(short_side(&plan), 500);
+        let (first, second) = (plan.images[0].dimensions, plan.images[1].dimensions);
+        assert_eq!((first.width, first.height), (second.width, second.height));
+    }
+
+    #[test]
+    fn never_upscales_small_images() {
+        let options = MosaicOptions {
+            no_upscale: true,
+            ..MosaicOptions::default()
+        };
+        let large = |colour| create_with_colour(1000, 750, colour);
+        let tiny = create_with_colour(50, 37, PURPLE);
+
+        for images in [
+            vec![tiny.clone(), large(RED)],
+            vec![large(RED), tiny.clone(), large(BLUE)],
+            vec![large(RED), large(BLUE), large(GREEN), tiny.clone()],
+        ] {
+            let sizes: Vec<_> = images.iter().map(|image| image.dimensions()).collect();
+            let result = mosaic(images, &options);
+
+            for (region, (width, height)) in result.regions.iter().zip(sizes) {
+                assert!(region.dimensions.width <= width && region.dimensions.height <= height);
+            }
+        }
+    }
+
+    #[test]
+    fn no_upscale_undoes_rounding() {
+        let size = |width, height| Size { width, height };
+        let options = MosaicOptions {
+            no_upscale: true,
+            ..MosaicOptions::default()
+        };
+
+        // Scaled straight to native size, the right image would round up to 51x132.
+        let plan = plan_mosaic(&[size(100, 51), size(131, 100), size(51, 131)], &options);
+
+        assert_eq!(plan.layout, Layout::LeftLeftRight);
+        for image in &plan.images {
+            assert!(image.dimensions.width <= image.original_dimensions.width);
+            assert!(image.dimensions.height <= image.original_dimensions.height);
+        }
+    }
+
+    #[test]
+    fn no_upscale_keeps_mosaic_small_on_canvas() {
+        let options = MosaicOptions {
+            no_upscale: true,
+            canvas: Some(Size {
+                width: 800,
+                height: 800,
+            }),
+            ..MosaicOptions::default()
+        };
+
+        let result = mosaic(vec![create_with_colour(100, 200, RED), create_with_colour(100, 200, BLUE)], &options);
+
+        assert_eq!(result.image.dimensions(), (800, 800));
+        assert_eq!(rectangles(&result.regions), vec![(295, 300, 100, 200), (405, 300, 100, 200)]);
+    }
+
+    #[test]
+    fn scale_tolerance_trades_uniformity_for_squareness() {
+        let sizes = [
+            Size {
+                width: 100,
+                height: 100,
+            },
+            Size {
+                width: 200,
+                height: 100,
+            },
+        ];
+        let with_tolerance = |scale_tolerance| MosaicOptions {
+            scale_tolerance,
+            ..MosaicOptions::default()
+        };
+
+        // Side by side, both images keep their scale. Stacked is squarer, but halves the second image.
+        assert_eq!(plan_mosaic(&sizes, &with_tolerance(0.0)).layout, Layout::LeftRight);
+        assert_eq!(plan_mosaic(&sizes, &MosaicOptions::default()).layout, Layout::LeftRight);
+        assert_eq!(plan_mosaic(&sizes, &with_tolerance(1.0)).layout, Layout::TopBottom);
+    }
+
+    #[test]
+    fn flips_individual_images() {
+        let split = |first, second| {
+            image::RgbImage::from_fn(100, 100, |x, y| if x < 50 && y < 50 { first } else { second })
+        };
+        let options = MosaicOptions {
+            flips: vec![
+                mosaic::Flip::default(),
+                mosaic::Flip {
+                    horizontal: true,
+                    vertical: true,
+                },
+            ],
+            ..MosaicOptions::default()
+        };
+
+        let result = mosaic(vec![split(RED, BLUE), split(RED, BLUE)], &options);
+
+        let (x, y) = (result.regions[1].offset.width, result.regions[1].offset.height);
+        assert!(is_colour_in_range(0, 0, 50, 50, &result.image, RED));
+        assert!(is_colour_in_range(x, y, x + 50, y + 100, &result.image, BLUE));
+        assert!(is_colour_in_range(x + 50, y + 50, x + 100, y + 100, &result.image, RED));
+    }
+
+    #[test]
+    fn crops_around_focal_point() {
+        let image = framed(200, 100, RED, BLUE, (100, 0), (200, 100));
+        let size = Size {
+            width: 100,
+            height: 100,
+        };
+
+        let left = crop_around(&image, size, FocalPoint { x: 0.1, y: 0.5 });
+        let right = crop_around(&image, size, FocalPoint { x: 0.9, y: 0.5 });
+        let middle = crop_around(&image, size, FocalPoint::default());
+
+        assert!(is_colour_in_range(0, 0, 100, 100, &left, RED));
+        assert!(is_colour_in_range(0, 0, 100, 100, &right, BLUE));
+        assert!(is_colour_in_range(0, 0, 50, 100, &middle, RED));
+        assert!(is_colour_in_range(50, 0, 100, 100, &middle, BLUE));
+    }
+
+    #[test]
+    fn prepares_images_ahead_of_layout() {
+        let letterboxed = framed(200, 400, WHITE, RED, (50, 0), (150, 400));
+        let banner = create_with_colour(800, 100, BLUE);
+        let options = MosaicOptions {
+            trim: true,
+            max_aspect_ratio: Some(4.0),
+            ..MosaicOptions::default()
+        };
+
+        let expected = mosaic(vec![letterboxed.clone(), banner.clone()], &options).image;
+        let prepared: Vec<_> = vec![letterboxed, banner]
+            .into_iter()
+            .map(|image| prepare_image(image, &options))
+            .collect();
+
+        assert_eq!(prepared[0].dimensions(), (100, 400));
+        assert_eq!(prepared[1].dimensions(), (400, 100));
+        assert_eq!(mosaic_prepared(prepared, &options).image, expected);
+    }
+
+    #[test]
+    fn leaves_inputs_within_max_aspect_ratio() {
+        let left = create_with_colour(300, 100, RED);
+        let right = create_with_colour(300, 100, BLUE);
+        let options = MosaicOptions {
+            max_aspect_ratio: Some(4.0),
+            ..MosaicOptions::default()
+        };
+
+        let result = mosaic(vec![left, right], &options).image;
+
+        assert_eq!((result.width(), result.height()), (300, 210));
+    }
+
+    #[test]
+    fn plans_without_images() {
+        let sizes = [
+            Size {
+                width: 100,
+                height: 400,
+            },
+            Size {
+                width: 200,
+                height: 400,
+            },
+        ];
+
+        let plan = plan_mosaic(&sizes, &MosaicOptions::default());
+
+        assert_eq!(plan.layout, Layout::LeftRight);
+        assert_eq!((plan.total_size.width, plan.total_size.height), (310, 400));
+        assert_eq!(plan.images.len(), 2);
+        assert_eq!(plan.images[1].offset.width, 110);
+        assert_eq!(plan.images[1].dimensions.width, 200);
+        assert_eq!(plan.images[1].dimensions.height, 400);
+    }
+
+    #[test]
+    fn picks_lower_ranked_layouts() {
+        let sizes = [
+            Size {
+                width: 100,
+                height: 400,
+            },
+            Size {
+                width: 200,
+                height: 400,
+            },
+        ];
+        let ranked = |rank| {
+            let options = MosaicOptions {
+                rank,
+                ..MosaicOptions::default()
+            };
+            plan_mosaic(&sizes, &options).layout
+        };
+
+        assert_eq!(ranked(1), Layout::LeftRight);
+        assert_eq!(ranked(0), Layout::LeftRight);
+        assert_eq!(ranked(2), Layout::TopBottom);
+        assert_eq!(ranked(99), Layout::TopBottom);
+    }
+
+    #[test]
+    fn writes_candidate_rows() {
+        let size = |width, height| Size { width, height };
+        let left_right = mosaic::twos::left_right_2_mosaic(size(100, 200), size(300, 200), Spacing::default(), Anchor::First);
+        let top_bottom = mosaic::twos::top_bottom_2_mosaic(size(100, 200), size(300, 200), Spacing::default(), Anchor::First);
+
+        let row = candidates_row(&[left_right, top_bottom], &left_right, 0.5, |mosaic| mosaic.unsquaredness(), |mosaic| {
+            mosaic.layout() == Layout::LeftRight
+        });
+
+        assert_eq!(
+            row,
+            "{\"sizes\":[[100,200],[300,200]],\"tolerance\":0.5,\"candidates\":[\
+             {\"layout\":\"left_right\",\"scale_factor_ratio\":1.0,\"unsquaredness\":2.05,\"score\":2.05,\"eligible\":true},\
+             {\"layout\":\"top_bottom\",\"scale_factor_ratio\":3.0,\"unsquaredness\":2.77,\"score\":2.77,\"eligible\":false}\
+             ],\"chosen\":\"left_right\"}"
+        );
+        let nan = candidates_row(&[left_right], &left_right, f32::NAN, |_| f32::NAN, |_| false);
+        assert!(nan.contains("\"tolerance\":null") && nan.contains("\"score\":null"));
+    }
+
+    #[test]
+    fn best_mosaic_without_candidates() {
+        let candidates: [&MosaicImageDims<2>; 0] = [];
+
+        assert!(best_mosaic(&candidates, &[], NativeImage::default(), MAX_SIZE, DEFAULT_SCALE_TOLERANCE, 1).is_none());
+    }
+
+    #[test]
+    fn plans_with_over_restrictive_limits() {
+        let size = Size {
+            width: 100,
+            height: 400,
+        };
+        let options = MosaicOptions {
+            max_columns: Some(0),
+            max_rows: Some(0),
+            ..MosaicOptions::default()
+        };
+
+        for count in 2..=4 {
+            let plan = plan_mosaic(&vec![size; count], &options);
+            assert_eq!(plan.images.len(), count);
+        }
+    }
+
+    #[test]
+    fn plans_with_empty_images() {
+        let empty = Size::default();
+        let size = Size {
+            width: 100,
+            height: 400,
+        };
+
+        let plan = plan_mosaic(&[empty, size, size, size], &MosaicOptions::default());
+
+        assert_eq!(plan.layout, Layout::TwoRowsOfTwo);
+    }
+
+    #[test]
+    fn plans_without_overflowing() {
+        let huge = Size {
+            width: u32::MAX - 5,
+            height: u32::MAX - 5,
+        };
+
+        let banner = Size {
+            width: u32::MAX - 5,
+            height: 1,
+        };
+        let tower = Size {
+            width: 1,
+            height: u32::MAX - 5,
+        };
+
+        for sizes in [vec![huge; 2], vec![huge; 3], vec![huge; 4], vec![banner, tower, banner], vec![tower, banner, banner, tower]] {
+            let plan = plan_mosaic(&sizes, &MosaicOptions::default());
+
+            assert!(plan.total_size.width <= MAX_SIZE && plan.total_size.height <= MAX_SIZE);
+            for image in &plan.images {
+                assert!(image.total_width() <= plan.total_size.width);
+                assert!(image.total_height() <= plan.total_size.height);
+            }
+        }
+    }
+
+    #[test]
+    fn bleeds_towards_neighbours_only() {
+        let left = create_with_colour(100, 400, RED);
+        let right = create_with_colour(200, 400, BLUE);
+        let options = MosaicOptions {
+            bleed: 2,
+            ..MosaicOptions::default()
+        };
+
+        let result = mosaic(vec![left, right], &options).image;
+
+        save_result(&result, "bleed");
+        assert_eq!((result.width(), result.height()), (310, 400));
+        assert!(is_colour_in_range(0, 0, 102, 400, &result, RED));
+        assert!(has_gutter(102, 1, BLACK, Orientation::Vertical, &result));
+        assert!(has_gutter(107, 1, BLACK, Orientation::Vertical, &result));
+        assert!(is_colour_in_range(108, 0, 310, 400, &result, BLUE));
+    }
+
+    #[test]
+    fn fills_gutters_with_blurred_first_image() {
+        let left = create_with_colour(100, 400, RED);
+        let right = create_with_colour(200, 400, BLUE);
+        let options = MosaicOptions {
+            background: BackgroundMode::Blur,
+            ..MosaicOptions::default()
+        };
+
+        let result = mosaic(vec![left, right], &options).image;
+
+        save_result(&result, "blurred_background");
+        assert!(is_colour_in_range(0, 0, 100, 400, &result, RED));
+        assert!(is_colour_in_range(110, 0, 310, 400, &result, BLUE));
+        // The gutter shows the (entirely red) first image through the blur.
+        assert!(!is_colour_at_pixel(105, 200, &result, BLACK));
+        assert!(result.get_pixel(105, 200).0[0] > 200);
+    }
+
+    #[test]
+    fn blur_radius_is_tunable_and_capped() {
+        let halves = RgbImage::from_fn(400, 400, |x, _| if x < 200 { RED } else { BLUE });
+        let size = Size {
+            width: 400,
+            height: 400,
+        };
+        // How much blue has bled into the red half just left of the middle.
+        let bleed = |radius| mosaic::create_blurred_background(&halves, size, radius).get_pixel(150, 200).0[2];
+
+        assert!(bleed(8.0) < bleed(40.0));
+        assert_eq!(bleed(40.0), bleed(10_000.0));
+        assert!(bleed(30.0) < bleed(40.0));
+    }
+
+    #[test]
+    fn pads_mosaic_to_canvas() {
+        let left = create_with_colour(100, 400, RED);
+        let right = create_with_colour(100, 400, BLUE);
+        let options = MosaicOptions {
+            canvas: Some(Size {
+                width: 400,
+                height: 400,
+            }),
+            ..MosaicOptions::default()
+        };
+
+        let result = mosaic(vec![left, right], &options).image;
+
+        save_result(&result, "canvas_padded");
+        assert_eq!(result.dimensions(), (400, 400));
+        // The 210x400 mosaic is centred, leaving 95 pixels of background either side.
+        assert!(is_colour_in_range(0, 0, 95, 400, &result, BLACK));
+        assert!(is_colour_in_range(95, 0, 195, 400, &result, RED));
+        assert!(is_colour_in_range(205, 0, 305, 400, &result, BLUE));
+        assert!(is_colour_in_range(305, 0, 400, 400, &result, BLACK));
+    }
+
+    #[test]
+    fn scales_mosaic_down_to_canvas() {
+        let left = create_with_colour(100, 400, RED);
+        let right = create_with_colour(100, 400, BLUE);
+        let options = MosaicOptions {
+            canvas: Some(Size {
+                width: 200,
+                height: 100,
+            }),
+            ..MosaicOptions::default()
+        };
+
+        let result = mosaic(vec![left, right], &options).image;
+
+        assert_eq!(result.dimensions(), (200, 100));
+        // Scaled to 53x100, the mosaic keeps its aspect ratio and sits in the middle.
+        assert!(is_colour_in_range(0, 0, 73, 100, &result, BLACK));
+        assert!(is_colour_in_range(127, 0, 200, 100, &result, BLACK));
+        assert!(is_colour_in_range(76, 0, 96, 100, &result, RED));
+        assert!(is_colour_in_range(104, 0, 124, 100, &result, BLUE));
+    }
+
+    #[test]
+    fn canvas_fills_max_size_with_margin_or_caption() {
+        let canvas = Some(Size {
+            width: MAX_SIZE,
+            height: MAX_SIZE,
+        });
+        let options = MosaicOptions {
+            canvas,
+            margin: 100,
+            ..MosaicOptions::default()
+        };
+
+        let result = mosaic(vec![create_with_colour(100, 400, RED), create_with_colour(100, 400, BLUE)], &options);
+
+        assert_eq!(result.image.dimensions(), (MAX_SIZE, MAX_SIZE));
+        let options = MosaicOptions {
+            canvas,
+            caption: Some("Hello".to_string()),
+            ..MosaicOptions::default()
+        };
+        let plan = plan_mosaic(&[Size { width: 100, height: 400 }, Size { width: 100, height: 400 }], &options);
+        assert_eq!((plan.total_size.width, plan.total_size.height), (MAX_SIZE, MAX_SIZE));
+    }
+
+    fn rectangles(regions: &[mosaic::ImageOffset]) -> Vec<(u32, u32, u32, u32)> {
+        regions.iter().map(|region| {
+            (region.offset.width, region.offset.height, region.dimensions.width, region.dimensions.height)
+        }).collect()
+    }
+
+    #[test]
+    fn reports_image_regions() {
+        let left = create_with_colour(100, 400, RED);
+        let right = create_with_colour(200, 400, BLUE);
+
+        let result = mosaic(vec![left, right], &MosaicOptions::default());
+
+        assert_eq!(rectangles(&result.regions), vec![(0, 0, 100, 400), (110, 0, 200, 400)]);
+    }
+
+    #[test]
+    fn splits_mosaic_into_tiles() {
+        let left = create_with_colour(100, 400, RED);
+        let right = create_with_colour(50, 100, BLUE);
+
+        let result = mosaic::mosaic_tiles(vec![left, right], &MosaicOptions::default());
+
+        assert_eq!((result.total_size.width, result.total_size.height), (100, 610));
+        assert_eq!(rectangles(&result.regions), vec![(0, 0, 100, 400), (0, 410, 100, 200)]);
+        assert_eq!(result.tiles[0].dimensions(), (100, 400));
+        assert_eq!(result.tiles[1].dimensions(), (100, 200));
+        assert!(is_colour_in_range(0, 0, 100, 200, &result.tiles[1], BLUE));
+    }
+
+    #[test]
+    fn reports_image_regions_on_canvas() {
+        let left = create_with_colour(100, 400, RED);
+        let right = create_with_colour(100, 400, BLUE);
+        let options = MosaicOptions {
+            canvas: Some(Size {
+                width: 420,
+                height: 200,
+            }),
+            ..MosaicOptions::default()
+        };
+
+        let result = mosaic(vec![left, right], &options);
+
+        // The 210x400 mosaic is halved to 105x200 and centred.
+        assert_eq!(rectangles(&result.regions), vec![(157, 0, 50, 200), (212, 0, 50, 200)]);
+    }
+
+    #[test]
+    fn slideshow_frames_share_a_size() {
+        let wide = create_with_colour(200, 100, RED);
+        let tall = create_with_colour(100, 200, BLUE);
+
+        let frames = slideshow_frames(vec![wide, tall], &MosaicOptions::default());
+
+        assert_eq!(frames.len(), 2);
+        assert!(frames.iter().all(|frame| frame.dimensions() == (200, 200)));
+        // Each image keeps its aspect ratio and is centred in the frame.
+        assert!(is_colour_in_range(0, 0, 200, 50, &frames[0], BLACK));
+        assert!(is_colour_in_range(0, 50, 200, 150, &frames[0], RED));
+        assert!(is_colour_in_range(0, 0, 50, 200, &frames[1], BLACK));
+        assert!(is_colour_in_range(50, 0, 150, 200, &frames[1], BLUE));
+    }
+
+    #[test]
+    fn picks_filter_by_resize_direction() {
+        let filters = ResizeFilters {
+            upscale: ResizeFilter::Lanczos3,
+            downscale: ResizeFilter::Gaussian,
+        };
+        let small = Size {
+            width: 100,
+            height: 100,
+        };
+        let big = Size {
+            width: 200,
+            height: 200,
+        };
+
+        assert_eq!(filters.for_resize(small, big), FilterType::Lanczos3);
+        assert_eq!(filters.for_resize(big, small), FilterType::Gaussian);
+    }
+
+    #[test]
+    fn nearest_filter_doesnt_interpolate() {
+        let sprite = RgbImage::from_fn(8, 8, |x, y| if (x + y) % 2 == 0 { RED } else { BLUE });
+        let filters = ResizeFilters {
+            upscale: ResizeFilter::Nearest,
+            downscale: ResizeFilter::Nearest,
+        };
+        let size = Size {
+            width: 24,
+            height: 24,
+        };
+
+        let resized = mosaic::resize_image(sprite.clone(), size, None, filters);
+
+        for (x, y, pixel) in resized.enumerate_pixels() {
+            assert_eq!(pixel, sprite.get_pixel(x / 3, y / 3), "at {}, {}", x, y);
+        }
     }
 }
@@ -0,0 +1,229 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2022 Antonio32A (antonio32a.com) <~@antonio32a.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use image::{Rgb, RgbImage};
+
+use crate::mosaic::{create_background, Size};
+
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+/// Dots from the start of one character to the next, and from the top of one line to the next.
+const ADVANCE: u32 = GLYPH_WIDTH + 1;
+const LINE_HEIGHT: u32 = GLYPH_HEIGHT + 2;
+/// Dots between the text and the edges of the bar.
+const PADDING: u32 = 3;
+/// About how many characters fit on a line; dots are scaled up from 1 pixel until they would fit fewer.
+const CHARS_PER_LINE: u32 = 48;
+const MAX_SCALE: u32 = 8;
+/// Captions are cut off with `...` after this many lines.
+const MAX_LINES: usize = 3;
+/// The tallest bar a caption can add, at `MAX_SCALE` and `MAX_LINES`.
+pub const MAX_BAR_HEIGHT: u32 = bar_height(MAX_SCALE, MAX_LINES);
+/// 5x7 bitmaps of printable ASCII from space to `~`, the classic character LCD font, one column per entry
+/// with the top dot in the lowest bit.
+const FONT: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], [0x00, 0x00, 0x5F, 0x00, 0x00], [0x00, 0x07, 0x00, 0x07, 0x00],
+    [0x14, 0x7F, 0x14, 0x7F, 0x14], [0x24, 0x2A, 0x7F, 0x2A, 0x12], [0x23, 0x13, 0x08, 0x64, 0x62],
+    [0x36, 0x49, 0x55, 0x22, 0x50], [0x00, 0x05, 0x03, 0x00, 0x00], [0x00, 0x1C, 0x22, 0x41, 0x00],
+    [0x00, 0x41, 0x22, 0x1C, 0x00], [0x08, 0x2A, 0x1C, 0x2A, 0x08], [0x08, 0x08, 0x3E, 0x08, 0x08],
+    [0x00, 0x50, 0x30, 0x00, 0x00], [0x08, 0x08, 0x08, 0x08, 0x08], [0x00, 0x60, 0x60, 0x00, 0x00],
+    [0x20, 0x10, 0x08, 0x04, 0x02], [0x3E, 0x51, 0x49, 0x45, 0x3E], [0x00, 0x42, 0x7F, 0x40, 0x00],
+    [0x42, 0x61, 0x51, 0x49, 0x46], [0x21, 0x41, 0x45, 0x4B, 0x31], [0x18, 0x14, 0x12, 0x7F, 0x10],
+    [0x27, 0x45, 0x45, 0x45, 0x39], [0x3C, 0x4A, 0x49, 0x49, 0x30], [0x01, 0x71, 0x09, 0x05, 0x03],
+    [0x36, 0x49, 0x49, 0x49, 0x36], [0x06, 0x49, 0x49, 0x29, 0x1E], [0x00, 0x36, 0x36, 0x00, 0x00],
+    [0x00, 0x56, 0x36, 0x00, 0x00], [0x08, 0x14, 0x22, 0x41, 0x00], [0x14, 0x14, 0x14, 0x14, 0x14],
+    [0x00, 0x41, 0x22, 0x14, 0x08], [0x02, 0x01, 0x51, 0x09, 0x06], [0x32, 0x49, 0x79, 0x41, 0x3E],
+    [0x7E, 0x11, 0x11, 0x11, 0x7E], [0x7F, 0x49, 0x49, 0x49, 0x36], [0x3E, 0x41, 0x41, 0x41, 0x22],
+    [0x7F, 0x41, 0x41, 0x22, 0x1C], [0x7F, 0x49, 0x49, 0x49, 0x41], [0x7F, 0x09, 0x09, 0x01, 0x01],
+    [0x3E, 0x41, 0x41, 0x51, 0x32], [0x7F, 0x08, 0x08, 0x08, 0x7F], [0x00, 0x41, 0x7F, 0x41, 0x00],
+    [0x20, 0x40, 0x41, 0x3F, 0x01], [0x7F, 0x08, 0x14, 0x22, 0x41], [0x7F, 0x40, 0x40, 0x40, 0x40],
+    [0x7F, 0x02, 0x04, 0x02, 0x7F], [0x7F, 0x04, 0x08, 0x10, 0x7F], [0x3E, 0x41, 0x41, 0x41, 0x3E],
+    [0x7F, 0x09, 0x09, 0x09, 0x06], [0x3E, 0x41, 0x51, 0x21, 0x5E], [0x7F, 0x09, 0x19, 0x29, 0x46],
+    [0x46, 0x49, 0x49, 0x49, 0x31], [0x01, 0x01, 0x7F, 0x01, 0x01], [0x3F, 0x40, 0x40, 0x40, 0x3F],
+    [0x1F, 0x20, 0x40, 0x20, 0x1F], [0x7F, 0x20, 0x18, 0x20, 0x7F], [0x63, 0x14, 0x08, 0x14, 0x63],
+    [0x03, 0x04, 0x78, 0x04, 0x03], [0x61, 0x51, 0x49, 0x45, 0x43], [0x00, 0x7F, 0x41, 0x41, 0x00],
+    [0x02, 0x04, 0x08, 0x10, 0x20], [0x00, 0x41, 0x41, 0x7F, 0x00], [0x04, 0x02, 0x01, 0x02, 0x04],
+    [0x40, 0x40, 0x40, 0x40, 0x40], [0x00, 0x01, 0x02, 0x04, 0x00], [0x20, 0x54, 0x54, 0x54, 0x78],
+    [0x7F, 0x48, 0x44, 0x44, 0x38], [0x38, 0x44, 0x44, 0x44, 0x20], [0x38, 0x44, 0x44, 0x48, 0x7F],
+    [0x38, 0x54, 0x54, 0x54, 0x18], [0x08, 0x7E, 0x09, 0x01, 0x02], [0x0C, 0x52, 0x52, 0x52, 0x3E],
+    [0x7F, 0x08, 0x04, 0x04, 0x78], [0x00, 0x44, 0x7D, 0x40, 0x00], [0x20, 0x40, 0x44, 0x3D, 0x00],
+    [0x7F, 0x10, 0x28, 0x44, 0x00], [0x00, 0x41, 0x7F, 0x40, 0x00], [0x7C, 0x04, 0x18, 0x04, 0x78],
+    [0x7C, 0x08, 0x04, 0x04, 0x78], [0x38, 0x44, 0x44, 0x44, 0x38], [0x7C, 0x14, 0x14, 0x14, 0x08],
+    [0x08, 0x14, 0x14, 0x18, 0x7C], [0x7C, 0x08, 0x04, 0x04, 0x08], [0x48, 0x54, 0x54, 0x54, 0x20],
+    [0x04, 0x3F, 0x44, 0x40, 0x20], [0x3C, 0x40, 0x40, 0x20, 0x7C], [0x1C, 0x20, 0x40, 0x20, 0x1C],
+    [0x3C, 0x40, 0x30, 0x40, 0x3C], [0x44, 0x28, 0x10, 0x28, 0x44], [0x0C, 0x50, 0x50, 0x50, 0x3C],
+    [0x44, 0x64, 0x54, 0x4C, 0x44], [0x00, 0x08, 0x36, 0x41, 0x00], [0x00, 0x00, 0x7F, 0x00, 0x00],
+    [0x00, 0x41, 0x36, 0x08, 0x00], [0x08, 0x04, 0x08, 0x10, 0x08],
+];
+
+/// Returns `image` with a bar below it holding `caption` in `colour`, wrapped to the image's width and cut
+/// off after `MAX_LINES` lines. Only ASCII can be drawn; anything else shows up as `?`.
+pub fn add_caption(image: RgbImage, caption: &str, colour: Rgb<u8>) -> RgbImage {
+    let (scale, lines) = layout_caption(image.width(), caption);
+    if lines.is_empty() {
+        return image;
+    }
+
+    let padding = PADDING * scale;
+    let mut captioned = create_background(Size {
+        width: image.width(),
+        height: image.height() + bar_height(scale, lines.len()),
+    });
+    image::imageops::replace(&mut captioned, &image, 0, 0);
+
+    for (line_index, line) in lines.iter().enumerate() {
+        let top = image.height() + padding + line_index as u32 * LINE_HEIGHT * scale;
+        for (position, character) in line.chars().enumerate() {
+            let left = padding + position as u32 * ADVANCE * scale;
+            draw_glyph(&mut captioned, left, top, scale, character, colour);
+        }
+    }
+
+    captioned
+}
+
+/// How much taller `add_caption` makes an image `width` pixels wide.
+pub fn caption_height(width: u32, caption: &str) -> u32 {
+    let (scale, lines) = layout_caption(width, caption);
+    if lines.is_empty() {
+        0
+    } else {
+        bar_height(scale, lines.len())
+    }
+}
+
+/// The size of a dot and the lines `caption` is wrapped to in a bar `width` pixels wide.
+fn layout_caption(width: u32, caption: &str) -> (u32, Vec<String>) {
+    let scale = (width / (CHARS_PER_LINE * ADVANCE)).clamp(1, MAX_SCALE);
+    let chars_per_line = (width.saturating_sub(PADDING * scale * 2) / (ADVANCE * scale)).max(1) as usize;
+    (scale, wrap(caption, chars_per_line))
+}
+
+const fn bar_height(scale: u32, lines: usize) -> u32 {
+    (PADDING * 2 + lines as u32 * LINE_HEIGHT - (LINE_HEIGHT - GLYPH_HEIGHT)) * scale
+}
+
+/// Splits `text` into lines of at most `width` characters, breaking between words where it can, and cuts
+/// it off with `...` after `MAX_LINES` lines.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        let mut word: Vec<char> = word.chars().collect();
+        if !line.is_empty() && line.chars().count() + 1 + word.len() <= width {
+            line.push(' ');
+            line.extend(&word);
+            continue;
+        }
+        if !line.is_empty() {
+            lines.push(std::mem::take(&mut line));
+        }
+        // Words longer than a whole line are broken wherever they run out of room.
+        while word.len() > width {
+            lines.push(word.drain(..width).collect());
+        }
+        line.extend(word);
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+
+    if lines.len() > MAX_LINES {
+        lines.truncate(MAX_LINES);
+        let last = &mut lines[MAX_LINES - 1];
+        let kept: String = last.chars().take(width.saturating_sub(3)).collect();
+        *last = format!("{}...", kept.trim_end());
+    }
+    lines
+}
+
+/// Draws `character` with its top left corner at (`left`, `top`), each dot `scale` pixels square.
+fn draw_glyph(image: &mut RgbImage, left: u32, top: u32, scale: u32, character: char, colour: Rgb<u8>) {
+    let index = match character {
+        ' '..='~' => character as usize - ' ' as usize,
+        _ => '?' as usize - ' ' as usize,
+    };
+
+    for (column, bits) in FONT[index].iter().enumerate() {
+        for row in 0..GLYPH_HEIGHT {
+            if bits & (1 << row) == 0 {
+                continue;
+            }
+
+            let x = left + column as u32 * scale;
+            let y = top + row * scale;
+            for dy in 0..scale {
+                for dx in 0..scale {
+                    if x + dx < image.width() && y + dy < image.height() {
+                        image.put_pixel(x + dx, y + dy, colour);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mosaic::caption::{add_caption, caption_height, wrap, MAX_BAR_HEIGHT};
+    use crate::mosaic::testutils::{create_with_colour, is_colour_at_pixel, is_colour_in_range, BLACK, RED, WHITE};
+
+    #[test]
+    fn wraps_between_words() {
+        assert_eq!(wrap("the quick brown fox", 10), ["the quick", "brown fox"]);
+        assert_eq!(wrap("  spaced   out  ", 20), ["spaced out"]);
+        assert_eq!(wrap("abcdefghij klm", 4), ["abcd", "efgh", "i..."]);
+        assert!(wrap("", 10).is_empty());
+
+        let long = wrap("one two three four five six seven eight", 9);
+        assert_eq!(long, ["one two", "three", "four f..."]);
+    }
+
+    #[test]
+    fn adds_bar_below_the_image() {
+        let image = create_with_colour(600, 300, RED);
+
+        let captioned = add_caption(image, "Hi", WHITE);
+
+        // 600 pixels fit 48 characters at 2 pixels a dot, so the bar is 7 dots of text and 3 of padding
+        // either side.
+        assert_eq!(captioned.dimensions(), (600, 300 + 13 * 2));
+        assert!(is_colour_in_range(0, 0, 600, 300, &captioned, RED));
+        assert!(is_colour_in_range(0, 300, 600, 306, &captioned, BLACK));
+        // The top left dot of the H, then the gap in its middle.
+        assert!(is_colour_at_pixel(6, 306, &captioned, WHITE));
+        assert!(is_colour_at_pixel(8, 306, &captioned, BLACK));
+        assert!(is_colour_in_range(40, 300, 600, 326, &captioned, BLACK));
+        assert_eq!(caption_height(600, "Hi"), 13 * 2);
+    }
+
+    #[test]
+    fn bars_are_never_taller_than_the_maximum() {
+        let long = "a long caption ".repeat(100);
+
+        assert_eq!(caption_height(4000, &long), MAX_BAR_HEIGHT);
+        assert!(caption_height(300, &long) < MAX_BAR_HEIGHT);
+        assert_eq!(caption_height(4000, "  "), 0);
+    }
+}
@@ -0,0 +1,148 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2022 Antonio32A (antonio32a.com) <~@antonio32a.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use image::{Rgb, RgbImage};
+
+use crate::mosaic::{draw_border, ImageOffset, Size};
+
+const OUTLINE_COLOUR: Rgb<u8> = Rgb([255, 0, 255]);
+const OUTLINE_WIDTH: u32 = 2;
+const LABEL_BACKGROUND: Rgb<u8> = Rgb([0, 0, 0]);
+/// How many pixels each dot of a digit glyph is drawn as.
+const GLYPH_SCALE: u32 = 4;
+const GLYPH_WIDTH: u32 = 3;
+const GLYPH_HEIGHT: u32 = 5;
+/// 3x5 bitmaps of the digits 0-9, one row per entry with the leftmost dot in the highest bit.
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b010, 0b010, 0b010],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+/// Outlines every region and labels it with its index in the top left corner, to show which layout was
+/// picked and where each image went.
+pub fn draw_debug_overlay(image: &mut RgbImage, regions: &[ImageOffset]) {
+    for (index, region) in regions.iter().enumerate() {
+        draw_border(image, *region, OUTLINE_WIDTH, OUTLINE_COLOUR);
+        let corner = Size {
+            width: region.offset.width + OUTLINE_WIDTH,
+            height: region.offset.height + OUTLINE_WIDTH,
+        };
+        draw_label(image, corner, &index.to_string());
+    }
+}
+
+/// Writes `text`, which must only contain digits, at `corner` on a solid background so it stays readable.
+fn draw_label(image: &mut RgbImage, corner: Size, text: &str) {
+    let advance = (GLYPH_WIDTH + 1) * GLYPH_SCALE;
+    let width = advance * text.len() as u32 + GLYPH_SCALE;
+    let height = (GLYPH_HEIGHT + 2) * GLYPH_SCALE;
+    fill(image, corner, Size { width, height }, LABEL_BACKGROUND);
+
+    for (position, digit) in text.chars().filter_map(|c| c.to_digit(10)).enumerate() {
+        let glyph = DIGITS[digit as usize];
+        for (row, bits) in glyph.iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - column)) == 0 {
+                    continue;
+                }
+
+                let dot = Size {
+                    width: corner.width + GLYPH_SCALE + position as u32 * advance + column * GLYPH_SCALE,
+                    height: corner.height + GLYPH_SCALE + row as u32 * GLYPH_SCALE,
+                };
+                let dot_size = Size {
+                    width: GLYPH_SCALE,
+                    height: GLYPH_SCALE,
+                };
+                fill(image, dot, dot_size, OUTLINE_COLOUR);
+            }
+        }
+    }
+}
+
+/// Fills a rectangle, clipped to the image.
+fn fill(image: &mut RgbImage, offset: Size, size: Size, colour: Rgb<u8>) {
+    let right = (offset.width + size.width).min(image.width());
+    let bottom = (offset.height + size.height).min(image.height());
+    for y in offset.height..bottom {
+        for x in offset.width..right {
+            image.put_pixel(x, y, colour);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mosaic::debug::{draw_debug_overlay, OUTLINE_COLOUR};
+    use crate::mosaic::testutils::{create_with_colour, is_colour_at_pixel, is_colour_in_range, BLACK, RED};
+    use crate::mosaic::{ImageOffset, Size};
+
+    #[test]
+    fn outlines_and_labels_regions() {
+        let mut image = create_with_colour(200, 100, RED);
+        let regions = [
+            ImageOffset {
+                offset: Size {
+                    width: 0,
+                    height: 0,
+                },
+                dimensions: Size {
+                    width: 100,
+                    height: 100,
+                },
+                original_dimensions: Size::default(),
+            },
+            ImageOffset {
+                offset: Size {
+                    width: 100,
+                    height: 0,
+                },
+                dimensions: Size {
+                    width: 100,
+                    height: 100,
+                },
+                original_dimensions: Size::default(),
+            },
+        ];
+
+        draw_debug_overlay(&mut image, &regions);
+
+        assert!(is_colour_in_range(0, 0, 200, 2, &image, OUTLINE_COLOUR));
+        assert!(is_colour_in_range(98, 50, 102, 51, &image, OUTLINE_COLOUR));
+        // The label background, then the top left dot of the digit.
+        assert!(is_colour_at_pixel(3, 3, &image, BLACK));
+        assert!(is_colour_at_pixel(7, 7, &image, OUTLINE_COLOUR));
+        assert!(is_colour_at_pixel(107, 7, &image, BLACK));
+        assert!(is_colour_at_pixel(111, 7, &image, OUTLINE_COLOUR));
+        assert!(is_colour_in_range(20, 40, 90, 90, &image, RED));
+    }
+}
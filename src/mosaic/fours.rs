@@ -1,35 +1,54 @@
-use image::RgbImage;
-
-use crate::mosaic::{best_mosaic, build_mosaic, ImageOffset, MosaicDims, MosaicImageDims, scale_height_dimension, scale_width_dimension, Size, SPACING_SIZE};
+use crate::mosaic::{Anchor, best_mosaic, ImageOffset, Layout, LayoutMode, MosaicDims, MosaicImageDims, MosaicOptions, scale_height_dimension, scale_width_dimension, Size, Spacing, takes_uniform_fast_path, within_limits};
 use crate::mosaic::threes::{three_columns_3_mosaic, three_rows_3_mosaic};
 use crate::mosaic::twos::{left_right_2_mosaic, top_bottom_2_mosaic};
 
-pub fn build_4_mosaic(first: RgbImage, second: RgbImage, third: RgbImage, fourth: RgbImage) -> RgbImage {
-    let first_size = Size { width: first.width(), height: first.height() };
-    let second_size = Size { width: second.width(), height: second.height() };
-    let third_size = Size { width: third.width(), height: third.height() };
-    let fourth_size = Size { width: fourth.width(), height: fourth.height() };
-    let best_mosaic = best_4_mosaic(first_size, second_size, third_size, fourth_size);
-    build_mosaic(best_mosaic, [first, second, third, fourth])
+pub fn plan_4_mosaic(first: Size, second: Size, third: Size, fourth: Size, options: &MosaicOptions) -> MosaicImageDims<4> {
+    if options.layout == LayoutMode::Featured {
+        return featured_4_mosaic(first, second, third, fourth, options);
+    }
+
+    best_4_mosaic(first, second, third, fourth, options)
+}
+
+/// The first image on its own above or beside a strip of the other three.
+fn featured_4_mosaic(first: Size, second: Size, third: Size, fourth: Size, options: &MosaicOptions) -> MosaicImageDims<4> {
+    let spacing = options.spacing.clamped();
+    let two_rows_one_three = two_rows_one_three_4_mosaic(first, second, third, fourth, spacing);
+    let two_columns_one_three = two_columns_one_three_4_mosaic(first, second, third, fourth, spacing);
+    best_mosaic(
+        &within_limits(&[&two_rows_one_three, &two_columns_one_three], options),
+        &options.featured_weights(),
+        options.native_image(),
+        options.max_size(),
+        options.scale_tolerance,
+        options.rank,
+    ).unwrap_or_else(|| two_rows_one_three.scale_to_fit(options.native_image(), options.max_size()))
 }
 
-fn best_4_mosaic(first: Size, second: Size, third: Size, fourth: Size) -> MosaicImageDims<4> {
-    let four_columns = four_columns_4_mosaic(first, second, third, fourth);
-    let four_rows = four_rows_4_mosaic(first, second, third, fourth);
-    let two_rows_of_two = two_rows_of_two_4_mosaic(first, second, third, fourth);
-    let two_rows_one_three = two_rows_one_three_4_mosaic(first, second, third, fourth);
-    let two_rows_three_one = two_rows_three_one_4_mosaic(first, second, third, fourth);
-    let two_columns_one_three = two_columns_one_three_4_mosaic(first, second, third, fourth);
-    let two_columns_three_one = two_columns_three_one_4_mosaic(first, second, third, fourth);
-    let three_rows_211 = three_rows_211_4_mosaic(first, second, third, fourth);
-    let three_rows_121 = three_rows_121_4_mosaic(first, second, third, fourth);
-    let three_rows_112 = three_rows_112_4_mosaic(first, second, third, fourth);
+fn best_4_mosaic(first: Size, second: Size, third: Size, fourth: Size, options: &MosaicOptions) -> MosaicImageDims<4> {
+    let spacing = options.spacing.clamped();
+    let two_rows_of_two = two_rows_of_two_4_mosaic(first, second, third, fourth, spacing, options.equal);
+    // Four squares of the same size make a square 2x2, which no other layout can beat.
+    if takes_uniform_fast_path(&[first, second, third, fourth], &two_rows_of_two, options) {
+        tracing::debug!("images are all the same size, using a 2x2 grid");
+        return two_rows_of_two.scale_to_fit(options.native_image(), options.max_size());
+    }
+
+    let four_columns = four_columns_4_mosaic(first, second, third, fourth, spacing);
+    let four_rows = four_rows_4_mosaic(first, second, third, fourth, spacing);
+    let two_rows_one_three = two_rows_one_three_4_mosaic(first, second, third, fourth, spacing);
+    let two_rows_three_one = two_rows_three_one_4_mosaic(first, second, third, fourth, spacing);
+    let two_columns_one_three = two_columns_one_three_4_mosaic(first, second, third, fourth, spacing);
+    let two_columns_three_one = two_columns_three_one_4_mosaic(first, second, third, fourth, spacing);
+    let three_rows_211 = three_rows_211_4_mosaic(first, second, third, fourth, spacing);
+    let three_rows_121 = three_rows_121_4_mosaic(first, second, third, fourth, spacing);
+    let three_rows_112 = three_rows_112_4_mosaic(first, second, third, fourth, spacing);
     // These four are omitted from the options, as they are just not very readable
-    // let two_columns_of_two = two_columns_of_two_4_mosaic(first, second, third, fourth);
-    // let three_columns_211 = three_columns_211_4_mosaic(first, second, third, fourth);
-    // let three_columns_121 = three_columns_121_4_mosaic(first, second, third, fourth);
-    // let three_columns_112 = three_columns_112_4_mosaic(first, second, third, fourth);
-    return best_mosaic(&[
+    // let two_columns_of_two = two_columns_of_two_4_mosaic(first, second, third, fourth, spacing);
+    // let three_columns_211 = three_columns_211_4_mosaic(first, second, third, fourth, spacing);
+    // let three_columns_121 = three_columns_121_4_mosaic(first, second, third, fourth, spacing);
+    // let three_columns_112 = three_columns_112_4_mosaic(first, second, third, fourth, spacing);
+    best_mosaic(&within_limits(&[
         &four_columns,
         &four_rows,
         &two_rows_of_two,
@@ -40,13 +59,13 @@ fn best_4_mosaic(first: Size, second: Size, third: Size, fourth: Size) -> Mosaic
         &three_rows_211,
         &three_rows_121,
         &three_rows_112
-    ]);
+    ], options), &options.weights, options.native_image(), options.max_size(), options.scale_tolerance, options.rank).unwrap_or_else(|| two_rows_of_two.scale_to_fit(options.native_image(), options.max_size()))
 }
 
-fn four_columns_4_mosaic(first: Size, second: Size, third: Size, fourth: Size) -> MosaicImageDims<4> {
+fn four_columns_4_mosaic(first: Size, second: Size, third: Size, fourth: Size, spacing: Spacing) -> MosaicImageDims<4> {
     let image2_offset = ImageOffset {
         offset: Size {
-            width: first.width + SPACING_SIZE,
+            width: first.width.saturating_add(spacing.horizontal),
             height: 0,
         },
         dimensions: scale_height_dimension(second, first.height),
@@ -54,7 +73,7 @@ fn four_columns_4_mosaic(first: Size, second: Size, third: Size, fourth: Size) -
     };
     let image3_offset = ImageOffset {
         offset: Size {
-            width: image2_offset.total_width() + SPACING_SIZE,
+            width: image2_offset.total_width().saturating_add(spacing.horizontal),
             height: 0,
         },
         dimensions: scale_height_dimension(third, first.height),
@@ -75,21 +94,23 @@ fn four_columns_4_mosaic(first: Size, second: Size, third: Size, fourth: Size) -
             image3_offset,
             ImageOffset {
                 offset: Size {
-                    width: image3_offset.total_width() + SPACING_SIZE,
+                    width: image3_offset.total_width().saturating_add(spacing.horizontal),
                     height: 0,
                 },
                 dimensions: scale_height_dimension(fourth, first.height),
                 original_dimensions: fourth,
             },
         ],
+        layout: Layout::FourColumns,
+        cropped: false,
     }
 }
 
-fn four_rows_4_mosaic(first: Size, second: Size, third: Size, fourth: Size) -> MosaicImageDims<4> {
+fn four_rows_4_mosaic(first: Size, second: Size, third: Size, fourth: Size, spacing: Spacing) -> MosaicImageDims<4> {
     let image2_offset = ImageOffset {
         offset: Size {
             width: 0,
-            height: first.height + SPACING_SIZE,
+            height: first.height.saturating_add(spacing.vertical),
         },
         dimensions: scale_width_dimension(second, first.width),
         original_dimensions: second,
@@ -98,7 +119,7 @@ fn four_rows_4_mosaic(first: Size, second: Size, third: Size, fourth: Size) -> M
     let image3_offset = ImageOffset {
         offset: Size {
             width: 0,
-            height: image2_offset.total_height() + SPACING_SIZE,
+            height: image2_offset.total_height().saturating_add(spacing.vertical),
         },
         dimensions: scale_width_dimension(third, first.width),
         original_dimensions: third,
@@ -119,35 +140,61 @@ fn four_rows_4_mosaic(first: Size, second: Size, third: Size, fourth: Size) -> M
             ImageOffset {
                 offset: Size {
                     width: 0,
-                    height: image3_offset.total_height() + SPACING_SIZE,
+                    height: image3_offset.total_height().saturating_add(spacing.vertical),
                 },
                 dimensions: scale_width_dimension(fourth, first.width),
                 original_dimensions: fourth,
             },
         ],
+        layout: Layout::FourRows,
+        cropped: false,
     }
 }
 
-fn two_rows_of_two_4_mosaic(first: Size, second: Size, third: Size, fourth: Size) -> MosaicImageDims<4> {
-    let first_row = left_right_2_mosaic(first, second);
-    let second_row = left_right_2_mosaic(third, fourth);
+fn two_rows_of_two_4_mosaic(first: Size, second: Size, third: Size, fourth: Size, spacing: Spacing, equal: bool) -> MosaicImageDims<4> {
+    let first_row = left_right_2_mosaic(first, second, spacing, Anchor::First);
+    let second_row = left_right_2_mosaic(third, fourth, spacing, Anchor::First);
     let scale_factor = second_row.total_size().width as f32 / first_row.total_size().width as f32;
-    let second_row_moved = second_row.scale(scale_factor).add_height(first_row.total_size().height + SPACING_SIZE);
-
-    MosaicImageDims {
+    let second_row_moved = second_row.scale(scale_factor).add_height(first_row.total_size().height.saturating_add(spacing.vertical));
+    let natural = MosaicImageDims {
         images: [
             first_row.images[0],
             first_row.images[1],
             second_row_moved.images[0],
             second_row_moved.images[1],
         ],
+        layout: Layout::TwoRowsOfTwo,
+        cropped: false,
+    };
+    if !equal {
+        return natural;
+    }
+
+    // Equal cells split the grid the natural layout would take up evenly, and each image is cropped to fill its cell.
+    let total = natural.total_size();
+    let cell = Size {
+        width: (total.width.saturating_sub(spacing.horizontal) / 2).max(1),
+        height: (total.height.saturating_sub(spacing.vertical) / 2).max(1),
+    };
+    let originals = [first, second, third, fourth];
+    MosaicImageDims {
+        images: std::array::from_fn(|index| ImageOffset {
+            offset: Size {
+                width: (index as u32 % 2) * cell.width.saturating_add(spacing.horizontal),
+                height: (index as u32 / 2) * cell.height.saturating_add(spacing.vertical),
+            },
+            dimensions: cell,
+            original_dimensions: originals[index],
+        }),
+        layout: Layout::TwoRowsOfTwo,
+        cropped: true,
     }
 }
 
-fn two_rows_one_three_4_mosaic(first: Size, second: Size, third: Size, fourth: Size) -> MosaicImageDims<4> {
-    let second_row = three_columns_3_mosaic(second, third, fourth);
+fn two_rows_one_three_4_mosaic(first: Size, second: Size, third: Size, fourth: Size, spacing: Spacing) -> MosaicImageDims<4> {
+    let second_row = three_columns_3_mosaic(second, third, fourth, spacing);
     let image1_dims = scale_width_dimension(first, second_row.total_size().width);
-    let second_row_moved = second_row.add_height(image1_dims.height + SPACING_SIZE);
+    let second_row_moved = second_row.add_height(image1_dims.height.saturating_add(spacing.vertical));
 
     MosaicImageDims {
         images: [
@@ -163,11 +210,13 @@ fn two_rows_one_three_4_mosaic(first: Size, second: Size, third: Size, fourth: S
             second_row_moved.images[1],
             second_row_moved.images[2],
         ],
+        layout: Layout::TwoRowsOneThree,
+        cropped: false,
     }
 }
 
-fn two_rows_three_one_4_mosaic(first: Size, second: Size, third: Size, fourth: Size) -> MosaicImageDims<4> {
-    let first_row = three_columns_3_mosaic(first, second, third);
+fn two_rows_three_one_4_mosaic(first: Size, second: Size, third: Size, fourth: Size, spacing: Spacing) -> MosaicImageDims<4> {
+    let first_row = three_columns_3_mosaic(first, second, third, spacing);
     let image4_dims = scale_width_dimension(fourth, first_row.total_size().width);
 
     MosaicImageDims {
@@ -178,21 +227,23 @@ fn two_rows_three_one_4_mosaic(first: Size, second: Size, third: Size, fourth: S
             ImageOffset {
                 offset: Size {
                     width: 0,
-                    height: first_row.total_size().height + SPACING_SIZE,
+                    height: first_row.total_size().height.saturating_add(spacing.vertical),
                 },
                 dimensions: image4_dims,
                 original_dimensions: fourth,
             },
         ],
+        layout: Layout::TwoRowsThreeOne,
+        cropped: false,
     }
 }
 
 #[allow(dead_code)]
-fn two_columns_of_two_4_mosaic(first: Size, second: Size, third: Size, fourth: Size) -> MosaicImageDims<4> {
-    let first_col = top_bottom_2_mosaic(first, second);
-    let second_col = top_bottom_2_mosaic(third, fourth);
+fn two_columns_of_two_4_mosaic(first: Size, second: Size, third: Size, fourth: Size, spacing: Spacing) -> MosaicImageDims<4> {
+    let first_col = top_bottom_2_mosaic(first, second, spacing, Anchor::First);
+    let second_col = top_bottom_2_mosaic(third, fourth, spacing, Anchor::First);
     let scale_factor = second_col.total_size().height as f32 / first_col.total_size().height as f32;
-    let second_col_moved = second_col.scale(scale_factor).add_width(first_col.total_size().width + SPACING_SIZE);
+    let second_col_moved = second_col.scale(scale_factor).add_width(first_col.total_size().width.saturating_add(spacing.horizontal));
 
     MosaicImageDims {
         images: [
@@ -201,13 +252,15 @@ fn two_columns_of_two_4_mosaic(first: Size, second: Size, third: Size, fourth: S
             second_col_moved.images[0],
             second_col_moved.images[1],
         ],
+        layout: Layout::TwoColumnsOfTwo,
+        cropped: false,
     }
 }
 
-fn two_columns_one_three_4_mosaic(first: Size, second: Size, third: Size, fourth: Size) -> MosaicImageDims<4> {
-    let second_col = three_rows_3_mosaic(second, third, fourth);
+fn two_columns_one_three_4_mosaic(first: Size, second: Size, third: Size, fourth: Size, spacing: Spacing) -> MosaicImageDims<4> {
+    let second_col = three_rows_3_mosaic(second, third, fourth, spacing);
     let image1_dims = scale_height_dimension(first, second_col.total_size().height);
-    let second_col_moved = second_col.add_width(image1_dims.width + SPACING_SIZE);
+    let second_col_moved = second_col.add_width(image1_dims.width.saturating_add(spacing.horizontal));
 
     MosaicImageDims {
         images: [
@@ -223,11 +276,13 @@ fn two_columns_one_three_4_mosaic(first: Size, second: Size, third: Size, fourth
             second_col_moved.images[1],
             second_col_moved.images[2],
         ],
+        layout: Layout::TwoColumnsOneThree,
+        cropped: false,
     }
 }
 
-fn two_columns_three_one_4_mosaic(first: Size, second: Size, third: Size, fourth: Size) -> MosaicImageDims<4> {
-    let first_col = three_rows_3_mosaic(first, second, third);
+fn two_columns_three_one_4_mosaic(first: Size, second: Size, third: Size, fourth: Size, spacing: Spacing) -> MosaicImageDims<4> {
+    let first_col = three_rows_3_mosaic(first, second, third, spacing);
     let image4_dims = scale_height_dimension(fourth, first_col.total_size().height);
 
     MosaicImageDims {
@@ -237,22 +292,24 @@ fn two_columns_three_one_4_mosaic(first: Size, second: Size, third: Size, fourth
             first_col.images[2],
             ImageOffset {
                 offset: Size {
-                    width: first_col.total_size().width + SPACING_SIZE,
+                    width: first_col.total_size().width.saturating_add(spacing.horizontal),
                     height: 0,
                 },
                 dimensions: image4_dims,
                 original_dimensions: fourth,
             },
         ],
+        layout: Layout::TwoColumnsThreeOne,
+        cropped: false,
     }
 }
 
-fn three_rows_211_4_mosaic(first: Size, second: Size, third: Size, fourth: Size) -> MosaicImageDims<4> {
-    let first_row = left_right_2_mosaic(first, second);
+fn three_rows_211_4_mosaic(first: Size, second: Size, third: Size, fourth: Size, spacing: Spacing) -> MosaicImageDims<4> {
+    let first_row = left_right_2_mosaic(first, second, spacing, Anchor::First);
     let image3_offset = ImageOffset {
         offset: Size {
             width: 0,
-            height: first_row.total_size().height + SPACING_SIZE,
+            height: first_row.total_size().height.saturating_add(spacing.vertical),
         },
         dimensions: scale_width_dimension(third, first_row.total_size().width),
         original_dimensions: third,
@@ -266,19 +323,21 @@ fn three_rows_211_4_mosaic(first: Size, second: Size, third: Size, fourth: Size)
             ImageOffset {
                 offset: Size {
                     width: 0,
-                    height: image3_offset.total_height() + SPACING_SIZE,
+                    height: image3_offset.total_height().saturating_add(spacing.vertical),
                 },
                 dimensions: scale_width_dimension(fourth, first_row.total_size().width),
                 original_dimensions: fourth,
             }
         ],
+        layout: Layout::ThreeRows211,
+        cropped: false,
     }
 }
 
-fn three_rows_121_4_mosaic(first: Size, second: Size, third: Size, fourth: Size) -> MosaicImageDims<4> {
-    let second_row = left_right_2_mosaic(second, third);
+fn three_rows_121_4_mosaic(first: Size, second: Size, third: Size, fourth: Size, spacing: Spacing) -> MosaicImageDims<4> {
+    let second_row = left_right_2_mosaic(second, third, spacing, Anchor::First);
     let image1_dims = scale_width_dimension(first, second_row.total_size().width);
-    let second_row_moved = second_row.add_height(image1_dims.height + SPACING_SIZE);
+    let second_row_moved = second_row.add_height(image1_dims.height.saturating_add(spacing.vertical));
 
     MosaicImageDims {
         images: [
@@ -295,17 +354,19 @@ fn three_rows_121_4_mosaic(first: Size, second: Size, third: Size, fourth: Size)
             ImageOffset {
                 offset: Size {
                     width: 0,
-                    height: second_row_moved.total_size().height + SPACING_SIZE,
+                    height: second_row_moved.total_size().height.saturating_add(spacing.vertical),
                 },
                 dimensions: scale_width_dimension(fourth, second_row_moved.total_size().width),
                 original_dimensions: fourth,
             },
         ],
+        layout: Layout::ThreeRows121,
+        cropped: false,
     }
 }
 
-fn three_rows_112_4_mosaic(first: Size, second: Size, third: Size, fourth: Size) -> MosaicImageDims<4> {
-    let third_row = left_right_2_mosaic(third, fourth);
+fn three_rows_112_4_mosaic(first: Size, second: Size, third: Size, fourth: Size, spacing: Spacing) -> MosaicImageDims<4> {
+    let third_row = left_right_2_mosaic(third, fourth, spacing, Anchor::First);
     let image1_offset = ImageOffset {
         offset: Size {
             width: 0,
@@ -318,13 +379,13 @@ fn three_rows_112_4_mosaic(first: Size, second: Size, third: Size, fourth: Size)
     let image2_offset = ImageOffset {
         offset: Size {
             width: 0,
-            height: image1_offset.total_height() + SPACING_SIZE,
+            height: image1_offset.total_height().saturating_add(spacing.vertical),
         },
         dimensions: scale_width_dimension(second, third_row.total_size().width),
         original_dimensions: second,
     };
 
-    let third_row_moved = third_row.add_height(image2_offset.total_height() + SPACING_SIZE);
+    let third_row_moved = third_row.add_height(image2_offset.total_height().saturating_add(spacing.vertical));
 
     MosaicImageDims {
         images: [
@@ -333,15 +394,17 @@ fn three_rows_112_4_mosaic(first: Size, second: Size, third: Size, fourth: Size)
             third_row_moved.images[0],
             third_row_moved.images[1],
         ],
+        layout: Layout::ThreeRows112,
+        cropped: false,
     }
 }
 
 #[allow(dead_code)]
-fn three_columns_211_4_mosaic(first: Size, second: Size, third: Size, fourth: Size) -> MosaicImageDims<4> {
-    let first_col = top_bottom_2_mosaic(first, second);
+fn three_columns_211_4_mosaic(first: Size, second: Size, third: Size, fourth: Size, spacing: Spacing) -> MosaicImageDims<4> {
+    let first_col = top_bottom_2_mosaic(first, second, spacing, Anchor::First);
     let image3_offset = ImageOffset {
         offset: Size {
-            width: first_col.total_size().width + SPACING_SIZE,
+            width: first_col.total_size().width.saturating_add(spacing.horizontal),
             height: 0,
         },
         dimensions: scale_height_dimension(third, first_col.total_size().height),
@@ -355,19 +418,21 @@ fn three_columns_211_4_mosaic(first: Size, second: Size, third: Size, fourth: Si
             image3_offset,
             ImageOffset {
                 offset: Size {
-                    width: image3_offset.total_width() + SPACING_SIZE,
+                    width: image3_offset.total_width().saturating_add(spacing.horizontal),
                     height: 0,
                 },
                 dimensions: scale_height_dimension(fourth, first_col.total_size().height),
                 original_dimensions: fourth,
             },
         ],
+        layout: Layout::ThreeColumns211,
+        cropped: false,
     }
 }
 
 #[allow(dead_code)]
-fn three_columns_121_4_mosaic(first: Size, second: Size, third: Size, fourth: Size) -> MosaicImageDims<4> {
-    let second_col = top_bottom_2_mosaic(second, third);
+fn three_columns_121_4_mosaic(first: Size, second: Size, third: Size, fourth: Size, spacing: Spacing) -> MosaicImageDims<4> {
+    let second_col = top_bottom_2_mosaic(second, third, spacing, Anchor::First);
     let image1_offset = ImageOffset {
         offset: Size {
             width: 0,
@@ -377,7 +442,7 @@ fn three_columns_121_4_mosaic(first: Size, second: Size, third: Size, fourth: Si
         original_dimensions: first,
     };
 
-    let second_col_moved = second_col.add_width(image1_offset.total_width() + SPACING_SIZE);
+    let second_col_moved = second_col.add_width(image1_offset.total_width().saturating_add(spacing.horizontal));
 
     MosaicImageDims {
         images: [
@@ -386,19 +451,21 @@ fn three_columns_121_4_mosaic(first: Size, second: Size, third: Size, fourth: Si
             second_col_moved.images[1],
             ImageOffset {
                 offset: Size {
-                    width: second_col_moved.total_size().width + SPACING_SIZE,
+                    width: second_col_moved.total_size().width.saturating_add(spacing.horizontal),
                     height: 0,
                 },
                 dimensions: scale_height_dimension(fourth, second_col_moved.total_size().height),
                 original_dimensions: fourth,
             },
         ],
+        layout: Layout::ThreeColumns121,
+        cropped: false,
     }
 }
 
 #[allow(dead_code)]
-fn three_columns_112_4_mosaic(first: Size, second: Size, third: Size, fourth: Size) -> MosaicImageDims<4> {
-    let third_col = top_bottom_2_mosaic(third, fourth);
+fn three_columns_112_4_mosaic(first: Size, second: Size, third: Size, fourth: Size, spacing: Spacing) -> MosaicImageDims<4> {
+    let third_col = top_bottom_2_mosaic(third, fourth, spacing, Anchor::First);
     let image1_offset = ImageOffset {
         offset: Size {
             width: 0,
@@ -410,14 +477,14 @@ fn three_columns_112_4_mosaic(first: Size, second: Size, third: Size, fourth: Si
 
     let image2_offset = ImageOffset {
         offset: Size {
-            width: image1_offset.total_width() + SPACING_SIZE,
+            width: image1_offset.total_width().saturating_add(spacing.horizontal),
             height: 0,
         },
         dimensions: scale_height_dimension(second, third_col.total_size().height),
         original_dimensions: second,
     };
 
-    let third_col_moved = third_col.add_width(image2_offset.total_width() + SPACING_SIZE);
+    let third_col_moved = third_col.add_width(image2_offset.total_width().saturating_add(spacing.horizontal));
 
     MosaicImageDims {
         images: [
@@ -426,21 +493,24 @@ fn three_columns_112_4_mosaic(first: Size, second: Size, third: Size, fourth: Si
             third_col_moved.images[0],
             third_col_moved.images[1],
         ],
+        layout: Layout::ThreeColumns112,
+        cropped: false,
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::mosaic;
+    use crate::mosaic::mosaic;
+    use crate::mosaic::{Layout, LayoutMode, MosaicOptions};
     use crate::mosaic::testutils::{
+        BLACK,
         BLUE,
         create_with_colour,
         GREEN,
-        has_black_horizontal_line,
-        has_black_horizontal_line_partial,
-        has_black_vertical_line,
-        has_black_vertical_line_partial,
+        has_gutter,
+        has_gutter_between,
         is_colour_in_range,
+        Orientation,
         PURPLE,
         RED,
         save_result,
@@ -453,18 +523,117 @@ mod tests {
         let col3 = create_with_colour(100, 400, GREEN);
         let col4 = create_with_colour(100, 400, PURPLE);
 
-        let result = mosaic(vec![col1, col2, col3, col4]);
+        let result = mosaic(vec![col1, col2, col3, col4], &MosaicOptions::default()).image;
 
         save_result(&result, "4-four_cols");
         assert!(is_colour_in_range(0, 0, 100, 400, &result, RED));
-        assert!(has_black_vertical_line(105, &result));
+        assert!(has_gutter(100, 10, BLACK, Orientation::Vertical, &result));
         assert!(is_colour_in_range(120, 0, 200, 400, &result, BLUE));
-        assert!(has_black_vertical_line(215, &result));
+        assert!(has_gutter(210, 10, BLACK, Orientation::Vertical, &result));
         assert!(is_colour_in_range(230, 0, 300, 400, &result, GREEN));
-        assert!(has_black_vertical_line(325, &result));
+        assert!(has_gutter(320, 10, BLACK, Orientation::Vertical, &result));
         assert!(is_colour_in_range(340, 0, 400, 400, &result, PURPLE));
     }
 
+    #[test]
+    fn mosaic_4_uniform_squares_make_a_grid() {
+        let avatars = || [RED, BLUE, GREEN, PURPLE].map(|colour| create_with_colour(1000, 1000, colour)).to_vec();
+        let capped = MosaicOptions {
+            max_columns: Some(1),
+            ..MosaicOptions::default()
+        };
+
+        let result = mosaic(avatars(), &MosaicOptions::default());
+
+        assert_eq!(result.layout, Layout::TwoRowsOfTwo);
+        assert_eq!(result.image.dimensions(), (2010, 2010));
+        assert!(is_colour_in_range(1010, 1010, 2010, 2010, &result.image, PURPLE));
+        // A grid that doesn't fit the limits goes through the usual search instead.
+        assert_eq!(mosaic(avatars(), &capped).layout, Layout::FourRows);
+    }
+
+    #[test]
+    fn mosaic_4_column_cap_forces_two_rows_of_two() {
+        let columns = || vec![
+            create_with_colour(100, 400, RED),
+            create_with_colour(100, 400, BLUE),
+            create_with_colour(100, 400, GREEN),
+            create_with_colour(100, 400, PURPLE),
+        ];
+        let capped = MosaicOptions {
+            max_columns: Some(2),
+            ..MosaicOptions::default()
+        };
+
+        let uncapped = mosaic(columns(), &MosaicOptions::default());
+        let result = mosaic(columns(), &capped);
+
+        save_result(&result.image, "4-column_cap");
+        assert_eq!(uncapped.layout, Layout::FourColumns);
+        assert_eq!(result.layout, Layout::TwoRowsOfTwo);
+        assert!(is_colour_in_range(0, 0, 100, 400, &result.image, RED));
+        assert!(is_colour_in_range(110, 0, 210, 400, &result.image, BLUE));
+        assert!(is_colour_in_range(0, 410, 100, 810, &result.image, GREEN));
+        assert!(is_colour_in_range(110, 410, 210, 810, &result.image, PURPLE));
+    }
+
+    #[test]
+    fn mosaic_4_equal_cells() {
+        let images = || vec![
+            create_with_colour(300, 200, RED),
+            create_with_colour(100, 300, BLUE),
+            create_with_colour(200, 200, GREEN),
+            create_with_colour(400, 100, PURPLE),
+        ];
+        let options = MosaicOptions {
+            equal: true,
+            max_columns: Some(2),
+            max_rows: Some(2),
+            ..MosaicOptions::default()
+        };
+
+        let natural = mosaic(images(), &MosaicOptions { equal: false, ..options.clone() });
+        let result = mosaic(images(), &options);
+
+        save_result(&result.image, "4-equal");
+        assert_eq!(natural.layout, Layout::TwoRowsOfTwo);
+        assert_ne!(natural.regions[0].dimensions.width, natural.regions[1].dimensions.width);
+        assert_eq!(result.layout, Layout::TwoRowsOfTwo);
+        let cell = result.regions[0].dimensions;
+        assert!(result
+            .regions
+            .iter()
+            .all(|region| region.dimensions.width == cell.width && region.dimensions.height == cell.height));
+        // Each image is cropped to fill its cell rather than leaving a gap.
+        for (region, colour) in result.regions.iter().zip([RED, BLUE, GREEN, PURPLE]) {
+            assert!(is_colour_in_range(
+                region.offset.width,
+                region.offset.height,
+                region.total_width(),
+                region.total_height(),
+                &result.image,
+                colour
+            ));
+        }
+    }
+
+    #[test]
+    fn mosaic_4_four_cols_fills_rounded_heights() {
+        let col1 = create_with_colour(100, 400, RED);
+        let col2 = create_with_colour(199, 399, BLUE);
+        let col3 = create_with_colour(99, 401, GREEN);
+        let col4 = create_with_colour(100, 400, PURPLE);
+
+        let result = mosaic(vec![col1, col2, col3, col4], &MosaicOptions::default()).image;
+
+        save_result(&result, "4-four_cols_rounded_heights");
+        assert_eq!(result.height(), 400);
+        assert!(is_colour_in_range(0, 0, 100, 400, &result, RED));
+        assert!(is_colour_in_range(110, 0, 309, 400, &result, BLUE));
+        assert!(is_colour_in_range(319, 0, 418, 400, &result, GREEN));
+        assert!(is_colour_in_range(428, 0, 528, 400, &result, PURPLE));
+    }
+
     #[test]
     fn mosaic_4_four_rows() {
         let row1 = create_with_colour(400, 100, RED);
@@ -472,15 +641,15 @@ mod tests {
         let row3 = create_with_colour(400, 100, GREEN);
         let row4 = create_with_colour(400, 100, PURPLE);
 
-        let result = mosaic(vec![row1, row2, row3, row4]);
+        let result = mosaic(vec![row1, row2, row3, row4], &MosaicOptions::default()).image;
 
         save_result(&result, "4-four_rows");
         assert!(is_colour_in_range(0, 0, 400, 100, &result, RED));
-        assert!(has_black_horizontal_line(105, &result));
+        assert!(has_gutter(100, 10, BLACK, Orientation::Horizontal, &result));
         assert!(is_colour_in_range(0, 120, 400, 200, &result, BLUE));
-        assert!(has_black_horizontal_line(215, &result));
+        assert!(has_gutter(210, 10, BLACK, Orientation::Horizontal, &result));
         assert!(is_colour_in_range(0, 230, 400, 300, &result, GREEN));
-        assert!(has_black_horizontal_line(325, &result));
+        assert!(has_gutter(320, 10, BLACK, Orientation::Horizontal, &result));
         assert!(is_colour_in_range(0, 340, 400, 400, &result, PURPLE));
     }
 
@@ -491,15 +660,15 @@ mod tests {
         let bot_left = create_with_colour(300, 200, GREEN);
         let bot_right = create_with_colour(100, 200, PURPLE);
 
-        let result = mosaic(vec![top_left, top_right, bot_left, bot_right]);
+        let result = mosaic(vec![top_left, top_right, bot_left, bot_right], &MosaicOptions::default()).image;
 
         save_result(&result, "4-two_rows_of_two");
         assert!(is_colour_in_range(0, 0, 100, 200, &result, RED));
-        assert!(has_black_vertical_line_partial(105, 0, 200, &result));
+        assert!(has_gutter_between(100, 10, BLACK, Orientation::Vertical, 0, 200, &result));
         assert!(is_colour_in_range(120, 0, 400, 200, &result, BLUE));
-        assert!(has_black_horizontal_line(205, &result));
+        assert!(has_gutter(200, 10, BLACK, Orientation::Horizontal, &result));
         assert!(is_colour_in_range(0, 220, 300, 400, &result, GREEN));
-        assert!(has_black_vertical_line_partial(305, 220, 400, &result));
+        assert!(has_gutter_between(300, 10, BLACK, Orientation::Vertical, 220, 400, &result));
         assert!(is_colour_in_range(320, 220, 400, 400, &result, PURPLE));
     }
 
@@ -510,15 +679,15 @@ mod tests {
         let bot_mid = create_with_colour(100, 100, GREEN);
         let bot_right = create_with_colour(100, 100, PURPLE);
 
-        let result = mosaic(vec![top, bot_left, bot_mid, bot_right]);
+        let result = mosaic(vec![top, bot_left, bot_mid, bot_right], &MosaicOptions::default()).image;
 
         save_result(&result, "4-two_rows_one_three");
         assert!(is_colour_in_range(0, 0, 300, 200, &result, RED));
-        assert!(has_black_horizontal_line(205, &result));
+        assert!(has_gutter(213, 10, BLACK, Orientation::Horizontal, &result));
         assert!(is_colour_in_range(0, 230, 100, 300, &result, BLUE));
-        assert!(has_black_vertical_line_partial(105, 220, 300, &result));
+        assert!(has_gutter_between(100, 10, BLACK, Orientation::Vertical, 220, 300, &result));
         assert!(is_colour_in_range(120, 230, 200, 300, &result, GREEN));
-        assert!(has_black_vertical_line_partial(215, 220, 300, &result));
+        assert!(has_gutter_between(210, 10, BLACK, Orientation::Vertical, 220, 300, &result));
         assert!(is_colour_in_range(230, 230, 300, 300, &result, PURPLE));
     }
 
@@ -529,15 +698,15 @@ mod tests {
         let top_right = create_with_colour(100, 100, GREEN);
         let bottom = create_with_colour(300, 200, PURPLE);
 
-        let result = mosaic(vec![top_left, top_mid, top_right, bottom]);
+        let result = mosaic(vec![top_left, top_mid, top_right, bottom], &MosaicOptions::default()).image;
 
         save_result(&result, "4-two_rows_three_one");
         assert!(is_colour_in_range(0, 0, 100, 100, &result, RED));
-        assert!(has_black_vertical_line_partial(105, 0, 100, &result));
+        assert!(has_gutter_between(100, 10, BLACK, Orientation::Vertical, 0, 100, &result));
         assert!(is_colour_in_range(120, 0, 200, 100, &result, BLUE));
-        assert!(has_black_vertical_line_partial(215, 0, 100, &result));
+        assert!(has_gutter_between(210, 10, BLACK, Orientation::Vertical, 0, 100, &result));
         assert!(is_colour_in_range(230, 0, 300, 100, &result, GREEN));
-        assert!(has_black_horizontal_line(105, &result));
+        assert!(has_gutter(100, 10, BLACK, Orientation::Horizontal, &result));
         assert!(is_colour_in_range(0, 120, 300, 300, &result, PURPLE));
     }
 
@@ -548,15 +717,15 @@ mod tests {
         let right_mid = create_with_colour(100, 100, GREEN);
         let right_bot = create_with_colour(100, 100, PURPLE);
 
-        let result = mosaic(vec![left, right_top, right_mid, right_bot]);
+        let result = mosaic(vec![left, right_top, right_mid, right_bot], &MosaicOptions::default()).image;
 
         save_result(&result, "4-two_columns_one_three");
         assert!(is_colour_in_range(0, 0, 200, 300, &result, RED));
-        assert!(has_black_vertical_line(105, &result));
+        assert!(has_gutter(213, 10, BLACK, Orientation::Vertical, &result));
         assert!(is_colour_in_range(230, 0, 300, 100, &result, BLUE));
-        assert!(has_black_horizontal_line_partial(105, 220, 300, &result));
+        assert!(has_gutter_between(100, 10, BLACK, Orientation::Horizontal, 220, 300, &result));
         assert!(is_colour_in_range(230, 120, 300, 200, &result, GREEN));
-        assert!(has_black_horizontal_line_partial(215, 220, 300, &result));
+        assert!(has_gutter_between(210, 10, BLACK, Orientation::Horizontal, 220, 300, &result));
         assert!(is_colour_in_range(230, 230, 300, 300, &result, PURPLE));
     }
 
@@ -567,15 +736,15 @@ mod tests {
         let left_bot = create_with_colour(100, 100, GREEN);
         let right = create_with_colour(200, 300, PURPLE);
 
-        let result = mosaic(vec![left_top, left_mid, left_bot, right]);
+        let result = mosaic(vec![left_top, left_mid, left_bot, right], &MosaicOptions::default()).image;
 
         save_result(&result, "4-two_columns_three_one");
         assert!(is_colour_in_range(0, 0, 100, 100, &result, RED));
-        assert!(has_black_horizontal_line_partial(105, 0, 200, &result));
+        assert!(has_gutter_between(100, 10, BLACK, Orientation::Horizontal, 0, 100, &result));
         assert!(is_colour_in_range(0, 120, 100, 200, &result, BLUE));
-        assert!(has_black_horizontal_line_partial(215, 0, 200, &result));
+        assert!(has_gutter_between(210, 10, BLACK, Orientation::Horizontal, 0, 100, &result));
         assert!(is_colour_in_range(0, 230, 100, 300, &result, GREEN));
-        assert!(has_black_vertical_line(105, &result));
+        assert!(has_gutter(100, 10, BLACK, Orientation::Vertical, &result));
         assert!(is_colour_in_range(220, 230, 300, 300, &result, PURPLE));
     }
 
@@ -586,15 +755,15 @@ mod tests {
         let mid = create_with_colour(600, 200, GREEN);
         let bot = create_with_colour(600, 200, PURPLE);
 
-        let result = mosaic(vec![top_left, top_right, mid, bot]);
+        let result = mosaic(vec![top_left, top_right, mid, bot], &MosaicOptions::default()).image;
 
         save_result(&result, "4-three_rows_211");
         assert!(is_colour_in_range(0, 0, 300, 200, &result, RED));
-        assert!(has_black_vertical_line_partial(305, 0, 200, &result));
+        assert!(has_gutter_between(300, 10, BLACK, Orientation::Vertical, 0, 200, &result));
         assert!(is_colour_in_range(320, 0, 600, 200, &result, BLUE));
-        assert!(has_black_horizontal_line(205, &result));
+        assert!(has_gutter(200, 10, BLACK, Orientation::Horizontal, &result));
         assert!(is_colour_in_range(0, 220, 600, 400, &result, GREEN));
-        assert!(has_black_horizontal_line(415, &result));
+        assert!(has_gutter(413, 10, BLACK, Orientation::Horizontal, &result));
         assert!(is_colour_in_range(0, 430, 600, 600, &result, PURPLE));
     }
 
@@ -605,15 +774,15 @@ mod tests {
         let mid_right = create_with_colour(300, 200, GREEN);
         let bot = create_with_colour(600, 200, PURPLE);
 
-        let result = mosaic(vec![top, mid_left, mid_right, bot]);
+        let result = mosaic(vec![top, mid_left, mid_right, bot], &MosaicOptions::default()).image;
 
         save_result(&result, "4-three_rows_121");
         assert!(is_colour_in_range(0, 0, 600, 200, &result, RED));
-        assert!(has_black_horizontal_line(205, &result));
+        assert!(has_gutter(203, 10, BLACK, Orientation::Horizontal, &result));
         assert!(is_colour_in_range(0, 220, 300, 400, &result, BLUE));
-        assert!(has_black_vertical_line_partial(305, 210, 400, &result));
+        assert!(has_gutter_between(300, 10, BLACK, Orientation::Vertical, 210, 400, &result));
         assert!(is_colour_in_range(320, 220, 600, 400, &result, GREEN));
-        assert!(has_black_horizontal_line(415, &result));
+        assert!(has_gutter(413, 10, BLACK, Orientation::Horizontal, &result));
         assert!(is_colour_in_range(0, 430, 600, 600, &result, PURPLE));
     }
 
@@ -624,15 +793,32 @@ mod tests {
         let bot_left = create_with_colour(300, 200, GREEN);
         let bot_right = create_with_colour(300, 200, PURPLE);
 
-        let result = mosaic(vec![top, mid, bot_left, bot_right]);
+        let result = mosaic(vec![top, mid, bot_left, bot_right], &MosaicOptions::default()).image;
 
         save_result(&result, "4-three_rows_112");
         assert!(is_colour_in_range(0, 0, 600, 200, &result, RED));
-        assert!(has_black_horizontal_line(205, &result));
+        assert!(has_gutter(203, 10, BLACK, Orientation::Horizontal, &result));
         assert!(is_colour_in_range(0, 220, 600, 400, &result, BLUE));
-        assert!(has_black_horizontal_line(415, &result));
+        assert!(has_gutter(416, 10, BLACK, Orientation::Horizontal, &result));
         assert!(is_colour_in_range(0, 430, 300, 600, &result, GREEN));
-        assert!(has_black_vertical_line_partial(305, 420, 600, &result));
+        assert!(has_gutter_between(300, 10, BLACK, Orientation::Vertical, 420, 600, &result));
         assert!(is_colour_in_range(320, 430, 600, 600, &result, PURPLE));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn mosaic_4_featured() {
+        let images = || [RED, BLUE, GREEN, PURPLE].map(|colour| create_with_colour(100, 100, colour)).to_vec();
+        let options = MosaicOptions {
+            layout: LayoutMode::Featured,
+            ..MosaicOptions::default()
+        };
+
+        let result = mosaic(images(), &options);
+
+        save_result(&result.image, "4-featured");
+        assert_eq!(mosaic(images(), &MosaicOptions::default()).layout, Layout::TwoRowsOfTwo);
+        assert!(matches!(result.layout, Layout::TwoRowsOneThree | Layout::TwoColumnsOneThree));
+        let area = |index: usize| result.regions[index].dimensions.width * result.regions[index].dimensions.height;
+        assert!((1..4).all(|index| area(0) > area(index) * 4));
+    }
+}
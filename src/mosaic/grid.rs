@@ -0,0 +1,232 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2022 Antonio32A (antonio32a.com) <~@antonio32a.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use std::cmp::max;
+
+use crate::mosaic::{
+    ImageOffset,
+    Layout,
+    MosaicPlan,
+    Size,
+    Spacing,
+};
+
+/// Lays the images out in rows and columns of identically sized cells. Every image is center-cropped to
+/// the cell's aspect ratio when the mosaic is built, so nothing is letterboxed or stretched.
+pub fn plan_grid_mosaic(sizes: &[Size], spacing: Spacing, max_size: u32) -> MosaicPlan {
+    let (columns, rows) = grid_shape(sizes);
+    let cell = grid_cell_size(sizes, columns, rows, spacing, max_size);
+
+    let images = sizes
+        .iter()
+        .enumerate()
+        .map(|(index, size)| {
+            let column = index as u32 % columns;
+            let row = index as u32 / columns;
+            ImageOffset {
+                offset: Size {
+                    width: column * (cell.width + spacing.horizontal),
+                    height: row * (cell.height + spacing.vertical),
+                },
+                dimensions: cell,
+                original_dimensions: *size,
+            }
+        })
+        .collect();
+
+    MosaicPlan {
+        layout: Layout::Grid,
+        total_size: Size {
+            width: columns * cell.width + (columns - 1) * spacing.horizontal,
+            height: rows * cell.height + (rows - 1) * spacing.vertical,
+        },
+        images,
+    }
+}
+
+/// Returns the number of columns and rows for `sizes`. Two images sit side by side and four make a 2x2
+/// square, while three are stacked when they are mostly landscape and put in a row otherwise.
+fn grid_shape(sizes: &[Size]) -> (u32, u32) {
+    match sizes.len() {
+        2 => (2, 1),
+        3 if average_aspect_ratio(sizes) > 1.0 => (1, 3),
+        3 => (3, 1),
+        4 => (2, 2),
+        _ => panic!("impossible image length"),
+    }
+}
+
+fn average_aspect_ratio(sizes: &[Size]) -> f32 {
+    sizes
+        .iter()
+        .map(|size| size.width as f32 / size.height as f32)
+        .sum::<f32>()
+        / sizes.len() as f32
+}
+
+/// Picks the biggest cell, at the average aspect ratio of the inputs, that every image can cover without
+/// being upscaled, shrunk further if the whole grid wouldn't fit in `max_size`.
+fn grid_cell_size(sizes: &[Size], columns: u32, rows: u32, spacing: Spacing, max_size: u32) -> Size {
+    let aspect_ratio = average_aspect_ratio(sizes);
+    let width = sizes
+        .iter()
+        .map(|size| (size.width as f32).min(size.height as f32 * aspect_ratio))
+        .fold(f32::MAX, f32::min);
+
+    let max_width = (max_size - (columns - 1) * spacing.horizontal) as f32 / columns as f32;
+    let max_height = (max_size - (rows - 1) * spacing.vertical) as f32 / rows as f32;
+    let width = width.min(max_width).min(max_height * aspect_ratio);
+
+    Size {
+        width: max(width.floor() as u32, 1),
+        height: max((width / aspect_ratio).floor() as u32, 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbImage;
+
+    use crate::mosaic::{self, mosaic};
+    use crate::mosaic::{Layout, MosaicOptions, MosaicStyle, plan_mosaic, Size, SPACING_SIZE};
+    use crate::mosaic::testutils::{
+        BLUE,
+        create_with_colour,
+        GREEN,
+        is_colour_in_range,
+        PURPLE,
+        RED,
+        save_result,
+    };
+
+    fn grid_options() -> MosaicOptions {
+        MosaicOptions {
+            style: MosaicStyle::Grid,
+            ..MosaicOptions::default()
+        }
+    }
+
+    fn size(width: u32, height: u32) -> Size {
+        Size { width, height }
+    }
+
+    #[test]
+    fn grid_4_has_equal_cells() {
+        let sizes = [size(400, 300), size(1000, 500), size(300, 300), size(600, 900)];
+
+        let plan = plan_mosaic(&sizes, &grid_options());
+
+        assert_eq!(plan.layout, Layout::Grid);
+        let cell = plan.images[0].dimensions;
+        for image in &plan.images {
+            assert_eq!((image.dimensions.width, image.dimensions.height), (cell.width, cell.height));
+        }
+        let offsets: Vec<(u32, u32)> = plan.images.iter().map(|image| (image.offset.width, image.offset.height)).collect();
+        assert_eq!(offsets, vec![
+            (0, 0),
+            (cell.width + SPACING_SIZE, 0),
+            (0, cell.height + SPACING_SIZE),
+            (cell.width + SPACING_SIZE, cell.height + SPACING_SIZE),
+        ]);
+        assert_eq!(plan.total_size.width, 2 * cell.width + SPACING_SIZE);
+        assert_eq!(plan.total_size.height, 2 * cell.height + SPACING_SIZE);
+    }
+
+    #[test]
+    fn grid_cells_never_upscale() {
+        let sizes = [size(400, 300), size(1000, 500), size(300, 300), size(600, 900)];
+
+        let plan = plan_mosaic(&sizes, &grid_options());
+
+        for image in &plan.images {
+            assert!(image.dimensions.width <= image.original_dimensions.width);
+            assert!(image.dimensions.height <= image.original_dimensions.height);
+        }
+    }
+
+    #[test]
+    fn grid_fits_in_max_size() {
+        let sizes = [size(4000, 4000), size(4000, 4000), size(4000, 4000), size(4000, 4000)];
+
+        let plan = plan_mosaic(&sizes, &grid_options());
+
+        assert!(plan.total_size.width <= mosaic::MAX_SIZE);
+        assert!(plan.total_size.height <= mosaic::MAX_SIZE);
+    }
+
+    #[test]
+    fn grid_2_is_side_by_side() {
+        let plan = plan_mosaic(&[size(800, 200), size(800, 200)], &grid_options());
+
+        assert_eq!(plan.images[1].offset.height, 0);
+        assert!(plan.images[1].offset.width > 0);
+    }
+
+    #[test]
+    fn grid_3_follows_orientation() {
+        let landscape = plan_mosaic(&[size(800, 200), size(800, 200), size(800, 200)], &grid_options());
+        let portrait = plan_mosaic(&[size(200, 800), size(200, 800), size(200, 800)], &grid_options());
+
+        assert_eq!(landscape.images[2].offset.width, 0);
+        assert!(landscape.images[2].offset.height > 0);
+        assert_eq!(portrait.images[2].offset.height, 0);
+        assert!(portrait.images[2].offset.width > 0);
+    }
+
+    /// A `width`x`height` image of `colour` with a red stripe of `edge` pixels down its left and right sides.
+    fn with_red_sides(width: u32, height: u32, edge: u32, colour: image::Rgb<u8>) -> RgbImage {
+        let mut image = create_with_colour(width, height, colour);
+        for y in 0..height {
+            for x in (0..edge).chain(width - edge..width) {
+                image.put_pixel(x, y, RED);
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn grid_center_crops_wide_images() {
+        // The 5:4 cells cut 60px off either side of the scaled-down wide image, taking its red sides with them.
+        let square = create_with_colour(200, 200, BLUE);
+        let wide = with_red_sides(400, 200, 50, GREEN);
+        let other = create_with_colour(200, 200, PURPLE);
+        let last = create_with_colour(200, 200, BLUE);
+
+        let options = grid_options();
+        let result = mosaic(vec![square, wide, other, last], &options).image;
+
+        save_result(&result, "grid-4-center_crop");
+        let plan = plan_mosaic(&[size(200, 200), size(400, 200), size(200, 200), size(200, 200)], &options);
+        let cell = plan.images[1];
+        assert_eq!((cell.dimensions.width, cell.dimensions.height), (200, 160));
+        assert!(is_colour_in_range(
+            cell.offset.width,
+            cell.offset.height,
+            cell.offset.width + cell.dimensions.width,
+            cell.offset.height + cell.dimensions.height,
+            &result,
+            GREEN,
+        ));
+    }
+}
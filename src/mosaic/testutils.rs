@@ -13,9 +13,13 @@ pub const BLUE: Rgb<u8> = Rgb([0, 0, 255]);
 #[cfg(test)]
 pub const GREEN: Rgb<u8> = Rgb([0, 255, 0]);
 #[cfg(test)]
+pub const WHITE: Rgb<u8> = Rgb([255, 255, 255]);
+#[cfg(test)]
 pub const PURPLE: Rgb<u8> = Rgb([255, 64, 255]);
 #[cfg(test)]
 const TEST_RESULT_DIR: &str = "./mosaic_tests/";
+#[cfg(test)]
+const GOLDEN_DIR: &str = "./tests/fixtures/golden/";
 
 #[cfg(test)]
 pub fn create_with_colour(width: u32, height: u32, colour: Rgb<u8>) -> RgbImage {
@@ -48,24 +52,36 @@ pub fn is_colour_in_range(start_x: u32, start_y: u32, end_x: u32, end_y: u32, im
     true
 }
 
-#[cfg(test)]
-pub fn has_black_vertical_line(x: u32, image: &RgbImage) -> bool {
-    is_colour_in_range(x, 0, x, image.height(), image, BLACK)
+/// Which way a gutter runs through an image.
+#[cfg(test)]
+#[derive(Copy, Clone, Debug)]
+pub enum Orientation {
+    /// A column of pixels from top to bottom, between images side by side.
+    Vertical,
+    /// A row of pixels from left to right, between images stacked on top of each other.
+    Horizontal,
 }
 
-#[cfg(test)]
-pub fn has_black_horizontal_line(y: u32, image: &RgbImage) -> bool {
-    is_colour_in_range(0, y, image.width(), y, image, BLACK)
-}
-
-#[cfg(test)]
-pub fn has_black_vertical_line_partial(x: u32, start_y: u32, end_y: u32, image: &RgbImage) -> bool {
-    is_colour_in_range(x, start_y, x, end_y, image, BLACK)
+/// Whether `image` has a `width` pixel gutter of `colour` starting at column or row `position`, running
+/// all the way through it.
+#[cfg(test)]
+pub fn has_gutter(position: u32, width: u32, colour: Rgb<u8>, orientation: Orientation, image: &RgbImage) -> bool {
+    let length = match orientation {
+        Orientation::Vertical => image.height(),
+        Orientation::Horizontal => image.width(),
+    };
+    has_gutter_between(position, width, colour, orientation, 0, length, image)
 }
 
-#[cfg(test)]
-pub fn has_black_horizontal_line_partial(y: u32, start_x: u32, end_x: u32, image: &RgbImage) -> bool {
-    is_colour_in_range(start_x, y, end_x, y, image, BLACK)
+/// Like `has_gutter`, but only checks the gutter from `start` to `end` along its length.
+#[cfg(test)]
+pub fn has_gutter_between(position: u32, width: u32, colour: Rgb<u8>, orientation: Orientation, start: u32, end: u32, image: &RgbImage) -> bool {
+    // An empty range is trivially the right colour, which would make the check pass whatever was drawn.
+    assert!(width > 0 && start < end, "empty gutter");
+    match orientation {
+        Orientation::Vertical => is_colour_in_range(position, start, position + width, end, image, colour),
+        Orientation::Horizontal => is_colour_in_range(start, position, end, position + width, image, colour),
+    }
 }
 
 #[cfg(test)]
@@ -74,3 +90,74 @@ pub fn save_result(result: &RgbImage, filename: &str) {
     fs::create_dir_all(TEST_RESULT_DIR).unwrap();
     result.save(file_path).unwrap();
 }
+
+/// How far apart two images of the same size are.
+#[cfg(test)]
+#[derive(Debug, PartialEq)]
+pub struct ImageDiff {
+    /// The biggest difference in any channel of any pixel.
+    pub max: u8,
+    /// The mean difference across every channel of every pixel.
+    pub mean: f32,
+    /// The smallest rectangle, as `(x, y, width, height)`, holding every pixel that is more than the
+    /// tolerance off. `None` when they all match.
+    pub bounds: Option<(u32, u32, u32, u32)>,
+}
+
+/// Compares two images of the same size channel by channel, counting pixels more than `tolerance` off as
+/// different.
+#[cfg(test)]
+pub fn diff_images(actual: &RgbImage, expected: &RgbImage, tolerance: u8) -> ImageDiff {
+    assert_eq!(actual.dimensions(), expected.dimensions(), "images are different sizes");
+
+    let mut max = 0;
+    let mut total = 0u64;
+    let mut bounds: Option<(u32, u32, u32, u32)> = None;
+    for (x, y, pixel) in actual.enumerate_pixels() {
+        let expected = expected.get_pixel(x, y);
+        let difference = pixel.0.iter().zip(expected.0).map(|(a, b)| a.abs_diff(b)).max().unwrap();
+        total += pixel.0.iter().zip(expected.0).map(|(a, b)| a.abs_diff(b) as u64).sum::<u64>();
+        max = max.max(difference);
+        if difference > tolerance {
+            bounds = Some(match bounds {
+                Some((left, top, right, bottom)) => (left.min(x), top.min(y), right.max(x), bottom.max(y)),
+                None => (x, y, x, y),
+            });
+        }
+    }
+
+    ImageDiff {
+        max,
+        mean: total as f32 / (actual.width() * actual.height() * 3) as f32,
+        bounds: bounds.map(|(left, top, right, bottom)| (left, top, right - left + 1, bottom - top + 1)),
+    }
+}
+
+/// Checks `result` against the reference image `name` in `GOLDEN_DIR`, allowing each channel to be up to
+/// `tolerance` off so small changes to resampling don't fail it. On a mismatch `result` is saved alongside
+/// the other test results for comparing by eye. Run the tests with `UPDATE_GOLDENS=1` to write `result` as
+/// the new reference instead, after checking the change is intended.
+#[cfg(test)]
+pub fn assert_matches_golden(result: &RgbImage, name: &str, tolerance: u8) {
+    let file_path = [GOLDEN_DIR, name, ".png"].join("");
+    if std::env::var_os("UPDATE_GOLDENS").is_some() {
+        fs::create_dir_all(GOLDEN_DIR).unwrap();
+        result.save(file_path).unwrap();
+        return;
+    }
+
+    let golden = match image::open(&file_path) {
+        Ok(golden) => golden.into_rgb8(),
+        Err(err) => panic!("could not open {} ({}), run with UPDATE_GOLDENS=1 to create it", file_path, err),
+    };
+    if golden.dimensions() != result.dimensions() {
+        save_result(result, name);
+        panic!("{} is {:?}, but the result is {:?}", file_path, golden.dimensions(), result.dimensions());
+    }
+
+    let diff = diff_images(result, &golden, tolerance);
+    if diff.bounds.is_some() {
+        save_result(result, name);
+        panic!("result doesn't match {}: {:?}", file_path, diff);
+    }
+}
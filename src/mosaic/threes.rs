@@ -1,47 +1,68 @@
-use image::RgbImage;
-
 use crate::mosaic::{
     best_mosaic,
-    build_mosaic,
     ImageOffset,
+    Layout,
+    LayoutMode,
+    MosaicDims,
     MosaicImageDims,
+    MosaicOptions,
+    MIN_TOP_SPLIT,
     scale_height_dimension,
     scale_width_dimension,
     Size,
-    SPACING_SIZE,
+    Spacing,
+    within_limits,
 };
 
-pub fn build_3_mosaic(first: RgbImage, second: RgbImage, third: RgbImage) -> RgbImage {
-    let first_size = Size {
-        width: first.width(),
-        height: first.height(),
-    };
-    let second_size = Size {
-        width: second.width(),
-        height: second.height(),
-    };
-    let third_size = Size {
-        width: third.width(),
-        height: third.height(),
-    };
-    let best_mosaic = best_3_mosaic(first_size, second_size, third_size);
-    build_mosaic(best_mosaic, [first, second, third])
+pub fn plan_3_mosaic(first: Size, second: Size, third: Size, options: &MosaicOptions) -> MosaicImageDims<3> {
+    // Diagonal edges stretch the images past their columns, so it's skipped when nothing may be upscaled.
+    if options.layout == LayoutMode::Diagonal && !options.no_upscale {
+        // Diagonal mosaics lean the edges of a row of columns, see `DiagonalMosaicDims`.
+        let mut row = three_columns_3_mosaic(first, second, third, options.spacing.clamped()).scale_to_fit(options.native_image(), options.max_size());
+        row.layout = Layout::Diagonal;
+        return row;
+    }
+
+    if options.layout == LayoutMode::Featured {
+        return featured_3_mosaic(first, second, third, options);
+    }
+
+    best_3_mosaic(first, second, third, options)
+}
+
+/// The first image on its own above or beside the other two.
+fn featured_3_mosaic(first: Size, second: Size, third: Size, options: &MosaicOptions) -> MosaicImageDims<3> {
+    let spacing = options.spacing.clamped();
+    let top_bottom_bottom = top_bottom_bottom_3_mosaic(first, second, third, spacing);
+    let left_right_right = left_right_right_3_mosaic(first, second, third, spacing);
+    best_mosaic(
+        &within_limits(&[&top_bottom_bottom, &left_right_right], options),
+        &options.featured_weights(),
+        options.native_image(),
+        options.max_size(),
+        options.scale_tolerance,
+        options.rank,
+    ).unwrap_or_else(|| top_bottom_bottom.scale_to_fit(options.native_image(), options.max_size()))
 }
 
-fn best_3_mosaic(first: Size, second: Size, third: Size) -> MosaicImageDims<3> {
-    let three_columns = three_columns_3_mosaic(first, second, third);
-    let top_top_bottom = top_top_bottom_3_mosaic(first, second, third);
-    let left_right_right = left_right_right_3_mosaic(first, second, third);
-    let left_left_right = left_left_right_3_mosaic(first, second, third);
-    let top_bottom_bottom = top_bottom_bottom_3_mosaic(first, second, third);
-    let three_rows = three_rows_3_mosaic(first, second, third);
-    return best_mosaic(&[&three_columns, &top_top_bottom, &left_left_right, &left_right_right, &top_bottom_bottom, &three_rows]);
+fn best_3_mosaic(first: Size, second: Size, third: Size, options: &MosaicOptions) -> MosaicImageDims<3> {
+    let spacing = options.spacing.clamped();
+    let three_columns = three_columns_3_mosaic(first, second, third, spacing);
+    let top_top_bottom = top_top_bottom_3_mosaic(first, second, third, spacing, options.top_split);
+    let left_right_right = left_right_right_3_mosaic(first, second, third, spacing);
+    let left_left_right = left_left_right_3_mosaic(first, second, third, spacing);
+    let top_bottom_bottom = top_bottom_bottom_3_mosaic(first, second, third, spacing);
+    let three_rows = three_rows_3_mosaic(first, second, third, spacing);
+    best_mosaic(&within_limits(
+        &[&three_columns, &top_top_bottom, &left_left_right, &left_right_right, &top_bottom_bottom, &three_rows],
+        options,
+    ), &options.weights, options.native_image(), options.max_size(), options.scale_tolerance, options.rank).unwrap_or_else(|| three_columns.scale_to_fit(options.native_image(), options.max_size()))
 }
 
-pub fn three_columns_3_mosaic(first: Size, second: Size, third: Size) -> MosaicImageDims<3> {
+pub fn three_columns_3_mosaic(first: Size, second: Size, third: Size, spacing: Spacing) -> MosaicImageDims<3> {
     let image2_offset = ImageOffset {
         offset: Size {
-            width: first.width + SPACING_SIZE,
+            width: first.width.saturating_add(spacing.horizontal),
             height: 0,
         },
         dimensions: scale_height_dimension(second, first.height),
@@ -61,23 +82,47 @@ pub fn three_columns_3_mosaic(first: Size, second: Size, third: Size) -> MosaicI
             image2_offset,
             ImageOffset {
                 offset: Size {
-                    width: image2_offset.total_width() + SPACING_SIZE,
+                    width: image2_offset.total_width().saturating_add(spacing.horizontal),
                     height: 0,
                 },
                 dimensions: scale_height_dimension(third, first.height),
                 original_dimensions: third,
             },
         ],
+        layout: Layout::ThreeColumns,
+        cropped: false,
     }
 }
 
-fn top_top_bottom_3_mosaic(first: Size, second: Size, third: Size) -> MosaicImageDims<3> {
+fn top_top_bottom_3_mosaic(first: Size, second: Size, third: Size, spacing: Spacing, top_split: Option<f32>) -> MosaicImageDims<3> {
+    let second_dims = scale_height_dimension(second, first.height);
+    // A fixed split keeps the row as wide and tall as it would be anyway, and only moves where it divides.
+    let images_width = first.width.saturating_add(second_dims.width);
+    // A row too narrow to give each image a pixel keeps its natural split.
+    let top_split = top_split.filter(|_| images_width >= 2);
+    let (first_dims, second_dims) = match top_split {
+        Some(split) => {
+            let split = split.clamp(MIN_TOP_SPLIT, 1.0 - MIN_TOP_SPLIT);
+            let first_width = ((images_width as f32 * split).round() as u32).clamp(1, images_width - 1);
+            (
+                Size {
+                    width: first_width,
+                    height: first.height,
+                },
+                Size {
+                    width: images_width - first_width,
+                    height: first.height,
+                },
+            )
+        }
+        None => (first, second_dims),
+    };
     let image2_offset = ImageOffset {
         offset: Size {
-            width: first.width + SPACING_SIZE,
+            width: first_dims.width.saturating_add(spacing.horizontal),
             height: 0,
         },
-        dimensions: scale_height_dimension(second, first.height),
+        dimensions: second_dims,
         original_dimensions: second,
     };
 
@@ -88,27 +133,29 @@ fn top_top_bottom_3_mosaic(first: Size, second: Size, third: Size) -> MosaicImag
                     width: 0,
                     height: 0,
                 },
-                dimensions: first,
+                dimensions: first_dims,
                 original_dimensions: first,
             },
             image2_offset,
             ImageOffset {
                 offset: Size {
                     width: 0,
-                    height: first.height + SPACING_SIZE,
+                    height: first.height.saturating_add(spacing.vertical),
                 },
                 dimensions: scale_width_dimension(third, image2_offset.total_width()),
                 original_dimensions: third,
             },
         ],
+        layout: Layout::TopTopBottom,
+        cropped: top_split.is_some(),
     }
 }
 
-fn left_left_right_3_mosaic(first: Size, second: Size, third: Size) -> MosaicImageDims<3> {
+fn left_left_right_3_mosaic(first: Size, second: Size, third: Size, spacing: Spacing) -> MosaicImageDims<3> {
     let image2_offset = ImageOffset {
         offset: Size {
             width: 0,
-            height: first.height + SPACING_SIZE,
+            height: first.height.saturating_add(spacing.vertical),
         },
         dimensions: scale_width_dimension(second, first.width),
         original_dimensions: second,
@@ -127,22 +174,24 @@ fn left_left_right_3_mosaic(first: Size, second: Size, third: Size) -> MosaicIma
             image2_offset,
             ImageOffset {
                 offset: Size {
-                    width: first.width + SPACING_SIZE,
+                    width: first.width.saturating_add(spacing.horizontal),
                     height: 0,
                 },
                 dimensions: scale_height_dimension(third, image2_offset.total_height()),
                 original_dimensions: third,
             },
         ],
+        layout: Layout::LeftLeftRight,
+        cropped: false,
     }
 }
 
-fn left_right_right_3_mosaic(first: Size, second: Size, third: Size) -> MosaicImageDims<3> {
+fn left_right_right_3_mosaic(first: Size, second: Size, third: Size, spacing: Spacing) -> MosaicImageDims<3> {
     let image3_dims = scale_width_dimension(third, second.width);
-    let image1_dims = scale_height_dimension(first, second.height + image3_dims.height + SPACING_SIZE);
+    let image1_dims = scale_height_dimension(first, second.height.saturating_add(image3_dims.height).saturating_add(spacing.vertical));
     let image2_offset = ImageOffset {
         offset: Size {
-            width: image1_dims.width + SPACING_SIZE,
+            width: image1_dims.width.saturating_add(spacing.horizontal),
             height: 0,
         },
         dimensions: second,
@@ -151,8 +200,8 @@ fn left_right_right_3_mosaic(first: Size, second: Size, third: Size) -> MosaicIm
 
     let image3_offset = ImageOffset {
         offset: Size {
-            width: image1_dims.width + SPACING_SIZE,
-            height: image2_offset.total_height() + SPACING_SIZE,
+            width: image1_dims.width.saturating_add(spacing.horizontal),
+            height: image2_offset.total_height().saturating_add(spacing.vertical),
         },
         dimensions: scale_width_dimension(third, second.width),
         original_dimensions: third,
@@ -171,12 +220,14 @@ fn left_right_right_3_mosaic(first: Size, second: Size, third: Size) -> MosaicIm
             image2_offset,
             image3_offset,
         ],
+        layout: Layout::LeftRightRight,
+        cropped: false,
     }
 }
 
-fn top_bottom_bottom_3_mosaic(first: Size, second: Size, third: Size) -> MosaicImageDims<3> {
+fn top_bottom_bottom_3_mosaic(first: Size, second: Size, third: Size, spacing: Spacing) -> MosaicImageDims<3> {
     let image3_dims = scale_height_dimension(third, second.height);
-    let image1_dims = scale_width_dimension(first, second.width + image3_dims.width + SPACING_SIZE);
+    let image1_dims = scale_width_dimension(first, second.width.saturating_add(image3_dims.width).saturating_add(spacing.horizontal));
 
     MosaicImageDims {
         images: [
@@ -191,28 +242,30 @@ fn top_bottom_bottom_3_mosaic(first: Size, second: Size, third: Size) -> MosaicI
             ImageOffset {
                 offset: Size {
                     width: 0,
-                    height: image1_dims.height + SPACING_SIZE,
+                    height: image1_dims.height.saturating_add(spacing.vertical),
                 },
                 dimensions: second,
                 original_dimensions: second,
             },
             ImageOffset {
                 offset: Size {
-                    width: second.width + SPACING_SIZE,
-                    height: image1_dims.height + SPACING_SIZE,
+                    width: second.width.saturating_add(spacing.horizontal),
+                    height: image1_dims.height.saturating_add(spacing.vertical),
                 },
                 dimensions: image3_dims,
                 original_dimensions: third,
             },
         ],
+        layout: Layout::TopBottomBottom,
+        cropped: false,
     }
 }
 
-pub fn three_rows_3_mosaic(first: Size, second: Size, third: Size) -> MosaicImageDims<3> {
+pub fn three_rows_3_mosaic(first: Size, second: Size, third: Size, spacing: Spacing) -> MosaicImageDims<3> {
     let image2_offset = ImageOffset {
         offset: Size {
             width: 0,
-            height: first.height + SPACING_SIZE,
+            height: first.height.saturating_add(spacing.vertical),
         },
         dimensions: scale_width_dimension(second, first.width),
         original_dimensions: second,
@@ -232,61 +285,143 @@ pub fn three_rows_3_mosaic(first: Size, second: Size, third: Size) -> MosaicImag
             ImageOffset {
                 offset: Size {
                     width: 0,
-                    height: image2_offset.total_height() + SPACING_SIZE,
+                    height: image2_offset.total_height().saturating_add(spacing.vertical),
                 },
                 dimensions: scale_width_dimension(third, first.width),
                 original_dimensions: third,
             },
         ],
+        layout: Layout::ThreeRows,
+        cropped: false,
     }
 }
 
 
 #[cfg(test)]
 mod tests {
-    use crate::mosaic;
+    use crate::mosaic::{mosaic, plan_mosaic};
+    use crate::mosaic::{Direction, Layout, LayoutMode, MosaicDims, MosaicOptions, Size, Spacing};
+    use crate::mosaic::threes::top_top_bottom_3_mosaic;
     use crate::mosaic::testutils::{
+        BLACK,
         BLUE,
         create_with_colour,
         GREEN,
-        has_black_horizontal_line,
-        has_black_horizontal_line_partial,
-        has_black_vertical_line,
-        has_black_vertical_line_partial,
+        has_gutter,
+        has_gutter_between,
+        is_colour_at_pixel,
         is_colour_in_range,
+        Orientation,
         RED,
         save_result,
     };
 
+    #[test]
+    fn mosaic_3_top_split_evens_out_the_top_row() {
+        let images = || {
+            vec![
+                create_with_colour(200, 400, RED),
+                create_with_colour(300, 400, BLUE),
+                create_with_colour(500, 200, GREEN),
+            ]
+        };
+        let options = MosaicOptions {
+            top_split: Some(0.5),
+            ..MosaicOptions::default()
+        };
+
+        let natural = mosaic(images(), &MosaicOptions::default());
+        let split = mosaic(images(), &options);
+
+        save_result(&split.image, "3-top_split");
+        assert_eq!(natural.layout, Layout::TopTopBottom);
+        assert_eq!(split.layout, Layout::TopTopBottom);
+        assert_eq!(split.image.dimensions(), natural.image.dimensions());
+        let [left, right, bottom] = [split.regions[0], split.regions[1], split.regions[2]];
+        assert!(natural.regions[0].dimensions.width < natural.regions[1].dimensions.width);
+        assert!(left.dimensions.width.abs_diff(right.dimensions.width) <= 1);
+        assert_eq!(right.total_width(), bottom.total_width());
+        // Each image is cropped to fill its half rather than leaving a gap.
+        assert!(is_colour_in_range(0, 0, left.total_width(), left.total_height(), &split.image, RED));
+        assert!(is_colour_in_range(right.offset.width, 0, right.total_width(), right.total_height(), &split.image, BLUE));
+    }
+
+    #[test]
+    fn mosaic_3_top_split_survives_a_sliver_of_a_row() {
+        let size = |width, height| Size { width, height };
+        let options = MosaicOptions {
+            top_split: Some(0.5),
+            ..MosaicOptions::default()
+        };
+
+        // The second image rounds to no width at all next to the first one's height.
+        let plan = top_top_bottom_3_mosaic(size(1, 1), size(1, 1000), size(40, 30), Spacing::default(), Some(0.5));
+        let planned = plan_mosaic(&[size(1, 1), size(1, 1000), size(40, 30)], &options);
+
+        assert_eq!(plan.images[0].dimensions.width, 1);
+        assert_eq!(planned.images.len(), 3);
+    }
+
+    #[test]
+    fn mosaic_3_scaling_keeps_shared_edges() {
+        let size = |width, height| Size { width, height };
+        let plan = top_top_bottom_3_mosaic(size(333, 217), size(251, 199), size(601, 307), Spacing::default(), None);
+
+        for step in 1..40 {
+            let scaled = plan.scale(1.0 + step as f32 * 0.137);
+
+            // The bottom image spans the top row, so its right edge must stay lined up with the top right
+            // image's, and the top images must stay the same height.
+            assert_eq!(scaled.images[1].total_width(), scaled.images[2].total_width());
+            assert_eq!(scaled.images[0].total_height(), scaled.images[1].total_height());
+        }
+    }
+
     #[test]
     fn mosaic_3_three_cols() {
         let left = create_with_colour(100, 400, RED);
         let mid = create_with_colour(200, 400, BLUE);
         let right = create_with_colour(100, 400, GREEN);
 
-        let result = mosaic(vec![left, mid, right]);
+        let result = mosaic(vec![left, mid, right], &MosaicOptions::default()).image;
 
         save_result(&result, "3-three_cols");
         assert!(is_colour_in_range(0, 0, 100, 400, &result, RED));
-        assert!(has_black_vertical_line(105, &result));
+        assert!(has_gutter(100, 10, BLACK, Orientation::Vertical, &result));
         assert!(is_colour_in_range(120, 0, 300, 400, &result, BLUE));
-        assert!(has_black_vertical_line(315, &result));
+        assert!(has_gutter(310, 10, BLACK, Orientation::Vertical, &result));
         assert!(is_colour_in_range(330, 0, 400, 400, &result, GREEN));
     }
 
+    #[test]
+    fn mosaic_3_three_cols_fills_rounded_heights() {
+        // 199x399 scales to 199x400, so only the height changes.
+        let left = create_with_colour(100, 400, RED);
+        let mid = create_with_colour(199, 399, BLUE);
+        let right = create_with_colour(100, 400, GREEN);
+
+        let result = mosaic(vec![left, mid, right], &MosaicOptions::default()).image;
+
+        save_result(&result, "3-three_cols_rounded_heights");
+        assert_eq!(result.height(), 400);
+        assert!(is_colour_in_range(0, 0, 100, 400, &result, RED));
+        assert!(is_colour_in_range(110, 0, 309, 400, &result, BLUE));
+        assert!(is_colour_in_range(319, 0, 419, 400, &result, GREEN));
+    }
+
     #[test]
     fn mosaic_3_top_top_bottom() {
         let top_left = create_with_colour(200, 300, RED);
         let top_right = create_with_colour(200, 300, BLUE);
         let bottom = create_with_colour(400, 100, GREEN);
 
-        let result = mosaic(vec![top_left, top_right, bottom]);
+        let result = mosaic(vec![top_left, top_right, bottom], &MosaicOptions::default()).image;
 
         save_result(&result, "3-top_top_bottom");
         assert!(is_colour_in_range(0, 0, 200, 300, &result, RED));
-        assert!(has_black_vertical_line_partial(205, 0, 300, &result));
+        assert!(has_gutter_between(200, 10, BLACK, Orientation::Vertical, 0, 300, &result));
         assert!(is_colour_in_range(220, 0, 400, 300, &result, BLUE));
-        assert!(has_black_horizontal_line(305, &result));
+        assert!(has_gutter(300, 10, BLACK, Orientation::Horizontal, &result));
         assert!(is_colour_in_range(0, 320, 400, 400, &result, GREEN));
     }
 
@@ -296,13 +431,13 @@ mod tests {
         let left_bot = create_with_colour(300, 200, BLUE);
         let right = create_with_colour(100, 400, GREEN);
 
-        let result = mosaic(vec![left_top, left_bot, right]);
+        let result = mosaic(vec![left_top, left_bot, right], &MosaicOptions::default()).image;
 
         save_result(&result, "3-left_left_right");
         assert!(is_colour_in_range(0, 0, 300, 200, &result, RED));
-        assert!(has_black_horizontal_line_partial(205, 0, 300, &result));
+        assert!(has_gutter_between(200, 10, BLACK, Orientation::Horizontal, 0, 300, &result));
         assert!(is_colour_in_range(0, 220, 300, 400, &result, BLUE));
-        assert!(has_black_vertical_line(305, &result));
+        assert!(has_gutter(300, 10, BLACK, Orientation::Vertical, &result));
         assert!(is_colour_in_range(320, 0, 400, 400, &result, GREEN));
     }
 
@@ -312,13 +447,13 @@ mod tests {
         let right_top = create_with_colour(300, 200, BLUE);
         let right_bot = create_with_colour(300, 200, GREEN);
 
-        let result = mosaic(vec![left, right_top, right_bot]);
+        let result = mosaic(vec![left, right_top, right_bot], &MosaicOptions::default()).image;
 
         save_result(&result, "3-left_right_right");
         assert!(is_colour_in_range(0, 0, 100, 400, &result, RED));
-        assert!(has_black_vertical_line(105, &result));
+        assert!(has_gutter(103, 10, BLACK, Orientation::Vertical, &result));
         assert!(is_colour_in_range(120, 0, 400, 200, &result, BLUE));
-        assert!(has_black_horizontal_line_partial(205, 120, 400, &result));
+        assert!(has_gutter_between(200, 10, BLACK, Orientation::Horizontal, 120, 400, &result));
         assert!(is_colour_in_range(120, 220, 400, 400, &result, GREEN));
     }
 
@@ -328,13 +463,13 @@ mod tests {
         let bot_left = create_with_colour(200, 300, BLUE);
         let bot_right = create_with_colour(200, 300, GREEN);
 
-        let result = mosaic(vec![top, bot_left, bot_right]);
+        let result = mosaic(vec![top, bot_left, bot_right], &MosaicOptions::default()).image;
 
         save_result(&result, "3-top_bottom_bottom");
         assert!(is_colour_in_range(0, 0, 400, 100, &result, RED));
-        assert!(has_black_horizontal_line(105, &result));
+        assert!(has_gutter(103, 10, BLACK, Orientation::Horizontal, &result));
         assert!(is_colour_in_range(0, 120, 200, 400, &result, BLUE));
-        assert!(has_black_vertical_line_partial(205, 120, 400, &result));
+        assert!(has_gutter_between(200, 10, BLACK, Orientation::Vertical, 120, 400, &result));
         assert!(is_colour_in_range(220, 120, 400, 400, &result, GREEN));
     }
 
@@ -344,13 +479,114 @@ mod tests {
         let row2 = create_with_colour(300, 100, BLUE);
         let row3 = create_with_colour(300, 100, GREEN);
 
-        let result = mosaic(vec![row1, row2, row3]);
+        let result = mosaic(vec![row1, row2, row3], &MosaicOptions::default()).image;
 
         save_result(&result, "3-three_rows");
         assert!(is_colour_in_range(0, 0, 300, 100, &result, RED));
-        assert!(has_black_horizontal_line(105, &result));
+        assert!(has_gutter(100, 10, BLACK, Orientation::Horizontal, &result));
         assert!(is_colour_in_range(0, 120, 300, 200, &result, BLUE));
-        assert!(has_black_horizontal_line(215, &result));
+        assert!(has_gutter(210, 10, BLACK, Orientation::Horizontal, &result));
         assert!(is_colour_in_range(0, 230, 300, 300, &result, GREEN));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn mosaic_3_weight_features_image() {
+        let images = || vec![
+            create_with_colour(200, 100, RED),
+            create_with_colour(100, 100, BLUE),
+            create_with_colour(100, 200, GREEN),
+        ];
+        let featured = MosaicOptions {
+            weights: vec![3.0],
+            ..MosaicOptions::default()
+        };
+        let equal = MosaicOptions {
+            weights: vec![1.0, 1.0, 1.0],
+            ..MosaicOptions::default()
+        };
+
+        let unweighted = mosaic(images(), &MosaicOptions::default());
+        let result = mosaic(images(), &featured);
+
+        save_result(&result.image, "3-weighted");
+        assert_eq!(unweighted.layout, Layout::LeftLeftRight);
+        assert_eq!(mosaic(images(), &equal).layout, Layout::LeftLeftRight);
+        // The featured image moves to span the whole top of the mosaic.
+        assert_eq!(result.layout, Layout::TopBottomBottom);
+        assert!(is_colour_in_range(0, 0, 320, 160, &result.image, RED));
+    }
+
+    #[test]
+    fn mosaic_3_diagonal() {
+        let left = create_with_colour(100, 400, RED);
+        let mid = create_with_colour(100, 400, BLUE);
+        let right = create_with_colour(100, 400, GREEN);
+        let options = MosaicOptions {
+            layout: LayoutMode::Diagonal,
+            ..MosaicOptions::default()
+        };
+
+        let result = mosaic(vec![left, mid, right], &options);
+
+        save_result(&result.image, "3-diagonal");
+        assert_eq!(result.layout, Layout::Diagonal);
+        assert_eq!((result.image.width(), result.image.height()), (320, 400));
+        // Edges lean right at the top and left at the bottom, and are vertical halfway down.
+        assert!(is_colour_at_pixel(120, 2, &result.image, RED));
+        assert!(is_colour_at_pixel(120, 397, &result.image, BLUE));
+        assert!(is_colour_at_pixel(50, 200, &result.image, RED));
+        assert!(is_colour_at_pixel(105, 200, &result.image, BLACK));
+        assert!(is_colour_at_pixel(160, 200, &result.image, BLUE));
+        assert!(is_colour_at_pixel(270, 200, &result.image, GREEN));
+    }
+
+    #[test]
+    fn mosaic_3_columns_right_to_left() {
+        let images = || [RED, BLUE, GREEN].map(|colour| create_with_colour(100, 400, colour)).to_vec();
+        let options = MosaicOptions {
+            direction: Direction::Rtl,
+            ..MosaicOptions::default()
+        };
+
+        let result = mosaic(images(), &options);
+
+        save_result(&result.image, "3-columns-rtl");
+        assert_eq!(result.layout, Layout::ThreeColumns);
+        assert_eq!((result.image.width(), result.image.height()), (320, 400));
+        assert!(is_colour_in_range(220, 0, 320, 400, &result.image, RED));
+        assert!(has_gutter(100, 10, BLACK, Orientation::Vertical, &result.image));
+        assert!(is_colour_in_range(110, 0, 210, 400, &result.image, BLUE));
+        assert!(is_colour_in_range(0, 0, 100, 400, &result.image, GREEN));
+        let offsets: Vec<u32> = result.regions.iter().map(|region| region.offset.width).collect();
+        assert_eq!(offsets, [220, 110, 0]);
+
+        let diagonal = MosaicOptions {
+            layout: LayoutMode::Diagonal,
+            ..options
+        };
+        let result = mosaic(images(), &diagonal);
+
+        assert_eq!(result.layout, Layout::Diagonal);
+        assert!(is_colour_at_pixel(270, 200, &result.image, RED));
+        assert!(is_colour_at_pixel(160, 200, &result.image, BLUE));
+        assert!(is_colour_at_pixel(50, 200, &result.image, GREEN));
+        assert!(is_colour_at_pixel(0, 0, &result.image, GREEN));
+        assert!(is_colour_at_pixel(319, 399, &result.image, RED));
+    }
+
+    #[test]
+    fn mosaic_3_featured() {
+        let images = || [RED, BLUE, GREEN].map(|colour| create_with_colour(100, 100, colour)).to_vec();
+        let options = MosaicOptions {
+            layout: LayoutMode::Featured,
+            ..MosaicOptions::default()
+        };
+
+        let result = mosaic(images(), &options);
+
+        save_result(&result.image, "3-featured");
+        assert!(matches!(result.layout, Layout::TopBottomBottom | Layout::LeftRightRight));
+        let area = |index: usize| result.regions[index].dimensions.width * result.regions[index].dimensions.height;
+        assert!(area(0) > area(1) * 3 && area(0) > area(2) * 3);
+    }
+}
@@ -1,36 +1,103 @@
-use image::RgbImage;
-
 use crate::mosaic::{
+    Anchor,
     best_mosaic,
-    build_mosaic,
     ImageOffset,
+    Layout,
+    LayoutMode,
+    MosaicDims,
     MosaicImageDims,
+    MosaicOptions,
     scale_height_dimension,
     scale_width_dimension,
     Size,
-    SPACING_SIZE,
+    Spacing,
+    takes_uniform_fast_path,
+    within_limits,
 };
 
-pub fn build_2_mosaic(first: RgbImage, second: RgbImage) -> RgbImage {
-    let first_size = Size {
-        width: first.width(),
-        height: first.height(),
-    };
-    let second_size = Size {
-        width: second.width(),
-        height: second.height(),
-    };
-    let best_mosaic = best_2_mosaic(first_size, second_size);
-    build_mosaic(best_mosaic, [first, second])
+pub fn plan_2_mosaic(first: Size, second: Size, options: &MosaicOptions) -> MosaicImageDims<2> {
+    if options.layout == LayoutMode::Featured {
+        return featured_2_mosaic(first, second, options);
+    }
+    if options.layout == LayoutMode::Diptych {
+        return diptych_2_mosaic(first, second, options);
+    }
+
+    best_2_mosaic(first, second, options)
+}
+
+/// Either layout puts the second image in a strip of its own, so this only weighs the first one more.
+fn featured_2_mosaic(first: Size, second: Size, options: &MosaicOptions) -> MosaicImageDims<2> {
+    let spacing = options.spacing.clamped();
+    let top_bottom = top_bottom_2_mosaic(first, second, spacing, options.anchor);
+    let left_right = left_right_2_mosaic(first, second, spacing, options.anchor);
+    best_mosaic(&within_limits(&[&top_bottom, &left_right], options), &options.featured_weights(), options.native_image(), options.max_size(), options.scale_tolerance, options.rank)
+        .unwrap_or_else(|| top_bottom.scale_to_fit(options.native_image(), options.max_size()))
+}
+
+fn best_2_mosaic(first: Size, second: Size, options: &MosaicOptions) -> MosaicImageDims<2> {
+    let spacing = options.spacing.clamped();
+    let top_bottom = top_bottom_2_mosaic(first, second, spacing, options.anchor);
+    let left_right = left_right_2_mosaic(first, second, spacing, options.anchor);
+    // Two squares of the same size are as square stacked as side by side, so they go side by side rather
+    // than whichever the search happens to rank first.
+    if takes_uniform_fast_path(&[first, second], &left_right, options) {
+        tracing::debug!("images are squares of the same size, putting them side by side");
+        return left_right.scale_to_fit(options.native_image(), options.max_size());
+    }
+
+    best_mosaic(&within_limits(&[&top_bottom, &left_right], options), &options.weights, options.native_image(), options.max_size(), options.scale_tolerance, options.rank)
+        .unwrap_or_else(|| left_right.scale_to_fit(options.native_image(), options.max_size()))
+}
+
+/// Splits the width the images would take up side by side into two equal halves, and evens them out again
+/// after scaling, which can round the edges of one half differently to the other.
+fn diptych_2_mosaic(first: Size, second: Size, options: &MosaicOptions) -> MosaicImageDims<2> {
+    let spacing = options.spacing.clamped();
+    let left_right = left_right_2_mosaic(first, second, spacing, options.anchor);
+    let halves = diptych_halves(left_right.total_size(), spacing.horizontal, first, second);
+    let scaled = halves.scale_to_fit(options.native_image(), options.max_size());
+    let gap = scaled.images[1].offset.width - scaled.images[0].total_width();
+    diptych_halves(scaled.total_size(), gap, first, second)
 }
 
-fn best_2_mosaic(first: Size, second: Size) -> MosaicImageDims<2> {
-    let top_bottom = top_bottom_2_mosaic(first, second);
-    let left_right = left_right_2_mosaic(first, second);
-    return best_mosaic(&[&top_bottom, &left_right]);
+/// Two halves of the same size side by side, `gap` apart, covering no more than `total`.
+fn diptych_halves(total: Size, gap: u32, first: Size, second: Size) -> MosaicImageDims<2> {
+    let half = Size {
+        width: (total.width.saturating_sub(gap) / 2).max(1),
+        height: total.height.max(1),
+    };
+
+    MosaicImageDims {
+        images: [
+            ImageOffset {
+                offset: Size {
+                    width: 0,
+                    height: 0,
+                },
+                dimensions: half,
+                original_dimensions: first,
+            },
+            ImageOffset {
+                offset: Size {
+                    width: half.width.saturating_add(gap),
+                    height: 0,
+                },
+                dimensions: half,
+                original_dimensions: second,
+            },
+        ],
+        layout: Layout::Diptych,
+        cropped: true,
+    }
 }
 
-pub fn left_right_2_mosaic(first: Size, second: Size) -> MosaicImageDims<2> {
+pub fn left_right_2_mosaic(first: Size, second: Size, spacing: Spacing, anchor: Anchor) -> MosaicImageDims<2> {
+    let (first_dims, second_dims) = match anchor {
+        Anchor::Larger if second.height > first.height => (scale_height_dimension(first, second.height), second),
+        _ => (first, scale_height_dimension(second, first.height)),
+    };
+
     MosaicImageDims {
         images: [
             ImageOffset {
@@ -38,22 +105,29 @@ pub fn left_right_2_mosaic(first: Size, second: Size) -> MosaicImageDims<2> {
                     width: 0,
                     height: 0,
                 },
-                dimensions: first,
+                dimensions: first_dims,
                 original_dimensions: first,
             },
             ImageOffset {
                 offset: Size {
-                    width: first.width + SPACING_SIZE,
+                    width: first_dims.width.saturating_add(spacing.horizontal),
                     height: 0,
                 },
-                dimensions: scale_height_dimension(second, first.height),
+                dimensions: second_dims,
                 original_dimensions: second,
             },
-        ]
+        ],
+        layout: Layout::LeftRight,
+        cropped: false,
     }
 }
 
-pub fn top_bottom_2_mosaic(first: Size, second: Size) -> MosaicImageDims<2> {
+pub fn top_bottom_2_mosaic(first: Size, second: Size, spacing: Spacing, anchor: Anchor) -> MosaicImageDims<2> {
+    let (first_dims, second_dims) = match anchor {
+        Anchor::Larger if second.width > first.width => (scale_width_dimension(first, second.width), second),
+        _ => (first, scale_width_dimension(second, first.width)),
+    };
+
     MosaicImageDims {
         images: [
             ImageOffset {
@@ -61,30 +135,34 @@ pub fn top_bottom_2_mosaic(first: Size, second: Size) -> MosaicImageDims<2> {
                     width: 0,
                     height: 0,
                 },
-                dimensions: first,
+                dimensions: first_dims,
                 original_dimensions: first,
             },
             ImageOffset {
                 offset: Size {
                     width: 0,
-                    height: first.height + SPACING_SIZE,
+                    height: first_dims.height.saturating_add(spacing.vertical),
                 },
-                dimensions: scale_width_dimension(second, first.width),
+                dimensions: second_dims,
                 original_dimensions: second,
             },
-        ]
+        ],
+        layout: Layout::TopBottom,
+        cropped: false,
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::mosaic;
+    use crate::mosaic::{mosaic, plan_mosaic};
+    use crate::mosaic::{Anchor, Layout, LayoutMode, MosaicOptions, MosaicPlan, Size, Spacing};
     use crate::mosaic::testutils::{
+        BLACK,
         BLUE,
         create_with_colour,
-        has_black_horizontal_line,
-        has_black_vertical_line,
+        has_gutter,
         is_colour_in_range,
+        Orientation,
         RED,
         save_result,
     };
@@ -94,12 +172,136 @@ mod tests {
         let left = create_with_colour(100, 400, RED);
         let right = create_with_colour(200, 400, BLUE);
 
-        let result = mosaic(vec![left, right]);
+        let result = mosaic(vec![left, right], &MosaicOptions::default()).image;
 
         save_result(&result, "2-left_right");
         assert!(is_colour_in_range(0, 0, 100, 400, &result, RED));
         assert!(is_colour_in_range(120, 0, 300, 400, &result, BLUE));
-        assert!(has_black_vertical_line(105, &result));
+        assert!(has_gutter(100, 10, BLACK, Orientation::Vertical, &result));
+    }
+
+    #[test]
+    fn mosaic_2_diptych_splits_down_the_middle() {
+        let options = MosaicOptions {
+            layout: LayoutMode::Diptych,
+            ..MosaicOptions::default()
+        };
+        let images = || vec![create_with_colour(301, 400, RED), create_with_colour(200, 333, BLUE)];
+
+        let natural = mosaic(images(), &MosaicOptions::default());
+        let result = mosaic(images(), &options);
+
+        save_result(&result.image, "2-diptych");
+        assert_eq!(natural.layout, Layout::LeftRight);
+        assert_ne!(natural.regions[0].dimensions.width, natural.regions[1].dimensions.width);
+        assert_eq!(result.layout, Layout::Diptych);
+        let [left, right] = [result.regions[0], result.regions[1]];
+        let width = result.image.width();
+        assert_eq!(left.dimensions.width, right.dimensions.width);
+        assert_eq!(left.dimensions.height, right.dimensions.height);
+        // The gutter is centred on exactly half the width, with the same width either side of it.
+        assert_eq!(left.total_width() + right.offset.width, width);
+        assert_eq!(right.total_width(), width);
+        assert!(is_colour_in_range(0, 0, left.total_width(), left.total_height(), &result.image, RED));
+        assert!(is_colour_in_range(right.offset.width, 0, width, right.total_height(), &result.image, BLUE));
+        let gutter = right.offset.width - left.total_width();
+        assert!(has_gutter(left.total_width(), gutter, BLACK, Orientation::Vertical, &result.image));
+    }
+
+    #[test]
+    fn mosaic_2_gaps_follow_direction() {
+        let options = MosaicOptions {
+            spacing: Spacing {
+                horizontal: 4,
+                vertical: 30,
+            },
+            ..MosaicOptions::default()
+        };
+
+        let side_by_side = mosaic(
+            vec![create_with_colour(100, 400, RED), create_with_colour(200, 400, BLUE)],
+            &options,
+        );
+        let stacked = mosaic(
+            vec![create_with_colour(400, 200, RED), create_with_colour(400, 100, BLUE)],
+            &options,
+        );
+
+        assert_eq!(side_by_side.layout, Layout::LeftRight);
+        assert_eq!(side_by_side.image.dimensions(), (304, 400));
+        assert!(has_gutter(100, 4, BLACK, Orientation::Vertical, &side_by_side.image));
+        assert_eq!(stacked.layout, Layout::TopBottom);
+        assert_eq!(stacked.image.dimensions(), (400, 330));
+        assert!(has_gutter(200, 30, BLACK, Orientation::Horizontal, &stacked.image));
+    }
+
+    #[test]
+    fn mosaic_2_uniform_squares_go_side_by_side() {
+        let result = mosaic(vec![create_with_colour(300, 300, RED), create_with_colour(300, 300, BLUE)], &MosaicOptions::default());
+
+        assert_eq!(result.layout, Layout::LeftRight);
+        assert_eq!(result.image.dimensions(), (610, 300));
+    }
+
+    #[test]
+    fn mosaic_2_anchor_on_larger_keeps_proportions() {
+        let sizes = [
+            Size {
+                width: 10,
+                height: 10,
+            },
+            Size {
+                width: 3000,
+                height: 4000,
+            },
+        ];
+        let options = MosaicOptions {
+            anchor: Anchor::Larger,
+            ..MosaicOptions::default()
+        };
+        let aspect_ratio = |size: Size| size.width as f32 / size.height as f32;
+        let gutter = |plan: &MosaicPlan| plan.images[1].offset.height - plan.images[0].dimensions.height;
+
+        let first = plan_mosaic(&sizes, &MosaicOptions::default());
+        let larger = plan_mosaic(&sizes, &options);
+
+        // Scaling the big image down to the tiny one's 10 pixel edge rounds it off, and scaling the whole
+        // layout back up from there blows the gutter up with it.
+        assert_eq!(first.layout, Layout::TopBottom);
+        assert_eq!(larger.layout, Layout::TopBottom);
+        assert!(gutter(&first) > 100);
+        assert!(gutter(&larger) <= 10);
+        assert!((aspect_ratio(larger.images[1].dimensions) - 0.75).abs() < 0.01);
+        assert_eq!(larger.images[0].dimensions.width, larger.images[0].dimensions.height);
+    }
+
+    #[test]
+    fn mosaic_2_unmeetable_limits_are_ignored() {
+        let left = create_with_colour(100, 400, RED);
+        let right = create_with_colour(200, 400, BLUE);
+        let options = MosaicOptions {
+            max_columns: Some(1),
+            max_rows: Some(1),
+            ..MosaicOptions::default()
+        };
+
+        let result = mosaic(vec![left, right], &options);
+
+        assert_eq!(result.layout, Layout::LeftRight);
+    }
+
+    #[test]
+    fn mosaic_2_column_cap_stacks_images() {
+        let left = create_with_colour(100, 400, RED);
+        let right = create_with_colour(200, 400, BLUE);
+        let options = MosaicOptions {
+            max_columns: Some(1),
+            ..MosaicOptions::default()
+        };
+
+        let result = mosaic(vec![left, right], &options);
+
+        assert_eq!(result.layout, Layout::TopBottom);
     }
 
     #[test]
@@ -107,11 +309,11 @@ mod tests {
         let top = create_with_colour(400, 200, RED);
         let bottom = create_with_colour(400, 100, BLUE);
 
-        let result = mosaic(vec![top, bottom]);
+        let result = mosaic(vec![top, bottom], &MosaicOptions::default()).image;
 
         save_result(&result, "2-top_bottom");
         assert!(is_colour_in_range(0, 0, 400, 200, &result, RED));
         assert!(is_colour_in_range(0, 220, 400, 300, &result, BLUE));
-        assert!(has_black_horizontal_line(205, &result));
+        assert!(has_gutter(200, 10, BLACK, Orientation::Horizontal, &result));
     }
 }
\ No newline at end of file
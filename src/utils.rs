@@ -22,26 +22,79 @@
  * SOFTWARE.
  */
 
-use std::time::Instant;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::io::{Cursor, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use axum::{
+    body::StreamBody,
     http::{header, StatusCode},
-    response::IntoResponse,
+    response::{IntoResponse, Response},
 };
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use const_format::formatcp;
+use futures::stream::{FuturesUnordered, StreamExt};
 use image::{
-    codecs::{jpeg::JpegEncoder, png::PngEncoder},
-    EncodableLayout, ImageEncoder, ImageError, RgbImage,
+    codecs::{
+        bmp::BmpEncoder,
+        gif::GifDecoder,
+        jpeg::{JpegDecoder, JpegEncoder},
+        png::{CompressionType, FilterType, PngEncoder},
+        tiff::TiffEncoder,
+    },
+    error::{DecodingError, EncodingError, ImageFormatHint},
+    AnimationDecoder, DynamicImage, EncodableLayout, ImageDecoder, ImageEncoder, ImageError, ImageFormat,
+    Rgb, RgbImage,
 };
 use lazy_static::lazy_static;
 use reqwest::header::{HeaderMap, HeaderValue};
+use serde::{de::Error, Deserialize, Deserializer};
+use tokio::sync::mpsc;
 use tracing::instrument;
 
+use crate::cache::ImageCache;
+use crate::mosaic::{Flip, FocalPoint, Size};
 use crate::ImageType;
 
+/// Where source images are downloaded from by default.
+pub const TWIMG_MEDIA_URL: &str = "https://pbs.twimg.com/media";
 const FAKE_CHROME_VERSION: &str = "103";
-const MAX_IMAGE_SIZE: usize = 10_000_000;
+pub const MAX_IMAGE_SIZE: usize = 10_000_000;
+/// The most bytes all the downloads of one request may add up to. Each image can still be up to
+/// `MAX_IMAGE_SIZE`, but four of them that big would be more than a request should hold at once.
+pub const MAX_REQUEST_DOWNLOAD_SIZE: usize = 20_000_000;
+const MAX_IMAGE_ID_LENGTH: usize = 32;
+/// How many more times a download that arrives truncated is tried, since that is usually a flaky
+/// connection rather than a broken image.
+const TRUNCATED_RETRIES: usize = 1;
+const PLACEHOLDER_SIZE: u32 = 16;
+/// How many pixels NeuQuant skips between training samples. Higher is faster but less accurate, 10 is the
+/// quality the algorithm was tuned around.
+const QUANTIZE_SAMPLE_FACTOR: i32 = 10;
+/// The quality WebP output is encoded at, unless it has to fit a byte budget.
+const WEBP_QUALITY: u8 = 90;
+/// The quality JPEG output is encoded at, unless it has to fit a byte budget.
+const JPEG_QUALITY: u8 = 75;
+/// The lowest quality lossy output is lowered to when fitting a byte budget. Below it, images fall apart
+/// into blocks faster than they shrink.
+const MIN_QUALITY: u8 = 20;
+/// How many qualities are tried between the default and `MIN_QUALITY` when fitting a byte budget.
+const QUALITY_SEARCH_STEPS: usize = 4;
+/// How many pixels along each side `estimate_webp_size` samples at most.
+const WEBP_ESTIMATE_SAMPLES: u32 = 128;
+/// What a WebP costs before any of its image data, in bytes.
+const WEBP_HEADER_BYTES: usize = 100;
+/// What even a flat WebP spends per pixel on structure, in bits.
+const WEBP_BASE_BITS_PER_PIXEL: f32 = 0.02;
+/// How many bytes of a streamed image are collected before they are sent on to the client.
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+/// How many chunks of a streamed image can wait to be sent before the encoder waits for the client.
+const STREAM_CHANNEL_CHUNKS: usize = 4;
+/// How long a GIF frame is shown when it doesn't say. Browsers treat a zero delay the same way.
+const DEFAULT_GIF_FRAME_DELAY: Duration = Duration::from_millis(100);
 
 lazy_static! {
     static ref FETCH_HEADERS: HeaderMap = {
@@ -77,15 +130,240 @@ lazy_static! {
     };
 }
 
-pub fn image_response(img: RgbImage, encoder: ImageType) -> Result<impl IntoResponse, ImageError> {
+/// How PNG output is written.
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PngMode {
+    /// Full 24-bit colour.
+    #[default]
+    Full,
+    /// An indexed image with a palette of up to 256 colours. Lossy, but often much smaller.
+    Quantized,
+}
+
+/// How hard PNG output is compressed. PNGs decode the same either way, only their size and the time
+/// spent encoding them differ.
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PngCompression {
+    /// Minimal compression, which is much quicker on large mosaics at the cost of bigger files.
+    #[default]
+    Fast,
+    /// The encoder's usual balance between size and speed.
+    Default,
+    /// The smallest files, for when encoding time doesn't matter.
+    Best,
+}
+
+impl PngCompression {
+    fn compression_type(self) -> CompressionType {
+        match self {
+            PngCompression::Fast => CompressionType::Fast,
+            PngCompression::Default => CompressionType::Default,
+            PngCompression::Best => CompressionType::Best,
+        }
+    }
+
+    fn png_compression(self) -> png::Compression {
+        match self {
+            PngCompression::Fast => png::Compression::Fast,
+            PngCompression::Default => png::Compression::Default,
+            PngCompression::Best => png::Compression::Best,
+        }
+    }
+}
+
+/// Format-specific settings for encoding a mosaic.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct EncodeOptions {
+    pub png: PngMode,
+    pub png_compression: PngCompression,
+    /// Read the header of the output back before returning it, failing if it doesn't parse as the
+    /// requested format at the right size.
+    pub validate: bool,
+    /// The most bytes lossy formats may encode to. Their quality is lowered until the output fits.
+    pub max_bytes: Option<usize>,
+}
+
+pub fn encode_image(
+    img: RgbImage,
+    encoder: ImageType,
+    options: EncodeOptions,
+) -> Result<Vec<u8>, ImageError> {
+    encode_image_with_quality(img, encoder, options).map(|(encoded, _quality)| encoded)
+}
+
+/// Like `encode_image`, but also returns the quality lossy formats were encoded at, which is lower than usual
+/// when they had to be fit into `options.max_bytes`.
+pub fn encode_image_with_quality(
+    img: RgbImage,
+    encoder: ImageType,
+    options: EncodeOptions,
+) -> Result<(Vec<u8>, Option<u8>), ImageError> {
+    let default_quality = match encoder {
+        ImageType::Webp => Some(WEBP_QUALITY),
+        ImageType::Jpeg => Some(JPEG_QUALITY),
+        ImageType::Png | ImageType::Bmp | ImageType::Tiff => None,
+        // The encoder only writes lossless JPEG XL, so there is no quality to lower.
+        #[cfg(feature = "jxl")]
+        ImageType::Jxl => None,
+    };
+    let (encoded, quality) = match (default_quality, options.max_bytes) {
+        (Some(quality), Some(max_bytes)) => {
+            let (encoded, quality) = fit_to_budget(&img, encoder, options, quality, max_bytes)?;
+            (encoded, Some(quality))
+        }
+        (quality, _) => (
+            encode_at_quality(&img, encoder, options, quality.unwrap_or(WEBP_QUALITY))?,
+            quality,
+        ),
+    };
+
+    if options.validate {
+        validate_encoded(&encoded, encoder, img.dimensions())?;
+    }
+
+    Ok((encoded, quality))
+}
+
+/// Searches for the highest quality at which `img` encodes to at most `max_bytes`, starting from
+/// `default_quality`. The search is bounded, so the quality found may be a little lower than the best
+/// that would fit. If even `MIN_QUALITY` is too big, that is what's returned.
+fn fit_to_budget(
+    img: &RgbImage,
+    encoder: ImageType,
+    options: EncodeOptions,
+    default_quality: u8,
+    max_bytes: usize,
+) -> Result<(Vec<u8>, u8), ImageError> {
+    let encoded = encode_at_quality(img, encoder, options, default_quality)?;
+    if encoded.len() <= max_bytes {
+        return Ok((encoded, default_quality));
+    }
+
+    // The highest quality known to fit, with its encoding, and the lowest known not to.
+    let (mut best, mut fits, mut too_big) = (None, MIN_QUALITY, default_quality);
+
+    // For WebP, start from the quality the estimate says fits, scaled by how far off it was at the default.
+    // When it's close, that saves most of the search.
+    if matches!(encoder, ImageType::Webp) {
+        let correction = encoded.len() as f32 / estimate_webp_size(img, default_quality) as f32;
+        let (complexity, pixels) = (sampled_complexity(img), img.width() as f32 * img.height() as f32);
+        let guess = (MIN_QUALITY + 1..default_quality)
+            .rev()
+            .find(|quality| webp_size_for(complexity, pixels, *quality) as f32 * correction <= max_bytes as f32);
+        if let Some(guess) = guess {
+            let encoded = encode_at_quality(img, encoder, options, guess)?;
+            if encoded.len() <= max_bytes {
+                (best, fits) = (Some(encoded), guess);
+            } else {
+                too_big = guess;
+            }
+        }
+    }
+
+    let mut best = match best {
+        Some(best) => best,
+        None => {
+            let lowest = encode_at_quality(img, encoder, options, MIN_QUALITY)?;
+            if lowest.len() > max_bytes {
+                tracing::warn!(
+                    max_bytes,
+                    bytes = lowest.len(),
+                    "image doesn't fit its byte budget even at the lowest quality"
+                );
+                return Ok((lowest, MIN_QUALITY));
+            }
+            lowest
+        }
+    };
+
+    for _ in 0..QUALITY_SEARCH_STEPS {
+        if too_big - fits <= 1 {
+            break;
+        }
+
+        let quality = fits + (too_big - fits) / 2;
+        let encoded = encode_at_quality(img, encoder, options, quality)?;
+        if encoded.len() <= max_bytes {
+            best = encoded;
+            fits = quality;
+        } else {
+            too_big = quality;
+        }
+    }
+
+    tracing::debug!(max_bytes, bytes = best.len(), quality = fits, "fit image into its byte budget");
+    Ok((best, fits))
+}
+
+/// Roughly how many bytes `img` encodes to as a WebP at `quality`, without encoding it. It only looks at
+/// the image's size and at how much neighbouring pixels differ across a sample of it, so it is a factor of
+/// two or so out at best; good enough to seed the byte budget search or for capacity planning.
+pub fn estimate_webp_size(img: &RgbImage, quality: u8) -> usize {
+    webp_size_for(sampled_complexity(img), img.width() as f32 * img.height() as f32, quality)
+}
+
+/// The model behind `estimate_webp_size`, fitted to libwebp's output: bits per pixel grow exponentially with
+/// quality and with a power of the image's complexity. The power falls as quality rises, since at high
+/// qualities even smooth gradients stop being cheap.
+fn webp_size_for(complexity: f32, pixels: f32, quality: u8) -> usize {
+    let quality = quality as f32;
+    let bits_per_pixel =
+        WEBP_BASE_BITS_PER_PIXEL + 5.0 * (quality / 100.0).exp() * complexity.powf(0.92 - quality / 400.0);
+    WEBP_HEADER_BYTES + (pixels * bits_per_pixel / 8.0) as usize
+}
+
+/// The mean difference in brightness between neighbouring pixels, from 0 for a flat image to 1, sampled
+/// on a grid of at most `WEBP_ESTIMATE_SAMPLES` pixels each way.
+fn sampled_complexity(img: &RgbImage) -> f32 {
+    let luma = |x, y| {
+        let Rgb([r, g, b]) = *img.get_pixel(x, y);
+        r as f32 * 0.299 + g as f32 * 0.587 + b as f32 * 0.114
+    };
+    let step = (img.width().max(img.height()) / WEBP_ESTIMATE_SAMPLES).max(1) as usize;
+
+    let mut total = 0.0;
+    let mut samples = 0;
+    for y in (0..img.height().saturating_sub(1)).step_by(step) {
+        for x in (0..img.width().saturating_sub(1)).step_by(step) {
+            let here = luma(x, y);
+            total += (here - luma(x + 1, y)).abs() + (here - luma(x, y + 1)).abs();
+            samples += 1;
+        }
+    }
+
+    if samples == 0 {
+        return 0.0;
+    }
+    total / samples as f32 / 255.0
+}
+
+/// Encodes `img` once, at `quality` if the format is lossy.
+fn encode_at_quality(
+    img: &RgbImage,
+    encoder: ImageType,
+    options: EncodeOptions,
+    quality: u8,
+) -> Result<Vec<u8>, ImageError> {
     let encoded = match encoder {
         ImageType::Webp => webp::Encoder::from_rgb(img.as_bytes(), img.width(), img.height())
-            .encode(90.0)
+            .encode(quality as f32)
             .to_vec(),
 
+        ImageType::Png if options.png == PngMode::Quantized => {
+            encode_quantized_png(img, options.png_compression).map_err(|err| {
+                ImageError::Encoding(EncodingError::new(ImageFormatHint::Exact(ImageFormat::Png), err))
+            })?
+        }
+
         ImageType::Png => {
             let mut out = vec![];
-            let enc = PngEncoder::new(&mut out);
+            let enc = PngEncoder::new_with_quality(
+                &mut out,
+                options.png_compression.compression_type(),
+                FilterType::Adaptive,
+            );
             enc.write_image(
                 img.as_bytes(),
                 img.width(),
@@ -97,7 +375,7 @@ pub fn image_response(img: RgbImage, encoder: ImageType) -> Result<impl IntoResp
 
         ImageType::Jpeg => {
             let mut out = vec![];
-            let enc = JpegEncoder::new(&mut out);
+            let enc = JpegEncoder::new_with_quality(&mut out, quality);
             enc.write_image(
                 img.as_bytes(),
                 img.width(),
@@ -106,42 +384,917 @@ pub fn image_response(img: RgbImage, encoder: ImageType) -> Result<impl IntoResp
             )?;
             out.to_vec()
         }
+
+        ImageType::Bmp => {
+            let mut out = vec![];
+            let enc = BmpEncoder::new(&mut out);
+            enc.write_image(
+                img.as_bytes(),
+                img.width(),
+                img.height(),
+                image::ColorType::Rgb8,
+            )?;
+            out
+        }
+
+        ImageType::Tiff => {
+            // The TIFF encoder needs to seek back to write offsets, so it can't write to a Vec directly.
+            let mut out = Cursor::new(vec![]);
+            let enc = TiffEncoder::new(&mut out);
+            enc.write_image(
+                img.as_bytes(),
+                img.width(),
+                img.height(),
+                image::ColorType::Rgb8,
+            )?;
+            out.into_inner()
+        }
+
+        #[cfg(feature = "jxl")]
+        ImageType::Jxl => {
+            let options = zune_core::options::EncoderOptions::new(
+                img.width() as usize,
+                img.height() as usize,
+                zune_core::colorspace::ColorSpace::RGB,
+                zune_core::bit_depth::BitDepth::Eight,
+            );
+            zune_jpegxl::JxlSimpleEncoder::new(img.as_bytes(), options).encode().map_err(|err| {
+                ImageError::Encoding(EncodingError::new(jxl_format_hint(), format!("{:?}", err)))
+            })?
+        }
+    };
+
+    Ok(encoded)
+}
+
+/// Whether `encoder` writes its output as it goes with these options, so `streamed_image_response` can send
+/// it while it is still being encoded. Validating and fitting a byte budget need the whole output first, so
+/// they can't be streamed.
+pub fn can_stream(encoder: ImageType, options: EncodeOptions) -> bool {
+    let streamable = match encoder {
+        ImageType::Png => options.png == PngMode::Full,
+        ImageType::Jpeg => true,
+        // WebP is encoded in one shot, BMP and TIFF seek back to fill in their headers.
+        ImageType::Webp | ImageType::Bmp | ImageType::Tiff => false,
+        #[cfg(feature = "jxl")]
+        ImageType::Jxl => false,
+    };
+    streamable && !options.validate && options.max_bytes.is_none()
+}
+
+/// Encodes `img` into `out` as it goes, for the formats `can_stream` allows.
+fn encode_streamable<W: Write>(
+    img: &RgbImage,
+    encoder: ImageType,
+    options: EncodeOptions,
+    mut out: W,
+) -> Result<(), ImageError> {
+    match encoder {
+        ImageType::Png => {
+            let mut encoder = png::Encoder::new(&mut out, img.width(), img.height());
+            encoder.set_color(png::ColorType::Rgb);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_compression(options.png_compression.png_compression());
+            // The stream writer keeps the filtered previous row around, which only the Sub filter
+            // doesn't look at, so adaptive filtering would write rows that don't decode correctly.
+            encoder.set_filter(png::FilterType::Sub);
+            encoder.set_adaptive_filter(png::AdaptiveFilterType::NonAdaptive);
+            let png_error = |err: png::EncodingError| {
+                ImageError::Encoding(EncodingError::new(ImageFormatHint::Exact(ImageFormat::Png), err))
+            };
+            // The stream writer emits an IDAT chunk whenever `STREAM_CHUNK_SIZE` bytes of compressed data
+            // are ready, instead of compressing the whole image before writing any of it.
+            let mut writer = encoder.write_header().map_err(png_error)?;
+            let mut stream = writer.stream_writer_with_size(STREAM_CHUNK_SIZE).map_err(png_error)?;
+            stream.write_all(img.as_bytes())?;
+            stream.finish().map_err(png_error)?;
+        }
+        ImageType::Jpeg => {
+            JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY).write_image(
+                img.as_bytes(),
+                img.width(),
+                img.height(),
+                image::ColorType::Rgb8,
+            )?;
+        }
+        _ => unreachable!("{:?} can't be streamed", encoder),
+    }
+
+    Ok(out.flush()?)
+}
+
+/// Collects what is written to it into chunks of `STREAM_CHUNK_SIZE` and sends each one to a response body.
+/// Writing fails once the body is dropped, like when the client goes away, which stops the encoder early.
+struct ChannelWriter {
+    sender: mpsc::Sender<std::io::Result<Bytes>>,
+    buffer: Vec<u8>,
+}
+
+impl ChannelWriter {
+    fn send_buffer(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let chunk = Bytes::from(std::mem::replace(&mut self.buffer, Vec::with_capacity(STREAM_CHUNK_SIZE)));
+        self.sender
+            .blocking_send(Ok(chunk))
+            .map_err(|_err| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "response body was dropped"))
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= STREAM_CHUNK_SIZE {
+            self.send_buffer()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.send_buffer()
+    }
+}
+
+/// Like `image_response`, but sends the encoded image while it is still being encoded, for the formats
+/// `can_stream` allows. The encoder runs on a blocking thread until it is done, holding `permit` until then.
+/// The status and headers go out before encoding finishes, so a failure can only cut the body short.
+pub fn streamed_image_response<P: Send + 'static>(
+    img: RgbImage,
+    encoder: ImageType,
+    options: EncodeOptions,
+    permit: P,
+) -> impl IntoResponse {
+    let (sender, mut receiver) = mpsc::channel(STREAM_CHANNEL_CHUNKS);
+    let span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        let _span = span.entered();
+        let start = Instant::now();
+        let mut writer = ChannelWriter {
+            sender: sender.clone(),
+            buffer: Vec::with_capacity(STREAM_CHUNK_SIZE),
+        };
+
+        match encode_streamable(&img, encoder, options, &mut writer) {
+            Ok(()) => tracing::debug!(time = start.elapsed().as_millis(), "streamed image"),
+            Err(err) => {
+                tracing::error!("could not stream image: {}", err);
+                let err = std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string());
+                let _ = sender.blocking_send(Err(err));
+            }
+        }
+    });
+
+    let body = futures::stream::poll_fn(move |cx| receiver.poll_recv(cx));
+
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, content_type(encoder)),
+            (header::CONTENT_ENCODING, "identity"),
+        ],
+        StreamBody::new(body),
+    )
+}
+
+/// Parses the header of freshly encoded output, to catch an encoder writing a broken file for some edge
+/// case before a client sees it. Only the header is read, so this is much cheaper than a full decode.
+fn validate_encoded(encoded: &[u8], encoder: ImageType, dimensions: (u32, u32)) -> Result<(), ImageError> {
+    let (decoded, format) = match encoder {
+        // `image` can't read JPEG XL, so jxl-oxide parses its header instead.
+        #[cfg(feature = "jxl")]
+        ImageType::Jxl => {
+            let image = jxl_oxide::JxlImage::builder().read(Cursor::new(encoded)).map_err(|err| {
+                ImageError::Decoding(DecodingError::new(jxl_format_hint(), err))
+            })?;
+            ((image.width(), image.height()), jxl_format_hint())
+        }
+        _ => {
+            let format = image_format(encoder);
+            let decoded = image::io::Reader::with_format(Cursor::new(encoded), format).into_dimensions()?;
+            (decoded, ImageFormatHint::Exact(format))
+        }
     };
 
-    let content_type = match encoder {
+    if decoded != dimensions {
+        return Err(ImageError::Encoding(EncodingError::new(
+            format,
+            format!(
+                "encoded image reads back as {}x{} instead of {}x{}",
+                decoded.0, decoded.1, dimensions.0, dimensions.1
+            ),
+        )));
+    }
+
+    Ok(())
+}
+
+/// Reduces `img` to a 256 colour palette and writes it as an indexed PNG.
+#[instrument(skip(img))]
+fn encode_quantized_png(img: &RgbImage, compression: PngCompression) -> Result<Vec<u8>, png::EncodingError> {
+    let start = Instant::now();
+
+    // NeuQuant works on RGBA pixels.
+    let rgba: Vec<u8> = img
+        .pixels()
+        .flat_map(|pixel| [pixel[0], pixel[1], pixel[2], 255])
+        .collect();
+    let quantizer = color_quant::NeuQuant::new(QUANTIZE_SAMPLE_FACTOR, 256, &rgba);
+    let indices: Vec<u8> = rgba
+        .chunks_exact(4)
+        .map(|pixel| quantizer.index_of(pixel) as u8)
+        .collect();
+
+    tracing::debug!(time = start.elapsed().as_millis(), "quantized image");
+
+    let mut out = vec![];
+    let mut encoder = png::Encoder::new(&mut out, img.width(), img.height());
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(quantizer.color_map_rgb());
+    encoder.set_compression(compression.png_compression());
+    encoder.write_header()?.write_image_data(&indices)?;
+
+    Ok(out)
+}
+
+/// Owns a libwebp animation encoder, so it is freed on every return path.
+struct AnimEncoder(*mut libwebp_sys::WebPAnimEncoder);
+
+impl Drop for AnimEncoder {
+    fn drop(&mut self) {
+        unsafe { libwebp_sys::WebPAnimEncoderDelete(self.0) };
+    }
+}
+
+fn webp_encoding_error(message: &str) -> ImageError {
+    ImageError::Encoding(EncodingError::new(
+        ImageFormatHint::Exact(ImageFormat::WebP),
+        message.to_string(),
+    ))
+}
+
+/// Encodes `frames`, which must all be the same size, as an animated WebP that shows each one for
+/// `frame_duration` and loops forever.
+#[instrument(skip(frames))]
+pub fn encode_animated_webp(frames: &[RgbImage], frame_duration: Duration) -> Result<Vec<u8>, ImageError> {
+    use libwebp_sys::*;
+    use std::mem::MaybeUninit;
+    use std::os::raw::c_int;
+
+    let start = Instant::now();
+
+    let first = frames
+        .first()
+        .ok_or_else(|| webp_encoding_error("an animation needs at least one frame"))?;
+    let (width, height) = first.dimensions();
+    if frames.iter().any(|frame| frame.dimensions() != (width, height)) {
+        return Err(webp_encoding_error("animation frames must all be the same size"));
+    }
+    let frame_ms = frame_duration.as_millis().clamp(1, c_int::MAX as u128) as c_int;
+
+    unsafe {
+        let mut anim_options = MaybeUninit::<WebPAnimEncoderOptions>::uninit();
+        if WebPAnimEncoderOptionsInitInternal(anim_options.as_mut_ptr(), WEBP_MUX_ABI_VERSION) == 0 {
+            return Err(webp_encoding_error("libwebp version mismatch"));
+        }
+        let anim_options = anim_options.assume_init();
+
+        let encoder = AnimEncoder(WebPAnimEncoderNewInternal(
+            width as c_int,
+            height as c_int,
+            &anim_options,
+            WEBP_MUX_ABI_VERSION,
+        ));
+        if encoder.0.is_null() {
+            return Err(webp_encoding_error("could not create animation encoder"));
+        }
+
+        let mut config = MaybeUninit::<WebPConfig>::uninit();
+        if WebPConfigInitInternal(
+            config.as_mut_ptr(),
+            WebPPreset::WEBP_PRESET_DEFAULT,
+            90.0,
+            WEBP_ENCODER_ABI_VERSION,
+        ) == 0
+        {
+            return Err(webp_encoding_error("libwebp version mismatch"));
+        }
+        let config = config.assume_init();
+
+        let mut timestamp: c_int = 0;
+        for frame in frames {
+            let mut picture = MaybeUninit::<WebPPicture>::uninit();
+            if WebPPictureInitInternal(picture.as_mut_ptr(), WEBP_ENCODER_ABI_VERSION) == 0 {
+                return Err(webp_encoding_error("libwebp version mismatch"));
+            }
+            let mut picture = picture.assume_init();
+            picture.use_argb = 1;
+            picture.width = width as c_int;
+            picture.height = height as c_int;
+
+            let added = WebPPictureImportRGB(&mut picture, frame.as_ptr(), (width * 3) as c_int) != 0
+                && WebPAnimEncoderAdd(encoder.0, &mut picture, timestamp, &config) != 0;
+            WebPPictureFree(&mut picture);
+            if !added {
+                return Err(webp_encoding_error("could not add animation frame"));
+            }
+
+            timestamp = timestamp.saturating_add(frame_ms);
+        }
+
+        // A last, empty frame marks when the final real frame ends.
+        let mut data = WebPData::default();
+        if WebPAnimEncoderAdd(encoder.0, std::ptr::null_mut(), timestamp, std::ptr::null()) == 0
+            || WebPAnimEncoderAssemble(encoder.0, &mut data) == 0
+        {
+            return Err(webp_encoding_error("could not assemble animation"));
+        }
+
+        let encoded = std::slice::from_raw_parts(data.bytes, data.size).to_vec();
+        WebPDataClear(&mut data);
+
+        tracing::debug!(
+            frames = frames.len(),
+            time = start.elapsed().as_millis(),
+            "encoded animation"
+        );
+
+        Ok(encoded)
+    }
+}
+
+pub fn content_type(encoder: ImageType) -> &'static str {
+    match encoder {
         ImageType::Webp => "image/webp",
         ImageType::Png => "image/png",
         ImageType::Jpeg => "image/jpeg",
-    };
+        ImageType::Bmp => "image/bmp",
+        ImageType::Tiff => "image/tiff",
+        #[cfg(feature = "jxl")]
+        ImageType::Jxl => "image/jxl",
+    }
+}
+
+fn image_format(encoder: ImageType) -> ImageFormat {
+    match encoder {
+        ImageType::Webp => ImageFormat::WebP,
+        ImageType::Png => ImageFormat::Png,
+        ImageType::Jpeg => ImageFormat::Jpeg,
+        ImageType::Bmp => ImageFormat::Bmp,
+        ImageType::Tiff => ImageFormat::Tiff,
+        #[cfg(feature = "jxl")]
+        ImageType::Jxl => unreachable!("image has no JPEG XL support"),
+    }
+}
+
+/// `image` has no `ImageFormat` for JPEG XL, so its errors name it instead.
+#[cfg(feature = "jxl")]
+fn jxl_format_hint() -> ImageFormatHint {
+    ImageFormatHint::Name("JPEG XL".to_string())
+}
+
+/// Tells compression layers not to compress a body in `encoder` again, if the format is compressed already.
+fn mark_compressed(response: &mut Response, encoder: ImageType) {
+    if encoder.is_compressed() {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_ENCODING, HeaderValue::from_static("identity"));
+    }
+}
+
+/// A small gray image to send in place of a mosaic that could not be built.
+pub fn placeholder_image() -> RgbImage {
+    RgbImage::from_pixel(PLACEHOLDER_SIZE, PLACEHOLDER_SIZE, image::Rgb([128, 128, 128]))
+}
+
+pub fn image_response(
+    img: RgbImage,
+    encoder: ImageType,
+    options: EncodeOptions,
+) -> Result<impl IntoResponse, ImageError> {
+    let (encoded, quality) = encode_image_with_quality(img, encoder, options)?;
+
+    let mut response = (StatusCode::OK, [(header::CONTENT_TYPE, content_type(encoder))], encoded).into_response();
+    mark_compressed(&mut response, encoder);
+    if let (Some(quality), Some(_)) = (quality, options.max_bytes) {
+        response
+            .headers_mut()
+            .insert("X-Image-Quality", HeaderValue::from(u16::from(quality)));
+    }
+
+    Ok(response)
+}
+
+/// Like `image_response`, but for an animated WebP of `frames`.
+pub fn animation_response(
+    frames: &[RgbImage],
+    frame_duration: Duration,
+) -> Result<impl IntoResponse, ImageError> {
+    let encoded = encode_animated_webp(frames, frame_duration)?;
 
     Ok((
         StatusCode::OK,
-        [(header::CONTENT_TYPE, content_type)],
+        [
+            (header::CONTENT_TYPE, content_type(ImageType::Webp)),
+            (header::CONTENT_ENCODING, "identity"),
+        ],
         encoded,
     ))
 }
 
-#[instrument(skip(client))]
-pub async fn fetch_image(client: &reqwest::Client, id: &str) -> Option<RgbImage> {
+/// Like `image_response`, but encodes each tile separately and sends them as the parts of one
+/// `multipart/mixed` body, in order. Each tile comes with the `x,y,width,height` rectangle it covers in
+/// the mosaic, which its part carries in an `X-Image-Region` header.
+pub fn tiles_response(
+    tiles: Vec<(RgbImage, String)>,
+    encoder: ImageType,
+    options: EncodeOptions,
+) -> Result<impl IntoResponse, ImageError> {
+    let parts = tiles
+        .into_iter()
+        .enumerate()
+        .map(|(index, (tile, region))| {
+            Ok(MultipartPart {
+                headers: vec![
+                    ("Content-Type", content_type(encoder).to_string()),
+                    (
+                        "Content-Disposition",
+                        format!("inline; filename=\"tile-{}.{}\"", index, encoder.extension()),
+                    ),
+                    ("X-Image-Region", region),
+                ],
+                body: encode_image(tile, encoder, options)?,
+            })
+        })
+        .collect::<Result<Vec<_>, ImageError>>()?;
+    let (boundary, body) = encode_multipart(&parts);
+
+    let content_type = format!("multipart/mixed; boundary={}", boundary);
+    let mut response = (StatusCode::OK, [(header::CONTENT_TYPE, content_type)], body).into_response();
+    mark_compressed(&mut response, encoder);
+    Ok(response)
+}
+
+/// One part of a `multipart/mixed` body.
+pub struct MultipartPart {
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+/// Serializes `parts` into a `multipart/mixed` body, returning the boundary it uses along with it. The
+/// parts are binary, so the boundary is picked to not appear in any of them.
+pub fn encode_multipart(parts: &[MultipartPart]) -> (String, Vec<u8>) {
+    let boundary = (0..)
+        .map(|attempt| format!("mosaic-part-{}", attempt))
+        .find(|boundary| {
+            parts
+                .iter()
+                .all(|part| !part.body.windows(boundary.len()).any(|window| window == boundary.as_bytes()))
+        })
+        .unwrap();
+
+    let mut body = Vec::new();
+    for part in parts {
+        body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        for (name, value) in &part.headers {
+            body.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        body.extend_from_slice(b"\r\n");
+        body.extend_from_slice(&part.body);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+
+    (boundary, body)
+}
+
+/// Picks the output format for a request that lets the server choose, from its `Accept` header. WebP is
+/// used when the client lists it, and JPEG, which every client can show, otherwise.
+pub fn negotiate_image_type(accept: Option<&str>) -> ImageType {
+    let accepts_webp = accept.unwrap_or_default().split(',').any(|range| {
+        let mut parts = range.split(';').map(str::trim);
+        let media_type = parts.next().unwrap_or_default();
+        let quality = parts
+            .find_map(|param| param.strip_prefix("q="))
+            .and_then(|quality| quality.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        media_type.eq_ignore_ascii_case("image/webp") && quality > 0.0
+    });
+
+    if accepts_webp {
+        ImageType::Webp
+    } else {
+        ImageType::Jpeg
+    }
+}
+
+/// The format to request source images from twimg in. JPEG is much smaller, so PNG is only fetched when asked
+/// for.
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceFormat {
+    #[default]
+    Jpg,
+    Png,
+}
+
+impl SourceFormat {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SourceFormat::Jpg => "jpg",
+            SourceFormat::Png => "png",
+        }
+    }
+}
+
+/// Parses a `rrggbb` hex colour, with or without a leading `#`.
+pub fn parse_hex_colour(value: &str) -> Option<Rgb<u8>> {
+    let value = value.strip_prefix('#').unwrap_or(value);
+    if value.len() != 6 || !value.is_ascii() {
+        return None;
+    }
+
+    let channel = |i: usize| u8::from_str_radix(&value[i..i + 2], 16).ok();
+    Some(Rgb([channel(0)?, channel(2)?, channel(4)?]))
+}
+
+/// Deserializes an optional hex colour for use with `#[serde(deserialize_with)]`.
+pub fn deserialize_hex_colour<'de, D>(deserializer: D) -> Result<Option<Rgb<u8>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value: Option<String> = Option::deserialize(deserializer)?;
+    value
+        .map(|value| {
+            parse_hex_colour(&value)
+                .ok_or_else(|| D::Error::custom(format!("invalid hex colour: {}", value)))
+        })
+        .transpose()
+}
+
+/// Parses a `widthxheight` size, like `1200x675`.
+pub fn parse_size(value: &str) -> Option<Size> {
+    let (width, height) = value.split_once('x')?;
+    let size = Size {
+        width: width.parse().ok()?,
+        height: height.parse().ok()?,
+    };
+    (size.width > 0 && size.height > 0).then_some(size)
+}
+
+/// Deserializes an optional `widthxheight` size for use with `#[serde(deserialize_with)]`.
+pub fn deserialize_size<'de, D>(deserializer: D) -> Result<Option<Size>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value: Option<String> = Option::deserialize(deserializer)?;
+    value
+        .map(|value| {
+            parse_size(&value).ok_or_else(|| D::Error::custom(format!("invalid size: {}", value)))
+        })
+        .transpose()
+}
+
+/// Parses a comma separated list of positive weights, like `2,1,1`.
+pub fn parse_weights(value: &str) -> Option<Vec<f32>> {
+    value
+        .split(',')
+        .map(|weight| {
+            let weight: f32 = weight.trim().parse().ok()?;
+            (weight.is_finite() && weight > 0.0).then_some(weight)
+        })
+        .collect()
+}
+
+/// Deserializes a list of weights for use with `#[serde(deserialize_with)]`.
+pub fn deserialize_weights<'de, D>(deserializer: D) -> Result<Vec<f32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    parse_weights(&value).ok_or_else(|| D::Error::custom(format!("invalid weights: {}", value)))
+}
+
+/// Parses comma separated intrinsic scales, like `2,,1.5`. An empty entry is a scale of 1.
+pub fn parse_scales(value: &str) -> Option<Vec<f32>> {
+    value
+        .split(',')
+        .map(|scale| {
+            let scale = scale.trim();
+            if scale.is_empty() {
+                return Some(1.0);
+            }
+
+            let scale: f32 = scale.parse().ok()?;
+            (scale.is_finite() && scale > 0.0).then_some(scale)
+        })
+        .collect()
+}
+
+/// Deserializes a list of intrinsic scales for use with `#[serde(deserialize_with)]`.
+pub fn deserialize_scales<'de, D>(deserializer: D) -> Result<Vec<f32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    parse_scales(&value).ok_or_else(|| D::Error::custom(format!("invalid scales: {}", value)))
+}
+
+/// Parses comma separated opacities from 0 to 1, like `1,0.5`. An empty entry is fully opaque.
+pub fn parse_opacities(value: &str) -> Option<Vec<f32>> {
+    value
+        .split(',')
+        .map(|opacity| {
+            let opacity = opacity.trim();
+            if opacity.is_empty() {
+                return Some(1.0);
+            }
+
+            let opacity: f32 = opacity.parse().ok()?;
+            (0.0..=1.0).contains(&opacity).then_some(opacity)
+        })
+        .collect()
+}
+
+/// Deserializes a list of opacities for use with `#[serde(deserialize_with)]`.
+pub fn deserialize_opacities<'de, D>(deserializer: D) -> Result<Vec<f32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    parse_opacities(&value).ok_or_else(|| D::Error::custom(format!("invalid opacities: {}", value)))
+}
+
+/// Parses comma separated aspect ratios, each either a number like `1.5` or a `width:height` pair like
+/// `16:9`. An empty entry keeps that image's own aspect ratio.
+pub fn parse_aspect_ratios(value: &str) -> Option<Vec<Option<f32>>> {
+    value
+        .split(',')
+        .map(|ratio| {
+            let ratio = ratio.trim();
+            if ratio.is_empty() {
+                return Some(None);
+            }
+
+            let ratio = match ratio.split_once(':') {
+                Some((width, height)) => {
+                    width.trim().parse::<f32>().ok()? / height.trim().parse::<f32>().ok()?
+                }
+                None => ratio.parse().ok()?,
+            };
+            (ratio.is_finite() && ratio > 0.0).then_some(Some(ratio))
+        })
+        .collect()
+}
+
+/// Deserializes a list of aspect ratios for use with `#[serde(deserialize_with)]`.
+pub fn deserialize_aspect_ratios<'de, D>(deserializer: D) -> Result<Vec<Option<f32>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    parse_aspect_ratios(&value)
+        .ok_or_else(|| D::Error::custom(format!("invalid aspect ratios: {}", value)))
+}
+
+/// Parses comma separated flips, each made of `h` to mirror left to right and `v` to mirror top to bottom,
+/// like `h,,hv`. An empty entry leaves that image as it is.
+pub fn parse_flips(value: &str) -> Option<Vec<Flip>> {
+    value
+        .split(',')
+        .map(|flip| {
+            let flip = flip.trim();
+            flip.chars().all(|axis| axis == 'h' || axis == 'v').then(|| Flip {
+                horizontal: flip.contains('h'),
+                vertical: flip.contains('v'),
+            })
+        })
+        .collect()
+}
+
+/// Deserializes a list of flips for use with `#[serde(deserialize_with)]`.
+pub fn deserialize_flips<'de, D>(deserializer: D) -> Result<Vec<Flip>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    parse_flips(&value).ok_or_else(|| D::Error::custom(format!("invalid flips: {}", value)))
+}
+
+/// Parses `;` separated `x,y` focal points, like `0.5,0.2;;0.3,0.7`. An empty entry is the middle of the image.
+pub fn parse_focal_points(value: &str) -> Option<Vec<FocalPoint>> {
+    value
+        .split(';')
+        .map(|point| {
+            if point.trim().is_empty() {
+                return Some(FocalPoint::default());
+            }
+
+            let (x, y) = point.split_once(',')?;
+            let coordinate = |value: &str| {
+                let value: f32 = value.trim().parse().ok()?;
+                (0.0..=1.0).contains(&value).then_some(value)
+            };
+            Some(FocalPoint {
+                x: coordinate(x)?,
+                y: coordinate(y)?,
+            })
+        })
+        .collect()
+}
+
+/// Deserializes a list of focal points for use with `#[serde(deserialize_with)]`.
+pub fn deserialize_focal_points<'de, D>(deserializer: D) -> Result<Vec<FocalPoint>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    parse_focal_points(&value)
+        .ok_or_else(|| D::Error::custom(format!("invalid focal points: {}", value)))
+}
+
+/// Hashes what a request asks for, ignoring the order of the query parameters, so the same mosaic always
+/// gets the same hash. The image IDs are only sorted when `sort_ids` is set, as with `?order=sorted`, since
+/// otherwise their order changes the layout. Repeated parameters, like the `data=` images, keep their order
+/// among themselves for the same reason. FNV is used because, unlike `DefaultHasher`, it is guaranteed to
+/// stay the same between builds.
+pub fn request_hash(image_type: ImageType, image_ids: &str, query: Option<&str>, sort_ids: bool) -> u64 {
+    let mut ids: Vec<_> = image_ids.split('/').filter(|id| !id.is_empty()).collect();
+    if sort_ids {
+        ids.sort_unstable();
+    }
+    let mut params: Vec<(String, String)> = query
+        .and_then(|query| serde_urlencoded::from_str(query).ok())
+        .unwrap_or_default();
+    params.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut hasher = fnv::FnvHasher::default();
+    image_type.extension().hash(&mut hasher);
+    ids.hash(&mut hasher);
+    params.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Checks that an image ID looks like a twimg media key before it gets put into a URL.
+pub fn is_valid_image_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_IMAGE_ID_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Counts the bytes downloaded by all the fetches of one request, so their total can be capped, and notes
+/// which images were skipped for being over `MAX_IMAGE_SIZE` on their own.
+pub struct DownloadBudget {
+    limit: usize,
+    used: AtomicUsize,
+    oversized: Mutex<Vec<String>>,
+}
+
+impl DownloadBudget {
+    pub fn new(limit: usize) -> DownloadBudget {
+        DownloadBudget {
+            limit,
+            used: AtomicUsize::new(0),
+            oversized: Mutex::new(vec![]),
+        }
+    }
+
+    fn reject_oversized(&self, id: &str) {
+        self.oversized.lock().unwrap().push(id.to_string());
+    }
+
+    /// Whether the image `id` was skipped for being over `MAX_IMAGE_SIZE`.
+    pub fn is_oversized(&self, id: &str) -> bool {
+        self.oversized.lock().unwrap().iter().any(|oversized| oversized == id)
+    }
+
+    /// Counts `bytes` more towards the budget, returning whether the request is still within it.
+    fn spend(&self, bytes: usize) -> bool {
+        let used = self.used.fetch_add(bytes, Ordering::Relaxed).saturating_add(bytes);
+        used <= self.limit
+    }
+
+    /// Takes `bytes` that were spent on a download that was thrown away back out of the budget.
+    fn refund(&self, bytes: usize) {
+        // Only what was spent is refunded, so this can't underflow.
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// Whether the downloads went over the budget, in which case some of them were abandoned.
+    pub fn is_exceeded(&self) -> bool {
+        self.used.load(Ordering::Relaxed) > self.limit
+    }
+}
+
+/// Downloads and decodes the image `id`, shrinking JPEGs towards `max_side` as `decode_image_within` does.
+#[instrument(skip(client, cache, budget, upstream))]
+pub async fn fetch_image(
+    client: &reqwest::Client,
+    cache: &ImageCache,
+    budget: &DownloadBudget,
+    upstream: &str,
+    id: &str,
+    format: SourceFormat,
+    max_side: u32,
+) -> Option<RgbImage> {
+    let url = format!(
+        "{}/{}?format={}&name=large",
+        upstream,
+        id,
+        format.as_str()
+    );
+
+    // An image shrunk while decoding can't stand in for the full one, so it is cached under its own key.
+    let key = match max_side {
+        u32::MAX => url.clone(),
+        max_side => format!("{}#{}", url, max_side),
+    };
+    if let Some(im) = cache.get(&key) {
+        tracing::debug!(hit_rate = cache.hit_rate(), "image was cached");
+        return Some(im);
+    }
+
+    for attempt in 0..=TRUNCATED_RETRIES {
+        let buf = download_image(client, budget, &url, id).await?;
+
+        match decode_image_within(&buf, max_side) {
+            // The JPEG decoder fills in whatever is missing, so a cut off JPEG can still decode.
+            Ok(_) if attempt < TRUNCATED_RETRIES && is_truncated(&buf) => {
+                tracing::warn!(bytes = buf.len(), attempt, "image is truncated, upstream may be flaky");
+                budget.refund(buf.len());
+            }
+            // Out of retries, the partial image is better than none, but it shouldn't outlive this request.
+            Ok(im) if is_truncated(&buf) => {
+                tracing::warn!(bytes = buf.len(), attempt, "image is still truncated, not caching it");
+                return Some(im);
+            }
+            Ok(im) => {
+                if cache.is_enabled() {
+                    cache.insert(&key, im.clone());
+                    tracing::debug!(hit_rate = cache.hit_rate(), "cached image");
+                }
+                return Some(im);
+            }
+            Err(err) if is_truncated(&buf) => {
+                tracing::warn!(bytes = buf.len(), attempt, "image is truncated, upstream may be flaky: {}", err);
+                budget.refund(buf.len());
+            }
+            Err(err) => {
+                tracing::warn!(bytes = buf.len(), "image could not be loaded: {}", err);
+                return None;
+            }
+        }
+    }
+
+    None
+}
+
+/// Downloads `url`, returning `None` if upstream doesn't have it or it goes over a size limit. A download
+/// that is cut off partway is returned as far as it got, so decoding it tells a truncated image apart.
+async fn download_image(client: &reqwest::Client, budget: &DownloadBudget, url: &str, id: &str) -> Option<BytesMut> {
     tracing::trace!("starting to download image");
 
     let start = Instant::now();
 
-    let mut resp = client
-        .get(format!(
-            "https://pbs.twimg.com/media/{}?format=jpg&name=large",
-            id
-        ))
-        .headers(FETCH_HEADERS.clone())
-        .send()
-        .await
-        .ok()?;
+    let mut resp = match client.get(url).headers(FETCH_HEADERS.clone()).send().await {
+        Ok(resp) => resp,
+        Err(err) => {
+            tracing::warn!("image could not be requested: {}", err);
+            return None;
+        }
+    };
+    if !resp.status().is_success() {
+        tracing::warn!(status = resp.status().as_u16(), "upstream doesn't have the image, skipping");
+        return None;
+    }
+    let expected = resp.content_length();
 
     let mut buf = BytesMut::new();
 
-    while let Some(chunk) = resp.chunk().await.ok()? {
+    loop {
+        let chunk = match resp.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(err) => {
+                tracing::warn!(bytes = buf.len(), expected, "download was cut off: {}", err);
+                break;
+            }
+        };
+
         if buf.len() + chunk.len() > MAX_IMAGE_SIZE {
             tracing::warn!("image was too large, skipping");
+            budget.reject_oversized(id);
+            return None;
+        }
+
+        // Once one download takes the request over its budget, the others stop at their next chunk.
+        if !budget.spend(chunk.len()) {
+            tracing::warn!("images of the request were too large in total, skipping");
             return None;
         }
 
@@ -154,11 +1307,982 @@ pub async fn fetch_image(client: &reqwest::Client, id: &str) -> Option<RgbImage>
         "downloaded image"
     );
 
-    match image::load_from_memory(&buf) {
-        Ok(im) => Some(im.into_rgb8()),
+    Some(buf)
+}
+
+/// Waits for `fetches` and returns the images they produced, in order. With `partial_after`, stops waiting
+/// once that much time has passed and at least `needed` images have arrived, dropping the fetches that are
+/// still running. None of them are spawned, so dropping them closes their connections too.
+pub async fn join_partial<F>(
+    fetches: impl IntoIterator<Item = F>,
+    needed: usize,
+    partial_after: Option<Duration>,
+) -> Vec<RgbImage>
+where
+    F: Future<Output = Option<RgbImage>>,
+{
+    let mut pending: FuturesUnordered<_> = fetches
+        .into_iter()
+        .enumerate()
+        .map(|(index, fetch)| async move { (index, fetch.await) })
+        .collect();
+    let mut images = vec![None; pending.len()];
+    let mut arrived = 0;
+    let deadline = partial_after.map(|after| tokio::time::Instant::now() + after);
+
+    loop {
+        let next = match deadline {
+            Some(deadline) if arrived >= needed => {
+                match tokio::time::timeout_at(deadline, pending.next()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        tracing::warn!(
+                            arrived,
+                            missing = pending.len(),
+                            "partial deadline passed, continuing without the remaining images"
+                        );
+                        break;
+                    }
+                }
+            }
+            _ => pending.next().await,
+        };
+
+        match next {
+            Some((index, image)) => {
+                arrived += image.is_some() as usize;
+                images[index] = image;
+            }
+            None => break,
+        }
+    }
+
+    images.into_iter().flatten().collect()
+}
+
+/// Decodes an image passed inline as base64, in either the standard or URL-safe alphabet and with or
+/// without padding. Inputs over `MAX_IMAGE_SIZE` once decoded are rejected, like downloaded ones, and JPEGs
+/// are shrunk while decoding as `decode_image_within` does.
+pub fn decode_inline_image(data: &str, max_side: u32) -> Option<RgbImage> {
+    let data = data.trim_end_matches('=');
+    if data.len() / 4 * 3 > MAX_IMAGE_SIZE {
+        tracing::warn!("inline image was too large, skipping");
+        return None;
+    }
+
+    let data = data.replace('+', "-").replace('/', "_");
+    let buf = match base64::decode_config(data, base64::URL_SAFE_NO_PAD) {
+        Ok(buf) => buf,
+        Err(err) => {
+            tracing::warn!("inline image was not valid base64: {}", err);
+            return None;
+        }
+    };
+
+    match decode_image_within(&buf, max_side) {
+        Ok(im) => Some(im),
         Err(err) => {
-            tracing::warn!("image could not be loaded: {}", err);
+            tracing::warn!("inline image could not be loaded: {}", err);
             None
         }
     }
 }
+
+/// Decodes every frame of an animated GIF to RGB, along with how long the first one is shown for. Anything
+/// else decodes to a single frame like `decode_image`.
+pub fn decode_frames(buf: &[u8]) -> Result<(Vec<RgbImage>, Duration), ImageError> {
+    if !buf.starts_with(b"GIF8") {
+        return Ok((vec![decode_image(buf)?], DEFAULT_GIF_FRAME_DELAY));
+    }
+
+    let frames = GifDecoder::new(Cursor::new(buf))?.into_frames().collect_frames()?;
+    let delay = frames
+        .first()
+        .map(|frame| Duration::from(frame.delay()))
+        .filter(|delay| !delay.is_zero())
+        .unwrap_or(DEFAULT_GIF_FRAME_DELAY);
+    let frames = frames
+        .into_iter()
+        .map(|frame| DynamicImage::ImageRgba8(frame.into_buffer()).into_rgb8())
+        .collect();
+
+    Ok((frames, delay))
+}
+
+/// Decodes an image of any supported format to RGB.
+pub fn decode_image(buf: &[u8]) -> Result<RgbImage, ImageError> {
+    match jpeg_colour_info(buf) {
+        Some(info) if info.components == 4 => decode_cmyk_jpeg(buf, info.adobe),
+        _ => match image::load_from_memory(buf) {
+            Ok(image) => Ok(image.into_rgb8()),
+            // `image` has no HEIF decoder, so iPhone photos go through libheif instead.
+            #[cfg(feature = "heif")]
+            Err(_) if is_heif(buf) => decode_heif(buf),
+            #[cfg(feature = "svg")]
+            Err(_) if is_svg(buf) => rasterize_svg(buf, crate::mosaic::MAX_SIZE),
+            Err(err) => Err(err),
+        },
+    }
+}
+
+/// HEIF major brands, as found in the `ftyp` box at the start of the file. AVIF shares the container but
+/// not these brands.
+#[cfg(feature = "heif")]
+const HEIF_BRANDS: [&[u8; 4]; 8] = [b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis", b"mif1", b"msf1"];
+
+/// Whether `buf` looks like a HEIF/HEIC file.
+#[cfg(feature = "heif")]
+fn is_heif(buf: &[u8]) -> bool {
+    buf.len() >= 12 && &buf[4..8] == b"ftyp" && HEIF_BRANDS.iter().any(|brand| &buf[8..12] == *brand)
+}
+
+/// Decodes the primary image of a HEIF file with libheif, applying any rotation or crop it asks for.
+#[cfg(feature = "heif")]
+fn decode_heif(buf: &[u8]) -> Result<RgbImage, ImageError> {
+    use libheif_rs::{ColorSpace, HeifContext, HeifError, LibHeif, RgbChroma};
+
+    let decoding_error = |err: HeifError| {
+        ImageError::Decoding(DecodingError::new(ImageFormatHint::Name("HEIF".to_string()), err))
+    };
+    tracing::debug!("decoding HEIF");
+
+    let context = HeifContext::read_from_bytes(buf).map_err(decoding_error)?;
+    let handle = context.primary_image_handle().map_err(decoding_error)?;
+    let image = LibHeif::new()
+        .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgb), None)
+        .map_err(decoding_error)?;
+    // Interleaved RGB always comes as a single plane, whose rows may be padded out past the pixels.
+    let plane = image.planes().interleaved.unwrap();
+    let row = plane.width as usize * 3;
+    let pixels = plane.data.chunks(plane.stride).flat_map(|line| &line[..row]).copied().collect();
+
+    Ok(RgbImage::from_raw(plane.width, plane.height, pixels).unwrap())
+}
+
+/// How far into a file to look for the `<svg` tag, past any XML declaration, doctype and comments.
+#[cfg(feature = "svg")]
+const SVG_SNIFF_BYTES: usize = 1024;
+
+/// Whether `buf` looks like an SVG document.
+#[cfg(feature = "svg")]
+fn is_svg(buf: &[u8]) -> bool {
+    let head = &buf[..buf.len().min(SVG_SNIFF_BYTES)];
+    let text = String::from_utf8_lossy(head);
+    let text = text.trim_start_matches('\u{feff}').trim_start();
+    text.starts_with('<') && text.contains("<svg")
+}
+
+/// Rasterizes an SVG with resvg so its long side is `long_side`, over white. An SVG has no resolution of
+/// its own to keep, so it is drawn as big as a mosaic can use rather than at the size it declares.
+#[cfg(feature = "svg")]
+fn rasterize_svg(buf: &[u8], long_side: u32) -> Result<RgbImage, ImageError> {
+    use resvg::usvg::{self, TreeParsing};
+    use resvg::tiny_skia::{Color, Pixmap, Transform};
+
+    let decoding_error = |err: usvg::Error| {
+        ImageError::Decoding(DecodingError::new(ImageFormatHint::Name("SVG".to_string()), err))
+    };
+    tracing::debug!("rasterizing SVG");
+
+    let tree = usvg::Tree::from_data(buf, &usvg::Options::default()).map_err(decoding_error)?;
+    let size = tree.size;
+    let scale = long_side as f32 / size.width().max(size.height());
+    let width = ((size.width() * scale).round() as u32).clamp(1, long_side);
+    let height = ((size.height() * scale).round() as u32).clamp(1, long_side);
+    let mut pixmap = Pixmap::new(width, height).unwrap();
+    pixmap.fill(Color::WHITE);
+    resvg::Tree::from_usvg(&tree).render(Transform::from_scale(scale, scale), &mut pixmap.as_mut());
+
+    // The pixmap is opaque after filling it white, so dropping alpha leaves the colours as drawn.
+    let pixels = pixmap.data().chunks_exact(4).flat_map(|pixel| &pixel[..3]).copied().collect();
+    Ok(RgbImage::from_raw(width, height, pixels).unwrap())
+}
+
+/// Like `decode_image`, but JPEGs whose long side is over `max_side` are scaled down by a half, a quarter or
+/// an eighth while decoding, as far as they can go with the long side still at least `max_side`. A mosaic
+/// no bigger than `max_side` can't show more than that, so this saves holding the full resolution image
+/// just to shrink it, and is much faster too. Other formats can't be decoded scaled, so decode in full.
+pub fn decode_image_within(buf: &[u8], max_side: u32) -> Result<RgbImage, ImageError> {
+    match jpeg_colour_info(buf) {
+        Some(info) if info.components == 4 => decode_cmyk_jpeg(buf, info.adobe),
+        Some(_) => {
+            let mut decoder = JpegDecoder::new(Cursor::new(buf))?;
+            let (width, height) = decoder.dimensions();
+            if width.max(height) > max_side {
+                let scale = max_side as f32 / width.max(height) as f32;
+                let requested = |side: u32| ((side as f32 * scale).ceil() as u32).clamp(1, u16::MAX as u32) as u16;
+                let (scaled_width, scaled_height) = decoder.scale(requested(width), requested(height))?;
+                tracing::debug!(width, height, scaled_width, scaled_height, "scaled JPEG down while decoding");
+            }
+            Ok(DynamicImage::from_decoder(decoder)?.into_rgb8())
+        }
+        None => decode_image(buf),
+    }
+}
+
+/// What a JPEG's headers say about how its colours are stored.
+struct JpegColourInfo {
+    components: u8,
+    /// Whether there is an Adobe APP14 marker. Adobe software, the source of almost all CMYK JPEGs,
+    /// stores CMYK inverted and says so with this marker.
+    adobe: bool,
+}
+
+/// Walks the markers before the first scan of a JPEG. Returns `None` for anything that isn't a JPEG.
+fn jpeg_colour_info(buf: &[u8]) -> Option<JpegColourInfo> {
+    if !buf.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+
+    let mut adobe = false;
+    let mut pos = 2;
+    while pos + 4 <= buf.len() && buf[pos] == 0xFF {
+        let marker = buf[pos + 1];
+        let length = u16::from_be_bytes([buf[pos + 2], buf[pos + 3]]) as usize;
+        let segment = buf.get(pos + 4..pos + 2 + length)?;
+
+        match marker {
+            0xEE if segment.starts_with(b"Adobe") => adobe = true,
+            // Start of frame markers, other than DHT, JPG and DAC which share the range.
+            0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                return Some(JpegColourInfo {
+                    components: *segment.get(5)?,
+                    adobe,
+                });
+            }
+            0xDA => return None,
+            _ => {}
+        }
+
+        pos += 2 + length;
+    }
+
+    None
+}
+
+/// Decodes a CMYK JPEG. `image` assumes every CMYK JPEG is stored inverted, which turns the colours of
+/// plain CMYK files into something garish, so this undoes that for files without the Adobe marker.
+fn decode_cmyk_jpeg(buf: &[u8], adobe: bool) -> Result<RgbImage, ImageError> {
+    let decoding_error =
+        |err| ImageError::Decoding(DecodingError::new(ImageFormatHint::Exact(ImageFormat::Jpeg), err));
+    tracing::debug!(adobe, "converting CMYK JPEG to RGB");
+
+    let mut decoder = jpeg_decoder::Decoder::new(buf);
+    let pixels = decoder.decode().map_err(decoding_error)?;
+    let info = decoder.info().unwrap();
+    if info.pixel_format != jpeg_decoder::PixelFormat::CMYK32 {
+        return Ok(image::load_from_memory(buf)?.into_rgb8());
+    }
+
+    // The decoder inverts every channel on the way out, so that is what Adobe files contain.
+    let rgb = pixels
+        .chunks_exact(4)
+        .flat_map(|pixel| {
+            let [c, m, y, k] = if adobe {
+                [pixel[0], pixel[1], pixel[2], pixel[3]]
+            } else {
+                [255 - pixel[0], 255 - pixel[1], 255 - pixel[2], 255 - pixel[3]]
+            };
+            let white = 255 - k as u16;
+            [c, m, y].map(|ink| ((255 - ink as u16) * white / 255) as u8)
+        })
+        .collect();
+
+    Ok(RgbImage::from_raw(info.width as u32, info.height as u32, rgb).unwrap())
+}
+
+/// Whether `buf` starts like a JPEG, PNG, GIF or WebP but stops before the end of one, as a download that
+/// was cut short does. Other formats are never considered truncated.
+fn is_truncated(buf: &[u8]) -> bool {
+    if buf.starts_with(&[0xFF, 0xD8]) {
+        // Some encoders pad JPEGs after their end marker.
+        let end = buf.iter().rposition(|byte| *byte != 0).map_or(0, |last| last + 1);
+        !buf[..end].ends_with(&[0xFF, 0xD9])
+    } else if buf.starts_with(b"\x89PNG") {
+        !buf.ends_with(b"IEND\xAE\x42\x60\x82")
+    } else if buf.starts_with(b"GIF8") {
+        !buf.ends_with(b";")
+    } else if buf.len() >= 12 && buf.starts_with(b"RIFF") && &buf[8..12] == b"WEBP" {
+        let size = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]) as usize;
+        buf.len() < size.saturating_add(8)
+    } else {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use axum::{http::header, response::IntoResponse, routing::get, Router};
+    use bytes::Bytes;
+    use image::{ImageFormat, Rgb, RgbImage};
+    use tokio::sync::mpsc;
+
+    use crate::utils::{
+        can_stream, content_type, decode_image, decode_image_within, decode_inline_image, encode_animated_webp, encode_at_quality, encode_image, encode_image_with_quality, encode_multipart, encode_streamable, estimate_webp_size, fetch_image, image_response, negotiate_image_type, join_partial, is_truncated, is_valid_image_id,
+        parse_aspect_ratios, parse_flips, parse_focal_points, parse_hex_colour, parse_opacities, parse_scales, parse_size, parse_weights, request_hash, validate_encoded, ChannelWriter, DownloadBudget, EncodeOptions, MultipartPart, PngCompression, PngMode, SourceFormat, JPEG_QUALITY, MAX_IMAGE_SIZE, MIN_QUALITY, STREAM_CHANNEL_CHUNKS, STREAM_CHUNK_SIZE, WEBP_QUALITY,
+    };
+    use crate::cache::ImageCache;
+    use crate::mosaic::{Flip, MAX_SIZE};
+    use crate::ImageType;
+
+    fn assert_round_trip(image_type: ImageType, format: ImageFormat) {
+        let img = RgbImage::from_pixel(120, 80, image::Rgb([255, 0, 0]));
+
+        let encoded = encode_image(img, image_type, EncodeOptions::default()).unwrap();
+        let decoded = image::load_from_memory_with_format(&encoded, format).unwrap();
+
+        assert_eq!(decoded.width(), 120);
+        assert_eq!(decoded.height(), 80);
+    }
+
+    #[test]
+    fn animated_webp_has_a_frame_per_image() {
+        let frames = [
+            RgbImage::from_pixel(40, 30, Rgb([255, 0, 0])),
+            RgbImage::from_pixel(40, 30, Rgb([0, 0, 255])),
+        ];
+
+        let encoded = encode_animated_webp(&frames, Duration::from_millis(500)).unwrap();
+
+        assert_eq!(&encoded[..4], b"RIFF");
+        assert_eq!(&encoded[8..12], b"WEBP");
+        assert_eq!(encoded.windows(4).filter(|chunk| chunk == b"ANMF").count(), 2);
+    }
+
+    #[test]
+    fn animated_webp_rejects_mismatched_frames() {
+        let frames = [
+            RgbImage::from_pixel(40, 30, Rgb([255, 0, 0])),
+            RgbImage::from_pixel(30, 40, Rgb([0, 0, 255])),
+        ];
+
+        assert!(encode_animated_webp(&frames, Duration::from_millis(500)).is_err());
+        assert!(encode_animated_webp(&[], Duration::from_millis(500)).is_err());
+    }
+
+    #[test]
+    fn parses_aspect_ratios() {
+        assert_eq!(parse_aspect_ratios("1.5,,16:9"), Some(vec![Some(1.5), None, Some(16.0 / 9.0)]));
+        assert!(parse_aspect_ratios("0").is_none());
+        assert!(parse_aspect_ratios("4:0").is_none());
+        assert!(parse_aspect_ratios("wide").is_none());
+    }
+
+    #[test]
+    fn download_budget_is_shared() {
+        let budget = DownloadBudget::new(100);
+
+        assert!(budget.spend(60));
+        assert!(budget.spend(40));
+        assert!(!budget.is_exceeded());
+        assert!(!budget.spend(1));
+        assert!(budget.is_exceeded());
+        assert!(!budget.spend(0));
+        budget.refund(1);
+        assert!(!budget.is_exceeded());
+
+        assert!(!budget.is_oversized("a"));
+        budget.reject_oversized("a");
+        assert!(budget.is_oversized("a"));
+        assert!(!budget.is_oversized("b"));
+    }
+
+    #[test]
+    fn parses_opacities() {
+        assert_eq!(parse_opacities("0.5,,1"), Some(vec![0.5, 1.0, 1.0]));
+        assert_eq!(parse_opacities("0"), Some(vec![0.0]));
+        assert!(parse_opacities("1.5").is_none());
+        assert!(parse_opacities("-0.1").is_none());
+        assert!(parse_opacities("NaN").is_none());
+    }
+
+    #[test]
+    fn parses_scales() {
+        assert_eq!(parse_scales("2,,1.5"), Some(vec![2.0, 1.0, 1.5]));
+        assert!(parse_scales("0").is_none());
+        assert!(parse_scales("-2").is_none());
+        assert!(parse_scales("2x").is_none());
+    }
+
+    #[test]
+    fn parses_flips() {
+        let flips = parse_flips("h,,vh").unwrap();
+
+        assert_eq!(flips.len(), 3);
+        assert!(flips[0].horizontal && !flips[0].vertical);
+        assert_eq!(flips[1], Flip::default());
+        assert!(flips[2].horizontal && flips[2].vertical);
+        assert!(parse_flips("x").is_none());
+    }
+
+    #[test]
+    fn parses_focal_points() {
+        let points = parse_focal_points("0.25,1;;0,0.5").unwrap();
+
+        assert_eq!(points.len(), 3);
+        assert_eq!((points[0].x, points[0].y), (0.25, 1.0));
+        assert_eq!((points[1].x, points[1].y), (0.5, 0.5));
+        assert_eq!((points[2].x, points[2].y), (0.0, 0.5));
+        assert!(parse_focal_points("0.5").is_none());
+        assert!(parse_focal_points("1.5,0.5").is_none());
+        assert!(parse_focal_points("a,b").is_none());
+    }
+
+    #[test]
+    fn negotiates_webp_when_accepted() {
+        let chrome = "image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8";
+
+        assert!(matches!(negotiate_image_type(Some(chrome)), ImageType::Webp));
+        assert!(matches!(negotiate_image_type(Some("image/webp;q=0")), ImageType::Jpeg));
+        assert!(matches!(negotiate_image_type(Some("image/png,*/*")), ImageType::Jpeg));
+        assert!(matches!(negotiate_image_type(None), ImageType::Jpeg));
+    }
+
+    #[test]
+    fn request_hash_ignores_order() {
+        let hash = request_hash(ImageType::Webp, "a/b/c", Some("border=2&trim=true"), true);
+
+        assert_eq!(hash, request_hash(ImageType::Webp, "c/a/b", Some("trim=true&border=2"), true));
+        assert_ne!(hash, request_hash(ImageType::Png, "a/b/c", Some("border=2&trim=true"), true));
+        assert_ne!(hash, request_hash(ImageType::Webp, "a/b/c", Some("border=4&trim=true"), true));
+        assert_ne!(hash, request_hash(ImageType::Webp, "a/b", Some("border=2&trim=true"), true));
+    }
+
+    #[test]
+    fn request_hash_keeps_image_order() {
+        assert_ne!(request_hash(ImageType::Webp, "a/b", None, false), request_hash(ImageType::Webp, "b/a", None, false));
+        assert_eq!(
+            request_hash(ImageType::Webp, "a/b", Some("data=x&gap=2&data=y"), false),
+            request_hash(ImageType::Webp, "a/b", Some("gap=2&data=x&data=y"), false)
+        );
+        assert_ne!(
+            request_hash(ImageType::Webp, "a/b", Some("data=x&data=y"), false),
+            request_hash(ImageType::Webp, "a/b", Some("data=y&data=x"), false)
+        );
+    }
+
+    #[test]
+    fn responses_are_not_recompressed() {
+        for image_type in [ImageType::Webp, ImageType::Png, ImageType::Jpeg] {
+            let response = image_response(RgbImage::new(4, 4), image_type, EncodeOptions::default())
+                .unwrap()
+                .into_response();
+
+            assert_eq!(response.headers()[header::CONTENT_ENCODING], "identity");
+        }
+
+        // Raw pixels are still worth compressing.
+        for image_type in [ImageType::Bmp, ImageType::Tiff] {
+            let response = image_response(RgbImage::new(4, 4), image_type, EncodeOptions::default())
+                .unwrap()
+                .into_response();
+
+            assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+        }
+    }
+
+    #[test]
+    fn lowers_quality_to_fit_byte_budget() {
+        let img = RgbImage::from_fn(200, 200, |x, y| Rgb([((x * 31) ^ (y * 17)) as u8, (x * y) as u8, (x + y * 7) as u8]));
+        for (image_type, default_quality) in [(ImageType::Jpeg, JPEG_QUALITY), (ImageType::Webp, WEBP_QUALITY)] {
+            let full = encode_image(img.clone(), image_type, EncodeOptions::default()).unwrap();
+            let budget = |max_bytes| EncodeOptions {
+                max_bytes: Some(max_bytes),
+                ..EncodeOptions::default()
+            };
+
+            let (encoded, quality) = encode_image_with_quality(img.clone(), image_type, budget(full.len())).unwrap();
+            assert_eq!((encoded.len(), quality), (full.len(), Some(default_quality)));
+
+            let (encoded, quality) = encode_image_with_quality(img.clone(), image_type, budget(full.len() / 2)).unwrap();
+            assert!(encoded.len() <= full.len() / 2);
+            assert!(quality.unwrap() < default_quality && quality.unwrap() >= MIN_QUALITY);
+
+            let (_, quality) = encode_image_with_quality(img.clone(), image_type, budget(1)).unwrap();
+            assert_eq!(quality, Some(MIN_QUALITY));
+        }
+
+        let (_, quality) = encode_image_with_quality(img, ImageType::Png, EncodeOptions { max_bytes: Some(1), ..EncodeOptions::default() }).unwrap();
+        assert_eq!(quality, None);
+    }
+
+    #[test]
+    fn estimates_webp_size_within_a_factor_of_two() {
+        let images = [
+            RgbImage::from_pixel(300, 200, Rgb([40, 120, 200])),
+            RgbImage::from_fn(300, 200, |x, y| Rgb([(x * 255 / 300) as u8, (y * 255 / 200) as u8, 128])),
+            RgbImage::from_fn(300, 200, |x, y| Rgb([((x * 31) ^ (y * 17)) as u8, (x * y) as u8, (x + y * 7) as u8])),
+        ];
+        for img in images {
+            for quality in [MIN_QUALITY, 50, WEBP_QUALITY, 95] {
+                let actual = encode_at_quality(&img, ImageType::Webp, EncodeOptions::default(), quality).unwrap().len();
+                let estimate = estimate_webp_size(&img, quality);
+                assert!(
+                    estimate * 2 >= actual && estimate <= actual * 2,
+                    "estimated {} bytes for {} at quality {}",
+                    estimate,
+                    actual,
+                    quality
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn only_incremental_encoders_stream() {
+        let options = EncodeOptions::default();
+
+        assert!(can_stream(ImageType::Png, options));
+        assert!(can_stream(ImageType::Jpeg, options));
+        assert!(!can_stream(ImageType::Webp, options));
+        assert!(!can_stream(ImageType::Tiff, options));
+        assert!(!can_stream(ImageType::Png, EncodeOptions { png: PngMode::Quantized, ..options }));
+        assert!(!can_stream(ImageType::Jpeg, EncodeOptions { validate: true, ..options }));
+    }
+
+    #[test]
+    fn streamed_png_is_lossless() {
+        let img = RgbImage::from_fn(120, 80, |x, y| Rgb([x as u8, y as u8, (x ^ y) as u8]));
+
+        let mut encoded = Vec::new();
+        encode_streamable(&img, ImageType::Png, EncodeOptions::default(), &mut encoded).unwrap();
+        let decoded = image::load_from_memory_with_format(&encoded, ImageFormat::Png).unwrap();
+
+        assert_eq!(decoded.into_rgb8(), img);
+    }
+
+    #[test]
+    fn streamed_jpeg_matches_buffered() {
+        let img = RgbImage::from_fn(120, 80, |x, y| Rgb([x as u8, y as u8, (x ^ y) as u8]));
+
+        let mut encoded = Vec::new();
+        encode_streamable(&img, ImageType::Jpeg, EncodeOptions::default(), &mut encoded).unwrap();
+
+        assert_eq!(encoded, encode_image(img, ImageType::Jpeg, EncodeOptions::default()).unwrap());
+    }
+
+    #[test]
+    fn channel_writer_sends_chunks() {
+        let (sender, mut receiver) = mpsc::channel(STREAM_CHANNEL_CHUNKS);
+        let mut writer = ChannelWriter {
+            sender,
+            buffer: Vec::new(),
+        };
+
+        writer.write_all(&[1; STREAM_CHUNK_SIZE + 1]).unwrap();
+        writer.write_all(&[2; 10]).unwrap();
+        writer.flush().unwrap();
+
+        assert_eq!(receiver.try_recv().unwrap().unwrap().len(), STREAM_CHUNK_SIZE + 1);
+        assert_eq!(receiver.try_recv().unwrap().unwrap().as_ref(), &[2; 10]);
+        assert!(receiver.try_recv().is_err());
+
+        drop(receiver);
+        writer.write_all(&[3; 10]).unwrap();
+        assert_eq!(writer.flush().unwrap_err().kind(), std::io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn multipart_boundary_avoids_part_bodies() {
+        let parts = [
+            MultipartPart {
+                headers: vec![("Content-Type", "text/plain".to_string())],
+                body: b"--mosaic-part-0".to_vec(),
+            },
+            MultipartPart {
+                headers: Vec::new(),
+                body: b"second".to_vec(),
+            },
+        ];
+
+        let (boundary, body) = encode_multipart(&parts);
+
+        assert_eq!(boundary, "mosaic-part-1");
+        assert_eq!(
+            body,
+            b"--mosaic-part-1\r\nContent-Type: text/plain\r\n\r\n--mosaic-part-0\r\n\
+              --mosaic-part-1\r\n\r\nsecond\r\n\
+              --mosaic-part-1--\r\n"
+        );
+    }
+
+    #[test]
+    fn validates_encoded_output() {
+        let validate = EncodeOptions {
+            validate: true,
+            ..EncodeOptions::default()
+        };
+        for image_type in ImageType::ALL {
+            assert!(encode_image(RgbImage::new(33, 7), image_type, validate).is_ok());
+        }
+
+        let png = encode_image(RgbImage::new(33, 7), ImageType::Png, EncodeOptions::default()).unwrap();
+        assert!(validate_encoded(&png, ImageType::Png, (33, 7)).is_ok());
+        assert!(validate_encoded(&png, ImageType::Png, (7, 33)).is_err());
+        assert!(validate_encoded(&png, ImageType::Jpeg, (33, 7)).is_err());
+        assert!(validate_encoded(&png[..8], ImageType::Png, (33, 7)).is_err());
+    }
+
+    #[test]
+    fn bmp_round_trip() {
+        assert_round_trip(ImageType::Bmp, ImageFormat::Bmp);
+        assert_eq!(content_type(ImageType::Bmp), "image/bmp");
+    }
+
+    #[cfg(feature = "jxl")]
+    #[test]
+    fn jxl_round_trip() {
+        let img = RgbImage::from_fn(120, 80, |x, y| Rgb([x as u8, y as u8, 200]));
+
+        let encoded = encode_image(img.clone(), ImageType::Jxl, EncodeOptions::default()).unwrap();
+        let decoded = jxl_oxide::JxlImage::builder().read(&encoded[..]).unwrap();
+        let frame = decoded.render_frame(0).unwrap().image_all_channels();
+
+        assert_eq!((frame.width(), frame.height(), frame.channels()), (120, 80, 3));
+        // It's lossless, so every pixel comes back as it went in.
+        let pixels: Vec<u8> = frame.buf().iter().map(|sample| (sample * 255.0).round() as u8).collect();
+        assert_eq!(pixels, img.into_raw());
+        assert_eq!(content_type(ImageType::Jxl), "image/jxl");
+    }
+
+    #[test]
+    fn tiff_round_trip() {
+        assert_round_trip(ImageType::Tiff, ImageFormat::Tiff);
+        assert_eq!(content_type(ImageType::Tiff), "image/tiff");
+    }
+
+    #[test]
+    fn quantized_png_round_trip() {
+        let quantized = EncodeOptions {
+            png: PngMode::Quantized,
+            ..EncodeOptions::default()
+        };
+        let img = RgbImage::from_fn(120, 80, |x, _| {
+            if x < 60 {
+                Rgb([255, 0, 0])
+            } else {
+                Rgb([0, 0, 255])
+            }
+        });
+
+        let encoded = encode_image(img.clone(), ImageType::Png, quantized).unwrap();
+        let decoded = image::load_from_memory_with_format(&encoded, ImageFormat::Png)
+            .unwrap()
+            .into_rgb8();
+
+        assert_eq!(content_type(ImageType::Png), "image/png");
+        assert_eq!(decoded.dimensions(), img.dimensions());
+        for (decoded, original) in decoded.pixels().zip(img.pixels()) {
+            for channel in 0..3 {
+                assert!((decoded[channel] as i32 - original[channel] as i32).abs() <= 8);
+            }
+        }
+    }
+
+    #[test]
+    fn png_compression_levels_round_trip() {
+        let img = RgbImage::from_fn(120, 80, |x, y| Rgb([x as u8, y as u8, (x ^ y) as u8]));
+
+        let sizes: Vec<usize> = [PngCompression::Fast, PngCompression::Default, PngCompression::Best]
+            .into_iter()
+            .map(|png_compression| {
+                let options = EncodeOptions {
+                    png_compression,
+                    ..EncodeOptions::default()
+                };
+                let encoded = encode_image(img.clone(), ImageType::Png, options).unwrap();
+                let decoded = image::load_from_memory_with_format(&encoded, ImageFormat::Png).unwrap();
+                assert_eq!(decoded.into_rgb8(), img);
+                encoded.len()
+            })
+            .collect();
+
+        assert!(sizes[2] <= sizes[0]);
+    }
+
+    #[test]
+    fn quantized_png_is_smaller() {
+        let quantized = EncodeOptions {
+            png: PngMode::Quantized,
+            ..EncodeOptions::default()
+        };
+        // Noise is the worst case for deflate, where one byte per pixel instead of three shows the most.
+        let mut state = 0x2545_f491_u32;
+        let noise = RgbImage::from_fn(256, 256, |_, _| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let [r, g, b, _] = state.to_le_bytes();
+            Rgb([r, g, b])
+        });
+
+        let full = encode_image(noise.clone(), ImageType::Png, EncodeOptions::default()).unwrap();
+        let encoded = encode_image(noise, ImageType::Png, quantized).unwrap();
+
+        assert!(encoded.len() < full.len());
+    }
+
+    /// An image `width` pixels wide that takes `delay` milliseconds to "download".
+    async fn delayed(width: u32, delay: u64) -> Option<RgbImage> {
+        tokio::time::sleep(Duration::from_millis(delay)).await;
+        Some(RgbImage::new(width, 1))
+    }
+
+    #[tokio::test]
+    async fn doesnt_cache_images_that_stay_truncated() {
+        // A GIF decodes fine without its trailer, so this is the cut off download that still makes an image.
+        let mut gif = vec![];
+        let frame = image::Frame::new(image::RgbaImage::from_pixel(64, 64, image::Rgba([0, 128, 255, 255])));
+        image::codecs::gif::GifEncoder::new(&mut gif).encode_frame(frame).unwrap();
+        let truncated = Bytes::copy_from_slice(&gif[..gif.len() - 1]);
+        let kept = truncated.len();
+        let app = Router::new().route("/media/:id", get(move || async move { truncated }));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream = format!("http://{}/media", listener.local_addr().unwrap());
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+        let (client, cache) = (reqwest::Client::new(), ImageCache::new(4));
+        let budget = DownloadBudget::new(kept * 3);
+
+        let image = fetch_image(&client, &cache, &budget, &upstream, "a", SourceFormat::Jpg, u32::MAX).await;
+
+        assert_eq!(image.map(|image| image.dimensions()), Some((64, 64)));
+        assert_eq!(cache.get(&format!("{}/a?format=jpg&name=large", upstream)), None);
+        // Only the download that was kept counts towards the budget.
+        assert_eq!(budget.used.load(Ordering::Relaxed), kept);
+    }
+
+    fn widths(images: &[RgbImage]) -> Vec<u32> {
+        images.iter().map(|image| image.width()).collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn join_partial_waits_for_everything_by_default() {
+        let fetches = vec![delayed(1, 10), delayed(2, 5000), delayed(3, 20)];
+
+        let images = join_partial(fetches, 2, None).await;
+
+        assert_eq!(widths(&images), vec![1, 2, 3]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn join_partial_drops_slow_images_after_deadline() {
+        let fetches = vec![delayed(1, 10), delayed(2, 5000), delayed(3, 20), delayed(4, 30)];
+
+        let images = join_partial(fetches, 2, Some(Duration::from_millis(100))).await;
+
+        assert_eq!(widths(&images), vec![1, 3, 4]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn join_partial_cancels_fetches_it_stops_waiting_for() {
+        /// Counts how many fetches were dropped before they finished.
+        struct Unfinished(Arc<AtomicUsize>);
+        impl Drop for Unfinished {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        let unfinished = Arc::new(AtomicUsize::new(0));
+        let fetch = |width, delay| {
+            let guard = Unfinished(unfinished.clone());
+            async move {
+                let image = delayed(width, delay).await;
+                std::mem::forget(guard);
+                image
+            }
+        };
+        let fetches = vec![fetch(1, 10), fetch(2, 5000), fetch(3, 20), fetch(4, 60_000)];
+
+        let images = join_partial(fetches, 2, Some(Duration::from_millis(100))).await;
+
+        assert_eq!(widths(&images), vec![1, 3]);
+        // The slow downloads were dropped when it gave up on them, rather than left running in the background.
+        assert_eq!(unfinished.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn join_partial_waits_for_enough_images() {
+        let fetches = vec![delayed(1, 10), delayed(2, 500), delayed(3, 5000)];
+
+        let start = tokio::time::Instant::now();
+        let images = join_partial(fetches, 2, Some(Duration::from_millis(100))).await;
+
+        assert_eq!(widths(&images), vec![1, 2]);
+        assert_eq!(start.elapsed(), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn decodes_cmyk_jpegs() {
+        // Both fixtures are solid red, one stored inverted with an Adobe marker and one stored as is.
+        for fixture in [
+            &include_bytes!("../tests/fixtures/cmyk_adobe.jpg")[..],
+            &include_bytes!("../tests/fixtures/cmyk_plain.jpg")[..],
+        ] {
+            let decoded = decode_image(fixture).unwrap();
+
+            assert_eq!(decoded.dimensions(), (16, 16));
+            for pixel in decoded.pixels() {
+                assert!(pixel[0] > 250 && pixel[1] < 5 && pixel[2] < 5, "{:?}", pixel);
+            }
+        }
+    }
+
+    #[test]
+    fn scales_large_jpegs_while_decoding() {
+        let img = RgbImage::from_fn(800, 400, |x, y| Rgb([x as u8, y as u8, 128]));
+        let jpeg = encode_image(img.clone(), ImageType::Jpeg, EncodeOptions::default()).unwrap();
+        let png = encode_image(img, ImageType::Png, EncodeOptions::default()).unwrap();
+
+        // An eighth would be 100x50, too short, so it stops at a quarter.
+        assert_eq!(decode_image_within(&jpeg, 150).unwrap().dimensions(), (200, 100));
+        assert_eq!(decode_image_within(&jpeg, 100).unwrap().dimensions(), (100, 50));
+        assert_eq!(decode_image_within(&jpeg, 800).unwrap().dimensions(), (800, 400));
+        assert_eq!(decode_image_within(&png, 100).unwrap().dimensions(), (800, 400));
+
+        let decoded = decode_image_within(&jpeg, 200).unwrap();
+        let Rgb([r, g, _]) = *decoded.get_pixel(50, 25);
+        assert!(r.abs_diff(200) <= 8 && g.abs_diff(100) <= 8, "{}, {}", r, g);
+    }
+
+    #[test]
+    fn truncation_sniffing() {
+        let img = RgbImage::from_fn(64, 64, |x, y| Rgb([x as u8 * 4, y as u8 * 4, 0]));
+        for image_type in [ImageType::Jpeg, ImageType::Png, ImageType::Webp] {
+            let encoded = encode_image(img.clone(), image_type, EncodeOptions::default()).unwrap();
+            assert!(!is_truncated(&encoded));
+            assert!(is_truncated(&encoded[..encoded.len() / 2]));
+        }
+
+        let mut padded = encode_image(img, ImageType::Jpeg, EncodeOptions::default()).unwrap();
+        padded.extend([0; 16]);
+        assert!(!is_truncated(&padded));
+        assert!(!is_truncated(b"<html>Not Found</html>"));
+    }
+
+    #[test]
+    fn size_parsing() {
+        let size = parse_size("1200x675").unwrap();
+        assert_eq!((size.width, size.height), (1200, 675));
+        assert!(parse_size("1200").is_none());
+        assert!(parse_size("0x675").is_none());
+        assert!(parse_size("1200x-1").is_none());
+        assert!(parse_size("axb").is_none());
+    }
+
+    #[test]
+    fn weights_parsing() {
+        assert_eq!(parse_weights("2,1,1.5"), Some(vec![2.0, 1.0, 1.5]));
+        assert_eq!(parse_weights("3"), Some(vec![3.0]));
+        assert_eq!(parse_weights("2,,1"), None);
+        assert_eq!(parse_weights("2,0"), None);
+        assert_eq!(parse_weights("-1"), None);
+        assert_eq!(parse_weights("inf"), None);
+    }
+
+    #[test]
+    fn image_id_validation() {
+        assert!(is_valid_image_id("F3x-ebzWgAACauT"));
+        assert!(is_valid_image_id("Fa_b-0"));
+        assert!(!is_valid_image_id(""));
+        assert!(!is_valid_image_id(".."));
+        assert!(!is_valid_image_id("F3x ebz"));
+        assert!(!is_valid_image_id("F3x?format=png"));
+        assert!(!is_valid_image_id(&"a".repeat(33)));
+    }
+
+    #[test]
+    fn hex_colour_parsing() {
+        assert_eq!(parse_hex_colour("ff8000"), Some(Rgb([255, 128, 0])));
+        assert_eq!(parse_hex_colour("#00FF7f"), Some(Rgb([0, 255, 127])));
+        assert_eq!(parse_hex_colour("fff"), None);
+        assert_eq!(parse_hex_colour("gg0000"), None);
+        assert_eq!(parse_hex_colour("ff00é"), None);
+    }
+
+    #[test]
+    fn source_format_defaults_to_jpg() {
+        assert_eq!(SourceFormat::default(), SourceFormat::Jpg);
+    }
+
+    #[test]
+    fn decodes_both_source_formats() {
+        for image_type in [ImageType::Jpeg, ImageType::Png] {
+            let img = RgbImage::from_pixel(64, 48, Rgb([0, 0, 255]));
+            let encoded = encode_image(img, image_type, EncodeOptions::default()).unwrap();
+            let decoded = image::load_from_memory(&encoded).unwrap();
+
+            assert_eq!((decoded.width(), decoded.height()), (64, 48));
+        }
+    }
+
+    #[cfg(feature = "heif")]
+    #[test]
+    fn decodes_heif() {
+        use libheif_rs::{Channel, ColorSpace, CompressionFormat, EncoderQuality, HeifContext, Image, LibHeif, RgbChroma};
+
+        use crate::utils::is_heif;
+
+        let mut image = Image::new(64, 48, ColorSpace::Rgb(RgbChroma::C444)).unwrap();
+        for channel in [Channel::R, Channel::G, Channel::B] {
+            image.create_plane(channel, 64, 48, 8).unwrap();
+        }
+        let planes = image.planes_mut();
+        planes.r.unwrap().data.fill(0);
+        planes.g.unwrap().data.fill(0);
+        planes.b.unwrap().data.fill(255);
+        let lib_heif = LibHeif::new();
+        let mut encoder = lib_heif.encoder_for_format(CompressionFormat::Hevc).unwrap();
+        encoder.set_quality(EncoderQuality::LossLess).unwrap();
+        let mut context = HeifContext::new().unwrap();
+        context.encode_image(&image, &mut encoder, None).unwrap();
+        let encoded = context.write_to_bytes().unwrap();
+
+        assert!(is_heif(&encoded));
+        let decoded = decode_image(&encoded).unwrap();
+        assert_eq!(decoded.dimensions(), (64, 48));
+        let Rgb([red, green, blue]) = *decoded.get_pixel(32, 24);
+        assert!(red < 16 && green < 16 && blue > 239);
+        assert!(!is_heif(b"\0\0\0\x20ftypavif\0\0\0\0"));
+        assert!(!is_heif(b"ftyp"));
+    }
+
+    #[cfg(feature = "svg")]
+    #[test]
+    fn rasterizes_svg() {
+        use crate::utils::is_svg;
+
+        let svg = b"<?xml version=\"1.0\"?>\n<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"40\" height=\"20\">\
+            <rect width=\"20\" height=\"20\" fill=\"#f00\"/></svg>";
+        assert!(is_svg(svg));
+        assert!(!is_svg(b"<html><body>not found</body></html>"));
+        assert!(!is_svg(b"\x89PNG\r\n\x1a\n<svg"));
+
+        let decoded = decode_image(svg).unwrap();
+        assert_eq!(decoded.dimensions(), (MAX_SIZE, MAX_SIZE / 2));
+        assert_eq!(*decoded.get_pixel(MAX_SIZE / 4, MAX_SIZE / 4), Rgb([255, 0, 0]));
+        assert_eq!(*decoded.get_pixel(MAX_SIZE * 3 / 4, MAX_SIZE / 4), Rgb([255, 255, 255]));
+    }
+
+    #[test]
+    fn decodes_inline_images() {
+        let img = RgbImage::from_pixel(64, 48, Rgb([0, 0, 255]));
+        let encoded = encode_image(img, ImageType::Png, EncodeOptions::default()).unwrap();
+
+        for data in [
+            base64::encode_config(&encoded, base64::STANDARD),
+            base64::encode_config(&encoded, base64::URL_SAFE_NO_PAD),
+        ] {
+            let decoded = decode_inline_image(&data, MAX_SIZE).unwrap();
+            assert_eq!(decoded.dimensions(), (64, 48));
+        }
+        assert!(decode_inline_image("not base64!", MAX_SIZE).is_none());
+        assert!(decode_inline_image(&base64::encode(b"not an image"), MAX_SIZE).is_none());
+        assert!(decode_inline_image(&"A".repeat(MAX_IMAGE_SIZE / 3 * 4 + 8), MAX_SIZE).is_none());
+    }
+}